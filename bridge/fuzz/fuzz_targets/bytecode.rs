@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sha_bridge::shal::bytecode::Program;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(program) = Program::try_from(data) {
+        // Decoding is lossless, so encoding again gives back the same bytes
+        assert_eq!(Vec::<u8>::from(&program), data);
+    }
+});
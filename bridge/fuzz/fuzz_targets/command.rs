@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sha_bridge::controller::command::Command;
+
+fuzz_target!(|data: &[u8]| {
+    for b in data {
+        if let Ok(command) = Command::try_from(*b) {
+            let _: u8 = (&command).into();
+        }
+    }
+});
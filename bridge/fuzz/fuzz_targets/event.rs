@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sha_bridge::controller::event::Event;
+
+fuzz_target!(|data: &[u8]| {
+    for b in data {
+        if let Ok(event) = Event::try_from(*b) {
+            assert_eq!(*b, u8::from(&event));
+        }
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sha_bridge::controller::message::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::try_from(data) {
+        // Anything that decodes must also encode again
+        let _: Vec<u8> = (&message).into();
+    }
+});
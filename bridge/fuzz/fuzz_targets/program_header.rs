@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sha_bridge::controller::program_header::{ProgramHeader, PROGRAM_HEADER_LENGTH};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = ProgramHeader::try_from(data) {
+        let serialized: [u8; PROGRAM_HEADER_LENGTH] = (&header).into();
+        assert_eq!(&serialized[..], data);
+    }
+});
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::fmt::{Display, Formatter};
 
 #[derive(Parser, Debug)]
@@ -24,10 +24,37 @@ pub struct Args {
     #[arg(long, env = "SHA_SERIAL_DEVICE")]
     pub serial: Option<String>,
 
+    /// Write all raw serial frames to this capture file (JSON lines)
+    #[arg(long, requires = "serial", env = "SHA_CAPTURE")]
+    pub capture: Option<String>,
+
+    /// Replay a capture file instead of connecting to a serial device
+    #[arg(long, conflicts_with = "serial", env = "SHA_REPLAY")]
+    pub replay: Option<String>,
+
+    /// Run the program on a virtual controller with the inputs of the replayed capture,
+    /// instead of replaying what the controller sent
+    #[arg(
+        long,
+        requires_all = ["replay", "program"],
+        default_value_t = false,
+        env = "SHA_VIRTUAL_CONTROLLER"
+    )]
+    pub virtual_controller: bool,
+
     /// Program location
     #[arg(long, env = "SHAL_PROGRAM")]
     pub program: Option<String>,
 
+    /// Declarations file (HJSON, TOML or YAML), in addition to the declarations of the program
+    #[arg(long, requires = "program", env = "SHAL_DECLARATIONS")]
+    pub declarations: Option<String>,
+
+    /// Source map of the program on the controller (JSON), written after uploading the program,
+    /// used to show where in the program the failures of the controller happen
+    #[arg(long, env = "SHA_SOURCE_MAP")]
+    pub source_map: Option<String>,
+
     /// Determines whether we actually upload the program
     #[arg(long, default_value_t = false, env = "SHA_UPLOAD")]
     pub upload: bool,
@@ -36,6 +63,33 @@ pub struct Args {
     /// to MQTT
     #[arg(long, default_value_t = false, env = "SHA_ADVERTISE_NONVARS")]
     pub advertise_nonvars: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Format SHAL programs in place
+    Fmt {
+        /// Don't write the programs, fail if any of them is not formatted
+        #[arg(long, default_value_t = false)]
+        check: bool,
+
+        /// Programs to format
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Check whether two SHAL programs behave the same, and show where they don't
+    Compare {
+        /// The first program, e.g. before a change
+        first: String,
+
+        /// The second program
+        second: String,
+    },
+    /// Print the JSON schema of the declarations header of SHAL programs
+    Schema,
 }
 
 impl Display for Args {
@@ -63,12 +117,26 @@ impl Display for Args {
         }
         if let Some(serial) = &self.serial {
             writeln!(f, "  Serial port: {}", serial)?;
+            if let Some(capture) = &self.capture {
+                writeln!(f, "    capture: {}", capture)?;
+            }
+        } else if let Some(replay) = &self.replay {
+            writeln!(f, "  Serial: <replaying {}>", replay)?;
+            if self.virtual_controller {
+                writeln!(f, "    virtual controller: enabled")?;
+            }
         } else {
             writeln!(f, "  Serial: <disabled>")?;
         }
+        if let Some(source_map) = &self.source_map {
+            writeln!(f, "  Source map: {source_map}")?;
+        }
         if let Some(program) = &self.program {
             writeln!(f, "  Program:")?;
             writeln!(f, "    path: {program}")?;
+            if let Some(declarations) = &self.declarations {
+                writeln!(f, "    declarations: {declarations}")?;
+            }
             writeln!(
                 f,
                 "    upload: {}",
@@ -0,0 +1,8 @@
+use sha_bridge::shal::language_server;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    language_server::run().await;
+}
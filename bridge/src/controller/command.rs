@@ -1,4 +1,4 @@
-use crate::controller::command::Command::{Off, On, Toggle};
+use crate::controller::command::Command::{Off, On, Refresh, Toggle};
 use thiserror::Error;
 
 const COMMAND_TYPE_MASK: u8 = 0b1110_0000;
@@ -24,6 +24,13 @@ pub struct OutputOutOfRange;
 #[error("Error decoding command")]
 pub struct CommandDecodeError;
 
+/// A set of output (or flag) changes that the controller applies at once, in a single cycle
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OutputBatch {
+    pub mask: u32,
+    pub values: u32,
+}
+
 impl Command {
     pub fn refresh() -> Self {
         Command::Refresh
@@ -55,6 +62,43 @@ impl Command {
             Err(OutputOutOfRange)
         }
     }
+
+    /// The outputs after the controller handles the command, a refresh leaves them as they are
+    pub fn apply(&self, outputs: u32) -> u32 {
+        match self {
+            Refresh => outputs,
+            Toggle(output) => outputs ^ (1 << output),
+            Off(output) => outputs & !(1 << output),
+            On(output) => outputs | (1 << output),
+        }
+    }
+}
+
+impl OutputBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, output: u8, value: bool) -> Result<&mut Self, OutputOutOfRange> {
+        if output & OUTPUT_MASK != output {
+            return Err(OutputOutOfRange);
+        }
+        self.mask |= 1 << output;
+        if value {
+            self.values |= 1 << output;
+        } else {
+            self.values &= !(1 << output);
+        }
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mask == 0
+    }
+
+    pub fn apply(&self, outputs: u32) -> u32 {
+        (outputs & !self.mask) | (self.values & self.mask)
+    }
 }
 
 impl TryFrom<u8> for Command {
@@ -103,5 +147,38 @@ mod tests {
         let on: u8 = (&On(3)).into();
         assert_eq!(on, 0xC3);
         assert_eq!(Ok(On(3)), on.try_into());
+        assert_eq!(0b1010, Refresh.apply(0b1010));
+        assert_eq!(0b1000, Toggle(1).apply(0b1010));
+        assert_eq!(0b0010, Off(3).apply(0b1010));
+        assert_eq!(0b1011, On(0).apply(0b1010));
+    }
+
+    #[test]
+    fn test_decode_all_bytes() {
+        use crate::controller::command::Command;
+        for b in 0..=u8::MAX {
+            if let Ok(command) = Command::try_from(b) {
+                let encoded: u8 = (&command).into();
+                assert_eq!(Ok(command), Command::try_from(encoded));
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_batch() {
+        use crate::controller::command::{OutputBatch, OutputOutOfRange};
+        let mut batch = OutputBatch::new();
+        assert!(batch.is_empty());
+        batch.set(0, true).unwrap().set(4, false).unwrap();
+        batch.set(31, true).unwrap();
+        assert_eq!(Err(OutputOutOfRange), batch.set(32, true).map(|_| ()));
+        assert_eq!(
+            OutputBatch {
+                mask: 0x8000_0011,
+                values: 0x8000_0001,
+            },
+            batch
+        );
+        assert_eq!(0x8000_00E1, batch.apply(0x0000_00F0));
     }
 }
@@ -64,3 +64,18 @@ impl From<&Event> for u8 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::event::Event;
+
+    #[test]
+    fn test_decode_all_bytes() {
+        for b in 0..=u8::MAX {
+            if let Ok(event) = Event::try_from(b) {
+                let encoded: u8 = (&event).into();
+                assert_eq!(b, encoded);
+            }
+        }
+    }
+}
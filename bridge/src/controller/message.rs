@@ -1,12 +1,12 @@
 use static_assertions as sa;
 use std::string::FromUtf8Error;
 
-use crate::controller::command::{Command, CommandDecodeError};
+use crate::controller::command::{Command, CommandDecodeError, OutputBatch};
 use crate::controller::event::{Event, EventDecodeError};
 use crate::controller::message::MessageDecodingError::{
     CrcError, SizeTooLarge, SizeTooSmall, UnknownType,
 };
-use crate::controller::program_header::{ProgramHeader, ProgramHeaderDecodeError};
+use crate::controller::program_header::{Capabilities, ProgramHeader, ProgramHeaderDecodeError};
 use crc::{Crc, CRC_16_XMODEM};
 use thiserror::Error;
 
@@ -15,6 +15,9 @@ const MIN_MESSAGE_LENGTH: usize = MESSAGE_HEADER_LENGTH;
 const MAX_MESSAGE_LENGTH: usize = 128;
 pub const MAX_MESSAGE_BODY_LENGTH: usize = MAX_MESSAGE_LENGTH - MESSAGE_HEADER_LENGTH;
 
+/// Bit of the status message that is set when the MODE switch enables the program
+pub const STATUS_PROGRAM_ENABLED: u8 = 0b0000_0001;
+
 sa::const_assert_eq!(MIN_MESSAGE_LENGTH, 3);
 sa::const_assert_eq!(MAX_MESSAGE_BODY_LENGTH, 125);
 
@@ -55,9 +58,30 @@ pub enum MessageBody {
         outputs: u32,
         events: Vec<Event>,
     },
+    FullUpdate {
+        outputs: u32,
+        inputs: u32,
+        events: Vec<Event>,
+    },
     Command {
         commands: Vec<Command>,
     },
+    SetOutputs {
+        batch: OutputBatch,
+    },
+    Flags {
+        flags: u32,
+    },
+    Capabilities {
+        capabilities: Capabilities,
+    },
+    /// Whether the program runs, as set by the MODE switch of the controller
+    Status {
+        program_enabled: bool,
+    },
+    SetFlags {
+        batch: OutputBatch,
+    },
     Fail {
         message: String,
     },
@@ -95,7 +119,13 @@ impl MessageBody {
         use MessageBody::*;
         match self {
             Update { .. } => b'u',
+            FullUpdate { .. } => b'U',
             Command { .. } => b'c',
+            SetOutputs { .. } => b'o',
+            Flags { .. } => b'v',
+            Capabilities { .. } => b'C',
+            Status { .. } => b'm',
+            SetFlags { .. } => b'V',
             Fail { .. } => b'F',
             Info { .. } => b'I',
             ProgramStart { .. } => b's',
@@ -119,12 +149,35 @@ impl MessageBody {
                     digest.update(&[event_byte]);
                 }
             }
+            FullUpdate {
+                outputs,
+                inputs,
+                events,
+            } => {
+                digest.update(&outputs.to_be_bytes()[..]);
+                digest.update(&inputs.to_be_bytes()[..]);
+                for event in events {
+                    let event_byte: u8 = event.into();
+                    digest.update(&[event_byte]);
+                }
+            }
             Command { commands } => {
                 for command in commands {
                     let command_byte: u8 = command.into();
                     digest.update(&[command_byte]);
                 }
             }
+            SetOutputs { batch } | SetFlags { batch } => {
+                digest.update(&batch.mask.to_be_bytes()[..]);
+                digest.update(&batch.values.to_be_bytes()[..]);
+            }
+            Flags { flags } => digest.update(&flags.to_be_bytes()[..]),
+            Capabilities { capabilities } => digest.update(&[
+                capabilities.min_version,
+                capabilities.max_version,
+                capabilities.features,
+            ]),
+            Status { .. } => digest.update(&[self.status_byte()]),
             Fail { message } | Info { message } => digest.update(message.as_bytes()),
             ProgramStart { header } | ProgramStartAck { header } | ProgramEndAck { header } => {
                 let header_bytes: [u8; ProgramHeader::header_length()] = header.into();
@@ -136,6 +189,15 @@ impl MessageBody {
         }
         digest.finalize()
     }
+
+    fn status_byte(&self) -> u8 {
+        match self {
+            MessageBody::Status {
+                program_enabled: true,
+            } => STATUS_PROGRAM_ENABLED,
+            _ => 0,
+        }
+    }
 }
 
 impl TryFrom<&[u8]> for Message {
@@ -177,6 +239,23 @@ impl TryFrom<&[u8]> for Message {
                     body: MessageBody::Update { outputs, events },
                 })
             }
+            b'U' if body.len() >= 8 => {
+                let outputs = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let inputs = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                let mut events: Vec<Event> = vec![];
+                for b in &body[8..] {
+                    let e = (*b).try_into()?;
+                    events.push(e);
+                }
+                Ok(Message {
+                    crc: read_crc,
+                    body: MessageBody::FullUpdate {
+                        outputs,
+                        inputs,
+                        events,
+                    },
+                })
+            }
             b'c' => {
                 let mut commands: Vec<Command> = vec![];
                 for b in body {
@@ -187,6 +266,49 @@ impl TryFrom<&[u8]> for Message {
                     body: MessageBody::Command { commands },
                 })
             }
+            b'o' if body.len() == 8 => {
+                let mask = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let values = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                Ok(Message {
+                    crc: read_crc,
+                    body: MessageBody::SetOutputs {
+                        batch: OutputBatch { mask, values },
+                    },
+                })
+            }
+            b'v' if body.len() == 4 => Ok(Message {
+                crc: read_crc,
+                body: MessageBody::Flags {
+                    flags: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                },
+            }),
+            b'C' if body.len() == 3 => Ok(Message {
+                crc: read_crc,
+                body: MessageBody::Capabilities {
+                    capabilities: Capabilities {
+                        min_version: body[0],
+                        max_version: body[1],
+                        features: body[2],
+                    },
+                },
+            }),
+            // Bits that are not known yet are ignored
+            b'm' if body.len() == 1 => Ok(Message {
+                crc: read_crc,
+                body: MessageBody::Status {
+                    program_enabled: body[0] & STATUS_PROGRAM_ENABLED != 0,
+                },
+            }),
+            b'V' if body.len() == 8 => {
+                let mask = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let values = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                Ok(Message {
+                    crc: read_crc,
+                    body: MessageBody::SetFlags {
+                        batch: OutputBatch { mask, values },
+                    },
+                })
+            }
             b'F' => Ok(Message {
                 crc: read_crc,
                 body: MessageBody::Fail {
@@ -255,6 +377,19 @@ impl From<&MessageBody> for Vec<u8> {
                 }
                 result
             }
+            MessageBody::FullUpdate {
+                outputs,
+                inputs,
+                events,
+            } => {
+                let mut result = vec![];
+                result.extend_from_slice(&outputs.to_be_bytes());
+                result.extend_from_slice(&inputs.to_be_bytes());
+                for event in events {
+                    result.push(event.into());
+                }
+                result
+            }
             MessageBody::Command { commands } => {
                 let mut result = vec![];
                 for command in commands {
@@ -262,6 +397,19 @@ impl From<&MessageBody> for Vec<u8> {
                 }
                 result
             }
+            MessageBody::SetOutputs { batch } | MessageBody::SetFlags { batch } => {
+                let mut result = vec![];
+                result.extend_from_slice(&batch.mask.to_be_bytes());
+                result.extend_from_slice(&batch.values.to_be_bytes());
+                result
+            }
+            MessageBody::Flags { flags } => flags.to_be_bytes().into(),
+            MessageBody::Capabilities { capabilities } => vec![
+                capabilities.min_version,
+                capabilities.max_version,
+                capabilities.features,
+            ],
+            MessageBody::Status { .. } => vec![body.status_byte()],
             MessageBody::Fail { message } | MessageBody::Info { message } => {
                 message.as_bytes().into()
             }
@@ -292,9 +440,115 @@ impl Message {
 
 #[cfg(test)]
 mod tests {
+    use crate::controller::command::{Command, OutputBatch};
     use crate::controller::event::Event;
     use crate::controller::message::{Message, MessageBody};
-    use crate::controller::program_header::ProgramHeader;
+    use crate::controller::program_header::{Capabilities, ProgramHeader};
+    use proptest::prelude::*;
+
+    fn arb_event() -> impl Strategy<Value = Event> {
+        prop_oneof![
+            (0u8..32).prop_map(Event::RisingEdge),
+            (0u8..32).prop_map(Event::FallingEdge),
+        ]
+    }
+
+    fn arb_command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            Just(Command::Refresh),
+            (0u8..32).prop_map(Command::Toggle),
+            (0u8..32).prop_map(Command::Off),
+            (0u8..32).prop_map(Command::On),
+        ]
+    }
+
+    fn arb_header() -> impl Strategy<Value = ProgramHeader> {
+        (any::<u8>(), any::<u8>(), any::<u16>(), any::<u16>()).prop_map(
+            |(version, features, length, crc)| ProgramHeader::new(version, features, length, crc),
+        )
+    }
+
+    fn arb_text() -> impl Strategy<Value = String> {
+        // At most 31 chars of at most 4 bytes, so it always fits in a message body
+        prop::collection::vec(any::<char>(), 0..32).prop_map(String::from_iter)
+    }
+
+    fn arb_code() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..=Message::max_message_body_length())
+    }
+
+    fn arb_message_body() -> impl Strategy<Value = MessageBody> {
+        prop_oneof![
+            (any::<u32>(), prop::collection::vec(arb_event(), 0..=121))
+                .prop_map(|(outputs, events)| MessageBody::Update { outputs, events }),
+            (
+                any::<u32>(),
+                any::<u32>(),
+                prop::collection::vec(arb_event(), 0..=117)
+            )
+                .prop_map(|(outputs, inputs, events)| MessageBody::FullUpdate {
+                    outputs,
+                    inputs,
+                    events
+                }),
+            prop::collection::vec(arb_command(), 0..=125)
+                .prop_map(|commands| MessageBody::Command { commands }),
+            (any::<u32>(), any::<u32>()).prop_map(|(mask, values)| MessageBody::SetOutputs {
+                batch: OutputBatch { mask, values }
+            }),
+            any::<u32>().prop_map(|flags| MessageBody::Flags { flags }),
+            (any::<u8>(), any::<u8>(), any::<u8>()).prop_map(
+                |(min_version, max_version, features)| MessageBody::Capabilities {
+                    capabilities: Capabilities {
+                        min_version,
+                        max_version,
+                        features
+                    }
+                }
+            ),
+            any::<bool>().prop_map(|program_enabled| MessageBody::Status { program_enabled }),
+            (any::<u32>(), any::<u32>()).prop_map(|(mask, values)| MessageBody::SetFlags {
+                batch: OutputBatch { mask, values }
+            }),
+            arb_text().prop_map(|message| MessageBody::Fail { message }),
+            arb_text().prop_map(|message| MessageBody::Info { message }),
+            arb_header().prop_map(|header| MessageBody::ProgramStart { header }),
+            arb_header().prop_map(|header| MessageBody::ProgramStartAck { header }),
+            arb_code().prop_map(|code| MessageBody::ProgramData { code }),
+            arb_code().prop_map(|code| MessageBody::ProgramEnd { code }),
+            arb_header().prop_map(|header| MessageBody::ProgramEndAck { header }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(body in arb_message_body()) {
+            let message = Message::new(body);
+            let bytes: Vec<u8> = (&message).into();
+            prop_assert!(bytes.len() <= Message::max_message_length());
+            prop_assert_eq!(Ok(message), Message::try_from(&bytes[..]));
+        }
+
+        #[test]
+        fn prop_decode_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..140)) {
+            let _ = Message::try_from(&bytes[..]);
+        }
+
+        #[test]
+        fn prop_decode_arbitrary_body(type_byte in any::<u8>(), body in prop::collection::vec(any::<u8>(), 0..125)) {
+            // Arbitrary bodies with a correct CRC, so decoding gets past the CRC check
+            let crc = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+            let mut digest = crc.digest();
+            digest.update(&[type_byte]);
+            digest.update(&body);
+            let mut bytes = digest.finalize().to_be_bytes().to_vec();
+            bytes.push(type_byte);
+            bytes.extend_from_slice(&body);
+            if let Ok(message) = Message::try_from(&bytes[..]) {
+                prop_assert_eq!(type_byte, message.body.start_byte());
+            }
+        }
+    }
 
     #[test]
     fn test_update() {
@@ -311,14 +565,73 @@ mod tests {
         assert_eq!(Ok(message), message2);
     }
 
+    #[test]
+    fn test_full_update() {
+        let message = Message::new(MessageBody::FullUpdate {
+            outputs: 0xAABBCCDD,
+            inputs: 0x11223344,
+            events: vec![Event::RisingEdge(1), Event::FallingEdge(2)],
+        });
+        let bytes: Vec<u8> = (&message).into();
+        assert_eq!(
+            &bytes,
+            &[0x76, 0xE7, b'U', 0xAA, 0xBB, 0xCC, 0xDD, 0x11, 0x22, 0x33, 0x44, 0x61, 0x22,]
+        );
+        let message2 = (&bytes[..]).try_into();
+        assert_eq!(Ok(message), message2);
+    }
+
+    #[test]
+    fn test_set_outputs() {
+        let message = Message::new(MessageBody::SetOutputs {
+            batch: OutputBatch {
+                mask: 0x0000_00FF,
+                values: 0x0000_000F,
+            },
+        });
+        let bytes: Vec<u8> = (&message).into();
+        assert_eq!(
+            &bytes,
+            &[0xCB, 0x31, b'o', 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x0F]
+        );
+        let message2 = (&bytes[..]).try_into();
+        assert_eq!(Ok(message), message2);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let message = Message::new(MessageBody::Capabilities {
+            capabilities: Capabilities {
+                min_version: 1,
+                max_version: 2,
+                features: 0x03,
+            },
+        });
+        let bytes: Vec<u8> = (&message).into();
+        assert_eq!(&bytes, &[0x94, 0x71, b'C', 0x01, 0x02, 0x03]);
+        let message2 = (&bytes[..]).try_into();
+        assert_eq!(Ok(message), message2);
+    }
+
+    #[test]
+    fn test_status() {
+        let message = Message::new(MessageBody::Status {
+            program_enabled: true,
+        });
+        let bytes: Vec<u8> = (&message).into();
+        assert_eq!(&bytes, &[0x6D, 0x57, b'm', 0x01]);
+        let message2 = (&bytes[..]).try_into();
+        assert_eq!(Ok(message), message2);
+    }
+
     #[test]
     fn test_program_start() {
-        let header = ProgramHeader::new(0xAABB, 0xCCDD);
+        let header = ProgramHeader::new(0x01, 0x03, 0xAABB, 0xCCDD);
         let message = Message::new(MessageBody::ProgramStart { header });
         let bytes: Vec<u8> = (&message).into();
         assert_eq!(
             &bytes,
-            &[0xBB, 0x06, b's', b'S', b'H', b'A', b'L', 0xAA, 0xBB, 0xCC, 0xDD],
+            &[0xB2, 0xC1, b's', b'S', b'H', b'A', b'L', 0x01, 0x03, 0xAA, 0xBB, 0xCC, 0xDD],
         );
         let message2 = (&bytes[..]).try_into();
         assert_eq!(Ok(message), message2);
@@ -326,12 +639,12 @@ mod tests {
 
     #[test]
     fn test_program_start_ack() {
-        let header = ProgramHeader::new(0xAABB, 0xCCDD);
+        let header = ProgramHeader::new(0x01, 0x03, 0xAABB, 0xCCDD);
         let message = Message::new(MessageBody::ProgramStartAck { header });
         let bytes: Vec<u8> = (&message).into();
         assert_eq!(
             &bytes,
-            &[0x1C, 0xFA, b'S', b'S', b'H', b'A', b'L', 0xAA, 0xBB, 0xCC, 0xDD],
+            &[0x18, 0xBB, b'S', b'S', b'H', b'A', b'L', 0x01, 0x03, 0xAA, 0xBB, 0xCC, 0xDD],
         );
         let message2 = (&bytes[..]).try_into();
         assert_eq!(Ok(message), message2);
@@ -357,12 +670,12 @@ mod tests {
 
     #[test]
     fn test_program_end_ack() {
-        let header = ProgramHeader::new(0xAABB, 0xCCDD);
+        let header = ProgramHeader::new(0x01, 0x03, 0xAABB, 0xCCDD);
         let message = Message::new(MessageBody::ProgramEndAck { header });
         let bytes: Vec<u8> = (&message).into();
         assert_eq!(
             &bytes,
-            &[0x15, 0x8C, b'E', b'S', b'H', b'A', b'L', 0xAA, 0xBB, 0xCC, 0xDD],
+            &[0xBC, 0x72, b'E', b'S', b'H', b'A', b'L', 0x01, 0x03, 0xAA, 0xBB, 0xCC, 0xDD],
         );
         let message2 = (&bytes[..]).try_into();
         assert_eq!(Ok(message), message2);
@@ -1,4 +1,5 @@
 pub mod command;
 pub mod event;
 pub mod message;
+pub mod model;
 pub mod program_header;
@@ -0,0 +1,353 @@
+use crate::controller::command::Command;
+use crate::controller::event::Event;
+use crate::controller::message::MessageBody;
+use crate::controller::program_header::{Capabilities, ALL_FEATURES, PROGRAM_FORMAT_VERSION};
+use crate::shal::bytecode::Program;
+use crate::shal::interpreter::{run_program_with_memory, FixedBitSet, Memory};
+use std::time::Duration;
+
+/// How long a read input has to be stable before it's committed, see `DEBOUNCE_TIME_MILLIS`
+pub const DEBOUNCE_TIME: Duration = Duration::from_millis(30);
+
+const NB_INPUTS: u8 = 32;
+
+/// What the controller supports, see `SUPPORTED_FEATURES`
+const CAPABILITIES: Capabilities = Capabilities {
+    min_version: PROGRAM_FORMAT_VERSION,
+    max_version: PROGRAM_FORMAT_VERSION,
+    features: ALL_FEATURES,
+};
+
+/// Inputs after debouncing, inputs are high when the button is not pressed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct DebouncedInput {
+    current: u32,
+    previous: u32,
+    last_read: u32,
+    /// When the read value of each input last changed
+    timestamps: [Duration; NB_INPUTS as usize],
+}
+
+/// A model of the main loop of the controller (controller/src/main.cpp)
+///
+/// Every cycle handles at most one received message, debounces the inputs, reads the MODE switch,
+/// runs the program and sends updates, in the same order as the controller. Program uploads
+/// are not modeled, the program is given when the model is created.
+#[derive(Clone, Debug)]
+pub struct ControllerModel {
+    /// None if there's no program
+    program: Option<Program>,
+    /// Whether the MODE switch enabled the program in the last cycle, it's disabled until read
+    program_enabled: bool,
+    output: u32,
+    memory: Memory,
+    input: DebouncedInput,
+    /// The full state is sent at the end of the cycle
+    refresh: bool,
+}
+
+impl ControllerModel {
+    /// The controller after setup, the inputs are read once and committed without debouncing
+    pub fn new(program: Option<Program>, inputs: u32, now: Duration) -> Self {
+        ControllerModel {
+            program,
+            program_enabled: false,
+            output: 0,
+            memory: Memory::default(),
+            input: DebouncedInput {
+                current: inputs,
+                previous: inputs,
+                last_read: inputs,
+                timestamps: [now; NB_INPUTS as usize],
+            },
+            refresh: true,
+        }
+    }
+
+    pub fn outputs(&self) -> u32 {
+        self.output
+    }
+
+    /// The debounced inputs
+    pub fn inputs(&self) -> u32 {
+        self.input.current
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.memory.flags.into()
+    }
+
+    pub fn program_enabled(&self) -> bool {
+        self.program_enabled
+    }
+
+    /// Runs one cycle with the inputs and MODE switch read in it and the message received in it,
+    /// if any, and returns the messages the controller sends
+    pub fn cycle(
+        &mut self,
+        now: Duration,
+        inputs: u32,
+        program_enabled: bool,
+        message: Option<&MessageBody>,
+    ) -> Vec<MessageBody> {
+        let output_before = self.output;
+        let flags_before = self.flags();
+        let program_enabled_before = self.program_enabled;
+        if let Some(message) = message {
+            self.handle_message(message);
+        }
+        self.update_inputs(now, inputs);
+        self.program_enabled = program_enabled;
+        if let Some(program) = self.program.as_ref().filter(|_| program_enabled) {
+            self.output = run_program_with_memory(
+                program,
+                &mut self.memory,
+                now,
+                &self.input.previous.into(),
+                &self.input.current.into(),
+                &self.output.into(),
+            )
+            .into();
+        }
+        self.send_update(output_before, flags_before, program_enabled_before)
+    }
+
+    /// Commands are applied in order, messages that aren't meant for the controller are ignored
+    fn handle_message(&mut self, message: &MessageBody) {
+        match message {
+            MessageBody::Command { commands } => {
+                for command in commands {
+                    if *command == Command::Refresh {
+                        self.refresh = true;
+                    } else {
+                        self.output = command.apply(self.output);
+                    }
+                }
+            }
+            MessageBody::SetOutputs { batch } => self.output = batch.apply(self.output),
+            MessageBody::SetFlags { batch } => {
+                self.memory.flags = FixedBitSet::from(batch.apply(self.flags()));
+            }
+            _ => {}
+        }
+    }
+
+    /// An input is committed when it was read with the same value for the debounce time
+    fn update_inputs(&mut self, now: Duration, inputs: u32) {
+        let input = &mut self.input;
+        input.previous = input.current;
+        for i in 0..NB_INPUTS {
+            let bit = 1 << i;
+            let read_value = inputs & bit;
+            if read_value == input.current & bit {
+                continue;
+            }
+            if read_value == input.last_read & bit {
+                if now.saturating_sub(input.timestamps[i as usize]) >= DEBOUNCE_TIME {
+                    input.current = (input.current & !bit) | read_value;
+                }
+            } else {
+                input.timestamps[i as usize] = now;
+            }
+        }
+        input.last_read = inputs;
+    }
+
+    /// Input events are sent in the order of the inputs, then the flags, the status
+    /// and on refresh the capabilities, see `State::send_update`
+    fn send_update(
+        &mut self,
+        output_before: u32,
+        flags_before: u32,
+        program_enabled_before: bool,
+    ) -> Vec<MessageBody> {
+        let mut messages = vec![];
+        let input = &self.input;
+        if self.refresh || input.current != input.previous || self.output != output_before {
+            let events = (0..NB_INPUTS)
+                .filter_map(|i| {
+                    let before = input.previous & (1 << i) != 0;
+                    let after = input.current & (1 << i) != 0;
+                    match (before, after) {
+                        (false, true) => Some(Event::RisingEdge(i)),
+                        (true, false) => Some(Event::FallingEdge(i)),
+                        _ => None,
+                    }
+                })
+                .collect();
+            messages.push(MessageBody::FullUpdate {
+                outputs: self.output,
+                inputs: input.current,
+                events,
+            });
+        }
+        if self.refresh || self.flags() != flags_before {
+            messages.push(MessageBody::Flags {
+                flags: self.flags(),
+            });
+        }
+        if self.refresh || self.program_enabled != program_enabled_before {
+            messages.push(MessageBody::Status {
+                program_enabled: self.program_enabled,
+            });
+        }
+        if self.refresh {
+            messages.push(MessageBody::Capabilities {
+                capabilities: CAPABILITIES,
+            });
+        }
+        self.refresh = false;
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::command::{Command, OutputBatch};
+    use crate::controller::event::Event;
+    use crate::controller::message::MessageBody;
+    use crate::controller::model::{ControllerModel, CAPABILITIES};
+    use crate::shal::tests::compile_source;
+    use std::time::Duration;
+
+    const RELEASED: u32 = u32::MAX;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn full_update(outputs: u32, inputs: u32, events: Vec<Event>) -> MessageBody {
+        MessageBody::FullUpdate {
+            outputs,
+            inputs,
+            events,
+        }
+    }
+
+    fn status(program_enabled: bool) -> MessageBody {
+        MessageBody::Status { program_enabled }
+    }
+
+    fn capabilities() -> MessageBody {
+        MessageBody::Capabilities {
+            capabilities: CAPABILITIES,
+        }
+    }
+
+    /// Everything the controller sends on refresh
+    fn refreshed(outputs: u32, inputs: u32, flags: u32) -> Vec<MessageBody> {
+        vec![
+            full_update(outputs, inputs, vec![]),
+            MessageBody::Flags { flags },
+            status(true),
+            capabilities(),
+        ]
+    }
+
+    #[test]
+    fn test_debounce() {
+        let mut model = ControllerModel::new(None, RELEASED, Duration::ZERO);
+        assert_eq!(
+            model.cycle(ms(0), RELEASED, true, None),
+            refreshed(0, RELEASED, 0)
+        );
+        // Bouncing restarts the debounce time
+        for (now, inputs) in [(10, !1), (20, RELEASED), (25, !1), (54, !1)] {
+            assert_eq!(model.cycle(ms(now), inputs, true, None), vec![]);
+        }
+        assert_eq!(
+            model.cycle(ms(55), !1, true, None),
+            vec![full_update(0, !1, vec![Event::FallingEdge(0)])]
+        );
+        assert_eq!(model.inputs(), !1);
+        assert_eq!(model.cycle(ms(56), !1, true, None), vec![]);
+
+        // Inputs are debounced separately, and their events are sent together
+        model.cycle(ms(100), RELEASED & !0b110, true, None);
+        assert_eq!(
+            model.cycle(ms(130), RELEASED & !0b110, true, None),
+            vec![full_update(
+                0,
+                RELEASED & !0b110,
+                vec![
+                    Event::RisingEdge(0),
+                    Event::FallingEdge(1),
+                    Event::FallingEdge(2)
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_message_order() {
+        let program = compile_source("if output 1 is high { set output 2 high; }");
+        let mut model = ControllerModel::new(Some(program), RELEASED, Duration::ZERO);
+        model.cycle(ms(0), RELEASED, true, None);
+
+        // Commands are applied in order, before the program runs in the same cycle
+        let commands = MessageBody::Command {
+            commands: vec![Command::On(1), Command::Toggle(3), Command::Toggle(3)],
+        };
+        assert_eq!(
+            model.cycle(ms(1), RELEASED, true, Some(&commands)),
+            vec![full_update(0b110, RELEASED, vec![])]
+        );
+
+        // A refresh sends everything, even without changes
+        let refresh = MessageBody::Command {
+            commands: vec![Command::Refresh],
+        };
+        assert_eq!(
+            model.cycle(ms(2), RELEASED, true, Some(&refresh)),
+            refreshed(0b110, RELEASED, 0)
+        );
+
+        // Setting flags only sends the flags
+        let mut batch = OutputBatch::new();
+        batch.set(4, true).unwrap();
+        let set_flags = MessageBody::SetFlags { batch };
+        assert_eq!(
+            model.cycle(ms(3), RELEASED, true, Some(&set_flags)),
+            vec![MessageBody::Flags { flags: 0b1_0000 }]
+        );
+        assert_eq!(model.flags(), 0b1_0000);
+
+        // Messages from the controller are ignored
+        let info = MessageBody::Info {
+            message: "ignored".to_owned(),
+        };
+        assert_eq!(model.cycle(ms(4), RELEASED, true, Some(&info)), vec![]);
+        assert_eq!(model.outputs(), 0b110);
+    }
+
+    #[test]
+    fn test_mode() {
+        let program = compile_source("on fedge input 0 toggle output 0;");
+        let mut model = ControllerModel::new(Some(program), RELEASED, Duration::ZERO);
+        let mut refreshed_disabled = refreshed(0, RELEASED, 0);
+        refreshed_disabled[2] = status(false);
+        assert_eq!(
+            model.cycle(ms(0), RELEASED, false, None),
+            refreshed_disabled
+        );
+        assert!(!model.program_enabled());
+
+        // A disabled program doesn't run, the inputs are still debounced and sent
+        model.cycle(ms(10), !1, false, None);
+        assert_eq!(
+            model.cycle(ms(40), !1, false, None),
+            vec![full_update(0, !1, vec![Event::FallingEdge(0)])]
+        );
+
+        // The status is sent when the MODE switch changes, the program runs from then on
+        assert_eq!(model.cycle(ms(50), !1, true, None), vec![status(true)]);
+        model.cycle(ms(60), RELEASED, true, None);
+        model.cycle(ms(90), RELEASED, true, None);
+        model.cycle(ms(100), !1, true, None);
+        assert_eq!(
+            model.cycle(ms(130), !1, true, None),
+            vec![full_update(0b1, !1, vec![Event::FallingEdge(0)])]
+        );
+        assert_eq!(model.cycle(ms(140), !1, false, None), vec![status(false)]);
+    }
+}
@@ -1,19 +1,35 @@
 use crate::controller::program_header::ProgramHeaderDecodeError::{
     IncorrectHeaderSize, IncorrectPrefix,
 };
+use std::fmt::{Display, Formatter};
 use thiserror::Error;
 
-pub const PROGRAM_HEADER_LENGTH: usize = 8;
+pub const PROGRAM_HEADER_LENGTH: usize = 10;
+
+/// The version of the bytecode format, controllers refuse programs with a version they don't support
+pub const PROGRAM_FORMAT_VERSION: u8 = 1;
+
+/// Programs with timers use the start timer, cancel timer and if timer instructions
+pub const FEATURE_TIMERS: u8 = 0b0000_0001;
+/// Programs with flags use the set flag, toggle flag and if flag instructions
+pub const FEATURE_FLAGS: u8 = 0b0000_0010;
+/// Programs with compact code use the on toggle instruction
+pub const FEATURE_ON_TOGGLE: u8 = 0b0000_0100;
+/// All features this version of the bridge knows
+pub const ALL_FEATURES: u8 = FEATURE_TIMERS | FEATURE_FLAGS | FEATURE_ON_TOGGLE;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct ProgramHeader {
+    pub version: u8,
+    /// The features the program uses, a controller must support all of them to run it
+    pub features: u8,
     pub length: u16,
     pub crc: u16,
 }
 
 #[derive(Error, Debug, Eq, PartialEq)]
 pub enum ProgramHeaderDecodeError {
-    #[error("Incorrect header size: should be 8, was {actual_size}")]
+    #[error("Incorrect header size: should be {PROGRAM_HEADER_LENGTH}, was {actual_size}")]
     IncorrectHeaderSize { actual_size: usize },
     #[error(
         "Header does not start with \"SHAL\" ([83, 72, 65, 76]), but starts with {actual_prefix:?}"
@@ -25,7 +41,7 @@ impl TryFrom<&[u8]> for ProgramHeader {
     type Error = ProgramHeaderDecodeError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() != 8 {
+        if value.len() != PROGRAM_HEADER_LENGTH {
             return Err(IncorrectHeaderSize {
                 actual_size: value.len(),
             });
@@ -36,9 +52,11 @@ impl TryFrom<&[u8]> for ProgramHeader {
                 actual_prefix: prefix.into(),
             });
         }
-        let len_bytes = &value[4..6];
-        let crc_bytes = &value[6..8];
+        let len_bytes = &value[6..8];
+        let crc_bytes = &value[8..10];
         Ok(ProgramHeader {
+            version: value[4],
+            features: value[5],
             length: u16::from_be_bytes([len_bytes[0], len_bytes[1]]),
             crc: u16::from_be_bytes([crc_bytes[0], crc_bytes[1]]),
         })
@@ -54,6 +72,8 @@ impl From<&ProgramHeader> for [u8; PROGRAM_HEADER_LENGTH] {
             b'H',
             b'A',
             b'L',
+            value.version,
+            value.features,
             len_bytes[0],
             len_bytes[1],
             crc_bytes[0],
@@ -67,8 +87,37 @@ impl ProgramHeader {
         PROGRAM_HEADER_LENGTH
     }
 
-    pub fn new(length: u16, crc: u16) -> Self {
-        ProgramHeader { length, crc }
+    pub fn new(version: u8, features: u8, length: u16, crc: u16) -> Self {
+        ProgramHeader {
+            version,
+            features,
+            length,
+            crc,
+        }
+    }
+
+    /// Whether a controller that reports these capabilities can run the program
+    pub fn is_supported(&self, capabilities: &Capabilities) -> bool {
+        (capabilities.min_version..=capabilities.max_version).contains(&self.version)
+            && self.features & !capabilities.features == 0
+    }
+}
+
+/// The program versions and features a controller supports, it reports them on refresh
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub features: u8,
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "versions {} to {}, features {:#010b}",
+            self.min_version, self.max_version, self.features
+        )
     }
 }
 
@@ -78,28 +127,53 @@ mod tests {
         IncorrectHeaderSize, IncorrectPrefix,
     };
     use crate::controller::program_header::{
-        ProgramHeader, ProgramHeaderDecodeError, PROGRAM_HEADER_LENGTH,
+        Capabilities, ProgramHeader, ProgramHeaderDecodeError, FEATURE_FLAGS, FEATURE_TIMERS,
+        PROGRAM_HEADER_LENGTH,
     };
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(
+            version in any::<u8>(),
+            features in any::<u8>(),
+            length in any::<u16>(),
+            crc in any::<u16>(),
+        ) {
+            let header = ProgramHeader::new(version, features, length, crc);
+            let serialized: [u8; PROGRAM_HEADER_LENGTH] = (&header).into();
+            prop_assert_eq!(Ok(header), ProgramHeader::try_from(&serialized[..]));
+        }
+
+        #[test]
+        fn prop_decode_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..16)) {
+            let _ = ProgramHeader::try_from(&bytes[..]);
+        }
+    }
 
     #[test]
     fn test_serialize() {
         let header = ProgramHeader {
+            version: 1,
+            features: 3,
             length: 1000,
             crc: 2000,
         };
         let serialized: [u8; PROGRAM_HEADER_LENGTH] = (&header).into();
         assert_eq!(
-            &[b'S', b'H', b'A', b'L', 0x03, 0xE8, 0x07, 0xD0],
+            &[b'S', b'H', b'A', b'L', 0x01, 0x03, 0x03, 0xE8, 0x07, 0xD0],
             &serialized,
         )
     }
 
     #[test]
     fn test_deserialize() {
-        let serialized = [b'S', b'H', b'A', b'L', 0x03, 0xE8, 0x07, 0xD0];
+        let serialized = [b'S', b'H', b'A', b'L', 0x01, 0x03, 0x03, 0xE8, 0x07, 0xD0];
         let header: Result<ProgramHeader, ProgramHeaderDecodeError> = (&serialized[..]).try_into();
         assert_eq!(
             &Ok(ProgramHeader {
+                version: 1,
+                features: 3,
                 length: 1000,
                 crc: 2000,
             }),
@@ -109,18 +183,31 @@ mod tests {
 
     #[test]
     fn test_deserialize_incorrect_length() {
-        let serialized = [b'S', b'H', b'A', b'L', 1, 2, 3];
+        let serialized = [b'S', b'H', b'A', b'L', 1, 2, 3, 4, 5];
         let result: Result<ProgramHeader, ProgramHeaderDecodeError> = (&serialized[..]).try_into();
-        assert_eq!(&Err(IncorrectHeaderSize { actual_size: 7 }), &result);
+        assert_eq!(&Err(IncorrectHeaderSize { actual_size: 9 }), &result);
         assert_eq!(
-            "Incorrect header size: should be 8, was 7",
+            "Incorrect header size: should be 10, was 9",
             result.unwrap_err().to_string()
         )
     }
 
+    #[test]
+    fn test_is_supported() {
+        let capabilities = Capabilities {
+            min_version: 1,
+            max_version: 2,
+            features: FEATURE_TIMERS,
+        };
+        assert!(ProgramHeader::new(1, 0, 1, 0).is_supported(&capabilities));
+        assert!(ProgramHeader::new(2, FEATURE_TIMERS, 1, 0).is_supported(&capabilities));
+        assert!(!ProgramHeader::new(3, 0, 1, 0).is_supported(&capabilities));
+        assert!(!ProgramHeader::new(1, FEATURE_FLAGS, 1, 0).is_supported(&capabilities));
+    }
+
     #[test]
     fn test_deserialize_wrong_prefix() {
-        let serialized = [1, 2, 3, 4, 5, 6, 7, 8];
+        let serialized = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
         let result: Result<ProgramHeader, ProgramHeaderDecodeError> = (&serialized[..]).try_into();
         assert_eq!(
             &Err(IncorrectPrefix {
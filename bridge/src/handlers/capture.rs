@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::Instant;
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Frame received from the controller
+    Rx,
+    /// Frame sent to the controller
+    Tx,
+}
+
+/// One raw SLIP frame, as written to a capture file (one JSON object per line)
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CaptureRecord {
+    /// Milliseconds since the start of the capture
+    pub elapsed_ms: u64,
+    pub direction: Direction,
+    /// Frame contents (decoded SLIP, so CRC + type + body), hex encoded
+    pub frame: String,
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("Error accessing capture file")]
+    IOError(#[from] std::io::Error),
+    #[error("Malformed capture record on line {line}")]
+    JsonError {
+        line: usize,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Malformed frame on line {line}, expected an even number of hex digits")]
+    InvalidHex { line: usize },
+}
+
+pub struct CaptureWriter {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl CaptureRecord {
+    pub fn new(elapsed: Duration, direction: Direction, frame: &[u8]) -> Self {
+        CaptureRecord {
+            elapsed_ms: elapsed.as_millis() as u64,
+            direction,
+            frame: encode_hex(frame),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.elapsed_ms)
+    }
+
+    pub fn frame_bytes(&self) -> Option<Vec<u8>> {
+        decode_hex(&self.frame)
+    }
+}
+
+impl CaptureWriter {
+    pub async fn create(path: &str) -> Result<Self, CaptureError> {
+        let file = File::create(path).await?;
+        Ok(CaptureWriter {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub async fn record(&mut self, direction: Direction, frame: &[u8]) -> Result<(), CaptureError> {
+        let record = CaptureRecord::new(self.start.elapsed(), direction, frame);
+        let mut line = serde_json::to_string(&record).unwrap_or_else(|_| unreachable!());
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+        // Flush every frame, so the capture survives the bridge being killed
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Parses the contents of a capture file, skipping empty lines
+pub fn parse_capture(contents: &str) -> Result<Vec<CaptureRecord>, CaptureError> {
+    let mut records = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: CaptureRecord =
+            serde_json::from_str(line).map_err(|source| CaptureError::JsonError {
+                line: i + 1,
+                source,
+            })?;
+        if record.frame_bytes().is_none() {
+            return Err(CaptureError::InvalidHex { line: i + 1 });
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(result, "{b:02x}").unwrap_or_else(|_| unreachable!());
+    }
+    result
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::capture::{parse_capture, CaptureError, CaptureRecord, Direction};
+    use std::time::Duration;
+
+    #[test]
+    fn test_record() {
+        let record = CaptureRecord::new(
+            Duration::from_millis(1500),
+            Direction::Rx,
+            &[0xBF, 0x45, b'u', 0xAA, 0xBB, 0xCC, 0xDD, 0x61, 0x22],
+        );
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            r#"{"elapsed_ms":1500,"direction":"rx","frame":"bf4575aabbccdd6122"}"#,
+            line
+        );
+        assert_eq!(
+            Some(vec![0xBF, 0x45, b'u', 0xAA, 0xBB, 0xCC, 0xDD, 0x61, 0x22]),
+            record.frame_bytes()
+        );
+    }
+
+    #[test]
+    fn test_parse_capture() {
+        let records = parse_capture(
+            "{\"elapsed_ms\":0,\"direction\":\"tx\",\"frame\":\"f0a26320\"}\n\
+             \n\
+             {\"elapsed_ms\":12,\"direction\":\"rx\",\"frame\":\"bf4575aabbccdd6122\"}\n",
+        )
+        .unwrap();
+        assert_eq!(2, records.len());
+        assert_eq!(Direction::Tx, records[0].direction);
+        assert_eq!(Duration::from_millis(12), records[1].elapsed());
+
+        let result = parse_capture("{\"elapsed_ms\":0,\"direction\":\"rx\",\"frame\":\"abc\"}");
+        assert!(matches!(result, Err(CaptureError::InvalidHex { line: 1 })));
+        let result = parse_capture("\n{\"elapsed_ms\":0}");
+        assert!(matches!(
+            result,
+            Err(CaptureError::JsonError { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::controller::message::MessageBody::{Fail, ProgramEndAck};
+use crate::controller::program_header::ProgramHeader;
+use crate::handlers::message::Message;
+use crate::handlers::message::Message::ReceivedFromController;
+use crate::shal::source_map::{error_offset, SourceMap};
+use anyhow::Result;
+use log::{error, info, warn};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError::{Closed, Lagged};
+use tokio::sync::broadcast::Receiver;
+use tokio_util::sync::CancellationToken;
+
+/// Logs the failures of the controller, with the place in the SHAL source
+/// when the source map of the program on the controller is known
+struct FailLogger {
+    cancellation_token: CancellationToken,
+    rx: Receiver<Message>,
+    /// The source map of the program the bridge was started with
+    compiled: Option<SourceMap>,
+    /// Where the source map of the program on the controller is stored
+    path: Option<PathBuf>,
+    /// The source map of the program on the controller, if known
+    current: Option<SourceMap>,
+    /// Whether a program header from the controller matched the current source map,
+    /// a stored source map is only assumed to match until then
+    verified: bool,
+}
+
+pub async fn run(
+    cancellation_token: CancellationToken,
+    rx: Receiver<Message>,
+    compiled: Option<SourceMap>,
+    path: Option<String>,
+) -> Result<()> {
+    let path = path.map(PathBuf::from);
+    let current = match &path {
+        Some(path) => read_source_map(path).await,
+        None => None,
+    };
+    if let (Some(path), Some(_)) = (&path, &current) {
+        info!(
+            "Using source map {}, unverified until the controller acknowledges a program",
+            path.display()
+        );
+    }
+    let mut fail_logger = FailLogger {
+        cancellation_token,
+        rx,
+        compiled,
+        path,
+        current,
+        verified: false,
+    };
+    fail_logger.run().await;
+    Ok(())
+}
+
+/// A missing or unreadable source map only means failures can't be mapped to the source
+async fn read_source_map(path: &Path) -> Option<SourceMap> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read source map {}: {e}", path.display());
+            return None;
+        }
+    };
+    serde_json::from_str(&contents)
+        .inspect_err(|e| warn!("Failed to parse source map {}: {e}", path.display()))
+        .ok()
+}
+
+async fn write_source_map(path: &Path, source_map: &SourceMap) -> Result<()> {
+    let json = serde_json::to_string_pretty(source_map)?;
+    tokio::fs::write(path, json).await?;
+    Ok(())
+}
+
+impl FailLogger {
+    async fn run(&mut self) {
+        info!("Starting fail logger...");
+        loop {
+            select! {
+                _ = self.cancellation_token.cancelled() => {
+                    info!("Fail logger shutting down...");
+                    break
+                }
+                message = self.rx.recv() => {
+                    match message {
+                        Ok(ReceivedFromController(Fail { message })) => self.log_failure(&message),
+                        Ok(ReceivedFromController(ProgramEndAck { header })) => {
+                            self.program_uploaded(&header).await
+                        }
+                        Ok(_) => {}
+                        Err(Closed) => {
+                            info!("Fail logger can't receive any more messages, since there are no more senders.");
+                            break;
+                        }
+                        Err(Lagged(num_messages)) => {
+                            error!("Fail logger lagged behind {num_messages}!");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn log_failure(&self, message: &str) {
+        error!("{}", self.describe_failure(message));
+    }
+
+    fn describe_failure(&self, message: &str) -> String {
+        let location =
+            error_offset(message).and_then(|offset| self.current.as_ref()?.lookup(offset));
+        match location {
+            Some(location) if self.verified => {
+                format!("Controller failed: {message}, in {location}")
+            }
+            // The controller may have been reprogrammed without the bridge since the map was stored
+            Some(location) => {
+                format!("Controller failed: {message}, in {location} (unverified source map)")
+            }
+            None => format!("Controller failed: {message}"),
+        }
+    }
+
+    /// A new program is on the controller, keep its source map if it's the one we compiled
+    async fn program_uploaded(&mut self, header: &ProgramHeader) {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| current.matches(header.crc, header.length))
+        {
+            self.verified = true;
+            return;
+        }
+        self.current = self
+            .compiled
+            .clone()
+            .filter(|compiled| compiled.matches(header.crc, header.length));
+        self.verified = self.current.is_some();
+        let (Some(path), Some(current)) = (&self.path, &self.current) else {
+            return;
+        };
+        match write_source_map(path, current).await {
+            Ok(()) => info!("Stored source map in {}", path.display()),
+            Err(e) => warn!("Failed to store source map {}: {e}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::fail_logger::FailLogger;
+    use crate::shal::source_map::SourceMap;
+    use crate::shal::tests::compile_source;
+    use std::path::Path;
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn test_stored_source_map() {
+        let program = compile_source(
+            "set output 0 high;
+toggle output 1;
+",
+        );
+        let source_map = SourceMap::new(&program, Path::new("main.shal"));
+        let (_tx, rx) = broadcast::channel(1);
+        let mut fail_logger = FailLogger {
+            cancellation_token: CancellationToken::new(),
+            rx,
+            compiled: None,
+            path: None,
+            current: Some(source_map),
+            verified: false,
+        };
+        let message = "Unknown instruction at byte 2";
+        assert_eq!(
+            "Controller failed: Unknown instruction at byte 2, in main.shal, line 2, col 1 \
+             (unverified source map)",
+            fail_logger.describe_failure(message)
+        );
+
+        // The controller acknowledged the program of the stored source map
+        fail_logger.program_uploaded(&program.header()).await;
+        assert_eq!(
+            "Controller failed: Unknown instruction at byte 2, in main.shal, line 2, col 1",
+            fail_logger.describe_failure(message)
+        );
+    }
+}
@@ -1,7 +1,10 @@
+pub mod capture;
+pub mod fail_logger;
 pub mod logger;
 pub mod message;
 pub mod mqtt_handler;
 pub mod programmer;
 pub mod refresher;
+pub mod replayer;
 pub mod serial_handler;
 pub mod ctrlc_handler;
@@ -1,9 +1,9 @@
-use crate::controller::command::Command;
+use crate::controller::command::{Command, OutputBatch};
 use crate::controller::event::Event;
 use crate::controller::message::MessageBody;
 use crate::handlers::message::Message;
 use crate::handlers::message::Message::ReceivedFromController;
-use crate::shal::ast::PinID;
+use crate::shal::ast::{EntityID, GroupDeclaration, IODeclaration, PinID};
 use crate::shal::bytecode::Program;
 use if_chain::if_chain;
 use rumqttc::{
@@ -12,7 +12,7 @@ use rumqttc::{
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::panic;
-use log::warn;
+use log::{error, warn};
 use thiserror::Error;
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::task::JoinSet;
@@ -53,7 +53,7 @@ pub enum MqttHandlerError {
     #[error("MQTT client error")]
     ClientError(#[from] ClientError),
     #[error("MQTT connection error")]
-    ConnectionError(#[from] ConnectionError),
+    ConnectionError(#[from] Box<ConnectionError>),
 }
 
 impl MqttHandler {
@@ -131,8 +131,10 @@ impl MqttHandler {
                 unique_id: self.config.unique_input_id(i),
                 name: self.config.input_name(i),
                 icon: "mdi:light-switch-off".to_string(),
-                state_topic: state_topic.clone(),
+                state_topic,
             };
+            // No initial state is published: it follows from the input word in the
+            // first full update, which the refresher requests on startup
             self.client
                 .publish(
                     discovery_topic,
@@ -141,9 +143,6 @@ impl MqttHandler {
                     serde_json::to_string(&spec).unwrap(),
                 )
                 .await?;
-            self.client
-                .publish(state_topic, QoS::AtLeastOnce, false, "OFF")
-                .await?;
         }
         // Announce lights
         for i in 0..32 {
@@ -178,6 +177,61 @@ impl MqttHandler {
                 .publish(state_topic, QoS::AtLeastOnce, false, "OFF")
                 .await?;
         }
+        // Announce groups as lights that switch all of their outputs
+        for (id, declaration) in self.config.groups() {
+            let prefix = self.config.group_prefix(id);
+            let discovery_topic = format!("{}/config", prefix);
+            let spec = LightSpec {
+                unique_id: format!("{}_group_{}", self.config.options.client_id(), id),
+                name: declaration.name.clone().unwrap_or_else(|| id.to_string()),
+                state_topic: format!("{}/status", prefix),
+                command_topic: format!("{}/switch", prefix),
+            };
+            self.client
+                .publish(
+                    discovery_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_string(&spec).unwrap(),
+                )
+                .await?;
+        }
+        // Announce flags as switches, only declared flags are announced
+        for (id, pin, declaration) in self.config.flags() {
+            let prefix = self.config.flag_prefix(pin);
+            let discovery_topic = format!("{}/config", prefix);
+            let spec = SwitchSpec {
+                unique_id: format!("{}_flag_{}", self.config.options.client_id(), id),
+                name: declaration.name.clone().unwrap_or_else(|| id.to_string()),
+                state_topic: format!("{}/state", prefix),
+                command_topic: format!("{}/set", prefix),
+            };
+            self.client
+                .publish(
+                    discovery_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_string(&spec).unwrap(),
+                )
+                .await?;
+        }
+        // Announce the MODE switch of the controller, which enables or disables the program
+        let prefix = self.config.program_enabled_prefix();
+        let spec = BinarySensorSpec {
+            unique_id: format!("{}_program_enabled", self.config.options.client_id()),
+            name: "Program enabled".to_string(),
+            icon: "mdi:script-text-play".to_string(),
+            state_topic: format!("{}/state", prefix),
+        };
+        // The state follows from the status message the controller sends on refresh
+        self.client
+            .publish(
+                format!("{}/config", prefix),
+                QoS::AtLeastOnce,
+                false,
+                serde_json::to_string(&spec).unwrap(),
+            )
+            .await?;
         Ok(())
     }
 
@@ -187,7 +241,16 @@ impl MqttHandler {
             self.config.prefix,
             self.config.options.client_id()
         );
-        self.client.subscribe(topic, QoS::AtLeastOnce).await
+        self.client.subscribe(topic, QoS::AtLeastOnce).await?;
+        let topic = format!(
+            "{}/switch/{}/+/set",
+            self.config.prefix,
+            self.config.options.client_id()
+        );
+        self.client.subscribe(topic, QoS::AtLeastOnce).await?;
+        self.client
+            .subscribe(self.config.set_outputs_topic(), QoS::AtLeastOnce)
+            .await
     }
 
     pub async fn run(mut self) -> Result<(), MqttHandlerError> {
@@ -233,45 +296,135 @@ impl MqttHandler {
         &mut self,
         body: &MessageBody,
     ) -> Result<(), ClientError> {
-        if let MessageBody::Update { outputs, events } = body {
-            for i in 0..32 {
-                let state_topic = format!(
-                    "{}/light/{}/{}/status",
-                    self.config.prefix,
-                    self.config.options.client_id(),
-                    i
-                );
-                self.client
-                    .publish(
-                        state_topic,
-                        QoS::AtLeastOnce,
-                        false,
-                        if (outputs & (1 << i)) == 0 {
-                            "OFF"
-                        } else {
-                            "ON"
-                        },
-                    )
-                    .await?;
+        match body {
+            MessageBody::Update { outputs, events } => {
+                self.publish_outputs(*outputs).await?;
+                self.publish_events(events).await?;
+            }
+            MessageBody::FullUpdate {
+                outputs, inputs, ..
+            } => {
+                // The input word already reflects all events
+                self.publish_outputs(*outputs).await?;
+                self.publish_inputs(*inputs).await?;
             }
-            for event in events {
-                let (i, state) = match event {
-                    Event::RisingEdge(i) => (i, "OFF"),
-                    Event::FallingEdge(i) => (i, "ON"),
-                };
-                let state_topic = format!(
-                    "{}/binary_sensor/{}/{}/pressed",
-                    self.config.prefix,
-                    self.config.options.client_id(),
-                    i
-                );
-                self.client
-                    .publish(state_topic, QoS::AtLeastOnce, false, state)
-                    .await?;
+            MessageBody::Flags { flags } => self.publish_flags(*flags).await?,
+            MessageBody::Status { program_enabled } => {
+                self.publish_program_enabled(*program_enabled).await?
             }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn publish_outputs(&mut self, outputs: u32) -> Result<(), ClientError> {
+        for i in 0..32 {
+            let state_topic = format!(
+                "{}/light/{}/{}/status",
+                self.config.prefix,
+                self.config.options.client_id(),
+                i
+            );
+            self.client
+                .publish(
+                    state_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    if (outputs & (1 << i)) == 0 {
+                        "OFF"
+                    } else {
+                        "ON"
+                    },
+                )
+                .await?;
+        }
+        self.publish_groups(outputs).await
+    }
+
+    /// A group is on when any of its outputs is on
+    async fn publish_groups(&mut self, outputs: u32) -> Result<(), ClientError> {
+        let states: Vec<_> = self
+            .config
+            .groups()
+            .map(|(id, _)| {
+                let group_outputs = self.config.group_outputs(id).unwrap_or_default();
+                let on = group_outputs
+                    .iter()
+                    .any(|&pin| outputs & (1 << u8::from(pin)) != 0);
+                (format!("{}/status", self.config.group_prefix(id)), on)
+            })
+            .collect();
+        for (state_topic, on) in states {
+            self.client
+                .publish(
+                    state_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    if on { "ON" } else { "OFF" },
+                )
+                .await?;
         }
         Ok(())
     }
+
+    async fn publish_flags(&mut self, flags: u32) -> Result<(), ClientError> {
+        let state_topics: Vec<_> = self
+            .config
+            .flags()
+            .map(|(_, pin, _)| (pin, format!("{}/state", self.config.flag_prefix(pin))))
+            .collect();
+        for (pin, state_topic) in state_topics {
+            let state = if flags & (1 << u8::from(pin)) == 0 {
+                "OFF"
+            } else {
+                "ON"
+            };
+            self.client
+                .publish(state_topic, QoS::AtLeastOnce, false, state)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn publish_program_enabled(&mut self, program_enabled: bool) -> Result<(), ClientError> {
+        let state_topic = format!("{}/state", self.config.program_enabled_prefix());
+        let state = if program_enabled { "ON" } else { "OFF" };
+        self.client
+            .publish(state_topic, QoS::AtLeastOnce, false, state)
+            .await
+    }
+
+    async fn publish_inputs(&mut self, inputs: u32) -> Result<(), ClientError> {
+        for i in 0..32 {
+            // Inputs are pulled low while pressed
+            let state = if (inputs & (1 << i)) == 0 { "ON" } else { "OFF" };
+            self.publish_input_state(i, state).await?;
+        }
+        Ok(())
+    }
+
+    async fn publish_events(&mut self, events: &[Event]) -> Result<(), ClientError> {
+        for event in events {
+            let (i, state) = match event {
+                Event::RisingEdge(i) => (*i, "OFF"),
+                Event::FallingEdge(i) => (*i, "ON"),
+            };
+            self.publish_input_state(i, state).await?;
+        }
+        Ok(())
+    }
+
+    async fn publish_input_state(&mut self, input: u8, state: &str) -> Result<(), ClientError> {
+        let state_topic = format!(
+            "{}/binary_sensor/{}/{}/pressed",
+            self.config.prefix,
+            self.config.options.client_id(),
+            input
+        );
+        self.client
+            .publish(state_topic, QoS::AtLeastOnce, false, state)
+            .await
+    }
 }
 
 impl MqttHandlerConfig {
@@ -387,6 +540,166 @@ impl MqttHandlerConfig {
         let output_id = self.output_id(pin);
         format!("{client_id}_output_{output_id}")
     }
+
+    fn groups(&self) -> impl Iterator<Item = (&EntityID, &GroupDeclaration)> {
+        self.program
+            .iter()
+            .flat_map(|program| program.declarations.groups.iter())
+    }
+
+    fn group_prefix(&self, id: &EntityID) -> String {
+        format!(
+            "{}/light/{}/group_{}",
+            self.prefix,
+            self.options.client_id(),
+            id
+        )
+    }
+
+    fn group_outputs(&self, id: &EntityID) -> Option<Vec<PinID>> {
+        let declarations = &self.program.as_ref()?.declarations;
+        declarations
+            .groups
+            .get(id)?
+            .outputs
+            .iter()
+            .map(|output| declarations.outputs.get(output).map(|d| d.pin))
+            .collect()
+    }
+
+    /// Turns a message on the command topic of a group into a batch
+    /// that switches all outputs of the group
+    fn group_batch(&self, topic: &str, payload: &str) -> Option<OutputBatch> {
+        let prefix = format!("{}/light/{}/group_", self.prefix, self.options.client_id());
+        let id: EntityID = topic
+            .strip_prefix(&prefix)?
+            .strip_suffix("/switch")?
+            .try_into()
+            .ok()?;
+        let value = match payload {
+            "ON" => true,
+            "OFF" => false,
+            _ => return None,
+        };
+        let mut batch = OutputBatch::new();
+        for pin in self.group_outputs(&id)? {
+            batch
+                .set(pin.into(), value)
+                .unwrap_or_else(|_| unreachable!());
+        }
+        Some(batch)
+    }
+
+    fn flags(&self) -> impl Iterator<Item = (&EntityID, PinID, &IODeclaration)> {
+        self.program
+            .iter()
+            .flat_map(|program| program.declarations.flags.iter())
+            .map(|(id, declaration)| (id, declaration.pin, declaration))
+    }
+
+    fn flag_prefix(&self, pin: PinID) -> String {
+        format!(
+            "{}/switch/{}/{}",
+            self.prefix,
+            self.options.client_id(),
+            pin
+        )
+    }
+
+    /// Turns a message on the command topic of a flag switch into a batch
+    /// that changes only that flag
+    fn flag_batch(&self, topic: &str, payload: &str) -> Option<OutputBatch> {
+        let prefix = format!("{}/switch/{}/", self.prefix, self.options.client_id());
+        let pin: PinID = topic
+            .strip_prefix(&prefix)?
+            .strip_suffix("/set")?
+            .parse::<u8>()
+            .ok()?
+            .try_into()
+            .ok()?;
+        let value = match payload {
+            "ON" => true,
+            "OFF" => false,
+            _ => return None,
+        };
+        let mut batch = OutputBatch::new();
+        batch
+            .set(pin.into(), value)
+            .unwrap_or_else(|_| unreachable!());
+        Some(batch)
+    }
+
+    fn program_enabled_prefix(&self) -> String {
+        format!(
+            "{}/binary_sensor/{}/program_enabled",
+            self.prefix,
+            self.options.client_id()
+        )
+    }
+
+    /// Topic that accepts a JSON object with outputs to switch on and off all at once, e.g.
+    /// `{"on": ["light_kitchen", 3], "off": [4]}`, outputs are given by entity id or number,
+    /// payloads that switch an output both on and off are rejected
+    fn set_outputs_topic(&self) -> String {
+        format!(
+            "{}/light/{}/set_outputs",
+            self.prefix,
+            self.options.client_id()
+        )
+    }
+
+    fn resolve_output(&self, output: &OutputRef) -> Option<PinID> {
+        match output {
+            OutputRef::Pin(pin) => (*pin).try_into().ok(),
+            OutputRef::Entity(id) => {
+                let id: EntityID = id.as_str().try_into().ok()?;
+                let program = self.program.as_ref()?;
+                program.declarations.outputs.get(&id).map(|declaration| declaration.pin)
+            }
+        }
+    }
+
+    fn output_batch(&self, payload: &str) -> Option<OutputBatch> {
+        let payload: SetOutputsPayload = match serde_json::from_str(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Invalid set outputs payload: {e}");
+                return None;
+            }
+        };
+        let mut batch = OutputBatch::new();
+        let outputs = payload.on.iter().map(|output| (output, true));
+        let outputs = outputs.chain(payload.off.iter().map(|output| (output, false)));
+        for (output, value) in outputs {
+            let Some(pin) = self.resolve_output(output) else {
+                error!("Unknown output in set outputs payload: {output:?}");
+                return None;
+            };
+            let bit = 1 << u8::from(pin);
+            if batch.mask & bit != 0 && (batch.values & bit != 0) != value {
+                error!("Output {pin} is switched both on and off in set outputs payload");
+                return None;
+            }
+            batch
+                .set(pin.into(), value)
+                .unwrap_or_else(|_| unreachable!());
+        }
+        Some(batch)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum OutputRef {
+    Pin(u8),
+    Entity(String),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SetOutputsPayload {
+    on: Vec<OutputRef>,
+    off: Vec<OutputRef>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -405,6 +718,14 @@ struct LightSpec {
     state_topic: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct SwitchSpec {
+    unique_id: String,
+    name: String,
+    command_topic: String,
+    state_topic: String,
+}
+
 impl MqttEventLoop {
     async fn run(&mut self) -> Result<(), MqttHandlerError> {
         loop {
@@ -412,6 +733,41 @@ impl MqttEventLoop {
                 _ = self.cancellation_token.cancelled() => return Ok(()),
                 notification = self.event_loop.poll() => match notification {
                     Ok(event) => {
+                        if_chain!(
+                            if let rumqttc::Event::Incoming(Incoming::Publish(publish)) = &event;
+                            if publish.topic == self.config.set_outputs_topic();
+                            if let Ok(payload) = std::str::from_utf8(&publish.payload);
+                            then {
+                                if let Some(batch) = self.config.output_batch(payload) {
+                                    self.tx.send(Message::SendToController(
+                                        MessageBody::SetOutputs { batch }
+                                    )).unwrap_or_else(|_| unreachable!());
+                                }
+                                continue;
+                            }
+                        );
+                        if_chain!(
+                            if let rumqttc::Event::Incoming(Incoming::Publish(publish)) = &event;
+                            if let Ok(payload) = std::str::from_utf8(&publish.payload);
+                            if let Some(batch) = self.config.group_batch(&publish.topic, payload);
+                            then {
+                                self.tx.send(Message::SendToController(
+                                    MessageBody::SetOutputs { batch }
+                                )).unwrap_or_else(|_| unreachable!());
+                                continue;
+                            }
+                        );
+                        if_chain!(
+                            if let rumqttc::Event::Incoming(Incoming::Publish(publish)) = &event;
+                            if let Ok(payload) = std::str::from_utf8(&publish.payload);
+                            if let Some(batch) = self.config.flag_batch(&publish.topic, payload);
+                            then {
+                                self.tx.send(Message::SendToController(
+                                    MessageBody::SetFlags { batch }
+                                )).unwrap_or_else(|_| unreachable!());
+                                continue;
+                            }
+                        );
                         let prefix = format!("{}/light/{}/", self.config.prefix, self.config.options.client_id());
                         if_chain!(
                             if let rumqttc::Event::Incoming(incoming) = event;
@@ -437,10 +793,124 @@ impl MqttEventLoop {
                     },
                     Err(err) => {
                         self.cancellation_token.cancel();
-                        return Err(err.into());
+                        return Err(Box::new(err).into());
                     }
                 },
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::command::OutputBatch;
+    use crate::handlers::mqtt_handler::MqttHandlerConfig;
+    use crate::shal::{compiler, parser};
+
+    #[test]
+    fn test_output_batch() {
+        let program = compiler::compile(
+            &parser::parse(include_str!("../../static/short.shal")).unwrap(),
+        )
+        .unwrap();
+        let config = MqttHandlerConfig::new(
+            "homeassistant".to_string(),
+            Some(program),
+            "mqtt://localhost:1883?client_id=sha".to_string(),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            "homeassistant/light/sha/set_outputs",
+            config.set_outputs_topic()
+        );
+        assert_eq!(
+            Some(OutputBatch {
+                mask: 0x0000_0019,
+                values: 0x0000_0009,
+            }),
+            config.output_batch(r#"{"on": ["light_bedroom", 3], "off": [4]}"#)
+        );
+        assert_eq!(None, config.output_batch(r#"{"on": ["light_attic"]}"#));
+        assert_eq!(None, config.output_batch(r#"{"on": [32]}"#));
+        assert_eq!(None, config.output_batch(r#"{"toggle": [1]}"#));
+        // An output can't be switched on and off at once, also not by another name
+        assert_eq!(None, config.output_batch(r#"{"on": [3], "off": [3]}"#));
+        assert_eq!(
+            None,
+            config.output_batch(r#"{"on": ["light_bedroom"], "off": [0]}"#)
+        );
+        assert_eq!(
+            Some(OutputBatch {
+                mask: 0x0000_0008,
+                values: 0x0000_0008,
+            }),
+            config.output_batch(r#"{"on": [3, 3]}"#)
+        );
+    }
+
+    #[test]
+    fn test_group_batch() {
+        let program = compiler::compile(
+            &parser::parse(
+                "{outputs: {a: {pin: 0}, b: {pin: 3}}, groups: {ab: {outputs: [\"a\", \"b\"]}}}\n---\n",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let config = MqttHandlerConfig::new(
+            "homeassistant".to_string(),
+            Some(program),
+            "mqtt://localhost:1883?client_id=sha".to_string(),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(OutputBatch {
+                mask: 0x0000_0009,
+                values: 0x0000_0009,
+            }),
+            config.group_batch("homeassistant/light/sha/group_ab/switch", "ON")
+        );
+        assert_eq!(
+            Some(OutputBatch {
+                mask: 0x0000_0009,
+                values: 0x0000_0000,
+            }),
+            config.group_batch("homeassistant/light/sha/group_ab/switch", "OFF")
+        );
+        assert_eq!(None, config.group_batch("homeassistant/light/sha/group_cd/switch", "ON"));
+        assert_eq!(None, config.group_batch("homeassistant/light/sha/3/switch", "ON"));
+    }
+
+    #[test]
+    fn test_flag_batch() {
+        let config = MqttHandlerConfig::new(
+            "homeassistant".to_string(),
+            None,
+            "mqtt://localhost:1883?client_id=sha".to_string(),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(OutputBatch {
+                mask: 0x0000_0004,
+                values: 0x0000_0004,
+            }),
+            config.flag_batch("homeassistant/switch/sha/2/set", "ON")
+        );
+        assert_eq!(
+            Some(OutputBatch {
+                mask: 0x8000_0000,
+                values: 0x0000_0000,
+            }),
+            config.flag_batch("homeassistant/switch/sha/31/set", "OFF")
+        );
+        assert_eq!(None, config.flag_batch("homeassistant/switch/sha/32/set", "ON"));
+        assert_eq!(None, config.flag_batch("homeassistant/switch/sha/2/set", "TOGGLE"));
+        assert_eq!(None, config.flag_batch("homeassistant/light/sha/2/switch", "ON"));
+    }
+}
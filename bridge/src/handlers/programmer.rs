@@ -1,23 +1,35 @@
-use crate::controller::message::MessageBody::{ProgramEndAck, ProgramStart, ProgramStartAck};
+use crate::controller::command::Command::Refresh;
+use crate::controller::message::MessageBody::{
+    Capabilities, Command, ProgramEndAck, ProgramStart, ProgramStartAck, Status,
+};
 use crate::controller::message::{MessageBody, MAX_MESSAGE_BODY_LENGTH};
+use crate::controller::program_header;
 use crate::controller::program_header::PROGRAM_HEADER_LENGTH;
 use crate::handlers::message::Message;
 use crate::handlers::message::Message::{ReceivedFromController, SendToController};
 use crate::handlers::programmer::HandleMessageResult::{Continue, Done};
-use crate::handlers::programmer::State::{AwaitingAck, Uploading};
+use crate::handlers::programmer::State::{AwaitingAck, AwaitingCapabilities, Uploading};
+use crate::shal::ast::LintLevel;
 use crate::shal::bytecode::Program;
-use crate::shal::{bytecode, compiler, parser};
+use crate::shal::{ast, bytecode, compiler, linter, optimizer, parser};
 use log::{error, info, warn};
 use std::io;
+use std::path::Path;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::select;
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::error::RecvError::{Closed, Lagged};
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::{sleep_until, Instant};
 use tokio_util::sync::CancellationToken;
 
+/// How long to wait for the controller to report its capabilities before giving up
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Copy, Clone)]
 enum State {
+    AwaitingCapabilities,
     AwaitingAck,
     Uploading,
 }
@@ -34,6 +46,16 @@ pub enum ProgrammerError {
     StackLimitError(#[from] bytecode::StackLimitError),
     #[error("Program size error")]
     ProgramSizeError(#[from] bytecode::ProgramSizeError),
+    #[error("Program has {count} denied lint warnings")]
+    LintError { count: usize },
+    #[error("The controller did not report its capabilities, its firmware may be too old")]
+    CapabilitiesTimeout,
+    #[error("The controller supports {capabilities}, but the program needs version {version} and features {features:#010b}")]
+    UnsupportedProgram {
+        capabilities: program_header::Capabilities,
+        version: u8,
+        features: u8,
+    },
 }
 
 struct Programmer {
@@ -50,86 +72,159 @@ enum HandleMessageResult {
     Continue,
 }
 
-pub async fn compile(program_path: &str) -> Result<Program, ProgrammerError> {
+async fn parse(
+    program_path: &str,
+    declarations_path: Option<&str>,
+) -> Result<ast::Program, ProgrammerError> {
     let program_str = tokio::fs::read_to_string(&program_path).await?;
-    let program_ast = parser::parse(&program_str)?;
-    let program = compiler::compile(&program_ast)?;
-    let _stack_depth = program.check_stack_depth(Some(32))?;
-    let _program_size = program.check_program_size(Some(248))?;
+    Ok(parser::parse_file(
+        Path::new(program_path),
+        &program_str,
+        declarations_path.map(Path::new),
+        |path| std::fs::read_to_string(path),
+    )?)
+}
+
+pub async fn compile(
+    program_path: &str,
+    declarations_path: Option<&str>,
+) -> Result<Program, ProgrammerError> {
+    let program_ast = parse(program_path, declarations_path).await?;
+    let warnings = linter::lint(&program_ast);
+    for warning in &warnings {
+        match warning.level {
+            LintLevel::Deny => error!("{warning}"),
+            _ => warn!("{warning}"),
+        }
+    }
+    let count = warnings
+        .iter()
+        .filter(|warning| warning.level == LintLevel::Deny)
+        .count();
+    if count > 0 {
+        return Err(ProgrammerError::LintError { count });
+    }
+    let compiled = compiler::compile(&program_ast)?;
+    let program = optimizer::optimize(&compiled);
+    info!(
+        "Optimized program from {} to {} bytes",
+        compiled.calc_length(),
+        program.calc_length()
+    );
+    let _stack_depth = program.check_stack_depth(Some(bytecode::STACK_LIMIT))?;
+    let _program_size = program.check_program_size(Some(bytecode::PROGRAM_SIZE_LIMIT))?;
 
     Ok(program)
 }
 
+/// Compiles a program as it is written, without linting, optimizing or checking its size.
+/// Only the stack depth is checked, the interpreter needs the program to fit the stack.
+pub async fn compile_unoptimized(program_path: &str) -> Result<Program, ProgrammerError> {
+    let program = compiler::compile(&parse(program_path, None).await?)?;
+    let _stack_depth = program.check_stack_depth(Some(bytecode::STACK_LIMIT))?;
+    Ok(program)
+}
+
 pub async fn run(
     cancellation_token: CancellationToken,
     program: Program,
     sender: Sender<Message>,
 ) -> Result<(), anyhow::Error> {
     info!("Starting programmer");
+    let rx = sender.subscribe();
     sender
-        .send(SendToController(ProgramStart {
-            header: program.header(),
+        .send(SendToController(Command {
+            commands: vec![Refresh],
         }))
         .unwrap_or_else(|_| unreachable!());
 
-    let rx = sender.subscribe();
     let mut programmer = Programmer {
         cancellation_token,
         tx: sender,
         rx,
-        state: AwaitingAck,
+        state: AwaitingCapabilities,
         program,
     };
 
-    programmer.run().await;
+    let result = programmer.run().await;
 
     info!("Programmer shut down");
 
-    Ok(())
+    result.map_err(Into::into)
 }
 
 impl Programmer {
-    async fn run(&mut self) {
+    async fn run(&mut self) -> Result<(), ProgrammerError> {
+        let deadline = Instant::now() + CAPABILITIES_TIMEOUT;
         loop {
             select! {
                 _ = self.cancellation_token.cancelled() => break,
-                message = self.rx.recv() => if self.handle_message(&message) == Done {
+                _ = sleep_until(deadline), if matches!(self.state, AwaitingCapabilities) => {
+                    return Err(ProgrammerError::CapabilitiesTimeout);
+                },
+                message = self.rx.recv() => if self.handle_message(&message)? == Done {
                     break;
                 },
             }
         }
+        Ok(())
     }
 
-    fn handle_message(&mut self, message: &Result<Message, RecvError>) -> HandleMessageResult {
+    fn handle_message(
+        &mut self,
+        message: &Result<Message, RecvError>,
+    ) -> Result<HandleMessageResult, ProgrammerError> {
         match message {
             Ok(ReceivedFromController(body)) => match (self.state, body) {
+                (AwaitingCapabilities, Capabilities { capabilities }) => {
+                    let header = self.program.header();
+                    if !header.is_supported(capabilities) {
+                        return Err(ProgrammerError::UnsupportedProgram {
+                            capabilities: *capabilities,
+                            version: header.version,
+                            features: header.features,
+                        });
+                    }
+                    info!("Controller supports {capabilities}, starting upload");
+                    self.send_program_start();
+                }
                 (AwaitingAck, ProgramStartAck { header }) => {
                     if *header == self.program.header() {
                         info!("Program start ack received, starting upload");
                         self.upload();
                     } else {
                         warn!("Program start ack does not match, retrying upload");
-                        self.retry();
+                        self.send_program_start();
                     }
                 }
                 (Uploading, ProgramEndAck { header }) => {
                     if *header == self.program.header() {
                         info!("Upload done");
-                        return Done;
+                        return Ok(Done);
                     } else {
                         warn!("Program end ack does not match, retrying upload");
-                        self.retry();
+                        self.send_program_start();
                     }
                 }
+                // The controller sends its status on the refresh before the capabilities,
+                // later changes of the MODE switch are only published over MQTT
+                (
+                    AwaitingCapabilities,
+                    Status {
+                        program_enabled: false,
+                    },
+                ) => {
+                    warn!("The MODE switch of the controller disables the program, it won't run until it's enabled");
+                }
                 (_, _) => {}
             },
             Ok(_) => {}
             Err(Lagged(num_messages)) => {
                 error!("Programmer lagging behind {num_messages} messages!");
             }
-            Err(Closed) => return Done,
+            Err(Closed) => return Ok(Done),
         }
-        Continue
+        Ok(Continue)
     }
 
     fn upload(&mut self) {
@@ -154,7 +249,7 @@ impl Programmer {
         self.state = Uploading;
     }
 
-    fn retry(&mut self) {
+    fn send_program_start(&mut self) {
         self.tx
             .send(SendToController(ProgramStart {
                 header: self.program.header(),
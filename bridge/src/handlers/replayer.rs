@@ -0,0 +1,283 @@
+use crate::controller;
+use crate::controller::message::MessageBody;
+use crate::controller::model::{ControllerModel, DEBOUNCE_TIME};
+use crate::handlers::capture::{parse_capture, CaptureRecord, Direction};
+use crate::handlers::message::Message;
+use crate::handlers::message::Message::{ReceivedFromController, SendToController};
+use crate::shal::bytecode::Program;
+use log::{error, info, trace, warn};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::{interval_at, sleep_until, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+/// How often the virtual controller runs a cycle
+const CYCLE_TIME: Duration = Duration::from_millis(1);
+
+/// All inputs are high when no button is pressed
+const RELEASED: u32 = u32::MAX;
+
+struct Replayer {
+    cancellation_token: CancellationToken,
+    records: Vec<CaptureRecord>,
+    tx: Sender<Message>,
+}
+
+/// A controller model that reads the inputs and the MODE switch of a capture
+///
+/// Captures only contain the inputs the controller committed, so an input is read from
+/// the debounce time before the update it was sent in. The messages to the controller come
+/// from the bridge, not from the capture.
+pub struct VirtualController {
+    model: ControllerModel,
+    /// When the controller sent its first message, it misses the messages sent before
+    start: Duration,
+    end: Duration,
+    /// The inputs of every full update, with when it was sent
+    inputs: Vec<(Duration, u32)>,
+    /// The MODE switch of every status, with when it was sent
+    statuses: Vec<(Duration, bool)>,
+}
+
+impl VirtualController {
+    pub fn new(program: Option<Program>, records: &[CaptureRecord]) -> Self {
+        let received = records
+            .iter()
+            .filter(|record| record.direction == Direction::Rx)
+            .filter_map(|record| {
+                let frame = record.frame_bytes()?;
+                let message = controller::message::Message::try_from(&frame[..]).ok()?;
+                Some((record.elapsed(), message.body))
+            })
+            .collect::<Vec<_>>();
+        let inputs = received
+            .iter()
+            .filter_map(|(elapsed, body)| match body {
+                MessageBody::FullUpdate { inputs, .. } => Some((*elapsed, *inputs)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let statuses = received
+            .iter()
+            .filter_map(|(elapsed, body)| match body {
+                MessageBody::Status { program_enabled } => Some((*elapsed, *program_enabled)),
+                _ => None,
+            })
+            .collect();
+        let start = received
+            .first()
+            .map_or(Duration::ZERO, |(elapsed, _)| *elapsed);
+        let end = records
+            .last()
+            .map_or(Duration::ZERO, CaptureRecord::elapsed);
+        VirtualController {
+            model: ControllerModel::new(program, read_inputs(&inputs, start), start),
+            start,
+            end,
+            inputs,
+            statuses,
+        }
+    }
+
+    /// When the controller starts, relative to the start of the capture
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    /// When the capture ends
+    pub fn end(&self) -> Duration {
+        self.end
+    }
+
+    /// Runs a cycle at a time relative to the start of the capture
+    pub fn cycle(&mut self, now: Duration, message: Option<&MessageBody>) -> Vec<MessageBody> {
+        let inputs = read_inputs(&self.inputs, now);
+        let program_enabled = self.read_mode(now);
+        self.model.cycle(now, inputs, program_enabled, message)
+    }
+
+    /// The program is enabled until a status says otherwise
+    fn read_mode(&self, now: Duration) -> bool {
+        self.statuses
+            .iter()
+            .take_while(|(elapsed, _)| *elapsed <= now)
+            .last()
+            .is_none_or(|(_, program_enabled)| *program_enabled)
+    }
+}
+
+/// The inputs read at a time, which are the ones committed a debounce time later
+fn read_inputs(inputs: &[(Duration, u32)], now: Duration) -> u32 {
+    inputs
+        .iter()
+        .take_while(|(elapsed, _)| *elapsed <= now + DEBOUNCE_TIME)
+        .last()
+        .or(inputs.first())
+        .map_or(RELEASED, |(_, inputs)| *inputs)
+}
+
+/// Replays a capture file, as if the captured frames were received from the controller
+///
+/// Frames that were sent to the controller during the capture are only logged, since the
+/// bridge will generate those itself. With a program, the program runs on a virtual controller
+/// with the inputs of the capture instead, and handles the messages the bridge sends.
+pub async fn run(
+    cancellation_token: CancellationToken,
+    capture_path: &str,
+    program: Option<Program>,
+    tx: Sender<Message>,
+) -> Result<(), anyhow::Error> {
+    let contents = tokio::fs::read_to_string(capture_path).await?;
+    let records = parse_capture(&contents)?;
+    let replayer = Replayer {
+        cancellation_token,
+        records,
+        tx,
+    };
+    if let Some(program) = program {
+        info!("Running a virtual controller with the inputs from {capture_path}");
+        replayer.run_virtual_controller(program).await;
+    } else {
+        info!(
+            "Replaying {} frames from {capture_path}",
+            replayer.records.len()
+        );
+        replayer.run().await;
+    }
+    Ok(())
+}
+
+impl Replayer {
+    async fn run(&self) {
+        let start = Instant::now();
+        for record in &self.records {
+            select! {
+                _ = self.cancellation_token.cancelled() => return,
+                _ = sleep_until(start + record.elapsed()) => self.replay(record),
+            }
+        }
+        info!("Replay done");
+    }
+
+    fn replay(&self, record: &CaptureRecord) {
+        let frame = record.frame_bytes().unwrap_or_else(|| unreachable!());
+        match (
+            record.direction,
+            controller::message::Message::try_from(&frame[..]),
+        ) {
+            (Direction::Rx, Ok(message)) => {
+                self.tx
+                    .send(ReceivedFromController(message.body))
+                    .unwrap_or_else(|_| unreachable!());
+            }
+            (Direction::Rx, Err(e)) => error!("Failed to decode captured message: {e}"),
+            (Direction::Tx, message) => {
+                trace!("Skipping captured message to controller: {message:?}")
+            }
+        }
+    }
+
+    async fn run_virtual_controller(&self, program: Program) {
+        let mut controller = VirtualController::new(Some(program), &self.records);
+        let start = Instant::now();
+        select! {
+            _ = self.cancellation_token.cancelled() => return,
+            _ = sleep_until(start + controller.start()) => {}
+        }
+        let mut rx = self.tx.subscribe();
+        let mut received = VecDeque::new();
+        let mut cycles = interval_at(start + controller.start(), CYCLE_TIME);
+        cycles.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            select! {
+                _ = self.cancellation_token.cancelled() => return,
+                _ = cycles.tick() => {}
+            }
+            let now = start.elapsed();
+            if now > controller.end() {
+                break;
+            }
+            receive(&mut rx, &mut received);
+            for body in controller.cycle(now, received.pop_front().as_ref()) {
+                self.tx
+                    .send(ReceivedFromController(body))
+                    .unwrap_or_else(|_| unreachable!());
+            }
+        }
+        info!("Replay done");
+    }
+}
+
+/// Queues the messages the bridge sent to the controller, the controller handles one per cycle
+fn receive(rx: &mut Receiver<Message>, received: &mut VecDeque<MessageBody>) {
+    loop {
+        match rx.try_recv() {
+            Ok(SendToController(body)) => received.push_back(body),
+            Ok(ReceivedFromController(_)) => {}
+            Err(TryRecvError::Lagged(count)) => {
+                warn!("Virtual controller missed {count} messages")
+            }
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::message::{Message, MessageBody};
+    use crate::handlers::capture::{parse_capture, CaptureRecord, Direction};
+    use crate::handlers::replayer::{VirtualController, CYCLE_TIME};
+    use crate::shal::bytecode::Program;
+    use crate::shal::tests::compile_source;
+    use std::collections::VecDeque;
+
+    const CAPTURE: &str = include_str!("../../static/short.capture.jsonl");
+
+    fn body(record: &CaptureRecord) -> MessageBody {
+        Message::try_from(&record.frame_bytes().unwrap()[..])
+            .unwrap()
+            .body
+    }
+
+    /// Runs the virtual controller with the messages the bridge sent in the capture,
+    /// and returns the messages it sends
+    fn run(program: Program, records: &[CaptureRecord]) -> Vec<MessageBody> {
+        let mut controller = VirtualController::new(Some(program), records);
+        let start = controller.start();
+        let mut sent = records
+            .iter()
+            .filter(|record| record.direction == Direction::Tx)
+            .skip_while(|record| record.elapsed() < start)
+            .peekable();
+        let mut received = VecDeque::new();
+        let mut result = vec![];
+        let mut now = start;
+        while now <= controller.end() {
+            while let Some(record) = sent.next_if(|record| record.elapsed() <= now) {
+                received.push_back(body(record));
+            }
+            result.extend(controller.cycle(now, received.pop_front().as_ref()));
+            now += CYCLE_TIME;
+        }
+        result
+    }
+
+    #[test]
+    fn test_virtual_controller() {
+        let records = parse_capture(CAPTURE).unwrap();
+        let captured = records
+            .iter()
+            .filter(|record| record.direction == Direction::Rx)
+            .map(body)
+            .collect::<Vec<_>>();
+        let program = compile_source(include_str!("../../static/short.shal"));
+        assert_eq!(run(program, &records), captured);
+
+        // The outputs come from the program, not from the capture
+        let program = compile_source("on fedge input 0 toggle output 0;");
+        assert_ne!(run(program, &records), captured);
+    }
+}
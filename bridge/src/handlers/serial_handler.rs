@@ -1,6 +1,7 @@
 use crate::controller;
 use crate::controller::command::Command;
 use crate::controller::message::{MessageBody, MAX_MESSAGE_BODY_LENGTH};
+use crate::handlers::capture::{CaptureError, CaptureWriter, Direction};
 use crate::handlers::message::Message;
 use futures::stream::StreamExt;
 use futures::SinkExt;
@@ -29,12 +30,15 @@ pub enum SerialHandlerError {
     IOError(#[from] std::io::Error),
     #[error("No more serial messages")]
     NoMoreMessages,
+    #[error("Capture error")]
+    CaptureError(#[from] CaptureError),
 }
 
 pub struct SerialHandler {
     cancellation_token: CancellationToken,
     framed_port: Framed<SerialStream, SlipCodec>,
     commands_buffer: Vec<Command>,
+    capture: Option<CaptureWriter>,
     rx: Receiver<Message>,
     tx: Sender<Message>,
 }
@@ -43,15 +47,21 @@ impl SerialHandler {
     pub async fn new(
         cancellation_token: CancellationToken,
         serial_port: &str,
+        capture_path: Option<&str>,
         sender: Sender<Message>,
     ) -> Result<Self, SerialHandlerError> {
         let serial_stream = tokio_serial::new(serial_port, BAUD_RATE).open_native_async()?;
         let framed_port = SlipCodec::new().framed(serial_stream);
+        let capture = match capture_path {
+            Some(path) => Some(CaptureWriter::create(path).await?),
+            None => None,
+        };
         let receiver = sender.subscribe();
         Ok(Self {
             cancellation_token,
             framed_port,
             commands_buffer: vec![],
+            capture,
             rx: receiver,
             tx: sender,
         })
@@ -61,21 +71,24 @@ impl SerialHandler {
         loop {
             select! {
                 _ = self.cancellation_token.cancelled() => break,
-                message = self.framed_port.next() => self.handle_serial_message(message)?,
+                message = self.framed_port.next() => self.handle_serial_message(message).await?,
                 message = self.rx.recv() => match message {
                    Ok(message) => self.handle_broadcast_message(message).await?,
                    Err(RecvError::Lagged(n)) => warn!("Serial handler skipped {n} messages!"),
                    Err(RecvError::Closed) => break,
                 },
-                _ = sleep(Duration::from_millis(1)) => self.send_commands().await,
+                _ = sleep(Duration::from_millis(1)) => self.send_commands().await?,
             }
         }
         Ok(())
     }
 
-    fn handle_serial_message(&mut self, message: Option<Result<Bytes, SlipError>>) -> Result<(), SerialHandlerError> {
+    async fn handle_serial_message(&mut self, message: Option<Result<Bytes, SlipError>>) -> Result<(), SerialHandlerError> {
         match message {
             Some(Ok(message)) => {
+                if let Some(capture) = &mut self.capture {
+                    capture.record(Direction::Rx, &message).await?;
+                }
                 match controller::message::Message::try_from(&message[..]) {
                     Ok(message) => {
                         self.tx
@@ -101,28 +114,38 @@ impl SerialHandler {
                     self.commands_buffer.append(commands.as_mut());
                 }
                 _ => {
-                    let bytes: Vec<u8> = (&controller::message::Message::new(body)).into();
-                    self.framed_port.send(bytes.into()).await.map_err(std::io::Error::from)?;
+                    // Don't let this message overtake commands that are still buffered
+                    self.send_commands().await?;
+                    self.send_message(body).await?;
                 }
             }
         }
         Ok(())
     }
 
-    async fn send_commands(&mut self) {
+    async fn send_commands(&mut self) -> Result<(), SerialHandlerError> {
         if self.commands_buffer.is_empty() {
-            return;
+            return Ok(());
         }
-        for commands_chunk in self.commands_buffer.chunks(MAX_MESSAGE_BODY_LENGTH) {
+        let commands_buffer = std::mem::take(&mut self.commands_buffer);
+        for commands_chunk in commands_buffer.chunks(MAX_MESSAGE_BODY_LENGTH) {
             let message = MessageBody::Command {
                 commands: commands_chunk.to_vec(),
             };
-            let bytes: Vec<u8> = (&controller::message::Message::new(message)).into();
-            self.framed_port
-                .send(bytes.into())
-                .await
-                .unwrap_or_else(|_| unreachable!());
+            self.send_message(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_message(&mut self, body: MessageBody) -> Result<(), SerialHandlerError> {
+        let bytes: Vec<u8> = (&controller::message::Message::new(body)).into();
+        if let Some(capture) = &mut self.capture {
+            capture.record(Direction::Tx, &bytes).await?;
         }
-        self.commands_buffer.clear();
+        self.framed_port
+            .send(bytes.into())
+            .await
+            .map_err(std::io::Error::from)?;
+        Ok(())
     }
 }
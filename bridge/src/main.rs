@@ -1,21 +1,21 @@
 mod args;
-pub mod controller;
-pub mod handlers;
-pub mod shal;
-
-use crate::args::Args;
-use crate::handlers::message::Message;
-use crate::handlers::mqtt_handler::{MqttHandler, MqttHandlerConfig};
-use crate::handlers::serial_handler::SerialHandler;
-use crate::handlers::{ctrlc_handler, logger, programmer, refresher};
-use crate::shal::bytecode::Program;
-use anyhow::Result;
+
+use crate::args::{Args, Command};
+use anyhow::{Context, Result};
 use clap::Parser;
 use if_chain::if_chain;
 use log::Level::Trace;
 use log::{info, log_enabled};
+use sha_bridge::handlers::message::Message;
+use sha_bridge::handlers::mqtt_handler::{MqttHandler, MqttHandlerConfig};
+use sha_bridge::handlers::serial_handler::SerialHandler;
+use sha_bridge::handlers::{ctrlc_handler, fail_logger, logger, programmer, refresher, replayer};
+use sha_bridge::shal::bytecode::Program;
+use sha_bridge::shal::source_map::SourceMap;
+use sha_bridge::shal::{equivalence, formatter, parser};
 use std::collections::VecDeque;
 use std::panic;
+use std::path::Path;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Sender;
 use tokio::task::JoinSet;
@@ -29,11 +29,33 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
+    if let Some(Command::Schema) = &args.command {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&parser::declarations_schema())?
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::Fmt { check, files }) = &args.command {
+        if !format_programs(files, *check)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Compare { first, second }) = &args.command {
+        if !compare_programs(first, second).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     info!("Starting SHA bridge with arguments:\n{}", args);
 
     let mut program = None;
     if let Some(program_path) = &args.program {
-        program = Some(programmer::compile(program_path).await?);
+        program = Some(programmer::compile(program_path, args.declarations.as_deref()).await?);
     }
 
     let (sender, _receiver) = broadcast::channel(BROADCAST_CHANNEL_CAPACITY);
@@ -74,6 +96,49 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Returns false if a program is not formatted in check mode
+fn format_programs(files: &[String], check: bool) -> Result<bool> {
+    let mut formatted = true;
+    for file in files {
+        let input =
+            std::fs::read_to_string(file).with_context(|| format!("Failed to read {file}"))?;
+        let output =
+            formatter::format(&input).with_context(|| format!("Failed to format {file}"))?;
+        if output == input {
+            continue;
+        }
+        if check {
+            eprintln!("{file} is not formatted");
+            formatted = false;
+        } else {
+            std::fs::write(file, output).with_context(|| format!("Failed to write {file}"))?;
+            println!("Formatted {file}");
+        }
+    }
+    Ok(formatted)
+}
+
+/// Returns false if the programs don't behave the same
+async fn compare_programs(first: &str, second: &str) -> Result<bool> {
+    let first_program = programmer::compile_unoptimized(first)
+        .await
+        .with_context(|| format!("Failed to compile {first}"))?;
+    let second_program = programmer::compile_unoptimized(second)
+        .await
+        .with_context(|| format!("Failed to compile {second}"))?;
+    let comparison = equivalence::compare(&first_program, &second_program);
+    for counterexample in &comparison.counterexamples {
+        println!("{counterexample}");
+    }
+    if comparison.counterexamples.is_empty() {
+        println!("{first} and {second} behave the same");
+    }
+    if !comparison.exhaustive {
+        println!("Not all states were checked, some outputs depend on too many inputs");
+    }
+    Ok(comparison.counterexamples.is_empty())
+}
+
 async fn spawn_tasks(
     join_set: &mut JoinSet<Result<()>>,
     cancellation_token: &CancellationToken,
@@ -94,6 +159,20 @@ async fn spawn_tasks(
         join_set.spawn(async move { logger::run(cancellation_token, rx).await });
     }
 
+    {
+        let rx = sender.subscribe();
+        let cancellation_token = cancellation_token.clone();
+        let source_map = args
+            .program
+            .as_ref()
+            .zip(program.as_ref())
+            .map(|(program_path, program)| SourceMap::new(program, Path::new(program_path)));
+        let source_map_path = args.source_map.clone();
+        join_set.spawn(async move {
+            fail_logger::run(cancellation_token, rx, source_map, source_map_path).await
+        });
+    }
+
     if let Some(mqtt_url) = &args.mqtt_url {
         let mut credentials = None;
         if let (Some(user), Some(password)) = (&args.mqtt_user, &args.mqtt_password) {
@@ -114,10 +193,25 @@ async fn spawn_tasks(
     if let Some(serial_port) = &args.serial {
         let cancellation_token = cancellation_token.clone();
         let sender = sender.clone();
-        let handler = SerialHandler::new(cancellation_token, serial_port, sender).await?;
+        let handler = SerialHandler::new(
+            cancellation_token,
+            serial_port,
+            args.capture.as_deref(),
+            sender,
+        )
+        .await?;
         join_set.spawn(async move { handler.run().await.map_err(Into::into) });
     }
 
+    if let Some(replay_path) = args.replay.clone() {
+        let cancellation_token = cancellation_token.clone();
+        let program = program.clone().filter(|_| args.virtual_controller);
+        let sender = sender.clone();
+        join_set.spawn(async move {
+            replayer::run(cancellation_token, &replay_path, program, sender).await
+        });
+    }
+
     if_chain!(
         if let Some(program) = program;
         if args.upload;
@@ -1,15 +1,25 @@
-use crate::shal::common::{Edge, IsWas, Value};
+use crate::shal::common::{parse_duration, Edge, IsWas, Value};
 use regex::RegexBuilder;
-use serde::Deserialize;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct SourceLoc(pub usize, pub usize);
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash)]
-#[serde(try_from = "String")]
+impl Display for SourceLoc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, col {}", self.0, self.1)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct EntityID {
     id: String,
 }
@@ -62,8 +72,21 @@ impl<'a> From<&'a EntityID> for &'a str {
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Hash)]
-#[serde(try_from = "u8")]
+impl JsonSchema for EntityID {
+    fn schema_name() -> Cow<'static, str> {
+        "EntityID".into()
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "pattern": "^[A-Za-z][A-Za-z0-9_]*$"
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
 pub struct PinID {
     id: u8,
 }
@@ -98,49 +121,294 @@ impl From<PinID> for u8 {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+impl JsonSchema for PinID {
+    fn schema_name() -> Cow<'static, str> {
+        "PinID".into()
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "integer",
+            "minimum": 0,
+            "maximum": 31
+        })
+    }
+}
+
+/// The declarations header of a program, above the `---` line
+#[derive(Clone, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct IODeclarations {
     #[serde(with = "::serde_with::rust::maps_duplicate_key_is_error")]
+    #[schemars(with = "HashMap<EntityID, IODeclaration>")]
     pub inputs: HashMap<EntityID, IODeclaration>,
     #[serde(with = "::serde_with::rust::maps_duplicate_key_is_error")]
+    #[schemars(with = "HashMap<EntityID, IODeclaration>")]
     pub outputs: HashMap<EntityID, IODeclaration>,
+    /// Virtual outputs, kept by the controller, the pin is the number of the flag
+    #[serde(with = "::serde_with::rust::maps_duplicate_key_is_error")]
+    #[schemars(with = "HashMap<EntityID, IODeclaration>")]
+    pub flags: HashMap<EntityID, IODeclaration>,
+    #[serde(with = "::serde_with::rust::maps_duplicate_key_is_error")]
+    #[schemars(with = "HashMap<EntityID, GroupDeclaration>")]
+    pub groups: HashMap<EntityID, GroupDeclaration>,
+    pub settings: Settings,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// How long an input has to be held for a long press
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    #[schemars(schema_with = "duration_schema")]
+    pub longpress: Duration,
+    /// Maximum time between the two presses of a double click
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    #[schemars(schema_with = "duration_schema")]
+    pub doubleclick: Duration,
+    /// Levels of the lints, e.g. `unused_entity: allow`, lints that are not listed warn
+    #[schemars(schema_with = "lints_schema")]
+    pub lints: HashMap<Lint, LintLevel>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            longpress: Duration::from_secs(1),
+            doubleclick: Duration::from_millis(500),
+            lints: HashMap::new(),
+        }
+    }
+}
+
+/// Durations are written like in the program, e.g. `500ms` or `2min`
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+/// Only used for the defaults in the JSON schema of the declarations
+fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if duration.subsec_millis() == 0 {
+        serializer.serialize_str(&format!("{}s", duration.as_secs()))
+    } else {
+        serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+    }
+}
+
+fn duration_schema(_generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "string",
+        "pattern": "^(0|[1-9][0-9]*)(ms|s|min|h)$"
+    })
+}
+
+/// The keys are restricted to the names of the lints, so editors can complete them
+fn lints_schema(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "object",
+        "propertyNames": generator.subschema_for::<Lint>(),
+        "additionalProperties": generator.subschema_for::<LintLevel>()
+    })
+}
+
+/// Mistakes the compiler accepts, they are configured in the settings of the declarations
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lint {
+    /// An input, output, flag or group that is declared but never used
+    UnusedEntity,
+    /// An output that is set or toggled, and set again by the next action
+    RedundantSet,
+    /// A condition that is always true or always false
+    ConstantCondition,
+    /// A `was` check on an output or flag that the program never writes
+    UnwrittenWasCheck,
+    /// An else branch that can't be reached, because the conditions before it cover all cases
+    UnreachableElse,
+    /// A used input without an event handler
+    UnhandledInput,
+    /// An output or flag written by two rules that can run in the same cycle, the later write wins
+    ConflictingWrites,
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Lint::UnusedEntity => "unused_entity",
+            Lint::RedundantSet => "redundant_set",
+            Lint::ConstantCondition => "constant_condition",
+            Lint::UnwrittenWasCheck => "unwritten_was_check",
+            Lint::UnreachableElse => "unreachable_else",
+            Lint::UnhandledInput => "unhandled_input",
+            Lint::ConflictingWrites => "conflicting_writes",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LintLevel {
+    /// The lint is not checked
+    Allow,
+    /// The program is compiled with a warning
+    #[default]
+    Warn,
+    /// The program is not uploaded
+    Deny,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct IODeclaration {
     pub pin: PinID,
     pub name: Option<String>,
 }
 
+/// A named group of outputs, the outputs are referred to by their entity ID
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupDeclaration {
+    pub outputs: Vec<EntityID>,
+    pub name: Option<String>,
+}
+
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub(crate) struct Program {
     pub(super) declarations: IODeclarations,
     pub(super) statements: Vec<Statement>,
+    /// The location of every top-level statement, an include for the statements it contains
+    pub(super) source_locations: Vec<SourceLoc>,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum DeclarationType {
     Input,
     Output,
+    Flag,
+    Group,
 }
 
+/// Statements are located in the file they are written in,
+/// which is the file a rule is defined in for the statements of a rule
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(super) enum Statement {
-    Action(Action),
-    IfElse(Condition, Vec<Statement>, Vec<Statement>),
+    Action(Action, SourceLoc),
+    /// The location is the one of the condition
+    IfElse(Condition, Vec<Statement>, Vec<Statement>, SourceLoc),
     Event {
         edge: Edge,
         input: Input,
         statements: Vec<Statement>,
+        location: SourceLoc,
+    },
+    Gesture {
+        gesture: Gesture,
+        input: Input,
+        statements: Vec<Statement>,
+        location: SourceLoc,
+    },
+    After {
+        duration: Duration,
+        timer: Option<EntityID>,
+        statements: Vec<Statement>,
+        location: SourceLoc,
+    },
+    /// The expansion of a rule, kept so errors can be reported at the call site,
+    /// with the included file the rule is defined in, if it isn't the main program
+    Call {
+        rule: EntityID,
+        location: SourceLoc,
+        file: Option<PathBuf>,
+        statements: Vec<Statement>,
+    },
+    /// The statements of an included file, the location is the one of the include
+    Include {
+        path: PathBuf,
+        location: SourceLoc,
+        statements: Vec<Statement>,
     },
 }
 
+impl Statement {
+    pub(super) fn location(&self) -> SourceLoc {
+        match self {
+            Statement::Action(_, location)
+            | Statement::IfElse(_, _, _, location)
+            | Statement::Event { location, .. }
+            | Statement::Gesture { location, .. }
+            | Statement::After { location, .. }
+            | Statement::Call { location, .. }
+            | Statement::Include { location, .. } => *location,
+        }
+    }
+
+    /// The statement with all its locations cleared, to compare statements written in different places
+    pub(super) fn without_locations(&self) -> Statement {
+        let mut statement = self.clone();
+        statement.clear_locations();
+        statement
+    }
+
+    fn clear_locations(&mut self) {
+        match self {
+            Statement::Action(_, location) => *location = SourceLoc(0, 0),
+            Statement::IfElse(_, if_block, else_block, location) => {
+                *location = SourceLoc(0, 0);
+                if_block
+                    .iter_mut()
+                    .chain(else_block.iter_mut())
+                    .for_each(Statement::clear_locations);
+            }
+            Statement::Event {
+                statements,
+                location,
+                ..
+            }
+            | Statement::Gesture {
+                statements,
+                location,
+                ..
+            }
+            | Statement::After {
+                statements,
+                location,
+                ..
+            }
+            | Statement::Call {
+                statements,
+                location,
+                ..
+            }
+            | Statement::Include {
+                statements,
+                location,
+                ..
+            } => {
+                *location = SourceLoc(0, 0);
+                statements.iter_mut().for_each(Statement::clear_locations);
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(super) enum Action {
     Toggle(Output),
     Set(Output, Value),
+    /// Switches all outputs of the group off if any of them is on, otherwise switches them all on
+    ToggleGroup(EntityID),
+    SetGroup(EntityID, Value),
+    Cancel(EntityID),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -152,6 +420,30 @@ pub(super) enum Condition {
     Input(Input, IsWas, Value),
     Output(Output, IsWas, Value),
     Entity(EntityID, IsWas, Value),
+    Group(Quantifier, EntityID, IsWas, Value),
+    Timer(EntityID, TimerStatus),
+    /// `true` or `false`, folded away by the compiler
+    Constant(bool),
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub(super) enum Quantifier {
+    Any,
+    All,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum Gesture {
+    /// The input was pressed for at least the long press duration, and is still pressed
+    LongPress,
+    /// The input was pressed twice within the double click duration
+    DoubleClick,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub(super) enum TimerStatus {
+    Running,
+    Stopped,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -163,5 +455,7 @@ pub(super) enum Input {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(super) enum Output {
     Number(PinID),
+    /// Flags can be set, toggled and checked like outputs
+    Flag(PinID),
     Entity(EntityID),
 }
@@ -1,8 +1,16 @@
-use crate::controller::program_header::ProgramHeader;
-use crate::shal::ast::{IODeclarations, PinID, SourceLoc};
+use crate::controller::program_header::{
+    ProgramHeader, FEATURE_FLAGS, FEATURE_ON_TOGGLE, FEATURE_TIMERS, PROGRAM_FORMAT_VERSION,
+    PROGRAM_HEADER_LENGTH,
+};
+use crate::shal::ast::{
+    EntityID, Gesture, IODeclarations, InvalidPinIDError, PinID, SourceLoc, Statement,
+};
 use crate::shal::common::{Edge, IsWas, Value};
 use crc::{Crc, CRC_16_XMODEM};
 use static_assertions::const_assert_eq;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
 const SET_VALUE_MASK: u8 = 0b0000_0001;
@@ -10,6 +18,13 @@ const ON_EDGE_MASK: u8 = 0b0000_0001;
 const IF_IS_WAS_MASK: u8 = 0b0000_0100;
 const IF_IO_MASK: u8 = 0b0000_0010;
 const IF_VALUE_MASK: u8 = 0b0000_0001;
+const START_TIMER_UNIT_MASK: u8 = 0b0001_1000;
+const START_TIMER_ID_MASK: u8 = 0b0000_0111;
+const IF_TIMER_CHECK_MASK: u8 = 0b0000_0001;
+const IF_FLAG_IS_WAS_MASK: u8 = 0b0000_0010;
+const IF_FLAG_VALUE_MASK: u8 = 0b0000_0001;
+const ON_TOGGLE_EDGE_MASK: u8 = 0b0010_0000;
+const ON_TOGGLE_INPUT_MASK: u8 = 0b0001_1111;
 
 const SINGLE_BYTE_MASK: u8 = 0b0111_1111;
 const DUAL_BYTE_MASK: u8 = 0b0011_1111;
@@ -17,6 +32,8 @@ const DUAL_BYTE_MASK: u8 = 0b0011_1111;
 const SINGLE_BYTE_PREFIX: u8 = 0b0000_0000;
 const FIRST_BYTE_PREFIX: u8 = 0b1000_0000;
 const SECOND_BYTE_PREFIX: u8 = 0b1100_0000;
+/// First byte of a compact instruction, which has no room for an opcode
+const COMPACT_BYTE_PREFIX: u8 = 0b0100_0000;
 
 const INSTR_END: u8 = 0b0000_0000;
 const INSTR_AND: u8 = 0b0000_0001;
@@ -29,16 +46,40 @@ const INSTR_SET: u8 = 0b0000_0000;
 const INSTR_TOGGLE: u8 = 0b0000_0010;
 const INSTR_ON: u8 = 0b0000_0100;
 const INSTR_IF: u8 = 0b0000_1000;
+const INSTR_IF_TIMER: u8 = 0b0001_0000;
+const INSTR_CANCEL_TIMER: u8 = 0b0001_0010;
+const INSTR_START_TIMER: u8 = 0b0010_0000;
+const INSTR_SET_FLAG: u8 = 0b0001_0100;
+const INSTR_TOGGLE_FLAG: u8 = 0b0001_0110;
+const INSTR_IF_FLAG: u8 = 0b0001_1000;
 
 const INSTR_SET_MASK: u8 = DUAL_BYTE_MASK & !SET_VALUE_MASK;
 const INSTR_TOGGLE_MASK: u8 = DUAL_BYTE_MASK;
 const INSTR_ON_MASK: u8 = DUAL_BYTE_MASK & !ON_EDGE_MASK;
 const INSTR_IF_MASK: u8 = DUAL_BYTE_MASK & !IF_IS_WAS_MASK & !IF_IO_MASK & !IF_VALUE_MASK;
+const INSTR_IF_TIMER_MASK: u8 = DUAL_BYTE_MASK & !IF_TIMER_CHECK_MASK;
+const INSTR_CANCEL_TIMER_MASK: u8 = DUAL_BYTE_MASK;
+const INSTR_START_TIMER_MASK: u8 = DUAL_BYTE_MASK & !START_TIMER_UNIT_MASK & !START_TIMER_ID_MASK;
+const INSTR_SET_FLAG_MASK: u8 = DUAL_BYTE_MASK & !SET_VALUE_MASK;
+const INSTR_TOGGLE_FLAG_MASK: u8 = DUAL_BYTE_MASK;
+const INSTR_IF_FLAG_MASK: u8 = DUAL_BYTE_MASK & !IF_FLAG_IS_WAS_MASK & !IF_FLAG_VALUE_MASK;
 
 const_assert_eq!(INSTR_SET_MASK, 0b0011_1110);
 const_assert_eq!(INSTR_TOGGLE_MASK, 0b0011_1111);
 const_assert_eq!(INSTR_ON_MASK, 0b0011_1110);
 const_assert_eq!(INSTR_IF_MASK, 0b0011_1000);
+const_assert_eq!(INSTR_IF_TIMER_MASK, 0b0011_1110);
+const_assert_eq!(INSTR_CANCEL_TIMER_MASK, 0b0011_1111);
+const_assert_eq!(INSTR_START_TIMER_MASK, 0b0010_0000);
+const_assert_eq!(INSTR_SET_FLAG_MASK, 0b0011_1110);
+const_assert_eq!(INSTR_TOGGLE_FLAG_MASK, 0b0011_1111);
+const_assert_eq!(INSTR_IF_FLAG_MASK, 0b0011_1100);
+
+/// Number of timers available in the virtual machine
+pub const NB_TIMERS: u8 = 8;
+
+/// Largest value that fits in the second byte of a START TIMER instruction
+const MAX_TIMER_VALUE: u8 = DUAL_BYTE_MASK;
 
 pub(super) trait AsBit {
     fn as_bit(&self) -> bool;
@@ -100,25 +141,147 @@ impl AsBit for IsWas {
 pub(super) enum InOut {
     Input,
     Output,
+    /// Flags are checked with a separate IF FLAG instruction
+    Flag,
 }
 
-impl AsBit for InOut {
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum TimerCheck {
+    /// The timer elapsed at the start of this cycle
+    Elapsed,
+    /// The timer was started and has not elapsed or been cancelled yet
+    Running,
+}
+
+impl AsBit for TimerCheck {
     fn as_bit(&self) -> bool {
         match self {
-            InOut::Input => false,
-            InOut::Output => true,
+            TimerCheck::Elapsed => false,
+            TimerCheck::Running => true,
         }
     }
 
     fn from_bit(b: bool) -> Self {
         if b {
-            InOut::Output
+            TimerCheck::Running
+        } else {
+            TimerCheck::Elapsed
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TimerID {
+    id: u8,
+}
+
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("Invalid timer ID: {id}, ids must be in range [0, {NB_TIMERS})")]
+pub struct InvalidTimerIDError {
+    id: u8,
+}
+
+impl Display for TimerID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.id, f)
+    }
+}
+
+impl TryFrom<u8> for TimerID {
+    type Error = InvalidTimerIDError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value < NB_TIMERS {
+            Ok(TimerID { id: value })
         } else {
-            InOut::Input
+            Err(InvalidTimerIDError { id: value })
+        }
+    }
+}
+
+impl From<TimerID> for u8 {
+    fn from(value: TimerID) -> Self {
+        value.id
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum TimeUnit {
+    Tenths,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl TimeUnit {
+    const ALL: [TimeUnit; 4] = [
+        TimeUnit::Tenths,
+        TimeUnit::Seconds,
+        TimeUnit::Minutes,
+        TimeUnit::Hours,
+    ];
+
+    fn as_duration(&self) -> Duration {
+        match self {
+            TimeUnit::Tenths => Duration::from_millis(100),
+            TimeUnit::Seconds => Duration::from_secs(1),
+            TimeUnit::Minutes => Duration::from_secs(60),
+            TimeUnit::Hours => Duration::from_secs(3600),
+        }
+    }
+
+    fn as_bits(&self) -> u8 {
+        match self {
+            TimeUnit::Tenths => 0b0000_0000,
+            TimeUnit::Seconds => 0b0000_1000,
+            TimeUnit::Minutes => 0b0001_0000,
+            TimeUnit::Hours => 0b0001_1000,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits & START_TIMER_UNIT_MASK {
+            0b0000_0000 => TimeUnit::Tenths,
+            0b0000_1000 => TimeUnit::Seconds,
+            0b0001_0000 => TimeUnit::Minutes,
+            _ => TimeUnit::Hours,
         }
     }
 }
 
+/// Duration of a timer, as encoded in a START TIMER instruction: a value of up to 63 units
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) struct TimerDuration {
+    value: u8,
+    unit: TimeUnit,
+}
+
+impl TimerDuration {
+    /// Uses the finest unit that can represent the duration exactly,
+    /// returns `None` if there is no such unit
+    pub(super) fn from_duration(duration: Duration) -> Option<Self> {
+        TimeUnit::ALL.iter().find_map(|unit| {
+            let unit_nanos = unit.as_duration().as_nanos();
+            let value = duration.as_nanos() / unit_nanos;
+            if value > 0
+                && value <= MAX_TIMER_VALUE as u128
+                && duration.as_nanos().is_multiple_of(unit_nanos)
+            {
+                Some(TimerDuration {
+                    value: value as u8,
+                    unit: *unit,
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    pub(super) fn as_duration(&self) -> Duration {
+        self.unit.as_duration() * self.value as u32
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum InstructionEncoding {
     SingleByte(u8),
@@ -136,6 +299,13 @@ impl InstructionEncoding {
             SECOND_BYTE_PREFIX | (in_out & DUAL_BYTE_MASK),
         )
     }
+
+    fn compact(instruction: u8, in_out: u8) -> InstructionEncoding {
+        InstructionEncoding::DualByte(
+            COMPACT_BYTE_PREFIX | (instruction & DUAL_BYTE_MASK),
+            SECOND_BYTE_PREFIX | (in_out & DUAL_BYTE_MASK),
+        )
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -153,6 +323,13 @@ pub(super) enum Instruction {
     Toggle {
         output: PinID,
     },
+    SetFlag {
+        flag: PinID,
+        value: Value,
+    },
+    ToggleFlag {
+        flag: PinID,
+    },
     On {
         input: PinID,
         edge: Edge,
@@ -163,13 +340,59 @@ pub(super) enum Instruction {
         value: Value,
         in_out: InOut,
     },
+    StartTimer {
+        timer: TimerID,
+        duration: TimerDuration,
+    },
+    CancelTimer {
+        timer: TimerID,
+    },
+    IfTimer {
+        timer: TimerID,
+        check: TimerCheck,
+    },
+    /// Short for ON, TOGGLE and POP, the most common statement in programs
+    OnToggle {
+        input: PinID,
+        edge: Edge,
+        output: PinID,
+    },
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct DecodingError {}
 
+impl From<InvalidPinIDError> for DecodingError {
+    fn from(_: InvalidPinIDError) -> Self {
+        DecodingError {}
+    }
+}
+
+impl From<InvalidTimerIDError> for DecodingError {
+    fn from(_: InvalidTimerIDError) -> Self {
+        DecodingError {}
+    }
+}
+
 impl Instruction {
-    fn byte_size(&self) -> usize {
+    /// The feature a controller needs to run the instruction, 0 for instructions every controller runs
+    fn feature(&self) -> u8 {
+        match *self {
+            Instruction::StartTimer { .. }
+            | Instruction::CancelTimer { .. }
+            | Instruction::IfTimer { .. } => FEATURE_TIMERS,
+            Instruction::SetFlag { .. }
+            | Instruction::ToggleFlag { .. }
+            | Instruction::If {
+                in_out: InOut::Flag,
+                ..
+            } => FEATURE_FLAGS,
+            Instruction::OnToggle { .. } => FEATURE_ON_TOGGLE,
+            _ => 0,
+        }
+    }
+
+    pub(super) fn byte_size(&self) -> usize {
         match *self {
             Instruction::End
             | Instruction::And
@@ -179,8 +402,14 @@ impl Instruction {
             | Instruction::Pop => 1,
             Instruction::Set { .. }
             | Instruction::Toggle { .. }
+            | Instruction::SetFlag { .. }
+            | Instruction::ToggleFlag { .. }
             | Instruction::On { .. }
-            | Instruction::If { .. } => 2,
+            | Instruction::If { .. }
+            | Instruction::StartTimer { .. }
+            | Instruction::CancelTimer { .. }
+            | Instruction::IfTimer { .. }
+            | Instruction::OnToggle { .. } => 2,
         }
     }
 
@@ -202,6 +431,16 @@ impl Instruction {
             Instruction::Toggle { output } => {
                 InstructionEncoding::dual_byte(INSTR_TOGGLE, output.into())
             }
+            Instruction::SetFlag { flag, value } => {
+                let mut instr = INSTR_SET_FLAG;
+                if value.as_bit() {
+                    instr |= SET_VALUE_MASK;
+                }
+                InstructionEncoding::dual_byte(instr, flag.into())
+            }
+            Instruction::ToggleFlag { flag } => {
+                InstructionEncoding::dual_byte(INSTR_TOGGLE_FLAG, flag.into())
+            }
             Instruction::On { input, edge } => {
                 let mut instr = INSTR_ON;
                 if edge.as_bit() {
@@ -209,6 +448,21 @@ impl Instruction {
                 }
                 InstructionEncoding::dual_byte(instr, input.into())
             }
+            Instruction::If {
+                number,
+                is_was,
+                value,
+                in_out: InOut::Flag,
+            } => {
+                let mut instr = INSTR_IF_FLAG;
+                if is_was.as_bit() {
+                    instr |= IF_FLAG_IS_WAS_MASK;
+                }
+                if value.as_bit() {
+                    instr |= IF_FLAG_VALUE_MASK;
+                }
+                InstructionEncoding::dual_byte(instr, number.into())
+            }
             Instruction::If {
                 number,
                 is_was,
@@ -220,7 +474,7 @@ impl Instruction {
                 if is_was.as_bit() {
                     instr |= IF_IS_WAS_MASK;
                 }
-                if in_out.as_bit() {
+                if in_out == InOut::Output {
                     instr |= IF_IO_MASK;
                 }
                 if value.as_bit() {
@@ -228,6 +482,31 @@ impl Instruction {
                 }
                 InstructionEncoding::dual_byte(instr, number.into())
             }
+            Instruction::StartTimer { timer, duration } => {
+                let instr = INSTR_START_TIMER | duration.unit.as_bits() | u8::from(timer);
+                InstructionEncoding::dual_byte(instr, duration.value)
+            }
+            Instruction::CancelTimer { timer } => {
+                InstructionEncoding::dual_byte(INSTR_CANCEL_TIMER, timer.into())
+            }
+            Instruction::IfTimer { timer, check } => {
+                let mut instr = INSTR_IF_TIMER;
+                if check.as_bit() {
+                    instr |= IF_TIMER_CHECK_MASK;
+                }
+                InstructionEncoding::dual_byte(instr, timer.into())
+            }
+            Instruction::OnToggle {
+                input,
+                edge,
+                output,
+            } => {
+                let mut instr = u8::from(input);
+                if edge.as_bit() {
+                    instr |= ON_TOGGLE_EDGE_MASK;
+                }
+                InstructionEncoding::compact(instr, output.into())
+            }
         }
     }
 
@@ -242,28 +521,74 @@ impl Instruction {
                 INSTR_POP => Ok(Instruction::Pop),
                 _ => Err(DecodingError {}),
             },
+            InstructionEncoding::DualByte(instr, value)
+                if instr & !DUAL_BYTE_MASK == COMPACT_BYTE_PREFIX =>
+            {
+                Ok(Instruction::OnToggle {
+                    input: (instr & ON_TOGGLE_INPUT_MASK).try_into()?,
+                    edge: Edge::from_bit(instr & ON_TOGGLE_EDGE_MASK != 0),
+                    output: (value & DUAL_BYTE_MASK).try_into()?,
+                })
+            }
             InstructionEncoding::DualByte(instr, value) => {
                 let value = value & DUAL_BYTE_MASK;
                 if instr & INSTR_SET_MASK == INSTR_SET {
                     Ok(Instruction::Set {
-                        output: value.try_into().unwrap(),
+                        output: value.try_into()?,
                         value: Value::from_bit(instr & SET_VALUE_MASK != 0),
                     })
                 } else if instr & INSTR_TOGGLE_MASK == INSTR_TOGGLE {
                     Ok(Instruction::Toggle {
-                        output: value.try_into().unwrap(),
+                        output: value.try_into()?,
                     })
                 } else if instr & INSTR_ON_MASK == INSTR_ON {
                     Ok(Instruction::On {
-                        input: value.try_into().unwrap(),
+                        input: value.try_into()?,
                         edge: Edge::from_bit(instr & ON_EDGE_MASK != 0),
                     })
                 } else if instr & INSTR_IF_MASK == INSTR_IF {
                     Ok(Instruction::If {
-                        number: value.try_into().unwrap(),
+                        number: value.try_into()?,
                         is_was: IsWas::from_bit(instr & IF_IS_WAS_MASK != 0),
                         value: Value::from_bit(instr & IF_VALUE_MASK != 0),
-                        in_out: InOut::from_bit(instr & IF_IO_MASK != 0),
+                        in_out: if instr & IF_IO_MASK != 0 {
+                            InOut::Output
+                        } else {
+                            InOut::Input
+                        },
+                    })
+                } else if instr & INSTR_START_TIMER_MASK == INSTR_START_TIMER {
+                    Ok(Instruction::StartTimer {
+                        timer: (instr & START_TIMER_ID_MASK).try_into()?,
+                        duration: TimerDuration {
+                            value,
+                            unit: TimeUnit::from_bits(instr),
+                        },
+                    })
+                } else if instr & INSTR_CANCEL_TIMER_MASK == INSTR_CANCEL_TIMER {
+                    Ok(Instruction::CancelTimer {
+                        timer: value.try_into()?,
+                    })
+                } else if instr & INSTR_SET_FLAG_MASK == INSTR_SET_FLAG {
+                    Ok(Instruction::SetFlag {
+                        flag: value.try_into()?,
+                        value: Value::from_bit(instr & SET_VALUE_MASK != 0),
+                    })
+                } else if instr & INSTR_TOGGLE_FLAG_MASK == INSTR_TOGGLE_FLAG {
+                    Ok(Instruction::ToggleFlag {
+                        flag: value.try_into()?,
+                    })
+                } else if instr & INSTR_IF_FLAG_MASK == INSTR_IF_FLAG {
+                    Ok(Instruction::If {
+                        number: value.try_into()?,
+                        is_was: IsWas::from_bit(instr & IF_FLAG_IS_WAS_MASK != 0),
+                        value: Value::from_bit(instr & IF_FLAG_VALUE_MASK != 0),
+                        in_out: InOut::Flag,
+                    })
+                } else if instr & INSTR_IF_TIMER_MASK == INSTR_IF_TIMER {
+                    Ok(Instruction::IfTimer {
+                        timer: value.try_into()?,
+                        check: TimerCheck::from_bit(instr & IF_TIMER_CHECK_MASK != 0),
                     })
                 } else {
                     Err(DecodingError {})
@@ -273,46 +598,96 @@ impl Instruction {
     }
 }
 
+/// What a timer of a compiled program is used for, so timers of two programs can be matched
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(super) enum TimerSource {
+    /// A named after block
+    Named(EntityID),
+    /// An after block without a name, by its body without locations
+    After(Vec<Statement>),
+    Gesture(Gesture, PinID),
+}
+
+/// Where an instruction was compiled from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) struct Origin {
+    /// The top-level statement, which is the include or rule call for the statements in it
+    pub(super) statement: SourceLoc,
+    /// The statement or condition itself, in the file it is written in
+    pub(super) location: SourceLoc,
+    /// The included file it is written in, by index in the files of the program,
+    /// none for the main program
+    pub(super) file: Option<usize>,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Program {
     pub declarations: IODeclarations,
     pub(super) instructions: Vec<Instruction>,
-    pub(super) source_locations: Vec<SourceLoc>,
+    /// Where every instruction comes from, empty for programs not compiled from source
+    pub(super) origins: Vec<Origin>,
+    /// The included files the instructions come from
+    pub(super) files: Vec<PathBuf>,
+    /// What every timer is used for, by timer ID, empty for programs not compiled from source
+    pub(super) timer_sources: Vec<TimerSource>,
 }
 
+/// The depth of the stack of the controller's VM
+pub(crate) const STACK_LIMIT: i32 = 32;
+/// The number of bytes of code the controller can store
+pub(crate) const PROGRAM_SIZE_LIMIT: usize = 246;
+
 #[derive(Copy, Clone, Error, Debug, Eq, PartialEq)]
 #[error("Stack limit error")]
 pub struct StackLimitError {
     source_location: Option<SourceLoc>,
 }
 
+impl StackLimitError {
+    /// The statement that exceeds the limit
+    pub fn source_location(&self) -> Option<SourceLoc> {
+        self.source_location
+    }
+}
+
 #[derive(Copy, Clone, Error, Debug, Eq, PartialEq)]
 #[error("Program size error")]
 pub struct ProgramSizeError {
     source_location: Option<SourceLoc>,
 }
 
+impl ProgramSizeError {
+    /// The statement that exceeds the limit
+    pub fn source_location(&self) -> Option<SourceLoc> {
+        self.source_location
+    }
+}
+
 impl Program {
     pub(crate) fn check_stack_depth(&self, limit: Option<i32>) -> Result<i32, StackLimitError> {
         let mut depth = 0;
         let mut max = 0;
         for (i, instr) in self.instructions.iter().enumerate() {
-            match *instr {
+            // The on toggle instruction pushes the edge, and pops it again
+            let temporary = match *instr {
                 Instruction::Pop | Instruction::And | Instruction::Or | Instruction::Xor => {
                     depth -= 1;
+                    continue;
                 }
-                Instruction::On { .. } | Instruction::If { .. } => {
+                Instruction::On { .. } | Instruction::If { .. } | Instruction::IfTimer { .. } => {
                     depth += 1;
-                    max = std::cmp::max(depth, max);
-                    if let Some(limit) = limit {
-                        if max > limit {
-                            return Err(StackLimitError {
-                                source_location: self.source_locations.get(i).copied(),
-                            });
-                        }
-                    }
+                    0
+                }
+                Instruction::OnToggle { .. } => 1,
+                _ => continue,
+            };
+            max = std::cmp::max(depth + temporary, max);
+            if let Some(limit) = limit {
+                if max > limit {
+                    return Err(StackLimitError {
+                        source_location: self.origins.get(i).map(|origin| origin.statement),
+                    });
                 }
-                _ => {}
             }
         }
         Ok(max)
@@ -328,7 +703,7 @@ impl Program {
             if let Some(limit) = limit {
                 if length > limit {
                     return Err(ProgramSizeError {
-                        source_location: self.source_locations.get(i).copied(),
+                        source_location: self.origins.get(i).map(|origin| origin.statement),
                     });
                 }
             }
@@ -354,8 +729,16 @@ impl Program {
         digest.finalize()
     }
 
+    pub(crate) fn features(&self) -> u8 {
+        self.instructions
+            .iter()
+            .fold(0, |features, instr| features | instr.feature())
+    }
+
     pub(crate) fn header(&self) -> ProgramHeader {
         ProgramHeader {
+            version: PROGRAM_FORMAT_VERSION,
+            features: self.features(),
             length: self.calc_length() as u16, // TODO(Roel): ???
             crc: self.calc_crc(),
         }
@@ -365,18 +748,19 @@ impl Program {
 impl TryFrom<&[u8]> for Program {
     type Error = DecodingError;
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        if bytes.len() < 8 {
+        if bytes.len() < PROGRAM_HEADER_LENGTH {
             return Err(DecodingError {});
         }
-        if &bytes[0..4] != b"SHAL" {
+        if &bytes[0..4] != b"SHAL" || bytes[4] != PROGRAM_FORMAT_VERSION {
             return Err(DecodingError {});
         }
-        let read_length = u16::from_be_bytes([bytes[4], bytes[5]]);
-        let read_crc = u16::from_be_bytes([bytes[6], bytes[7]]);
-        if bytes.len() - (read_length as usize) != 8 {
+        let read_features = bytes[5];
+        let read_length = u16::from_be_bytes([bytes[6], bytes[7]]);
+        let read_crc = u16::from_be_bytes([bytes[8], bytes[9]]);
+        if bytes.len() != PROGRAM_HEADER_LENGTH + read_length as usize {
             return Err(DecodingError {});
         }
-        let program_code = &bytes[8..];
+        let program_code = &bytes[PROGRAM_HEADER_LENGTH..];
         let crc = Crc::<u16>::new(&CRC_16_XMODEM);
         let mut digest = crc.digest();
         digest.update(program_code);
@@ -388,12 +772,14 @@ impl TryFrom<&[u8]> for Program {
         let mut first_byte: Option<u8> = None;
         for b in program_code {
             if let Some(fb) = first_byte {
-                if *b & SECOND_BYTE_PREFIX == 0 {
+                if *b & !DUAL_BYTE_MASK != SECOND_BYTE_PREFIX {
                     return Err(DecodingError {});
                 }
                 instructions.push(Instruction::decode(&InstructionEncoding::DualByte(fb, *b))?);
                 first_byte = None;
-            } else if *b & !DUAL_BYTE_MASK == FIRST_BYTE_PREFIX {
+            } else if *b & !DUAL_BYTE_MASK == FIRST_BYTE_PREFIX
+                || *b & !DUAL_BYTE_MASK == COMPACT_BYTE_PREFIX
+            {
                 first_byte = Some(*b);
             } else if *b & !DUAL_BYTE_MASK == SECOND_BYTE_PREFIX {
                 return Err(DecodingError {});
@@ -401,11 +787,22 @@ impl TryFrom<&[u8]> for Program {
                 instructions.push(Instruction::decode(&InstructionEncoding::SingleByte(*b))?);
             }
         }
-        Ok(Program {
+        if first_byte.is_some() {
+            // Program ends halfway through a dual byte instruction
+            return Err(DecodingError {});
+        }
+        let program = Program {
             declarations: IODeclarations::default(),
             instructions,
-            source_locations: vec![],
-        })
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
+        };
+        // The header lists exactly the features the code uses
+        if program.features() != read_features {
+            return Err(DecodingError {});
+        }
+        Ok(program)
     }
 }
 
@@ -427,16 +824,15 @@ impl From<&Program> for Vec<u8> {
         let crc = Crc::<u16>::new(&CRC_16_XMODEM);
         let mut digest = crc.digest();
         digest.update(&bytecode);
-        let crc = digest.finalize().to_be_bytes();
-
-        let length = (bytecode.len() as u16).to_be_bytes();
-
-        let mut header: Vec<u8> = vec![];
-        header.extend_from_slice(b"SHAL");
-        header.extend_from_slice(&length);
-        header.extend_from_slice(&crc);
+        let header = ProgramHeader {
+            version: PROGRAM_FORMAT_VERSION,
+            features: program.features(),
+            length: bytecode.len() as u16,
+            crc: digest.finalize(),
+        };
+        let header: [u8; PROGRAM_HEADER_LENGTH] = (&header).into();
 
-        let mut result = header;
+        let mut result = header.to_vec();
         result.append(&mut bytecode);
         result
     }
@@ -444,11 +840,289 @@ impl From<&Program> for Vec<u8> {
 
 #[cfg(test)]
 mod tests {
+    use crate::controller::program_header::{
+        ALL_FEATURES, FEATURE_FLAGS, FEATURE_ON_TOGGLE, FEATURE_TIMERS, PROGRAM_FORMAT_VERSION,
+        PROGRAM_HEADER_LENGTH,
+    };
     use crate::shal::ast::IODeclarations;
     use crate::shal::bytecode::Instruction::{End, If, Not, On, Or, Pop, Set, Toggle};
     use crate::shal::bytecode::{
-        Edge, InOut, IsWas, Program, ProgramSizeError, StackLimitError, Value,
+        AsBit, DecodingError, Edge, InOut, Instruction, InstructionEncoding, IsWas, Program,
+        ProgramSizeError, StackLimitError, TimeUnit, TimerCheck, TimerDuration, Value, NB_TIMERS,
     };
+    use proptest::prelude::*;
+    use std::time::Duration;
+
+    fn arb_instruction() -> impl Strategy<Value = Instruction> {
+        let pin = (0u8..32).prop_map(|p| p.try_into().unwrap());
+        let timer = (0u8..NB_TIMERS).prop_map(|t| t.try_into().unwrap());
+        let duration = (0u8..64, prop::sample::select(TimeUnit::ALL.to_vec()))
+            .prop_map(|(value, unit)| TimerDuration { value, unit });
+        prop_oneof![
+            Just(End),
+            Just(Instruction::And),
+            Just(Or),
+            Just(Instruction::Xor),
+            Just(Not),
+            Just(Pop),
+            (pin.clone(), any::<bool>()).prop_map(|(output, v)| Set {
+                output,
+                value: Value::from_bit(v)
+            }),
+            pin.clone().prop_map(|output| Toggle { output }),
+            (pin.clone(), any::<bool>()).prop_map(|(input, e)| On {
+                input,
+                edge: Edge::from_bit(e)
+            }),
+            (pin.clone(), any::<bool>()).prop_map(|(flag, v)| Instruction::SetFlag {
+                flag,
+                value: Value::from_bit(v)
+            }),
+            pin.clone()
+                .prop_map(|flag| Instruction::ToggleFlag { flag }),
+            (
+                pin.clone(),
+                any::<bool>(),
+                any::<bool>(),
+                prop::sample::select(vec![InOut::Input, InOut::Output, InOut::Flag])
+            )
+                .prop_map(|(number, v, i, in_out)| If {
+                    number,
+                    value: Value::from_bit(v),
+                    is_was: IsWas::from_bit(i),
+                    in_out,
+                }),
+            (timer.clone(), duration)
+                .prop_map(|(timer, duration)| Instruction::StartTimer { timer, duration }),
+            timer
+                .clone()
+                .prop_map(|timer| Instruction::CancelTimer { timer }),
+            (timer, any::<bool>()).prop_map(|(timer, c)| Instruction::IfTimer {
+                timer,
+                check: TimerCheck::from_bit(c)
+            }),
+            (pin.clone(), any::<bool>(), pin).prop_map(|(input, e, output)| {
+                Instruction::OnToggle {
+                    input,
+                    edge: Edge::from_bit(e),
+                    output,
+                }
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_timer_duration() {
+        let duration = |secs: f64| TimerDuration::from_duration(Duration::from_secs_f64(secs));
+        assert_eq!(
+            Some(TimerDuration {
+                value: 5,
+                unit: TimeUnit::Tenths
+            }),
+            duration(0.5)
+        );
+        assert_eq!(
+            Some(TimerDuration {
+                value: 30,
+                unit: TimeUnit::Seconds
+            }),
+            duration(30.0)
+        );
+        assert_eq!(
+            Some(TimerDuration {
+                value: 2,
+                unit: TimeUnit::Minutes
+            }),
+            duration(120.0)
+        );
+        assert_eq!(
+            Some(TimerDuration {
+                value: 63,
+                unit: TimeUnit::Hours
+            }),
+            duration(63.0 * 3600.0)
+        );
+        // Not a whole number of minutes, and too many seconds
+        assert_eq!(None, duration(90.0));
+        assert_eq!(None, duration(0.05));
+        assert_eq!(None, duration(0.0));
+        assert_eq!(None, duration(64.0 * 3600.0));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_instruction_round_trip(instruction in arb_instruction()) {
+            prop_assert_eq!(Ok(instruction), Instruction::decode(&instruction.encode()));
+        }
+
+        #[test]
+        fn prop_program_round_trip(instructions in prop::collection::vec(arb_instruction(), 0..100)) {
+            let program = Program {
+                declarations: IODeclarations::default(),
+                instructions,
+                origins: vec![],
+                files: vec![],
+                timer_sources: vec![],
+            };
+            let bytes: Vec<u8> = (&program).into();
+            prop_assert_eq!(Ok(program), Program::try_from(&bytes[..]));
+        }
+
+        #[test]
+        fn prop_decode_arbitrary_code(
+            features in 0..=ALL_FEATURES,
+            code in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            // Arbitrary code behind a valid header, so decoding gets past the header checks
+            let crc = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+            let mut bytes = b"SHAL".to_vec();
+            bytes.extend_from_slice(&[PROGRAM_FORMAT_VERSION, features]);
+            bytes.extend_from_slice(&(code.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&crc.checksum(&code).to_be_bytes());
+            bytes.extend_from_slice(&code);
+            if let Ok(program) = Program::try_from(&bytes[..]) {
+                prop_assert_eq!(bytes, Vec::<u8>::from(&program));
+            }
+        }
+
+        #[test]
+        fn prop_decode_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = Program::try_from(&bytes[..]);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        // Toggle of pin 63
+        assert_eq!(
+            Err(DecodingError {}),
+            Instruction::decode(&InstructionEncoding::DualByte(0b1000_0010, 0b1111_1111))
+        );
+        // On toggle of output 32
+        assert_eq!(
+            Err(DecodingError {}),
+            Instruction::decode(&InstructionEncoding::DualByte(0b0100_0000, 0b1110_0000))
+        );
+        // Declared length larger than the actual code
+        assert!(Program::try_from(&b"SHAL\x01\x00\x00\x10\x00\x00"[..]).is_err());
+        // Dangling first byte of a dual byte instruction
+        let crc = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM).checksum(&[0b1000_0010]);
+        let mut bytes = b"SHAL\x01\x00\x00\x01".to_vec();
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes.push(0b1000_0010);
+        assert!(Program::try_from(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_features() {
+        let program = Program {
+            declarations: IODeclarations::default(),
+            instructions: vec![
+                Instruction::CancelTimer {
+                    timer: 0.try_into().unwrap(),
+                },
+                End,
+            ],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
+        };
+        assert_eq!(FEATURE_TIMERS, program.features());
+        assert_eq!(FEATURE_TIMERS, program.header().features);
+        let mut encoded: Vec<u8> = (&program).into();
+        assert_eq!(&encoded[..6], b"SHAL\x01\x01");
+        assert_eq!(Ok(&program), Program::try_from(&encoded[..]).as_ref());
+
+        // The header must list the features the code uses, and have a known version
+        encoded[5] = FEATURE_TIMERS | FEATURE_FLAGS;
+        assert_eq!(Err(DecodingError {}), Program::try_from(&encoded[..]));
+        encoded[5] = FEATURE_TIMERS;
+        encoded[4] = PROGRAM_FORMAT_VERSION + 1;
+        assert_eq!(Err(DecodingError {}), Program::try_from(&encoded[..]));
+
+        let flags = Program {
+            declarations: IODeclarations::default(),
+            instructions: vec![
+                If {
+                    number: 0.try_into().unwrap(),
+                    value: Value::High,
+                    is_was: IsWas::Was,
+                    in_out: InOut::Flag,
+                },
+                Pop,
+                End,
+            ],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
+        };
+        assert_eq!(FEATURE_FLAGS, flags.features());
+    }
+
+    #[test]
+    fn test_on_toggle() {
+        let program = Program {
+            declarations: IODeclarations::default(),
+            instructions: vec![
+                Instruction::OnToggle {
+                    input: 3.try_into().unwrap(),
+                    edge: Edge::Rising,
+                    output: 5.try_into().unwrap(),
+                },
+                End,
+            ],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
+        };
+        let encoded: Vec<u8> = (&program).into();
+        assert_eq!(
+            &encoded[PROGRAM_HEADER_LENGTH..],
+            &[0b0110_0011, 0b1100_0101, 0]
+        );
+        assert_eq!(FEATURE_ON_TOGGLE, program.features());
+        assert_eq!(Ok(3), program.check_program_size(None));
+        // The edge is pushed for the toggle, and popped again
+        assert_eq!(Ok(1), program.check_stack_depth(None));
+    }
+
+    #[test]
+    fn test_flag_instructions() {
+        let flag = 3.try_into().unwrap();
+        let cases = [
+            (
+                Instruction::SetFlag {
+                    flag,
+                    value: Value::High,
+                },
+                0b1001_0101,
+            ),
+            (Instruction::ToggleFlag { flag }, 0b1001_0110),
+            (
+                If {
+                    number: flag,
+                    value: Value::Low,
+                    is_was: IsWas::Was,
+                    in_out: InOut::Flag,
+                },
+                0b1001_1000,
+            ),
+            (
+                If {
+                    number: flag,
+                    value: Value::High,
+                    is_was: IsWas::Is,
+                    in_out: InOut::Flag,
+                },
+                0b1001_1011,
+            ),
+        ];
+        for (instruction, first_byte) in cases {
+            let encoding = InstructionEncoding::DualByte(first_byte, 0b1100_0011);
+            assert_eq!(encoding, instruction.encode());
+            assert_eq!(Ok(instruction), Instruction::decode(&encoding));
+        }
+    }
 
     #[test]
     fn test_program() {
@@ -496,7 +1170,9 @@ mod tests {
                 Pop,
                 End,
             ],
-            source_locations: vec![],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
         };
         assert_eq!(Ok(2), program.check_stack_depth(None));
         assert_eq!(
@@ -519,6 +1195,8 @@ mod tests {
                 b'H',
                 b'A',
                 b'L',
+                1, // version
+                0, // features
                 0,
                 22,
                 0x59,
@@ -1,17 +1,82 @@
+use std::time::Duration;
+use thiserror::Error;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub(super) enum Edge {
     Rising,
     Falling,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub(super) enum IsWas {
     Was,
     Is,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub(super) enum Value {
     Low,
     High,
 }
+
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub(super) enum DurationError {
+    #[error("Duration out of range: {duration}")]
+    OutOfRange { duration: String },
+    #[error("Invalid duration: {duration}, expected e.g. 500ms, 10s, 2min or 1h")]
+    Invalid { duration: String },
+}
+
+/// Parses a duration like `500ms` or `2min`, in programs and in the declarations header
+pub(super) fn parse_duration(input: &str) -> Result<Duration, DurationError> {
+    let invalid = || DurationError::Invalid {
+        duration: input.to_owned(),
+    };
+    let out_of_range = || DurationError::OutOfRange {
+        duration: input.to_owned(),
+    };
+    let unit_start = input
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (number, unit) = input.split_at(unit_start);
+    if number.is_empty() || (number.len() > 1 && number.starts_with('0')) {
+        return Err(invalid());
+    }
+    let unit_millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "min" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return Err(invalid()),
+    };
+    let number = number.parse::<u64>().map_err(|_| out_of_range())?;
+    let millis = number.checked_mul(unit_millis).ok_or_else(out_of_range)?;
+    Ok(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shal::common::{parse_duration, DurationError};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(Duration::from_millis(500)), parse_duration("500ms"));
+        assert_eq!(Ok(Duration::from_secs(120)), parse_duration("2min"));
+        assert_eq!(Ok(Duration::ZERO), parse_duration("0s"));
+        for invalid in ["2s ", "2", "s", "02s", "2 s", "2sec", "-1s"] {
+            assert!(
+                matches!(parse_duration(invalid), Err(DurationError::Invalid { .. })),
+                "{invalid}"
+            );
+        }
+        assert!(matches!(
+            parse_duration("99999999999999999h"),
+            Err(DurationError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            parse_duration("99999999999999999999ms"),
+            Err(DurationError::OutOfRange { .. })
+        ));
+    }
+}
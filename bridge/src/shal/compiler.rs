@@ -1,8 +1,17 @@
 use crate::shal::ast::{EntityID, IODeclaration, IODeclarations, PinID};
-use crate::shal::bytecode::Instruction;
+use crate::shal::bytecode::{
+    Instruction, Origin, TimerCheck, TimerDuration, TimerID, TimerSource, NB_TIMERS,
+};
 use crate::shal::common;
-use crate::shal::compiler::CompileError::UnknownEntityError;
+use crate::shal::common::{IsWas, Value};
+use crate::shal::compiler::CompileError::{
+    DuplicateTimerError, ExpansionError, IncludeError, TimerDurationError, TooManyTimersError,
+    UnknownEntityError, UnknownTimerError,
+};
 use crate::shal::{ast, bytecode};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
 fn loc_to_string(source_loc: &Option<ast::SourceLoc>) -> String {
@@ -20,6 +29,61 @@ pub enum CompileError {
         name: EntityID,
         location: Option<ast::SourceLoc>,
     },
+    #[error("Unknown timer: {name}, timers are named with `after ... as {name}`")]
+    UnknownTimerError { name: EntityID },
+    #[error("Duplicate timer: {name}, all timer names must be unique")]
+    DuplicateTimerError { name: EntityID },
+    #[error("Too many timers, at most {NB_TIMERS} timers can be used")]
+    TooManyTimersError,
+    #[error("Unsupported timer duration: {duration:?}, durations must be a whole number of at most 63 tenths of a second, seconds, minutes or hours")]
+    TimerDurationError { duration: Duration },
+    #[error("{source}, in rule {rule} called at {location}")]
+    ExpansionError {
+        rule: EntityID,
+        location: ast::SourceLoc,
+        source: Box<CompileError>,
+    },
+    #[error("{source}, in {} included at {location}", path.display())]
+    IncludeError {
+        path: PathBuf,
+        location: ast::SourceLoc,
+        source: Box<CompileError>,
+    },
+}
+
+/// A rule call or included file, errors inside it are reported at its location,
+/// a rule call also has the included file the rule is defined in
+#[derive(Copy, Clone)]
+enum Frame<'a> {
+    Call(&'a EntityID, ast::SourceLoc, Option<&'a Path>),
+    Include(&'a Path, ast::SourceLoc),
+}
+
+impl Frame<'_> {
+    fn wrap(&self, error: CompileError) -> CompileError {
+        match *self {
+            Frame::Call(rule, location, _) => ExpansionError {
+                rule: rule.clone(),
+                location,
+                source: Box::new(error),
+            },
+            Frame::Include(path, location) => IncludeError {
+                path: path.to_owned(),
+                location,
+                source: Box::new(error),
+            },
+        }
+    }
+}
+
+/// Frames that are being compiled, from the outermost to the innermost one
+type CallStack<'a> = Vec<Frame<'a>>;
+
+fn wrap_in_frames(frames: &[Frame], error: CompileError) -> CompileError {
+    frames
+        .iter()
+        .rev()
+        .fold(error, |error, frame| frame.wrap(error))
 }
 
 fn retrieve_input(
@@ -41,15 +105,19 @@ fn retrieve_input(
     }
 }
 
+/// Resolves an output or flag, returns whether it is an output or a flag as well
 fn retrieve_output(
     declarations: &IODeclarations,
     output: &ast::Output,
-) -> Result<PinID, CompileError> {
+) -> Result<(PinID, bytecode::InOut), CompileError> {
     match output {
-        ast::Output::Number(number) => Ok(*number),
+        ast::Output::Number(number) => Ok((*number, bytecode::InOut::Output)),
+        ast::Output::Flag(number) => Ok((*number, bytecode::InOut::Flag)),
         ast::Output::Entity(entity_id) => {
             if let Some(IODeclaration { pin, .. }) = declarations.outputs.get(entity_id) {
-                Ok(*pin)
+                Ok((*pin, bytecode::InOut::Output))
+            } else if let Some(IODeclaration { pin, .. }) = declarations.flags.get(entity_id) {
+                Ok((*pin, bytecode::InOut::Flag))
             } else {
                 Err(UnknownEntityError {
                     name: entity_id.clone(),
@@ -66,144 +134,712 @@ fn retrieve_entity(
 ) -> Result<(PinID, bytecode::InOut), CompileError> {
     retrieve_input(declarations, &ast::Input::Entity(entity_id.clone()))
         .map(|i| (i, bytecode::InOut::Input))
-        .or_else(|_| {
-            retrieve_output(declarations, &ast::Output::Entity(entity_id.clone()))
-                .map(|i| (i, bytecode::InOut::Output))
+        .or_else(|_| retrieve_output(declarations, &ast::Output::Entity(entity_id.clone())))
+}
+
+/// Resolves the outputs of a group, in the order they were declared
+fn retrieve_group(
+    declarations: &IODeclarations,
+    group: &EntityID,
+) -> Result<Vec<PinID>, CompileError> {
+    let unknown = |name: &EntityID| UnknownEntityError {
+        name: name.clone(),
+        location: None,
+    };
+    let declaration = declarations
+        .groups
+        .get(group)
+        .ok_or_else(|| unknown(group))?;
+    declaration
+        .outputs
+        .iter()
+        .map(|output| {
+            declarations
+                .outputs
+                .get(output)
+                .map(|declaration| declaration.pin)
+                .ok_or_else(|| unknown(output))
         })
+        .collect()
+}
+
+/// Evaluates `true` and `false` in a condition, the result is either a constant
+/// or a condition without any constants
+fn fold_constants(condition: &ast::Condition) -> ast::Condition {
+    use ast::Condition::{And, Constant, Not, Or, Xor};
+    match condition {
+        And(l, r) => match (fold_constants(l), fold_constants(r)) {
+            (Constant(false), _) | (_, Constant(false)) => Constant(false),
+            (Constant(true), c) | (c, Constant(true)) => c,
+            (l, r) => And(Box::new(l), Box::new(r)),
+        },
+        Or(l, r) => match (fold_constants(l), fold_constants(r)) {
+            (Constant(true), _) | (_, Constant(true)) => Constant(true),
+            (Constant(false), c) | (c, Constant(false)) => c,
+            (l, r) => Or(Box::new(l), Box::new(r)),
+        },
+        Xor(l, r) => match (fold_constants(l), fold_constants(r)) {
+            (Constant(false), c) | (c, Constant(false)) => c,
+            (Constant(true), c) | (c, Constant(true)) => fold_constants(&Not(Box::new(c))),
+            (l, r) => Xor(Box::new(l), Box::new(r)),
+        },
+        Not(c) => match fold_constants(c) {
+            Constant(b) => Constant(!b),
+            c => Not(Box::new(c)),
+        },
+        c => c.clone(),
+    }
+}
+
+fn timer_duration(duration: &Duration) -> Result<TimerDuration, CompileError> {
+    TimerDuration::from_duration(*duration).ok_or(TimerDurationError {
+        duration: *duration,
+    })
+}
+
+/// The body of an after block or long press, compiled at the end of the program
+struct ElapsedBlock<'a> {
+    timer: TimerID,
+    statements: &'a [ast::Statement],
+    /// The rule calls and included files it is in
+    frames: CallStack<'a>,
+    /// The location of the top-level statement it is in
+    statement: Option<ast::SourceLoc>,
+    /// The origin of the after block or gesture
+    origin: Option<Origin>,
+}
+
+struct Compiler<'a> {
+    program: bytecode::Program,
+    timers: HashMap<EntityID, TimerID>,
+    nb_timers: u8,
+    elapsed_blocks: VecDeque<ElapsedBlock<'a>>,
+    /// Inputs, timers and origins of long presses, their timers are canceled on release
+    /// after the top-level statement they are in, outside of any enclosing condition
+    releases: Vec<(PinID, TimerID, Option<Origin>)>,
+    frames: CallStack<'a>,
+    /// The location of the top-level statement that is being compiled
+    statement: Option<ast::SourceLoc>,
+    /// The origin of the instructions that are being added
+    origin: Option<Origin>,
 }
 
 pub(crate) fn compile(ast_program: &ast::Program) -> Result<bytecode::Program, CompileError> {
-    let mut bytecode_program = bytecode::Program {
-        declarations: ast_program.declarations.clone(),
-        instructions: vec![],
-        source_locations: vec![],
+    let mut compiler = Compiler {
+        program: bytecode::Program {
+            declarations: ast_program.declarations.clone(),
+            instructions: vec![],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
+        },
+        timers: HashMap::new(),
+        nb_timers: 0,
+        elapsed_blocks: VecDeque::new(),
+        releases: vec![],
+        frames: vec![],
+        statement: None,
+        origin: None,
     };
-    for statement in ast_program.statements.iter() {
-        handle_statement(&mut bytecode_program, statement);
+    compiler.declare_timers(&ast_program.statements)?;
+    // Programs that weren't parsed from source have no locations
+    let locations = (ast_program.source_locations.len() == ast_program.statements.len())
+        .then_some(&ast_program.source_locations);
+    for (i, statement) in ast_program.statements.iter().enumerate() {
+        compiler.statement = locations.map(|locations| locations[i]);
+        compiler.handle_statement(statement)?;
+        compiler.push_releases();
+        compiler.add_origins();
+    }
+    while let Some(block) = compiler.elapsed_blocks.pop_front() {
+        compiler.statement = block.statement;
+        compiler.handle_elapsed(block)?;
+        compiler.push_releases();
+        compiler.add_origins();
     }
-    bytecode_program.instructions.push(Instruction::End);
-    Ok(bytecode_program)
+    compiler.program.instructions.push(Instruction::End);
+    Ok(compiler.program)
 }
 
-fn handle_statement(program: &mut bytecode::Program, statement: &ast::Statement) {
-    match statement {
-        ast::Statement::Action(action) => handle_action(program, action),
-        ast::Statement::IfElse(condition, if_block, else_block) => {
-            handle_if_else(program, condition, if_block, else_block);
+impl<'a> Compiler<'a> {
+    /// Gives the instructions that were added since the last call the current origin
+    fn add_origins(&mut self) {
+        if let Some(origin) = self.origin {
+            let length = self.program.instructions.len();
+            self.program.origins.resize(length, origin);
         }
-        ast::Statement::Event {
-            edge,
-            input,
-            statements,
-        } => {
-            handle_event(program, edge, input, statements);
+    }
+
+    /// Instructions that are added from now on have this origin
+    fn set_origin(&mut self, origin: Option<Origin>) {
+        self.add_origins();
+        self.origin = origin;
+    }
+
+    /// Instructions that are added from now on come from the statement or condition
+    /// at `location`, returns their origin to restore it after nested statements
+    fn locate(&mut self, location: ast::SourceLoc) -> Option<Origin> {
+        let origin = self.statement.map(|statement| Origin {
+            statement,
+            location,
+            file: self.file(),
+        });
+        self.set_origin(origin);
+        origin
+    }
+
+    /// The index in the files of the program of the included file
+    /// the statements that are being compiled are written in, none for the main program
+    fn file(&mut self) -> Option<usize> {
+        let path = match *self.frames.last()? {
+            Frame::Call(_, _, file) => file?,
+            Frame::Include(path, _) => path,
+        };
+        let files = &mut self.program.files;
+        Some(match files.iter().position(|file| file == path) {
+            Some(i) => i,
+            None => {
+                files.push(path.to_owned());
+                files.len() - 1
+            }
+        })
+    }
+
+    /// Cancels the timers of the long presses in the last top-level statement on release,
+    /// even when the condition the long press is in no longer holds
+    fn push_releases(&mut self) {
+        for (input, timer, origin) in std::mem::take(&mut self.releases) {
+            self.set_origin(origin);
+            self.program.instructions.extend([
+                Instruction::On {
+                    input,
+                    edge: common::Edge::Rising,
+                },
+                Instruction::CancelTimer { timer },
+                Instruction::Pop,
+            ]);
         }
     }
-}
 
-fn handle_action(program: &mut bytecode::Program, action: &ast::Action) {
-    match action {
-        ast::Action::Toggle(output) => {
-            let number = retrieve_output(&program.declarations, output).unwrap();
-            program
-                .instructions
-                .push(Instruction::Toggle { output: number });
+    fn allocate_timer(&mut self, source: TimerSource) -> Result<TimerID, CompileError> {
+        let timer = self.nb_timers.try_into().map_err(|_| TooManyTimersError)?;
+        self.nb_timers += 1;
+        self.program.timer_sources.push(source);
+        Ok(timer)
+    }
+
+    /// Assigns timers to all named after blocks up front,
+    /// so they can be referred to before the after block itself
+    fn declare_timers(&mut self, statements: &[ast::Statement]) -> Result<(), CompileError> {
+        for statement in statements {
+            match statement {
+                ast::Statement::Action(..) => {}
+                ast::Statement::IfElse(_, if_block, else_block, _) => {
+                    self.declare_timers(if_block)?;
+                    self.declare_timers(else_block)?;
+                }
+                ast::Statement::Event { statements, .. }
+                | ast::Statement::Gesture { statements, .. } => self.declare_timers(statements)?,
+                ast::Statement::After {
+                    timer, statements, ..
+                } => {
+                    if let Some(name) = timer {
+                        if self.timers.contains_key(name) {
+                            return Err(DuplicateTimerError { name: name.clone() });
+                        }
+                        let timer = self.allocate_timer(TimerSource::Named(name.clone()))?;
+                        self.timers.insert(name.clone(), timer);
+                    }
+                    self.declare_timers(statements)?;
+                }
+                ast::Statement::Call {
+                    rule,
+                    location,
+                    statements,
+                    ..
+                } => self
+                    .declare_timers(statements)
+                    .map_err(|error| Frame::Call(rule, *location, None).wrap(error))?,
+                ast::Statement::Include {
+                    path,
+                    location,
+                    statements,
+                } => self
+                    .declare_timers(statements)
+                    .map_err(|error| Frame::Include(path, *location).wrap(error))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn retrieve_timer(&self, name: &EntityID) -> Result<TimerID, CompileError> {
+        self.timers
+            .get(name)
+            .copied()
+            .ok_or_else(|| UnknownTimerError { name: name.clone() })
+    }
+
+    fn handle_statement(&mut self, statement: &'a ast::Statement) -> Result<(), CompileError> {
+        match statement {
+            ast::Statement::Action(action, location) => {
+                self.locate(*location);
+                self.handle_action(action)
+            }
+            ast::Statement::IfElse(condition, if_block, else_block, location) => {
+                self.handle_if_else(condition, if_block, else_block, *location)
+            }
+            ast::Statement::Event {
+                edge,
+                input,
+                statements,
+                location,
+            } => self.handle_event(edge, input, statements, *location),
+            ast::Statement::Gesture {
+                gesture,
+                input,
+                statements,
+                location,
+            } => self.handle_gesture(gesture, input, statements, *location),
+            ast::Statement::After {
+                duration,
+                timer,
+                statements,
+                location,
+            } => self.handle_after(duration, timer, statements, *location),
+            ast::Statement::Call {
+                rule,
+                location,
+                file,
+                statements,
+            } => self.handle_frame(Frame::Call(rule, *location, file.as_deref()), statements),
+            ast::Statement::Include {
+                path,
+                location,
+                statements,
+            } => self.handle_frame(Frame::Include(path, *location), statements),
+        }
+    }
+
+    fn handle_frame(
+        &mut self,
+        frame: Frame<'a>,
+        statements: &'a [ast::Statement],
+    ) -> Result<(), CompileError> {
+        self.frames.push(frame);
+        let result = self.handle_statements(statements);
+        self.frames.pop();
+        result.map_err(|error| frame.wrap(error))
+    }
+
+    fn handle_action(&mut self, action: &ast::Action) -> Result<(), CompileError> {
+        match action {
+            ast::Action::Toggle(output) => {
+                let instruction = match retrieve_output(&self.program.declarations, output)? {
+                    (flag, bytecode::InOut::Flag) => Instruction::ToggleFlag { flag },
+                    (output, _) => Instruction::Toggle { output },
+                };
+                self.program.instructions.push(instruction);
+            }
+            ast::Action::Set(output, value) => {
+                let value = *value;
+                let instruction = match retrieve_output(&self.program.declarations, output)? {
+                    (flag, bytecode::InOut::Flag) => Instruction::SetFlag { flag, value },
+                    (output, _) => Instruction::Set { output, value },
+                };
+                self.program.instructions.push(instruction);
+            }
+            ast::Action::ToggleGroup(group) => {
+                let outputs = retrieve_group(&self.program.declarations, group)?;
+                self.push_group_condition(ast::Quantifier::Any, &outputs, IsWas::Is, Value::High);
+                self.push_set_outputs(&outputs, Value::Low);
+                self.program.instructions.push(Instruction::Not);
+                self.push_set_outputs(&outputs, Value::High);
+                self.program.instructions.push(Instruction::Pop);
+            }
+            ast::Action::SetGroup(group, value) => {
+                let outputs = retrieve_group(&self.program.declarations, group)?;
+                self.push_set_outputs(&outputs, *value);
+            }
+            ast::Action::Cancel(name) => {
+                let timer = self.retrieve_timer(name)?;
+                self.program
+                    .instructions
+                    .push(Instruction::CancelTimer { timer });
+            }
         }
-        ast::Action::Set(output, value) => {
-            let number = retrieve_output(&program.declarations, output).unwrap();
-            program.instructions.push(Instruction::Set {
-                output: number,
-                value: *value,
+        Ok(())
+    }
+
+    fn push_set_outputs(&mut self, outputs: &[PinID], value: Value) {
+        self.program.instructions.extend(
+            outputs
+                .iter()
+                .map(|&output| Instruction::Set { output, value }),
+        );
+    }
+
+    /// Checks all outputs of a group, combining the results with `or` (any) or `and` (all)
+    fn push_group_condition(
+        &mut self,
+        quantifier: ast::Quantifier,
+        outputs: &[PinID],
+        is_was: IsWas,
+        value: Value,
+    ) {
+        for (i, &number) in outputs.iter().enumerate() {
+            self.program.instructions.push(Instruction::If {
+                number,
+                is_was,
+                value,
+                in_out: bytecode::InOut::Output,
             });
+            if i > 0 {
+                self.program.instructions.push(match quantifier {
+                    ast::Quantifier::Any => Instruction::Or,
+                    ast::Quantifier::All => Instruction::And,
+                });
+            }
         }
     }
-}
 
-fn handle_if_else(
-    program: &mut bytecode::Program,
-    condition: &ast::Condition,
-    if_block: &[ast::Statement],
-    else_block: &[ast::Statement],
-) {
-    handle_condition(program, condition);
-    for statement in if_block.iter() {
-        handle_statement(program, statement);
-    }
-    if !else_block.is_empty() {
-        program.instructions.push(Instruction::Not);
-        for statement in else_block.iter() {
-            handle_statement(program, statement);
+    fn handle_statements(&mut self, statements: &'a [ast::Statement]) -> Result<(), CompileError> {
+        for statement in statements.iter() {
+            self.handle_statement(statement)?;
         }
+        Ok(())
     }
-    program.instructions.push(Instruction::Pop);
-}
 
-fn handle_condition(program: &mut bytecode::Program, condition: &ast::Condition) {
-    match condition {
-        ast::Condition::And(l, r) => {
-            handle_condition(program, l.as_ref());
-            handle_condition(program, r.as_ref());
-            program.instructions.push(Instruction::And);
+    fn handle_if_else(
+        &mut self,
+        condition: &ast::Condition,
+        if_block: &'a [ast::Statement],
+        else_block: &'a [ast::Statement],
+        location: ast::SourceLoc,
+    ) -> Result<(), CompileError> {
+        // A constant condition selects one of the blocks at compile time,
+        // the dropped parts are still checked for unknown entities
+        self.check_condition(condition)?;
+        let condition = match fold_constants(condition) {
+            ast::Condition::Constant(true) => {
+                self.check_statements(else_block)?;
+                return self.handle_statements(if_block);
+            }
+            ast::Condition::Constant(false) => {
+                self.check_statements(if_block)?;
+                return self.handle_statements(else_block);
+            }
+            condition => condition,
+        };
+        let origin = self.locate(location);
+        self.handle_condition(&condition)?;
+        for statement in if_block.iter() {
+            self.handle_statement(statement)?;
         }
-        ast::Condition::Or(l, r) => {
-            handle_condition(program, l.as_ref());
-            handle_condition(program, r.as_ref());
-            program.instructions.push(Instruction::Or);
+        self.set_origin(origin);
+        if !else_block.is_empty() {
+            self.program.instructions.push(Instruction::Not);
+            for statement in else_block.iter() {
+                self.handle_statement(statement)?;
+            }
+            self.set_origin(origin);
         }
-        ast::Condition::Xor(l, r) => {
-            handle_condition(program, l.as_ref());
-            handle_condition(program, r.as_ref());
-            program.instructions.push(Instruction::Xor);
+        self.program.instructions.push(Instruction::Pop);
+        Ok(())
+    }
+
+    /// Resolves all entities and timers of a condition without compiling it
+    fn check_condition(&self, condition: &ast::Condition) -> Result<(), CompileError> {
+        let declarations = &self.program.declarations;
+        match condition {
+            ast::Condition::And(l, r) | ast::Condition::Or(l, r) | ast::Condition::Xor(l, r) => {
+                self.check_condition(l)?;
+                self.check_condition(r)?;
+            }
+            ast::Condition::Not(c) => self.check_condition(c)?,
+            ast::Condition::Input(input, ..) => {
+                retrieve_input(declarations, input)?;
+            }
+            ast::Condition::Output(output, ..) => {
+                retrieve_output(declarations, output)?;
+            }
+            ast::Condition::Entity(entity, ..) => {
+                retrieve_entity(declarations, entity)?;
+            }
+            ast::Condition::Group(_, group, ..) => {
+                retrieve_group(declarations, group)?;
+            }
+            ast::Condition::Constant(_) => {}
+            ast::Condition::Timer(name, _) => {
+                self.retrieve_timer(name)?;
+            }
         }
-        ast::Condition::Not(c) => {
-            handle_condition(program, c.as_ref());
-            program.instructions.push(Instruction::Not);
+        Ok(())
+    }
+
+    /// Resolves all entities, timers and durations of statements without compiling them,
+    /// for blocks that are dropped because their condition is constant
+    fn check_statements(&self, statements: &[ast::Statement]) -> Result<(), CompileError> {
+        let declarations = &self.program.declarations;
+        for statement in statements {
+            match statement {
+                ast::Statement::Action(action, _) => match action {
+                    ast::Action::Toggle(output) | ast::Action::Set(output, _) => {
+                        retrieve_output(declarations, output)?;
+                    }
+                    ast::Action::ToggleGroup(group) | ast::Action::SetGroup(group, _) => {
+                        retrieve_group(declarations, group)?;
+                    }
+                    ast::Action::Cancel(name) => {
+                        self.retrieve_timer(name)?;
+                    }
+                },
+                ast::Statement::IfElse(condition, if_block, else_block, _) => {
+                    self.check_condition(condition)?;
+                    self.check_statements(if_block)?;
+                    self.check_statements(else_block)?;
+                }
+                ast::Statement::Event {
+                    input, statements, ..
+                }
+                | ast::Statement::Gesture {
+                    input, statements, ..
+                } => {
+                    retrieve_input(declarations, input)?;
+                    self.check_statements(statements)?;
+                }
+                ast::Statement::After {
+                    duration,
+                    timer,
+                    statements,
+                    ..
+                } => {
+                    timer_duration(duration)?;
+                    if let Some(name) = timer {
+                        self.retrieve_timer(name)?;
+                    }
+                    self.check_statements(statements)?;
+                }
+                ast::Statement::Call {
+                    rule,
+                    location,
+                    statements,
+                    ..
+                } => self
+                    .check_statements(statements)
+                    .map_err(|error| Frame::Call(rule, *location, None).wrap(error))?,
+                ast::Statement::Include {
+                    path,
+                    location,
+                    statements,
+                } => self
+                    .check_statements(statements)
+                    .map_err(|error| Frame::Include(path, *location).wrap(error))?,
+            }
         }
-        ast::Condition::Input(input, is_was, value) => {
-            let number = retrieve_input(&program.declarations, input).unwrap();
-            program.instructions.push(Instruction::If {
-                number,
-                is_was: *is_was,
-                value: *value,
-                in_out: bytecode::InOut::Input,
-            });
+        Ok(())
+    }
+
+    fn handle_condition(&mut self, condition: &ast::Condition) -> Result<(), CompileError> {
+        match condition {
+            ast::Condition::And(l, r) => {
+                self.handle_condition(l.as_ref())?;
+                self.handle_condition(r.as_ref())?;
+                self.program.instructions.push(Instruction::And);
+            }
+            ast::Condition::Or(l, r) => {
+                self.handle_condition(l.as_ref())?;
+                self.handle_condition(r.as_ref())?;
+                self.program.instructions.push(Instruction::Or);
+            }
+            ast::Condition::Xor(l, r) => {
+                self.handle_condition(l.as_ref())?;
+                self.handle_condition(r.as_ref())?;
+                self.program.instructions.push(Instruction::Xor);
+            }
+            ast::Condition::Not(c) => {
+                self.handle_condition(c.as_ref())?;
+                self.program.instructions.push(Instruction::Not);
+            }
+            ast::Condition::Input(input, is_was, value) => {
+                let number = retrieve_input(&self.program.declarations, input)?;
+                self.program.instructions.push(Instruction::If {
+                    number,
+                    is_was: *is_was,
+                    value: *value,
+                    in_out: bytecode::InOut::Input,
+                });
+            }
+            ast::Condition::Output(output, is_was, value) => {
+                let (number, in_out) = retrieve_output(&self.program.declarations, output)?;
+                self.program.instructions.push(Instruction::If {
+                    number,
+                    is_was: *is_was,
+                    value: *value,
+                    in_out,
+                });
+            }
+            ast::Condition::Entity(entity, is_was, value) => {
+                let (number, in_out) = retrieve_entity(&self.program.declarations, entity)?;
+                self.program.instructions.push(Instruction::If {
+                    number,
+                    is_was: *is_was,
+                    value: *value,
+                    in_out,
+                });
+            }
+            ast::Condition::Group(quantifier, group, is_was, value) => {
+                let outputs = retrieve_group(&self.program.declarations, group)?;
+                self.push_group_condition(*quantifier, &outputs, *is_was, *value);
+            }
+            ast::Condition::Constant(_) => unreachable!("constants are folded away"),
+            ast::Condition::Timer(name, status) => {
+                let timer = self.retrieve_timer(name)?;
+                self.program.instructions.push(Instruction::IfTimer {
+                    timer,
+                    check: TimerCheck::Running,
+                });
+                if *status == ast::TimerStatus::Stopped {
+                    self.program.instructions.push(Instruction::Not);
+                }
+            }
         }
-        ast::Condition::Output(output, is_was, value) => {
-            let number = retrieve_output(&program.declarations, output).unwrap();
-            program.instructions.push(Instruction::If {
-                number,
-                is_was: *is_was,
-                value: *value,
-                in_out: bytecode::InOut::Output,
-            });
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        edge: &common::Edge,
+        input: &ast::Input,
+        statements: &'a [ast::Statement],
+        location: ast::SourceLoc,
+    ) -> Result<(), CompileError> {
+        let number = retrieve_input(&self.program.declarations, input)?;
+        let origin = self.locate(location);
+        self.program.instructions.push(Instruction::On {
+            input: number,
+            edge: *edge,
+        });
+        for statement in statements.iter() {
+            self.handle_statement(statement)?;
         }
-        ast::Condition::Entity(entity, is_was, value) => {
-            let (number, in_out) = retrieve_entity(&program.declarations, entity).unwrap();
-            program.instructions.push(Instruction::If {
-                number,
-                is_was: *is_was,
-                value: *value,
-                in_out,
-            });
+        self.set_origin(origin);
+        self.program.instructions.push(Instruction::Pop);
+        Ok(())
+    }
+
+    /// Starts the timer here, the body is only compiled at the end of the program,
+    /// guarded by a check whether the timer elapsed
+    fn handle_after(
+        &mut self,
+        duration: &Duration,
+        timer: &Option<EntityID>,
+        statements: &'a [ast::Statement],
+        location: ast::SourceLoc,
+    ) -> Result<(), CompileError> {
+        let origin = self.locate(location);
+        let timer = match timer {
+            Some(name) => self.retrieve_timer(name)?,
+            None => self.allocate_timer(TimerSource::After(
+                statements
+                    .iter()
+                    .map(ast::Statement::without_locations)
+                    .collect(),
+            ))?,
+        };
+        self.program.instructions.push(Instruction::StartTimer {
+            timer,
+            duration: timer_duration(duration)?,
+        });
+        self.elapsed_blocks.push_back(ElapsedBlock {
+            timer,
+            statements,
+            frames: self.frames.clone(),
+            statement: self.statement,
+            origin,
+        });
+        Ok(())
+    }
+
+    /// Gestures are implemented with a timer, inputs are pulled low when pressed
+    fn handle_gesture(
+        &mut self,
+        gesture: &ast::Gesture,
+        input: &ast::Input,
+        statements: &'a [ast::Statement],
+        location: ast::SourceLoc,
+    ) -> Result<(), CompileError> {
+        let number = retrieve_input(&self.program.declarations, input)?;
+        let origin = self.locate(location);
+        let timer = self.allocate_timer(TimerSource::Gesture(*gesture, number))?;
+        let settings = &self.program.declarations.settings;
+        match gesture {
+            ast::Gesture::LongPress => {
+                // Start the timer on press, cancel it on release,
+                // the body is evaluated when the timer elapses
+                let duration = timer_duration(&settings.longpress)?;
+                self.program.instructions.extend([
+                    Instruction::On {
+                        input: number,
+                        edge: common::Edge::Falling,
+                    },
+                    Instruction::StartTimer { timer, duration },
+                    Instruction::Pop,
+                ]);
+                self.releases.push((number, timer, origin));
+                self.elapsed_blocks.push_back(ElapsedBlock {
+                    timer,
+                    statements,
+                    frames: self.frames.clone(),
+                    statement: self.statement,
+                    origin,
+                });
+            }
+            ast::Gesture::DoubleClick => {
+                // On press: if the timer is still running from the previous press,
+                // evaluate the body and cancel the timer, otherwise start it
+                let duration = timer_duration(&settings.doubleclick)?;
+                self.program.instructions.extend([
+                    Instruction::On {
+                        input: number,
+                        edge: common::Edge::Falling,
+                    },
+                    Instruction::IfTimer {
+                        timer,
+                        check: TimerCheck::Running,
+                    },
+                ]);
+                for statement in statements.iter() {
+                    self.handle_statement(statement)?;
+                }
+                self.set_origin(origin);
+                self.program.instructions.extend([
+                    Instruction::CancelTimer { timer },
+                    Instruction::Not,
+                    Instruction::StartTimer { timer, duration },
+                    Instruction::Pop,
+                    Instruction::Pop,
+                ]);
+            }
         }
+        Ok(())
     }
-}
 
-fn handle_event(
-    program: &mut bytecode::Program,
-    edge: &common::Edge,
-    input: &ast::Input,
-    statements: &[ast::Statement],
-) {
-    let number = retrieve_input(&program.declarations, input).unwrap();
-    program.instructions.push(Instruction::On {
-        input: number,
-        edge: *edge,
-    });
-    for statement in statements.iter() {
-        handle_statement(program, statement);
-    }
-    program.instructions.push(Instruction::Pop);
+    fn handle_elapsed(&mut self, block: ElapsedBlock<'a>) -> Result<(), CompileError> {
+        let origin = block.origin;
+        self.set_origin(origin);
+        self.program.instructions.push(Instruction::IfTimer {
+            timer: block.timer,
+            check: TimerCheck::Elapsed,
+        });
+        self.frames = block.frames;
+        for statement in block.statements.iter() {
+            self.handle_statement(statement)
+                .map_err(|error| wrap_in_frames(&self.frames, error))?;
+        }
+        self.set_origin(origin);
+        self.program.instructions.push(Instruction::Pop);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -211,10 +847,13 @@ mod tests {
     use crate::shal::ast;
     use crate::shal::ast::{IODeclaration, IODeclarations};
     use crate::shal::bytecode;
-    use crate::shal::bytecode::Instruction;
+    use crate::shal::bytecode::{Instruction, TimerCheck, TimerDuration};
     use crate::shal::common::{Edge, IsWas, Value};
-    use crate::shal::compiler::compile;
+    use crate::shal::compiler::{compile, CompileError};
+    use crate::shal::parser::{parse, parse_file};
     use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
 
     #[test]
     fn test_compile() {
@@ -259,21 +898,32 @@ mod tests {
                         },
                     ),
                 ]),
+                flags: HashMap::new(),
+                groups: HashMap::new(),
+                settings: Default::default(),
             },
             statements: vec![
                 ast::Statement::Event {
                     edge: Edge::Rising,
                     input: ast::Input::Entity("button_downstairs".try_into().unwrap()),
-                    statements: vec![ast::Statement::Action(ast::Action::Toggle(
-                        ast::Output::Entity("light_downstairs".try_into().unwrap()),
-                    ))],
+                    statements: vec![ast::Statement::Action(
+                        ast::Action::Toggle(ast::Output::Entity(
+                            "light_downstairs".try_into().unwrap(),
+                        )),
+                        ast::SourceLoc(1, 35),
+                    )],
+                    location: ast::SourceLoc(1, 1),
                 },
                 ast::Statement::Event {
                     edge: Edge::Rising,
                     input: ast::Input::Entity("button_upstairs".try_into().unwrap()),
-                    statements: vec![ast::Statement::Action(ast::Action::Toggle(
-                        ast::Output::Entity("light_upstairs".try_into().unwrap()),
-                    ))],
+                    statements: vec![ast::Statement::Action(
+                        ast::Action::Toggle(ast::Output::Entity(
+                            "light_upstairs".try_into().unwrap(),
+                        )),
+                        ast::SourceLoc(2, 33),
+                    )],
+                    location: ast::SourceLoc(2, 1),
                 },
                 ast::Statement::IfElse(
                     ast::Condition::Or(
@@ -288,16 +938,24 @@ mod tests {
                             Value::High,
                         )),
                     ),
-                    vec![ast::Statement::Action(ast::Action::Set(
-                        ast::Output::Entity("light_stairs".try_into().unwrap()),
-                        Value::High,
-                    ))],
-                    vec![ast::Statement::Action(ast::Action::Set(
-                        ast::Output::Entity("light_stairs".try_into().unwrap()),
-                        Value::Low,
-                    ))],
+                    vec![ast::Statement::Action(
+                        ast::Action::Set(
+                            ast::Output::Entity("light_stairs".try_into().unwrap()),
+                            Value::High,
+                        ),
+                        ast::SourceLoc(4, 3),
+                    )],
+                    vec![ast::Statement::Action(
+                        ast::Action::Set(
+                            ast::Output::Entity("light_stairs".try_into().unwrap()),
+                            Value::Low,
+                        ),
+                        ast::SourceLoc(6, 3),
+                    )],
+                    ast::SourceLoc(3, 4),
                 ),
             ],
+            source_locations: vec![],
         };
         let bytecode_program = compile(&ast_program);
 
@@ -343,6 +1001,9 @@ mod tests {
                             }
                         ),
                     ]),
+                    flags: HashMap::new(),
+                    groups: HashMap::new(),
+                    settings: Default::default(),
                 },
                 instructions: vec![
                     Instruction::On {
@@ -386,9 +1047,261 @@ mod tests {
                     Instruction::Pop,
                     Instruction::End,
                 ],
-                source_locations: vec![],
+                origins: vec![],
+                files: vec![],
+                timer_sources: vec![],
             }),
             &bytecode_program
         );
     }
+
+    #[test]
+    fn test_compile_after() {
+        let program = compile(
+            &parse(
+                "on fedge input 0 {
+                   after 2min as off {
+                     set output 0 low;
+                     after 1s toggle output 1;
+                   }
+                 }
+                 if timer off is stopped {
+                   cancel off;
+                 }",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let off = 0.try_into().unwrap();
+        let toggle = 1.try_into().unwrap();
+        assert_eq!(
+            vec![
+                Instruction::On {
+                    input: 0.try_into().unwrap(),
+                    edge: Edge::Falling,
+                },
+                Instruction::StartTimer {
+                    timer: off,
+                    duration: TimerDuration::from_duration(Duration::from_secs(120)).unwrap(),
+                },
+                Instruction::Pop,
+                Instruction::IfTimer {
+                    timer: off,
+                    check: TimerCheck::Running,
+                },
+                Instruction::Not,
+                Instruction::CancelTimer { timer: off },
+                Instruction::Pop,
+                Instruction::IfTimer {
+                    timer: off,
+                    check: TimerCheck::Elapsed,
+                },
+                Instruction::Set {
+                    output: 0.try_into().unwrap(),
+                    value: Value::Low,
+                },
+                Instruction::StartTimer {
+                    timer: toggle,
+                    duration: TimerDuration::from_duration(Duration::from_secs(1)).unwrap(),
+                },
+                Instruction::Pop,
+                Instruction::IfTimer {
+                    timer: toggle,
+                    check: TimerCheck::Elapsed,
+                },
+                Instruction::Toggle {
+                    output: 1.try_into().unwrap(),
+                },
+                Instruction::Pop,
+                Instruction::End,
+            ],
+            program.instructions
+        );
+        assert_eq!(Ok(1), program.check_stack_depth(None));
+    }
+
+    #[test]
+    fn test_compile_timer_errors() {
+        let compile_str = |s: &str| compile(&parse(s).unwrap());
+        assert!(matches!(
+            compile_str("cancel foo;"),
+            Err(CompileError::UnknownTimerError { .. })
+        ));
+        assert!(matches!(
+            compile_str("after 1s as foo {} after 2s as foo {}"),
+            Err(CompileError::DuplicateTimerError { .. })
+        ));
+        assert!(matches!(
+            compile_str("after 90s {}"),
+            Err(CompileError::TimerDurationError { .. })
+        ));
+        assert_eq!(
+            Err(CompileError::TooManyTimersError),
+            compile_str(&"after 1s {}".repeat(9))
+        );
+        assert!(compile_str(&"after 1s {}".repeat(8)).is_ok());
+    }
+
+    #[test]
+    fn test_compile_include_errors() {
+        let compile_with = |main: &str, included: &str| {
+            compile(
+                &parse_file(Path::new("main.shal"), main, None, |_| {
+                    Ok(included.to_owned())
+                })
+                .unwrap(),
+            )
+        };
+        let unknown = || CompileError::UnknownEntityError {
+            name: "lamp".try_into().unwrap(),
+            location: None,
+        };
+        let include = |line, source| CompileError::IncludeError {
+            path: PathBuf::from("lights.shal"),
+            location: ast::SourceLoc(line, 1),
+            source: Box::new(source),
+        };
+        assert_eq!(
+            Err(include(2, unknown())),
+            compile_with(
+                "set output 0 high;\ninclude \"lights.shal\";",
+                "set lamp high;"
+            )
+        );
+        // Also in after blocks, which are compiled at the end of the program
+        assert_eq!(
+            Err(include(1, unknown())),
+            compile_with(
+                "include \"lights.shal\";\nset output 0 high;",
+                "after 1s set lamp high;"
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_rule_errors() {
+        let compile_str = |s: &str| compile(&parse(s).unwrap());
+        let unknown = |name: &str| CompileError::UnknownEntityError {
+            name: name.try_into().unwrap(),
+            location: None,
+        };
+        let call = |rule: &str, (line, col), source| CompileError::ExpansionError {
+            rule: rule.try_into().unwrap(),
+            location: ast::SourceLoc(line, col),
+            source: Box::new(source),
+        };
+        let rules = "rule toggle_pair(btn, light) {\n  on redge btn toggle light;\n}\n\
+                     rule delayed(light) {\n  after 1s toggle light;\n}\n\
+                     rule both(btn, light) {\n  toggle_pair(btn, light);\n  delayed(light);\n}\n";
+        assert!(compile_str(&format!("{rules}both(input 0, output 1);")).is_ok());
+        // Errors inside an expansion are reported at the call site
+        assert_eq!(
+            Err(call("toggle_pair", (11, 1), unknown("lamp"))),
+            compile_str(&format!("{rules}toggle_pair(input 0, lamp);"))
+        );
+        assert_eq!(
+            Err(call(
+                "both",
+                (11, 1),
+                call("toggle_pair", (8, 3), unknown("button"))
+            )),
+            compile_str(&format!("{rules}both(button, output 1);"))
+        );
+        // Also in after blocks, which are compiled at the end of the program
+        assert_eq!(
+            Err(call("delayed", (12, 1), unknown("lamp"))),
+            compile_str(&format!("{rules}\ndelayed(lamp);\nset output 1 high;"))
+        );
+        assert_eq!(
+            Err(call(
+                "timed",
+                (3, 1),
+                CompileError::DuplicateTimerError {
+                    name: "t".try_into().unwrap()
+                }
+            )),
+            compile_str("rule timed(t) { after 1s as t {} }\ntimed(t);\ntimed(t);")
+        );
+    }
+
+    #[test]
+    fn test_compile_constants() {
+        let compile_source = |source: &str| compile(&parse(source).unwrap()).unwrap().instructions;
+        let set = |output: u8, value| Instruction::Set {
+            output: output.try_into().unwrap(),
+            value,
+        };
+        let check = |number: u8| Instruction::If {
+            number: number.try_into().unwrap(),
+            is_was: IsWas::Is,
+            value: Value::High,
+            in_out: bytecode::InOut::Input,
+        };
+        assert_eq!(
+            vec![set(1, Value::High), Instruction::End],
+            compile_source(
+                "if true or input 0 is high { set output 1 high; } else { set output 2 high; }"
+            )
+        );
+        assert_eq!(
+            vec![set(2, Value::High), Instruction::End],
+            compile_source("if not true { set output 1 high; } else { set output 2 high; }")
+        );
+        assert_eq!(
+            vec![
+                check(0),
+                Instruction::Not,
+                set(1, Value::High),
+                Instruction::Pop,
+                Instruction::End
+            ],
+            compile_source("if input 0 is high xor true and true { set output 1 high; }")
+        );
+        assert_eq!(
+            vec![
+                check(0),
+                check(1),
+                Instruction::And,
+                set(1, Value::High),
+                Instruction::Pop,
+                Instruction::End
+            ],
+            compile_source(
+                "if (false or input 0 is high) and input 1 is high { set output 1 high; }"
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_constants_unknown_entities() {
+        let compile_str = |s: &str| compile(&parse(s).unwrap());
+        let unknown = |name: &str| {
+            Err(CompileError::UnknownEntityError {
+                name: name.try_into().unwrap(),
+                location: None,
+            })
+        };
+        // Code that is dropped because of a constant condition is still checked
+        assert_eq!(
+            unknown("nonexist"),
+            compile_str("if false { set nonexist high; }")
+        );
+        assert_eq!(
+            unknown("nonexist"),
+            compile_str("if true { set output 1 high; } else { on redge nonexist {} }")
+        );
+        assert_eq!(
+            unknown("typo"),
+            compile_str("if typo is high and false { set output 1 high; }")
+        );
+        assert_eq!(
+            unknown("typo"),
+            compile_str("if true or not typo is high { set output 1 high; }")
+        );
+        assert!(matches!(
+            compile_str("if false { after 2s { cancel foo; } }"),
+            Err(CompileError::UnknownTimerError { .. })
+        ));
+        assert!(compile_str("if false { set output 1 high; }").is_ok());
+    }
 }
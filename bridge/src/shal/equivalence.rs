@@ -0,0 +1,605 @@
+use crate::shal::bytecode::{InOut, Instruction, Program, TimerCheck, TimerID};
+use crate::shal::common::IsWas;
+use crate::shal::interpreter::{run_program_with_memory, FixedBitSet, Memory};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Number of states that are checked at most for every group of targets,
+/// if there are more they are sampled
+const MAX_STATES: u64 = 1 << 20;
+
+/// The time at which the cycle runs, timers that elapse have this as their deadline
+const NOW: Duration = Duration::from_secs(60);
+
+/// The time left on a timer that is running
+const RUNNING: Duration = Duration::from_secs(1);
+
+/// State that a program changes, and that is compared after a cycle
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Target {
+    Output(u8),
+    Flag(u8),
+    Timer(u8),
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Output(number) => write!(f, "output {number}"),
+            Target::Flag(number) => write!(f, "flag {number}"),
+            Target::Timer(number) => write!(f, "timer {number}"),
+        }
+    }
+}
+
+/// Part of the state at the start of a cycle
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Variable {
+    InputOld(u8),
+    InputNew(u8),
+    OutputOld(u8),
+    Flag(u8),
+    Timer(u8),
+}
+
+impl Variable {
+    /// The number of values: timers are stopped, running, or elapse in the cycle
+    fn values(&self) -> u64 {
+        match self {
+            Variable::Timer(_) => 3,
+            _ => 2,
+        }
+    }
+
+    fn initial(target: Target) -> Self {
+        match target {
+            Target::Output(number) => Variable::OutputOld(number),
+            Target::Flag(number) => Variable::Flag(number),
+            Target::Timer(number) => Variable::Timer(number),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Dependency {
+    /// The state at the start of the cycle
+    Variable(Variable),
+    /// The state while the program runs, which depends on the actions that change it
+    Target(Target),
+}
+
+/// The value of a target after a cycle
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TargetValue {
+    Low,
+    High,
+    Stopped,
+    /// The timer is running, and elapses after the duration
+    Running(Duration),
+}
+
+impl Display for TargetValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetValue::Low => f.write_str("low"),
+            TargetValue::High => f.write_str("high"),
+            TargetValue::Stopped => f.write_str("stopped"),
+            TargetValue::Running(duration) => write!(f, "running for {duration:?}"),
+        }
+    }
+}
+
+/// A state in which the programs give a different value for a target
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Counterexample {
+    pub target: Target,
+    pub first: TargetValue,
+    pub second: TargetValue,
+    /// The values of the state the target depends on, the rest of the state is low or stopped
+    pub state: Vec<(Variable, u64)>,
+}
+
+impl Display for Counterexample {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is {} with the first program, and {} with the second program",
+            self.target, self.first, self.second
+        )?;
+        if self.state.is_empty() {
+            return Ok(());
+        }
+        f.write_str(", when:")?;
+        for &(variable, value) in &self.state {
+            let level = if value == 0 { "low" } else { "high" };
+            match variable {
+                Variable::InputOld(number) => write!(f, "\n  input {number} was {level}"),
+                Variable::InputNew(number) => write!(f, "\n  input {number} is {level}"),
+                Variable::OutputOld(number) => write!(f, "\n  output {number} was {level}"),
+                Variable::Flag(number) => write!(f, "\n  flag {number} was {level}"),
+                Variable::Timer(number) => write!(
+                    f,
+                    "\n  timer {number} {}",
+                    ["is stopped", "is running", "elapses"][value as usize]
+                ),
+            }?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of comparing two programs
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Comparison {
+    /// A counterexample for every output, flag or timer that can be different after a cycle
+    pub counterexamples: Vec<Counterexample>,
+    /// Whether all states were checked, rather than a sample
+    pub exhaustive: bool,
+}
+
+/// Compares the outputs, flags and timers of two programs after a cycle, in every state.
+///
+/// The state every target depends on is found in the code of both programs, and every
+/// combination of its values is run in the interpreter. A cycle only depends on this state,
+/// so programs that behave the same in every state also behave the same over several cycles.
+/// If the state of a target has more than `MAX_STATES` combinations, a sample is checked.
+/// Timers are matched by the after block or gesture they are used for, rather than by number.
+/// The programs have to fit the stack of the VM.
+pub fn compare(first: &Program, second: &Program) -> Comparison {
+    let second = &match_timers(first, second);
+    let mut dependencies = BTreeMap::new();
+    add_dependencies(first, &mut dependencies);
+    add_dependencies(second, &mut dependencies);
+    // Targets that depend on the same state are checked together
+    let mut groups: BTreeMap<Vec<Variable>, Vec<Target>> = BTreeMap::new();
+    for &target in dependencies.keys() {
+        groups
+            .entry(variables(target, &dependencies))
+            .or_default()
+            .push(target);
+    }
+    let mut comparison = Comparison {
+        counterexamples: Vec::new(),
+        exhaustive: true,
+    };
+    for (variables, targets) in groups {
+        let mut remaining = targets;
+        let states = variables
+            .iter()
+            .try_fold(1u64, |states, variable| {
+                states.checked_mul(variable.values())
+            })
+            .filter(|&states| states <= MAX_STATES);
+        if states.is_none() {
+            comparison.exhaustive = false;
+        }
+        let mut check = |digit: &mut dyn FnMut(u64) -> u64| {
+            let state: Vec<_> = variables
+                .iter()
+                .map(|variable| (*variable, digit(variable.values())))
+                .collect();
+            let first_result = run(first, &state);
+            let second_result = run(second, &state);
+            remaining.retain(|&target| {
+                let first = value(target, first_result);
+                let second = value(target, second_result);
+                if first != second {
+                    comparison.counterexamples.push(Counterexample {
+                        target,
+                        first,
+                        second,
+                        state: state.clone(),
+                    });
+                }
+                first == second
+            });
+            !remaining.is_empty()
+        };
+        match states {
+            Some(states) => {
+                for mut index in 0..states {
+                    let more = check(&mut |base| {
+                        let digit = index % base;
+                        index /= base;
+                        digit
+                    });
+                    if !more {
+                        break;
+                    }
+                }
+            }
+            None => {
+                // A fixed xorshift sequence, so results can be reproduced
+                let mut random = 0x2545_f491_4f6c_dd1du64;
+                for _ in 0..MAX_STATES {
+                    let more = check(&mut |base| {
+                        random ^= random << 13;
+                        random ^= random >> 7;
+                        random ^= random << 17;
+                        random % base
+                    });
+                    if !more {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    comparison
+        .counterexamples
+        .sort_by_key(|counterexample| counterexample.target);
+    comparison
+}
+
+/// Renumbers the timers of `second`, so a timer used for the same after block or gesture as a
+/// timer of `first` gets its number, and the other timers get numbers after those of `first`.
+/// Identical after blocks or gestures are matched in order. The program is kept as it is if
+/// either program was not compiled from source, or there are not enough timers for both.
+fn match_timers(first: &Program, second: &Program) -> Program {
+    let (first_sources, second_sources) = (&first.timer_sources, &second.timer_sources);
+    if first_sources.is_empty() || second_sources.is_empty() {
+        return second.clone();
+    }
+    let mut matched = vec![false; first_sources.len()];
+    let mut next = first_sources.len();
+    let mut numbers = Vec::with_capacity(second_sources.len());
+    for source in second_sources {
+        let number =
+            match (0..first_sources.len()).find(|&i| !matched[i] && first_sources[i] == *source) {
+                Some(i) => {
+                    matched[i] = true;
+                    i
+                }
+                None => {
+                    next += 1;
+                    next - 1
+                }
+            };
+        let Some(timer) = u8::try_from(number)
+            .ok()
+            .and_then(|number| TimerID::try_from(number).ok())
+        else {
+            return second.clone();
+        };
+        numbers.push(timer);
+    }
+    let renumber = |timer: TimerID| {
+        numbers
+            .get(usize::from(u8::from(timer)))
+            .copied()
+            .unwrap_or(timer)
+    };
+    let instructions = second
+        .instructions
+        .iter()
+        .map(|instruction| match *instruction {
+            Instruction::StartTimer { timer, duration } => Instruction::StartTimer {
+                timer: renumber(timer),
+                duration,
+            },
+            Instruction::CancelTimer { timer } => Instruction::CancelTimer {
+                timer: renumber(timer),
+            },
+            Instruction::IfTimer { timer, check } => Instruction::IfTimer {
+                timer: renumber(timer),
+                check,
+            },
+            instruction => instruction,
+        })
+        .collect();
+    Program {
+        instructions,
+        ..second.clone()
+    }
+}
+
+/// Adds the state every action depends on to the target it changes: the state that is checked
+/// by the values on the stack. This ignores the order of the actions, so it can be too much.
+fn add_dependencies(program: &Program, dependencies: &mut BTreeMap<Target, BTreeSet<Dependency>>) {
+    let mut stack: Vec<BTreeSet<Dependency>> = Vec::new();
+    for instruction in &program.instructions {
+        let checked = |variable| BTreeSet::from([Dependency::Variable(variable)]);
+        match *instruction {
+            Instruction::End => break,
+            Instruction::Pop => {
+                stack.pop();
+            }
+            Instruction::And | Instruction::Or | Instruction::Xor => {
+                let right = stack.pop().unwrap_or_default();
+                let left = stack.pop().unwrap_or_default();
+                stack.push(left.union(&right).copied().collect());
+            }
+            Instruction::Not => {}
+            Instruction::On { input, .. } => stack.push(BTreeSet::from([
+                Dependency::Variable(Variable::InputOld(input.into())),
+                Dependency::Variable(Variable::InputNew(input.into())),
+            ])),
+            Instruction::If {
+                number,
+                is_was,
+                in_out,
+                ..
+            } => {
+                let number = number.into();
+                stack.push(match (is_was, in_out) {
+                    (IsWas::Was, InOut::Input) => checked(Variable::InputOld(number)),
+                    (IsWas::Is, InOut::Input) => checked(Variable::InputNew(number)),
+                    (IsWas::Was, InOut::Output) => checked(Variable::OutputOld(number)),
+                    (IsWas::Was, InOut::Flag) => checked(Variable::Flag(number)),
+                    (IsWas::Is, InOut::Output) => {
+                        BTreeSet::from([Dependency::Target(Target::Output(number))])
+                    }
+                    (IsWas::Is, InOut::Flag) => {
+                        BTreeSet::from([Dependency::Target(Target::Flag(number))])
+                    }
+                })
+            }
+            Instruction::IfTimer { timer, check } => {
+                let timer = timer.into();
+                stack.push(match check {
+                    TimerCheck::Elapsed => checked(Variable::Timer(timer)),
+                    TimerCheck::Running => {
+                        BTreeSet::from([Dependency::Target(Target::Timer(timer))])
+                    }
+                })
+            }
+            Instruction::Set { output, .. } | Instruction::Toggle { output } => {
+                add_action(Target::Output(output.into()), &stack, dependencies)
+            }
+            Instruction::SetFlag { flag, .. } | Instruction::ToggleFlag { flag } => {
+                add_action(Target::Flag(flag.into()), &stack, dependencies)
+            }
+            Instruction::StartTimer { timer, .. } | Instruction::CancelTimer { timer } => {
+                add_action(Target::Timer(timer.into()), &stack, dependencies)
+            }
+            Instruction::OnToggle { input, output, .. } => {
+                stack.push(BTreeSet::from([
+                    Dependency::Variable(Variable::InputOld(input.into())),
+                    Dependency::Variable(Variable::InputNew(input.into())),
+                ]));
+                add_action(Target::Output(output.into()), &stack, dependencies);
+                stack.pop();
+            }
+        }
+    }
+}
+
+fn add_action(
+    target: Target,
+    stack: &[BTreeSet<Dependency>],
+    dependencies: &mut BTreeMap<Target, BTreeSet<Dependency>>,
+) {
+    let target_dependencies = dependencies.entry(target).or_insert_with(|| {
+        // A target keeps its value when no action changes it
+        BTreeSet::from([Dependency::Variable(Variable::initial(target))])
+    });
+    for checked in stack {
+        target_dependencies.extend(checked);
+    }
+}
+
+/// The state at the start of the cycle that a target depends on
+fn variables(
+    target: Target,
+    dependencies: &BTreeMap<Target, BTreeSet<Dependency>>,
+) -> Vec<Variable> {
+    let mut variables = BTreeSet::from([Variable::initial(target)]);
+    let mut visited = BTreeSet::from([target]);
+    let mut pending = vec![target];
+    while let Some(target) = pending.pop() {
+        for dependency in dependencies.get(&target).into_iter().flatten() {
+            match *dependency {
+                Dependency::Variable(variable) => {
+                    variables.insert(variable);
+                }
+                Dependency::Target(target) => {
+                    variables.insert(Variable::initial(target));
+                    if visited.insert(target) {
+                        pending.push(target);
+                    }
+                }
+            }
+        }
+    }
+    variables.into_iter().collect()
+}
+
+/// Runs a cycle from the given state, with everything else low or stopped
+fn run(program: &Program, state: &[(Variable, u64)]) -> (FixedBitSet, Memory) {
+    let (mut input_old, mut input_new, mut output_old, mut flags) = (0u32, 0u32, 0u32, 0u32);
+    let mut memory = Memory::default();
+    for &(variable, value) in state {
+        let high = |number: u8| (value as u32) << number;
+        match variable {
+            Variable::InputOld(number) => input_old |= high(number),
+            Variable::InputNew(number) => input_new |= high(number),
+            Variable::OutputOld(number) => output_old |= high(number),
+            Variable::Flag(number) => flags |= high(number),
+            Variable::Timer(number) => {
+                memory.timers.deadlines[number as usize] = match value {
+                    0 => None,
+                    1 => Some(NOW + RUNNING),
+                    _ => Some(NOW),
+                }
+            }
+        }
+    }
+    memory.flags = flags.into();
+    let output_new = run_program_with_memory(
+        program,
+        &mut memory,
+        NOW,
+        &input_old.into(),
+        &input_new.into(),
+        &output_old.into(),
+    );
+    (output_new, memory)
+}
+
+fn value(target: Target, (output, memory): (FixedBitSet, Memory)) -> TargetValue {
+    let level = |bits: FixedBitSet, number| {
+        if bits.get(number).unwrap_or_else(|_| unreachable!()) {
+            TargetValue::High
+        } else {
+            TargetValue::Low
+        }
+    };
+    match target {
+        Target::Output(number) => level(output, number),
+        Target::Flag(number) => level(memory.flags, number),
+        Target::Timer(number) => match memory.timers.deadlines[number as usize] {
+            Some(deadline) => TargetValue::Running(deadline - NOW),
+            None => TargetValue::Stopped,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, Counterexample, Target, TargetValue, Variable};
+    use crate::shal::tests::compile_source;
+    use std::time::Duration;
+
+    #[test]
+    fn test_equivalent_programs() {
+        let first = compile_source(
+            "on redge input 0 { set output 0 high; }
+             on redge input 0 { after 1s as t set output 1 high; }",
+        );
+        let second = compile_source(
+            "on redge input 0 { if output 0 is low { set output 0 high; } }
+             on redge input 0 { after 1s as t set output 1 high; }",
+        );
+        let comparison = compare(&first, &second);
+        assert_eq!(comparison.counterexamples, vec![]);
+        assert!(comparison.exhaustive);
+    }
+
+    #[test]
+    fn test_counterexample() {
+        let first = compile_source(
+            "on redge input 0 { set output 0 high; }
+             on redge input 1 { after 1s set output 1 high; }",
+        );
+        let second = compile_source(
+            "on redge input 0 { toggle output 0; }
+             on redge input 1 { after 2s set output 1 high; }",
+        );
+        let comparison = compare(&first, &second);
+        assert!(comparison.exhaustive);
+        assert_eq!(
+            comparison.counterexamples,
+            vec![
+                Counterexample {
+                    target: Target::Output(0),
+                    first: TargetValue::High,
+                    second: TargetValue::Low,
+                    state: vec![
+                        (Variable::InputOld(0), 0),
+                        (Variable::InputNew(0), 1),
+                        (Variable::OutputOld(0), 1)
+                    ],
+                },
+                Counterexample {
+                    target: Target::Timer(0),
+                    first: TargetValue::Running(Duration::from_secs(1)),
+                    second: TargetValue::Running(Duration::from_secs(2)),
+                    state: vec![
+                        (Variable::InputOld(1), 0),
+                        (Variable::InputNew(1), 1),
+                        (Variable::Timer(0), 0)
+                    ],
+                }
+            ]
+        );
+        assert_eq!(
+            comparison.counterexamples[0].to_string(),
+            "output 0 is high with the first program, and low with the second program, when:
+  input 0 was low
+  input 0 is high
+  output 0 was high"
+        );
+    }
+
+    #[test]
+    fn test_reordered_timers() {
+        // Timers are allocated in a different order, but used for the same after blocks and gestures
+        let first = compile_source(
+            "on redge input 0 { after 1s set output 0 high; }
+             on longpress input 1 toggle output 1;
+             on redge input 2 { after 2s as t set output 2 high; }",
+        );
+        let second = compile_source(
+            "on redge input 2 { after 2s as t set output 2 high; }
+             on longpress input 1 toggle output 1;
+             on redge input 0 { after 1s set output 0 high; }",
+        );
+        let comparison = compare(&first, &second);
+        assert!(comparison.exhaustive);
+        assert_eq!(comparison.counterexamples, vec![]);
+        // The named timer is allocated first, the renamed after block gets a timer of its own
+        let second = compile_source(
+            "on redge input 0 { after 1s set output 0 high; }
+             on longpress input 1 toggle output 1;
+             on redge input 2 { after 2s set output 3 high; }",
+        );
+        let targets: Vec<_> = compare(&first, &second)
+            .counterexamples
+            .iter()
+            .map(|counterexample| counterexample.target)
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                Target::Output(2),
+                Target::Output(3),
+                Target::Timer(0),
+                Target::Timer(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dependencies() {
+        // Output 0 depends on output 1 as it is during the cycle, so on what changes output 1
+        let first = compile_source(
+            "on redge input 2 { toggle output 1; }
+             if output 1 is high { on redge input 0 { set output 0 high; } }",
+        );
+        let second = compile_source(
+            "if output 1 is high { on redge input 0 { set output 0 high; } }
+             on redge input 2 { toggle output 1; }",
+        );
+        let comparison = compare(&first, &second);
+        assert!(comparison.exhaustive);
+        assert_eq!(comparison.counterexamples.len(), 1);
+        assert_eq!(
+            comparison.counterexamples[0].state,
+            vec![
+                (Variable::InputOld(0), 0),
+                (Variable::InputOld(2), 0),
+                (Variable::InputNew(0), 1),
+                (Variable::InputNew(2), 1),
+                (Variable::OutputOld(0), 0),
+                (Variable::OutputOld(1), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full_program() {
+        let source = include_str!("../../static/standaertha.shal");
+        let first = compile_source(source);
+        let second = compile_source(&source.replace(
+            "on fedge dbu toggle licht_bureau;",
+            "on fedge dbu set licht_bureau high;",
+        ));
+        // Every target only depends on a few inputs, so all states are checked
+        assert_eq!(compare(&first, &first).counterexamples, vec![]);
+        let comparison = compare(&first, &second);
+        assert!(comparison.exhaustive);
+        assert_eq!(comparison.counterexamples.len(), 1);
+        assert_eq!(comparison.counterexamples[0].second, TargetValue::High);
+    }
+}
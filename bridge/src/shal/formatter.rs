@@ -0,0 +1,405 @@
+use crate::shal::parser::{
+    parse_declarations, split_declarations, DeclarationFormat, ParseError, Rule, ShalParser,
+};
+use pest::Parser;
+use regex::Regex;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+const INDENT: &str = "  ";
+
+/// Words after which an opening parenthesis is preceded by a space, e.g. `not (`, but `rule(`
+const KEYWORDS_BEFORE_PARENTHESIS: [&str; 5] = ["if", "and", "or", "xor", "not"];
+
+static TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"//[^\n]*|"[^"\n]*"|[A-Za-z0-9_]+|\S"#).unwrap_or_else(|_| unreachable!())
+});
+
+static HJSON_KEY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap_or_else(|_| unreachable!()));
+
+/// Formats a program canonically, keeping its comments.
+/// The program is checked with the parser first, so only valid programs are formatted.
+pub fn format(input: &str) -> Result<String, ParseError> {
+    let (header, body) = split_declarations(input)?;
+    let mut output = String::new();
+    if let Some((header, format)) = header {
+        parse_declarations(header, format)?;
+        output.push_str(&format_header(header, format)?);
+        output.push_str(match format {
+            DeclarationFormat::Hjson => "---\n",
+            DeclarationFormat::Toml => "--- toml\n",
+            DeclarationFormat::Yaml => "--- yaml\n",
+        });
+    }
+    ShalParser::parse(Rule::program, body).map_err(Box::new)?;
+    output.push_str(&format_body(body));
+    Ok(output)
+}
+
+/// HJSON headers are written out again, unless they have comments, which would get lost.
+/// TOML and YAML headers are kept as they are.
+fn format_header(header: &str, format: DeclarationFormat) -> Result<String, ParseError> {
+    let comments = header.contains('#') || header.contains("//") || header.contains("/*");
+    if format != DeclarationFormat::Hjson || comments {
+        let lines: Vec<_> = header.lines().map(str::trim_end).collect();
+        let start = lines.iter().position(|l| !l.is_empty()).unwrap_or(0);
+        let end = lines
+            .iter()
+            .rposition(|l| !l.is_empty())
+            .map_or(0, |i| i + 1);
+        let mut output = lines[start..end.max(start)].join("\n");
+        output.push('\n');
+        return Ok(output);
+    }
+    let value = deser_hjson::from_str::<Value>(header)?;
+    let mut output = String::new();
+    write_hjson(&mut output, &value, 0);
+    output.push('\n');
+    Ok(output)
+}
+
+/// Objects are written on multiple lines without commas, like the example programs
+fn write_hjson(output: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::Object(map) if map.is_empty() => output.push_str("{}"),
+        Value::Object(map) => {
+            output.push_str("{\n");
+            for (key, value) in map {
+                output.push_str(&INDENT.repeat(depth + 1));
+                if HJSON_KEY.is_match(key) {
+                    output.push_str(key);
+                } else {
+                    output.push_str(&Value::from(key.as_str()).to_string());
+                }
+                output.push_str(": ");
+                write_hjson(output, value, depth + 1);
+                output.push('\n');
+            }
+            output.push_str(&INDENT.repeat(depth));
+            output.push('}');
+        }
+        // Quoteless strings end at the end of the line, so the elements are quoted
+        Value::Array(values) => {
+            let values: Vec<_> = values.iter().map(Value::to_string).collect();
+            output.push('[');
+            output.push_str(&values.join(", "));
+            output.push(']');
+        }
+        Value::String(s) if is_quoteless(s) => output.push_str(s),
+        value => output.push_str(&value.to_string()),
+    }
+}
+
+/// Whether a string reads back the same without quotes, e.g. `Night mode` but not `true` or `12`
+fn is_quoteless(s: &str) -> bool {
+    if s.contains(['#', '\n']) || s.contains("//") || s.contains("/*") {
+        return false;
+    }
+    deser_hjson::from_str::<Value>(&format!("{{\nkey: {s}\n}}"))
+        .is_ok_and(|value| value["key"].as_str() == Some(s))
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Token<'a> {
+    Comment(&'a str),
+    Code(&'a str),
+}
+
+/// Splits the program in tokens, with the number of newlines before each of them
+fn tokenize(body: &str) -> Vec<(Token<'_>, usize)> {
+    let mut tokens = vec![];
+    let mut end = 0;
+    for m in TOKEN.find_iter(body) {
+        let newlines = body[end..m.start()].matches('\n').count();
+        end = m.end();
+        let text = m.as_str();
+        let token = if text.starts_with("//") {
+            Token::Comment(text.trim_end())
+        } else {
+            Token::Code(text)
+        };
+        tokens.push((token, newlines));
+    }
+    tokens
+}
+
+/// Writes the program one statement per line, with the bodies of blocks indented.
+/// Single blank lines between statements are kept.
+#[derive(Default)]
+struct BodyFormatter<'a> {
+    lines: Vec<String>,
+    line: String,
+    depth: usize,
+    /// The current statement is not finished, it continues on the next line
+    open: bool,
+    /// The current line is finished, unless a comment follows on the same line
+    pending: bool,
+    previous: Option<&'a str>,
+}
+
+impl<'a> BodyFormatter<'a> {
+    fn finish_line(&mut self) {
+        self.lines.push(std::mem::take(&mut self.line));
+        self.pending = false;
+    }
+
+    fn start_line(&mut self, newlines: usize) {
+        let after_block_start = self.lines.last().is_none_or(|line| line.ends_with('{'));
+        if newlines > 1 && !self.open && !after_block_start {
+            self.lines.push(String::new());
+        }
+        let depth = if self.open {
+            self.depth + 1
+        } else {
+            self.depth
+        };
+        self.line = INDENT.repeat(depth);
+    }
+
+    fn comment(&mut self, comment: &str, newlines: usize) {
+        if newlines == 0 && !self.line.is_empty() {
+            self.line.push(' ');
+            self.line.push_str(comment);
+            self.finish_line();
+            return;
+        }
+        if !self.line.is_empty() {
+            self.finish_line();
+        }
+        self.start_line(newlines);
+        self.line.push_str(comment);
+        self.finish_line();
+    }
+
+    fn code(&mut self, token: &'a str, next: Option<Token>, newlines: usize) -> bool {
+        if self.pending {
+            if token == "else" {
+                self.line.push_str(" else");
+                self.pending = false;
+                self.open = true;
+                self.previous = Some(token);
+                return false;
+            }
+            self.finish_line();
+        }
+        let mut skip_next = false;
+        match token {
+            "}" => {
+                self.depth -= 1;
+                self.open = false;
+                self.start_line(0);
+                self.line.push('}');
+                self.pending = true;
+            }
+            "{" => {
+                if self.line.is_empty() {
+                    self.open = false;
+                    self.start_line(0);
+                } else {
+                    self.line.push(' ');
+                }
+                if next == Some(Token::Code("}")) {
+                    self.line.push_str("{}");
+                    skip_next = true;
+                } else {
+                    self.line.push('{');
+                    self.depth += 1;
+                }
+                self.open = false;
+                self.pending = true;
+            }
+            ";" => {
+                self.line.push(';');
+                self.open = false;
+                self.pending = true;
+            }
+            _ if self.line.is_empty() => {
+                self.start_line(newlines);
+                self.line.push_str(token);
+                self.open = true;
+            }
+            _ => {
+                let space = match (self.previous, token) {
+                    (_, ")" | ",") | (Some("("), _) => false,
+                    (Some(previous), "(") => KEYWORDS_BEFORE_PARENTHESIS.contains(&previous),
+                    _ => true,
+                };
+                if space {
+                    self.line.push(' ');
+                }
+                self.line.push_str(token);
+            }
+        }
+        self.previous = Some(token);
+        skip_next
+    }
+}
+
+fn format_body(body: &str) -> String {
+    let tokens = tokenize(body);
+    let mut formatter = BodyFormatter::default();
+    let mut i = 0;
+    while let Some(&(token, newlines)) = tokens.get(i) {
+        let next = tokens.get(i + 1).map(|(token, _)| *token);
+        match token {
+            Token::Comment(comment) => formatter.comment(comment, newlines),
+            Token::Code(code) => {
+                if formatter.code(code, next, newlines) {
+                    i += 1;
+                }
+            }
+        }
+        i += 1;
+    }
+    if !formatter.line.is_empty() {
+        formatter.finish_line();
+    }
+    let mut output = formatter.lines.join("\n");
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shal::parser::parse;
+
+    /// The tokens of the program without its comments
+    fn code(input: &str) -> Vec<Token<'_>> {
+        let (_, body) = split_declarations(input).unwrap();
+        tokenize(body)
+            .into_iter()
+            .map(|(token, _)| token)
+            .filter(|token| matches!(token, Token::Code(_)))
+            .collect()
+    }
+
+    #[test]
+    fn test_format_program() {
+        let input =
+            "{inputs: {button: {pin: 0, name: \"Night mode\"}}, outputs: {light: {pin: 1}}\n\
+            groups: {all_lights: {outputs: [\"light\"]}}}\n\
+            ---\n\
+            // Comment before the first statement\n\
+            on   redge button{toggle light;set   group all_lights high;} // trailing\n\
+            \n\
+            \n\
+            \n\
+            if not(button is high)and(light was low or true){\n\
+              // Comment in a block\n\
+            \n\
+            toggle light;\n\
+            }\n\
+            else if false {}else{after 2s as t set light low;}\n\
+            rule blink ( a,b ) { toggle a; toggle b; }\n\
+            blink ( light ,output 2 );\n\
+            // Comment at the end\n";
+        let expected = "{\n\
+            \x20 inputs: {\n\
+            \x20   button: {\n\
+            \x20     pin: 0\n\
+            \x20     name: Night mode\n\
+            \x20   }\n\
+            \x20 }\n\
+            \x20 outputs: {\n\
+            \x20   light: {\n\
+            \x20     pin: 1\n\
+            \x20   }\n\
+            \x20 }\n\
+            \x20 groups: {\n\
+            \x20   all_lights: {\n\
+            \x20     outputs: [\"light\"]\n\
+            \x20   }\n\
+            \x20 }\n\
+            }\n\
+            ---\n\
+            // Comment before the first statement\n\
+            on redge button {\n\
+            \x20 toggle light;\n\
+            \x20 set group all_lights high;\n\
+            } // trailing\n\
+            \n\
+            if not (button is high) and (light was low or true) {\n\
+            \x20 // Comment in a block\n\
+            \n\
+            \x20 toggle light;\n\
+            } else if false {} else {\n\
+            \x20 after 2s as t set light low;\n\
+            }\n\
+            rule blink(a, b) {\n\
+            \x20 toggle a;\n\
+            \x20 toggle b;\n\
+            }\n\
+            blink(light, output 2);\n\
+            // Comment at the end\n";
+        let formatted = format(input).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        assert_eq!(code(&formatted), code(input));
+    }
+
+    #[test]
+    fn test_format_comments_in_statements() {
+        let input = "on redge input 0 // the button\n  toggle output 0;\n\
+            if input 0 is high // first\n// second\nor input 1 is high {}\n";
+        let expected = "on redge input 0 // the button\n\
+            \x20 toggle output 0;\n\
+            if input 0 is high // first\n\
+            \x20 // second\n\
+            \x20 or input 1 is high {}\n";
+        let formatted = format(input).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_header_with_comments() {
+        // The comment would get lost, so the header is kept
+        let input = "{\n  # The pin map\n  inputs: {button: {pin: 0}}\n}   \n\n--- hjson\n\
+            on redge button toggle output 1;\n";
+        let expected = "{\n  # The pin map\n  inputs: {button: {pin: 0}}\n}\n---\n\
+            on redge button toggle output 1;\n";
+        assert_eq!(format(input).unwrap(), expected);
+        let input = "inputs:\n  button: {pin: 0}\n--- YAML\non redge button toggle output 1;\n";
+        let expected = "inputs:\n  button: {pin: 0}\n--- yaml\non redge button toggle output 1;\n";
+        assert_eq!(format(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_quoted_strings() {
+        let input =
+            "{outputs: {light: {pin: 1, name: \"12\"}, other: {pin: 2, name: \"true\"}}}\n---\n";
+        let expected =
+            "{\n  outputs: {\n    light: {\n      pin: 1\n      name: \"12\"\n    }\n    \
+            other: {\n      pin: 2\n      name: \"true\"\n    }\n  }\n}\n---\n";
+        assert_eq!(format(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert!(matches!(
+            format("on redge input 0 {"),
+            Err(ParseError::PestParseError(_))
+        ));
+        assert!(matches!(
+            format("{inputs: {button: {pin: 99}}}\n---\n"),
+            Err(ParseError::DeclarationError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_format_examples() {
+        for example in [
+            include_str!("../../static/1to1.shal"),
+            include_str!("../../static/short.shal"),
+            include_str!("../../static/standaertha.shal"),
+        ] {
+            let formatted = format(example).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted);
+            assert_eq!(parse(&formatted).unwrap(), parse(example).unwrap());
+        }
+    }
+}
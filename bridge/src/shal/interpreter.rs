@@ -1,29 +1,76 @@
-use crate::shal::bytecode::{AsBit, InOut, Instruction, Program};
+use crate::shal::ast::PinID;
+use crate::shal::bytecode::{AsBit, InOut, Instruction, Program, TimerCheck, NB_TIMERS};
 use crate::shal::common::{Edge, IsWas, Value};
+use std::time::Duration;
 
 struct VmState<'a> {
     input_old: &'a FixedBitSet,
     input_new: &'a FixedBitSet,
     output_old: &'a FixedBitSet,
     output_new: FixedBitSet,
+    flag_old: FixedBitSet,
     stack: BitStack,
+    memory: &'a mut Memory,
+    now: Duration,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-struct FixedBitSet {
+impl VmState<'_> {
+    /// Whether the input has the edge in this cycle
+    fn fired(&self, input: PinID, edge: Edge) -> bool {
+        let before = Value::from_bit(self.input_old.get(input.into()).unwrap());
+        let now = Value::from_bit(self.input_new.get(input.into()).unwrap());
+        matches!(
+            (edge, before, now),
+            (Edge::Rising, Value::Low, Value::High) | (Edge::Falling, Value::High, Value::Low)
+        )
+    }
+}
+
+/// State kept by the VM across cycles
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Memory {
+    pub(super) timers: Timers,
+    pub(crate) flags: FixedBitSet,
+}
+
+/// Timer state, kept across cycles
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(super) struct Timers {
+    pub(super) deadlines: [Option<Duration>; NB_TIMERS as usize],
+    elapsed: u8,
+}
+
+impl Timers {
+    /// Stops the timers whose deadline has passed, and marks them as elapsed for one cycle
+    fn update(&mut self, now: Duration) {
+        self.elapsed = 0;
+        for (i, deadline) in self.deadlines.iter_mut().enumerate() {
+            if matches!(deadline, Some(d) if *d <= now) {
+                self.elapsed |= 1 << i;
+                *deadline = None;
+            }
+        }
+    }
+
+    fn check(&self, timer: u8, check: TimerCheck) -> bool {
+        match check {
+            TimerCheck::Elapsed => self.elapsed & (1 << timer) != 0,
+            TimerCheck::Running => self.deadlines[timer as usize].is_some(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct FixedBitSet {
     set: u32,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum BitSetError {
+pub(super) enum BitSetError {
     OutOfBounds,
 }
 
 impl FixedBitSet {
-    fn new() -> Self {
-        FixedBitSet { set: 0 }
-    }
-
     fn set(&mut self, bit: u8, value: bool) -> Result<(), BitSetError> {
         if bit >= 32 {
             Err(BitSetError::OutOfBounds)
@@ -36,7 +83,7 @@ impl FixedBitSet {
         }
     }
 
-    fn get(&self, bit: u8) -> Result<bool, BitSetError> {
+    pub(super) fn get(&self, bit: u8) -> Result<bool, BitSetError> {
         if bit >= 32 {
             Err(BitSetError::OutOfBounds)
         } else {
@@ -44,10 +91,6 @@ impl FixedBitSet {
         }
     }
 
-    fn clear(&mut self) {
-        self.set = 0;
-    }
-
     fn value(&self) -> u32 {
         self.set
     }
@@ -118,23 +161,48 @@ impl BitStack {
     }
 
     fn all_one(&self) -> bool {
-        let mask = 0xFFFF_FFFF >> (32 - self.stack_depth);
+        let mask = 0xFFFF_FFFFu32
+            .checked_shr(32 - self.stack_depth as u32)
+            .unwrap_or(0);
         self.stack & mask == mask
     }
 }
 
+#[cfg(test)]
 fn run_program(
     program: &Program,
     input_old: &FixedBitSet,
     input_new: &FixedBitSet,
     output_old: &FixedBitSet,
 ) -> FixedBitSet {
+    run_program_with_memory(
+        program,
+        &mut Memory::default(),
+        Duration::ZERO,
+        input_old,
+        input_new,
+        output_old,
+    )
+}
+
+pub(crate) fn run_program_with_memory(
+    program: &Program,
+    memory: &mut Memory,
+    now: Duration,
+    input_old: &FixedBitSet,
+    input_new: &FixedBitSet,
+    output_old: &FixedBitSet,
+) -> FixedBitSet {
+    memory.timers.update(now);
     let mut state = VmState {
         input_old,
         input_new,
         output_old,
         output_new: *output_old,
+        flag_old: memory.flags,
         stack: BitStack::new(),
+        memory,
+        now,
     };
     for instr in program.instructions.iter() {
         match instr {
@@ -158,7 +226,7 @@ fn run_program(
             Instruction::Xor => {
                 let b1 = state.stack.pop().unwrap();
                 let b2 = state.stack.pop().unwrap();
-                state.stack.push(b1 == b2).unwrap();
+                state.stack.push(b1 != b2).unwrap();
             }
             Instruction::Pop => {
                 state.stack.pop().unwrap();
@@ -174,6 +242,8 @@ fn run_program(
                     (IsWas::Is, InOut::Input) => state.input_new,
                     (IsWas::Was, InOut::Output) => state.output_old,
                     (IsWas::Is, InOut::Output) => &state.output_new,
+                    (IsWas::Was, InOut::Flag) => &state.flag_old,
+                    (IsWas::Is, InOut::Flag) => &state.memory.flags,
                 };
                 state
                     .stack
@@ -181,16 +251,16 @@ fn run_program(
                     .unwrap();
             }
             Instruction::On { input, edge } => {
-                let before = Value::from_bit(state.input_old.get((*input).into()).unwrap());
-                let now = Value::from_bit(state.input_new.get((*input).into()).unwrap());
-                state
-                    .stack
-                    .push(matches!(
-                        (edge, before, now),
-                        (Edge::Rising, Value::Low, Value::High)
-                            | (Edge::Falling, Value::High, Value::Low)
-                    ))
-                    .unwrap();
+                let fired = state.fired(*input, *edge);
+                state.stack.push(fired).unwrap();
+            }
+            Instruction::OnToggle {
+                input,
+                edge,
+                output,
+            } if state.fired(*input, *edge) && state.stack.all_one() => {
+                let before = state.output_new.get((*output).into()).unwrap();
+                state.output_new.set((*output).into(), !before).unwrap();
             }
             Instruction::Toggle { output } if state.stack.all_one() => {
                 let before = state.output_new.get((*output).into()).unwrap();
@@ -202,6 +272,28 @@ fn run_program(
                     .set((*output).into(), value.as_bit())
                     .unwrap();
             }
+            Instruction::ToggleFlag { flag } if state.stack.all_one() => {
+                let before = state.memory.flags.get((*flag).into()).unwrap();
+                state.memory.flags.set((*flag).into(), !before).unwrap();
+            }
+            Instruction::SetFlag { flag, value } if state.stack.all_one() => {
+                state
+                    .memory
+                    .flags
+                    .set((*flag).into(), value.as_bit())
+                    .unwrap();
+            }
+            Instruction::StartTimer { timer, duration } if state.stack.all_one() => {
+                state.memory.timers.deadlines[u8::from(*timer) as usize] =
+                    Some(state.now + duration.as_duration());
+            }
+            Instruction::CancelTimer { timer } if state.stack.all_one() => {
+                state.memory.timers.deadlines[u8::from(*timer) as usize] = None;
+            }
+            Instruction::IfTimer { timer, check } => {
+                let b = state.memory.timers.check((*timer).into(), *check);
+                state.stack.push(b).unwrap();
+            }
             _ => {}
         }
     }
@@ -213,7 +305,10 @@ mod tests {
     use crate::shal::bytecode::Instruction::{End, If, Not, On, Or, Pop, Set, Toggle};
     use crate::shal::bytecode::{InOut, Program};
     use crate::shal::common::{Edge, IsWas, Value};
-    use crate::shal::interpreter::{run_program, FixedBitSet};
+    use crate::shal::compiler::compile;
+    use crate::shal::interpreter::{run_program, run_program_with_memory, FixedBitSet, Memory};
+    use crate::shal::parser::parse;
+    use std::time::Duration;
 
     #[test]
     fn test_interpret() {
@@ -261,7 +356,9 @@ mod tests {
                 Pop,
                 End,
             ],
-            source_locations: vec![],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
         };
         assert_eq!(
             FixedBitSet::from(0x0000_0005),
@@ -339,4 +436,262 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_timers() {
+        // Retriggerable auto-off, with a minimum on-time for the manual switch-off
+        let program = compile(
+            &parse(
+                "on fedge input 0 {
+                   set output 0 high;
+                   after 2min as auto_off set output 0 low;
+                 }
+                 on fedge input 1 {
+                   if timer auto_off is stopped {
+                     set output 0 low;
+                   }
+                 }",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mut memory = Memory::default();
+        let mut run = |secs: u64, input_old: u32, input_new: u32, output_old: u32| {
+            run_program_with_memory(
+                &program,
+                &mut memory,
+                Duration::from_secs(secs),
+                &input_old.into(),
+                &input_new.into(),
+                &output_old.into(),
+            )
+        };
+        let released = 0x0000_0003;
+        assert_eq!(FixedBitSet::from(0x1), run(0, released, 0x0000_0002, 0x0));
+        assert_eq!(FixedBitSet::from(0x1), run(60, released, released, 0x1));
+        // Retrigger
+        assert_eq!(FixedBitSet::from(0x1), run(90, released, 0x0000_0002, 0x1));
+        assert_eq!(FixedBitSet::from(0x1), run(120, released, released, 0x1));
+        // Timer is running, so the switch-off is ignored
+        assert_eq!(FixedBitSet::from(0x1), run(150, released, 0x0000_0001, 0x1));
+        assert_eq!(FixedBitSet::from(0x0), run(210, released, released, 0x1));
+        // Timer stopped, so the switch-off works (e.g. after switching on over MQTT)
+        assert_eq!(FixedBitSet::from(0x1), run(300, released, released, 0x1));
+        assert_eq!(FixedBitSet::from(0x0), run(310, released, 0x0000_0001, 0x1));
+    }
+
+    #[test]
+    fn test_gestures() {
+        let program = compile(
+            &parse(
+                "{settings: {longpress: \"2s\", doubleclick: \"500ms\"}}
+---
+                 on longpress input 0 set output 0 low;
+                 on doubleclick input 1 toggle output 1;",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mut memory = Memory::default();
+        let mut run = |millis: u64, input_old: u32, input_new: u32, output_old: u32| {
+            run_program_with_memory(
+                &program,
+                &mut memory,
+                Duration::from_millis(millis),
+                &input_old.into(),
+                &input_new.into(),
+                &output_old.into(),
+            )
+        };
+        let released = 0x0000_0003;
+        // Short press of input 0
+        assert_eq!(FixedBitSet::from(0x1), run(0, released, 0x2, 0x1));
+        assert_eq!(FixedBitSet::from(0x1), run(1000, 0x2, released, 0x1));
+        assert_eq!(FixedBitSet::from(0x1), run(3000, released, released, 0x1));
+        // Long press of input 0, fires while still pressed
+        assert_eq!(FixedBitSet::from(0x1), run(4000, released, 0x2, 0x1));
+        assert_eq!(FixedBitSet::from(0x1), run(5000, 0x2, 0x2, 0x1));
+        assert_eq!(FixedBitSet::from(0x0), run(6000, 0x2, 0x2, 0x1));
+        assert_eq!(FixedBitSet::from(0x0), run(7000, 0x2, released, 0x0));
+        // Two presses of input 1 that are too far apart
+        assert_eq!(FixedBitSet::from(0x0), run(10000, released, 0x1, 0x0));
+        assert_eq!(FixedBitSet::from(0x0), run(10100, 0x1, released, 0x0));
+        assert_eq!(FixedBitSet::from(0x0), run(10600, released, 0x1, 0x0));
+        assert_eq!(FixedBitSet::from(0x0), run(10700, 0x1, released, 0x0));
+        // Double click, the timer was restarted by the previous press
+        assert_eq!(FixedBitSet::from(0x2), run(10900, released, 0x1, 0x0));
+        assert_eq!(FixedBitSet::from(0x2), run(11000, 0x1, released, 0x2));
+        // A third press starts a new double click
+        assert_eq!(FixedBitSet::from(0x2), run(11100, released, 0x1, 0x2));
+    }
+
+    #[test]
+    fn test_gesture_in_condition() {
+        let program = compile(
+            &parse(
+                "{settings: {longpress: \"2s\"}}
+---
+                 if input 1 is high {
+                   on longpress input 0 set output 0 high;
+                 }",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mut memory = Memory::default();
+        let mut run = |millis: u64, input_old: u32, input_new: u32| {
+            run_program_with_memory(
+                &program,
+                &mut memory,
+                Duration::from_millis(millis),
+                &input_old.into(),
+                &input_new.into(),
+                &0x0.into(),
+            )
+        };
+        let released = 0x0000_0003;
+        // Short press of input 0, the condition turns false before the release
+        assert_eq!(FixedBitSet::from(0x0), run(0, released, 0x2));
+        assert_eq!(FixedBitSet::from(0x0), run(500, 0x2, 0x0));
+        assert_eq!(FixedBitSet::from(0x0), run(1000, 0x0, 0x1));
+        assert_eq!(FixedBitSet::from(0x0), run(3000, 0x1, 0x1));
+        // Long press that started while the condition held
+        assert_eq!(FixedBitSet::from(0x0), run(4000, 0x1, released));
+        assert_eq!(FixedBitSet::from(0x0), run(5000, released, 0x2));
+        assert_eq!(FixedBitSet::from(0x0), run(5500, 0x2, 0x0));
+        assert_eq!(FixedBitSet::from(0x1), run(7500, 0x0, 0x0));
+    }
+
+    #[test]
+    fn test_flags() {
+        // Input 0 toggles night mode, input 1 only switches on output 0 outside of night mode
+        let program = compile(
+            &parse(
+                "{flags: {night: {pin: 0}}}
+---
+                 on fedge input 0 toggle night;
+                 on fedge input 1 {
+                   if night is low and flag 0 was low {
+                     set output 0 high;
+                   }
+                 }
+                 if night is high { set flag 1 high; }",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mut memory = Memory::default();
+        let run = |memory: &mut Memory, input_old: u32, input_new: u32, output_old: u32| {
+            run_program_with_memory(
+                &program,
+                memory,
+                Duration::ZERO,
+                &input_old.into(),
+                &input_new.into(),
+                &output_old.into(),
+            )
+        };
+        let released = 0x0000_0003;
+        assert_eq!(FixedBitSet::from(0x1), run(&mut memory, released, 0x1, 0x0));
+        assert_eq!(FixedBitSet::from(0x0), run(&mut memory, released, 0x2, 0x0));
+        assert_eq!(FixedBitSet::from(0x3), memory.flags);
+        assert_eq!(FixedBitSet::from(0x0), run(&mut memory, released, 0x1, 0x0));
+        assert_eq!(FixedBitSet::from(0x3), memory.flags);
+    }
+
+    #[test]
+    fn test_groups() {
+        let program = compile(
+            &parse(
+                "{
+  outputs: {
+    a: {pin: 0}
+    b: {pin: 1}
+    c: {pin: 2}
+  }
+  groups: {
+    ab: {outputs: [\"a\", \"b\"]}
+  }
+}
+---
+                 on fedge input 0 toggle group ab;
+                 if all of group ab is high { set c high; } else { set c low; }",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let released = 0x0000_0001;
+        // Any output on: the whole group goes off
+        assert_eq!(
+            FixedBitSet::from(0x0),
+            run_program(&program, &released.into(), &0x0.into(), &0x2.into())
+        );
+        // All outputs off: the whole group goes on
+        assert_eq!(
+            FixedBitSet::from(0x7),
+            run_program(&program, &released.into(), &0x0.into(), &0x0.into())
+        );
+        assert_eq!(
+            FixedBitSet::from(0x2),
+            run_program(&program, &released.into(), &released.into(), &0x6.into())
+        );
+    }
+
+    #[test]
+    fn test_nested_events() {
+        let program = compile(
+            &parse(
+                "{flags: {night: {pin: 0}}}
+---
+                 on fedge input 0 toggle night;
+                 if night is high {
+                   on fedge input 1 toggle output 0;
+                 } else {
+                   on fedge input 1 toggle output 1;
+                 }
+                 on redge input 2 {
+                   on redge input 3 set output 2 high;
+                 }",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        // Events push on the stack like conditions, so they nest the same way
+        assert_eq!(Ok(2), program.check_stack_depth(None));
+        let mut memory = Memory::default();
+        let run = |memory: &mut Memory, input_old: u32, input_new: u32, output_old: u32| {
+            run_program_with_memory(
+                &program,
+                memory,
+                Duration::ZERO,
+                &input_old.into(),
+                &input_new.into(),
+                &output_old.into(),
+            )
+        };
+        let released = 0x0000_000F;
+        // Only the event in the branch that is taken is checked
+        assert_eq!(FixedBitSet::from(0x2), run(&mut memory, released, 0xD, 0x0));
+        assert_eq!(FixedBitSet::from(0x2), run(&mut memory, released, 0xE, 0x2));
+        assert_eq!(FixedBitSet::from(0x1), memory.flags);
+        assert_eq!(FixedBitSet::from(0x3), run(&mut memory, released, 0xD, 0x2));
+        // Nested events only fire when both happen in the same loop
+        assert_eq!(FixedBitSet::from(0x0), run(&mut memory, 0xB, released, 0x0));
+        assert_eq!(FixedBitSet::from(0x0), run(&mut memory, 0x7, released, 0x0));
+        assert_eq!(FixedBitSet::from(0x4), run(&mut memory, 0x3, released, 0x0));
+    }
+
+    #[test]
+    fn test_xor() {
+        let program = compile(
+            &parse("if input 0 is high xor input 1 is high { set output 0 high; }").unwrap(),
+        )
+        .unwrap();
+        for (input, output) in [(0x0, 0x0), (0x1, 0x1), (0x2, 0x1), (0x3, 0x0)] {
+            assert_eq!(
+                FixedBitSet::from(output),
+                run_program(&program, &0.into(), &input.into(), &0.into())
+            );
+        }
+    }
 }
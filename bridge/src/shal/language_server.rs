@@ -0,0 +1,742 @@
+use crate::shal::ast::{EntityID, IODeclaration, IODeclarations, Lint, LintLevel, SourceLoc};
+use crate::shal::bytecode::{PROGRAM_SIZE_LIMIT, STACK_LIMIT};
+use crate::shal::compiler::{compile, CompileError};
+use crate::shal::linter::{lint, Warning};
+use crate::shal::optimizer::optimize;
+use crate::shal::parser::{
+    parse_declarations, parse_file, split_declarations, DeclarationFormat, ParseError,
+};
+use pest::error::LineColLocation;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tower_lsp::jsonrpc::Result as RpcResult;
+use tower_lsp::lsp_types::{
+    CodeLens, CodeLensOptions, CodeLensParams, Command, CompletionItem, CompletionItemKind,
+    CompletionOptions, CompletionParams, CompletionResponse, Diagnostic, DiagnosticSeverity,
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, InitializeParams, InitializeResult, InitializedParams, Location,
+    MarkupContent, MarkupKind, MessageType, NumberOrString, OneOf, Position, Range,
+    ServerCapabilities, ServerInfo, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use tower_lsp::{Client, LanguageServer, LspService, Server};
+
+/// The keywords of the language, see `shal.pest`
+const KEYWORDS: [&str; 34] = [
+    "if",
+    "else",
+    "on",
+    "redge",
+    "fedge",
+    "longpress",
+    "doubleclick",
+    "and",
+    "or",
+    "xor",
+    "not",
+    "toggle",
+    "set",
+    "input",
+    "output",
+    "flag",
+    "group",
+    "any",
+    "all",
+    "of",
+    "high",
+    "low",
+    "true",
+    "false",
+    "is",
+    "was",
+    "after",
+    "as",
+    "cancel",
+    "timer",
+    "rule",
+    "include",
+    "running",
+    "stopped",
+];
+
+/// What is known about a program, updated on every change
+struct Analysis {
+    diagnostics: Vec<Diagnostic>,
+    /// Declarations of the program and the files it includes,
+    /// or only those of the header if the program doesn't parse
+    declarations: IODeclarations,
+    /// Program size and stack depth, if the program compiles
+    size: Option<(usize, i32)>,
+    /// The files the program includes with their text, to find the declarations in them
+    included: Vec<(PathBuf, String)>,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// The range of the word at a line and column starting from 1,
+/// or the rest of the line if there is no word there
+fn range_at(text: &str, line: usize, col: usize) -> Range {
+    let line = line.saturating_sub(1);
+    let chars: Vec<char> = text.lines().nth(line).unwrap_or("").chars().collect();
+    let start = col.saturating_sub(1).min(chars.len());
+    let mut end = start;
+    while end < chars.len() && is_word_char(chars[end]) {
+        end += 1;
+    }
+    if end == start {
+        end = chars.len();
+    }
+    Range::new(
+        Position::new(line as u32, start as u32),
+        Position::new(line as u32, end as u32),
+    )
+}
+
+/// The word under the cursor, with its range
+fn word_at(text: &str, position: Position) -> Option<(String, Range)> {
+    let chars: Vec<char> = text.lines().nth(position.line as usize)?.chars().collect();
+    let character = (position.character as usize).min(chars.len());
+    let start = (0..character)
+        .rev()
+        .take_while(|&i| is_word_char(chars[i]))
+        .last()
+        .unwrap_or(character);
+    let end = (character..chars.len())
+        .find(|&i| !is_word_char(chars[i]))
+        .unwrap_or(chars.len());
+    if start == end {
+        return None;
+    }
+    let range = Range::new(
+        Position::new(position.line, start as u32),
+        Position::new(position.line, end as u32),
+    );
+    Some((chars[start..end].iter().collect(), range))
+}
+
+fn error_diagnostic(range: Range, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("shal".to_owned()),
+        message,
+        ..Default::default()
+    }
+}
+
+fn location_range(text: &str, location: Option<SourceLoc>) -> Range {
+    let SourceLoc(line, col) = location.unwrap_or(SourceLoc(1, 1));
+    range_at(text, line, col)
+}
+
+fn parse_error_diagnostic(text: &str, error: &ParseError) -> Diagnostic {
+    let location = match error {
+        ParseError::PestParseError(error) => {
+            // Pest only sees the program below the header
+            let (_, body) = split_declarations(text).unwrap_or((None, text));
+            let header_lines = text[..text.len() - body.len()].matches('\n').count();
+            let (line, col) = match error.line_col {
+                LineColLocation::Pos(start) | LineColLocation::Span(start, _) => start,
+            };
+            let range = range_at(text, line + header_lines, col);
+            return error_diagnostic(range, error.variant.message().into_owned());
+        }
+        ParseError::DeclarationError { line, col, .. } => Some(SourceLoc(*line, *col)),
+        ParseError::UnknownRuleError { location, .. }
+        | ParseError::RuleArgumentCountError { location, .. }
+        | ParseError::RuleArgumentTypeError { location, .. }
+        | ParseError::IncludeNotSupportedError { location }
+        | ParseError::IncludeCycleError { location, .. }
+        | ParseError::IncludeReadError { location, .. }
+        | ParseError::IncludeError { location, .. } => Some(*location),
+        _ => None,
+    };
+    error_diagnostic(location_range(text, location), error.to_string())
+}
+
+/// The first use of a word in the program below the header
+fn find_word(text: &str, word: &EntityID) -> Option<SourceLoc> {
+    let (_, body) = split_declarations(text).ok()?;
+    let start = text.len() - body.len();
+    let word = Regex::new(&format!(r"\b{}\b", regex::escape(word.into()))).ok()?;
+    let offset = start + word.find(body)?.start();
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    Some(SourceLoc(
+        text[..offset].matches('\n').count() + 1,
+        text[line_start..offset].chars().count() + 1,
+    ))
+}
+
+fn compile_error_diagnostic(text: &str, error: &CompileError) -> Diagnostic {
+    // Entities and timers the compiler doesn't know the location of are pointed out at their first use
+    let location = match error {
+        CompileError::UnknownEntityError { name, location } => {
+            location.or_else(|| find_word(text, name))
+        }
+        CompileError::UnknownTimerError { name } | CompileError::DuplicateTimerError { name } => {
+            find_word(text, name)
+        }
+        CompileError::ExpansionError { location, .. }
+        | CompileError::IncludeError { location, .. } => Some(*location),
+        _ => None,
+    };
+    error_diagnostic(location_range(text, location), error.to_string())
+}
+
+fn warning_diagnostic(text: &str, warning: &Warning) -> Diagnostic {
+    // Unused and unhandled entities are pointed out at their declaration, others at their statement
+    let range = match (warning.location, &warning.entity) {
+        (None, Some(entity))
+            if matches!(warning.lint, Lint::UnusedEntity | Lint::UnhandledInput) =>
+        {
+            declaration_range(text, entity.into())
+        }
+        _ => None,
+    };
+    let range = range.unwrap_or_else(|| {
+        let location = warning
+            .location
+            .or_else(|| find_word(text, warning.entity.as_ref()?));
+        location_range(text, location)
+    });
+    Diagnostic {
+        severity: Some(match warning.level {
+            LintLevel::Deny => DiagnosticSeverity::ERROR,
+            _ => DiagnosticSeverity::WARNING,
+        }),
+        code: Some(NumberOrString::String(warning.lint.to_string())),
+        ..error_diagnostic(range, warning.message.clone())
+    }
+}
+
+/// Includes are read from disk, programs that are not saved yet can't include anything
+fn analyze(path: Option<&Path>, text: &str) -> Analysis {
+    analyze_with(path, text, |path: &Path| {
+        if path.is_absolute() {
+            std::fs::read_to_string(path)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the program is not saved",
+            ))
+        }
+    })
+}
+
+/// Analyzes a program, reading the files it includes with `read`
+fn analyze_with(
+    path: Option<&Path>,
+    text: &str,
+    read: impl Fn(&Path) -> io::Result<String>,
+) -> Analysis {
+    let header_declarations = || {
+        split_declarations(text)
+            .ok()
+            .and_then(|(header, _)| header)
+            .and_then(|(header, format)| parse_declarations(header, format).ok())
+            .unwrap_or_default()
+    };
+    let included = RefCell::new(vec![]);
+    let read = |path: &Path| {
+        let text = read(path)?;
+        included.borrow_mut().push((path.to_owned(), text.clone()));
+        Ok(text)
+    };
+    let parsed = parse_file(path.unwrap_or(Path::new("")), text, None, read);
+    let included = included.into_inner();
+    let ast_program = match parsed {
+        Ok(ast_program) => ast_program,
+        Err(error) => {
+            return Analysis {
+                diagnostics: vec![parse_error_diagnostic(text, &error)],
+                declarations: header_declarations(),
+                size: None,
+                included,
+            }
+        }
+    };
+    let declarations = ast_program.declarations.clone();
+    let mut diagnostics: Vec<_> = lint(&ast_program)
+        .iter()
+        .map(|warning| warning_diagnostic(text, warning))
+        .collect();
+    let program = match compile(&ast_program) {
+        Ok(program) => optimize(&program),
+        Err(error) => {
+            diagnostics.insert(0, compile_error_diagnostic(text, &error));
+            return Analysis {
+                diagnostics,
+                declarations,
+                size: None,
+                included,
+            };
+        }
+    };
+    if let Err(error) = program.check_stack_depth(Some(STACK_LIMIT)) {
+        diagnostics.push(error_diagnostic(
+            location_range(text, error.source_location()),
+            format!("The stack depth exceeds the limit of {STACK_LIMIT}"),
+        ));
+    }
+    if let Err(error) = program.check_program_size(Some(PROGRAM_SIZE_LIMIT)) {
+        diagnostics.push(error_diagnostic(
+            location_range(text, error.source_location()),
+            format!("The program exceeds the limit of {PROGRAM_SIZE_LIMIT} bytes"),
+        ));
+    }
+    let size = (
+        program.calc_length(),
+        program
+            .check_stack_depth(None)
+            .unwrap_or_else(|_| unreachable!()),
+    );
+    Analysis {
+        diagnostics,
+        declarations,
+        size: Some(size),
+        included,
+    }
+}
+
+/// Describes a declared entity, e.g. `input 3 (Bathroom)`
+fn describe(declarations: &IODeclarations, id: &str) -> Option<String> {
+    let find = |kind: &str, map: &HashMap<EntityID, IODeclaration>| {
+        map.iter()
+            .find(|(entity, _)| <&str>::from(*entity) == id)
+            .map(|(_, declaration)| match &declaration.name {
+                Some(name) => format!("{kind} {} ({name})", declaration.pin),
+                None => format!("{kind} {}", declaration.pin),
+            })
+    };
+    find("input", &declarations.inputs)
+        .or_else(|| find("output", &declarations.outputs))
+        .or_else(|| find("flag", &declarations.flags))
+        .or_else(|| {
+            let (_, group) = declarations
+                .groups
+                .iter()
+                .find(|(entity, _)| <&str>::from(*entity) == id)?;
+            let outputs: Vec<_> = group.outputs.iter().map(<&str>::from).collect();
+            Some(match &group.name {
+                Some(name) => format!("group of {} ({name})", outputs.join(", ")),
+                None => format!("group of {}", outputs.join(", ")),
+            })
+        })
+}
+
+/// Where an entity is declared in the header, as a key in HJSON or YAML or a table in TOML
+fn declaration_range(text: &str, id: &str) -> Option<Range> {
+    let (header, _) = split_declarations(text).ok()?;
+    let (header, _) = header?;
+    header_declaration_range(header, id)
+}
+
+/// Where an entity is declared, in the program or in the header or declarations of an included file
+fn definition(uri: &Url, text: &str, included: &[(PathBuf, String)], id: &str) -> Option<Location> {
+    if let Some(range) = declaration_range(text, id) {
+        return Some(Location::new(uri.clone(), range));
+    }
+    included.iter().find_map(|(path, text)| {
+        let range = match DeclarationFormat::from_path(path) {
+            Some(_) => header_declaration_range(text, id),
+            None => declaration_range(text, id),
+        }?;
+        Some(Location::new(Url::from_file_path(path).ok()?, range))
+    })
+}
+
+fn header_declaration_range(header: &str, id: &str) -> Option<Range> {
+    let id = regex::escape(id);
+    let declaration = Regex::new(&format!(
+        r#"(?m)(?:^|[\s{{,"])({id})"?\s*[:=]|\[\s*[A-Za-z_.]*\.({id})\s*\]"#
+    ))
+    .ok()?;
+    let captures = declaration.captures(header)?;
+    let m = captures.get(1).or_else(|| captures.get(2))?;
+    let line = header[..m.start()].matches('\n').count();
+    let line_start = header[..m.start()].rfind('\n').map_or(0, |i| i + 1);
+    let col = header[line_start..m.start()].chars().count();
+    Some(Range::new(
+        Position::new(line as u32, col as u32),
+        Position::new(line as u32, (col + m.as_str().chars().count()) as u32),
+    ))
+}
+
+fn completions(declarations: &IODeclarations) -> Vec<CompletionItem> {
+    let entities = declarations
+        .inputs
+        .keys()
+        .chain(declarations.outputs.keys())
+        .chain(declarations.flags.keys())
+        .chain(declarations.groups.keys())
+        .map(|entity| {
+            let id = <&str>::from(entity);
+            CompletionItem {
+                label: id.to_owned(),
+                kind: Some(CompletionItemKind::VARIABLE),
+                detail: describe(declarations, id),
+                ..Default::default()
+            }
+        });
+    let keywords = KEYWORDS.iter().map(|keyword| CompletionItem {
+        label: (*keyword).to_owned(),
+        kind: Some(CompletionItemKind::KEYWORD),
+        ..Default::default()
+    });
+    entities.chain(keywords).collect()
+}
+
+fn size_lens(size: (usize, i32)) -> CodeLens {
+    let (length, depth) = size;
+    CodeLens {
+        range: Range::default(),
+        command: Some(Command {
+            title: format!(
+                "Program size: {length}/{PROGRAM_SIZE_LIMIT} bytes, stack depth: {depth}/{STACK_LIMIT}"
+            ),
+            command: String::new(),
+            arguments: None,
+        }),
+        data: None,
+    }
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+struct Backend {
+    client: Client,
+    documents: Mutex<HashMap<Url, Document>>,
+}
+
+impl Backend {
+    async fn update(&self, uri: Url, text: String, version: i32) {
+        let path: Option<PathBuf> = uri.to_file_path().ok();
+        // A bug in the analysis must not take down the language server
+        let analysis =
+            panic::catch_unwind(|| analyze(path.as_deref(), &text)).unwrap_or_else(|_| Analysis {
+                diagnostics: vec![error_diagnostic(
+                    location_range(&text, None),
+                    "Internal error while analyzing the program".to_owned(),
+                )],
+                declarations: IODeclarations::default(),
+                size: None,
+                included: vec![],
+            });
+        let diagnostics = analysis.diagnostics.clone();
+        self.documents
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(uri.clone(), Document { text, analysis });
+        self.client
+            .publish_diagnostics(uri, diagnostics, Some(version))
+            .await;
+    }
+
+    fn with_document<T>(&self, uri: &Url, f: impl FnOnce(&Document) -> Option<T>) -> Option<T> {
+        let documents = self.documents.lock().unwrap_or_else(|e| e.into_inner());
+        f(documents.get(uri)?)
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, _params: InitializeParams) -> RpcResult<InitializeResult> {
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::FULL,
+                )),
+                definition_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                completion_provider: Some(CompletionOptions::default()),
+                code_lens_provider: Some(CodeLensOptions {
+                    resolve_provider: Some(false),
+                }),
+                ..Default::default()
+            },
+            server_info: Some(ServerInfo {
+                name: "shal_lsp".to_owned(),
+                version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            }),
+        })
+    }
+
+    async fn initialized(&self, _params: InitializedParams) {
+        self.client
+            .log_message(MessageType::INFO, "SHAL language server started")
+            .await;
+    }
+
+    async fn shutdown(&self) -> RpcResult<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let document = params.text_document;
+        self.update(document.uri, document.text, document.version)
+            .await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        // The whole document is synced, so the last change has all of the text
+        if let Some(change) = params.content_changes.into_iter().last() {
+            let document = params.text_document;
+            self.update(document.uri, change.text, document.version)
+                .await;
+        }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        self.documents
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&uri);
+        self.client.publish_diagnostics(uri, vec![], None).await;
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> RpcResult<Option<GotoDefinitionResponse>> {
+        let position = params.text_document_position_params;
+        let uri = position.text_document.uri;
+        Ok(self.with_document(&uri, |document| {
+            let (word, _) = word_at(&document.text, position.position)?;
+            describe(&document.analysis.declarations, &word)?;
+            let location = definition(&uri, &document.text, &document.analysis.included, &word)?;
+            Some(GotoDefinitionResponse::Scalar(location))
+        }))
+    }
+
+    async fn hover(&self, params: HoverParams) -> RpcResult<Option<Hover>> {
+        let position = params.text_document_position_params;
+        Ok(self.with_document(&position.text_document.uri, |document| {
+            let (word, range) = word_at(&document.text, position.position)?;
+            let description = describe(&document.analysis.declarations, &word)?;
+            Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!("`{word}`: {description}"),
+                }),
+                range: Some(range),
+            })
+        }))
+    }
+
+    async fn completion(&self, params: CompletionParams) -> RpcResult<Option<CompletionResponse>> {
+        let uri = params.text_document_position.text_document.uri;
+        Ok(self.with_document(&uri, |document| {
+            Some(CompletionResponse::Array(completions(
+                &document.analysis.declarations,
+            )))
+        }))
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> RpcResult<Option<Vec<CodeLens>>> {
+        Ok(self.with_document(&params.text_document.uri, |document| {
+            Some(vec![size_lens(document.analysis.size?)])
+        }))
+    }
+}
+
+/// Serves the language server over stdin and stdout
+pub async fn run() {
+    let (service, socket) = LspService::new(|client| Backend {
+        client,
+        documents: Mutex::new(HashMap::new()),
+    });
+    Server::new(tokio::io::stdin(), tokio::io::stdout(), socket)
+        .serve(service)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "{\n\
+        \x20 inputs: {\n\
+        \x20   button: {pin: 3, name: \"Bathroom\"}\n\
+        \x20 }\n\
+        \x20 outputs: {\n\
+        \x20   light: {pin: 4}\n\
+        \x20   fan: {pin: 5}\n\
+        \x20 }\n\
+        \x20 groups: {\n\
+        \x20   all: {outputs: [\"light\", \"fan\"]}\n\
+        \x20 }\n\
+        }\n\
+        ---\n\
+        on fedge button toggle light;\n\
+        on redge button toggle group all;\n";
+
+    #[test]
+    fn test_analyze() {
+        let analysis = analyze(None, PROGRAM);
+        assert_eq!(analysis.diagnostics, vec![]);
+        assert_eq!(analysis.size, Some((21, 3)));
+
+        let analysis = analyze(None, &PROGRAM.replace("toggle light;", "toggle light"));
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(analysis.diagnostics[0].range.start, Position::new(13, 23));
+        assert_eq!(analysis.size, None);
+        // The declarations are still known while typing
+        assert_eq!(
+            describe(&analysis.declarations, "button"),
+            Some("input 3 (Bathroom)".to_owned())
+        );
+
+        let analysis = analyze(None, &PROGRAM.replace("toggle light;", "toggle lamp;"));
+        let diagnostic = &analysis.diagnostics[0];
+        assert_eq!(
+            diagnostic.range,
+            Range::new(Position::new(13, 23), Position::new(13, 27))
+        );
+        assert!(diagnostic.message.contains("Unknown entity: lamp"));
+
+        let analysis = analyze(None, &PROGRAM.replace("pin: 5", "pin: 55"));
+        assert_eq!(analysis.diagnostics[0].range.start.line, 6);
+
+        let analysis = analyze(
+            None,
+            &PROGRAM.replace("toggle light;", "toggle output 300;"),
+        );
+        assert!(analysis.diagnostics[0]
+            .message
+            .contains("Pin ID out of range: 300"));
+
+        let analysis = analyze(None, &PROGRAM.replace("group all", "fan"));
+        let diagnostic = &analysis.diagnostics[0];
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(
+            diagnostic.code,
+            Some(NumberOrString::String("unused_entity".to_owned()))
+        );
+        assert_eq!(
+            diagnostic.range,
+            Range::new(Position::new(9, 4), Position::new(9, 7))
+        );
+        assert!(analysis.size.is_some());
+
+        let program = format!("{PROGRAM}if button is high and button is low {{}}\n");
+        let analysis = analyze(None, &program);
+        assert_eq!(analysis.diagnostics[0].range.start, Position::new(15, 3));
+
+        let program = "rule blink(a) { toggle a; }\nblink(input 0);\n";
+        let analysis = analyze(None, program);
+        assert_eq!(
+            analysis.diagnostics[0].range,
+            Range::new(Position::new(1, 0), Position::new(1, 5))
+        );
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        // Empty blocks are optimized away, so the innermost block has an action
+        let program = "if input 0 is high { ".repeat(33) + "toggle output 0; " + &"}".repeat(33);
+        let analysis = analyze(None, &program);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert!(analysis.diagnostics[0].message.contains("stack depth"));
+        assert_eq!(analysis.size.map(|(_, depth)| depth), Some(33));
+    }
+
+    #[test]
+    fn test_definition() {
+        assert_eq!(
+            word_at(PROGRAM, Position::new(13, 12)),
+            Some((
+                "button".to_owned(),
+                Range::new(Position::new(13, 9), Position::new(13, 15))
+            ))
+        );
+        assert_eq!(word_at(PROGRAM, Position::new(13, 8)).unwrap().0, "fedge");
+        assert_eq!(word_at(PROGRAM, Position::new(15, 0)), None);
+        assert_eq!(
+            declaration_range(PROGRAM, "button"),
+            Some(Range::new(Position::new(2, 4), Position::new(2, 10)))
+        );
+        // Not the use of light in the group
+        assert_eq!(
+            declaration_range(PROGRAM, "light"),
+            Some(Range::new(Position::new(5, 4), Position::new(5, 9)))
+        );
+        assert_eq!(
+            declaration_range(PROGRAM, "all"),
+            Some(Range::new(Position::new(9, 4), Position::new(9, 7)))
+        );
+        let toml = "[inputs.button]\npin = 3\n--- toml\non fedge button toggle output 1;\n";
+        assert_eq!(
+            declaration_range(toml, "button"),
+            Some(Range::new(Position::new(0, 8), Position::new(0, 14)))
+        );
+    }
+
+    #[test]
+    fn test_included_definition() {
+        let main = Path::new("/home/shal/main.shal");
+        let program = "include \"lights.shal\";\n\
+                       include \"buttons.toml\";\n\
+                       on fedge button toggle light;\n";
+        let analysis = analyze_with(Some(main), program, |path| match path.to_str() {
+            Some("/home/shal/lights.shal") => Ok("{outputs: {light: {pin: 1}}}\n---\n".to_owned()),
+            Some("/home/shal/buttons.toml") => Ok("[inputs.button]\npin = 0\n".to_owned()),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        });
+        assert!(analysis.size.is_some());
+        let uri = Url::from_file_path(main).unwrap();
+        let location = |path: &str, start: u32, end: u32| {
+            Some(Location::new(
+                Url::from_file_path(path).unwrap(),
+                Range::new(Position::new(0, start), Position::new(0, end)),
+            ))
+        };
+        assert_eq!(
+            definition(&uri, program, &analysis.included, "light"),
+            location("/home/shal/lights.shal", 11, 16)
+        );
+        assert_eq!(
+            definition(&uri, program, &analysis.included, "button"),
+            location("/home/shal/buttons.toml", 8, 14)
+        );
+        assert_eq!(
+            definition(&uri, PROGRAM, &analysis.included, "fan"),
+            Some(Location::new(
+                uri.clone(),
+                Range::new(Position::new(6, 4), Position::new(6, 7))
+            ))
+        );
+        assert_eq!(definition(&uri, program, &analysis.included, "fan"), None);
+    }
+
+    #[test]
+    fn test_hover_and_completion() {
+        let declarations = analyze(None, PROGRAM).declarations;
+        assert_eq!(
+            describe(&declarations, "light"),
+            Some("output 4".to_owned())
+        );
+        assert_eq!(
+            describe(&declarations, "all"),
+            Some("group of light, fan".to_owned())
+        );
+        assert_eq!(describe(&declarations, "toggle"), None);
+        let labels: Vec<_> = completions(&declarations)
+            .into_iter()
+            .map(|item| item.label)
+            .collect();
+        assert!(labels.contains(&"button".to_owned()));
+        assert!(labels.contains(&"all".to_owned()));
+        assert!(labels.contains(&"longpress".to_owned()));
+    }
+}
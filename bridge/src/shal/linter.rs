@@ -0,0 +1,1072 @@
+use crate::shal::ast::{
+    Action, Condition, EntityID, IODeclaration, IODeclarations, Input, Lint, LintLevel, Output,
+    PinID, Program, Quantifier, SourceLoc, Statement, TimerStatus,
+};
+use crate::shal::common::{Edge, IsWas, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+/// Conditions with more atoms than this are not checked, the number of cases doubles with every atom
+const MAX_ATOMS: usize = 12;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub level: LintLevel,
+    pub message: String,
+    /// The entity the warning is about, if any
+    pub entity: Option<EntityID>,
+    /// The statement or condition the warning is about, or the outermost rule call or include it is in
+    pub location: Option<SourceLoc>,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(location) = self.location {
+            write!(f, ", at {location}")?;
+        }
+        write!(f, " [{}]", self.lint)
+    }
+}
+
+/// What an entity or number refers to, so `output 3` and the output declared with pin 3 are the same
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Target {
+    Input(PinID),
+    Output(PinID),
+    Flag(PinID),
+    Group(EntityID),
+    Unknown(EntityID),
+}
+
+/// A part of a condition that is either true or false, independent of the other atoms
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Atom {
+    Pin(Target, IsWas),
+    Group(Quantifier, EntityID, IsWas, Value),
+    Timer(EntityID, TimerStatus),
+    /// The input changed in this cycle
+    Changed(Target),
+    /// The after block or gesture with this number fired in this cycle
+    Trigger(usize),
+    /// The atom after the given number of rules wrote to it in this cycle
+    Version(Box<Atom>, usize),
+}
+
+/// A condition with its atoms resolved, so conditions of different rules can be combined
+#[derive(Clone, Debug)]
+enum Formula {
+    Atom(Atom),
+    Not(Box<Formula>),
+    And(Box<Formula>, Box<Formula>),
+    Or(Box<Formula>, Box<Formula>),
+    Xor(Box<Formula>, Box<Formula>),
+    Constant(bool),
+}
+
+impl Formula {
+    fn atoms(&self, atoms: &mut Vec<Atom>) {
+        match self {
+            Formula::And(a, b) | Formula::Or(a, b) | Formula::Xor(a, b) => {
+                a.atoms(atoms);
+                b.atoms(atoms);
+            }
+            Formula::Not(a) => a.atoms(atoms),
+            Formula::Constant(_) => {}
+            Formula::Atom(atom) => {
+                if !atoms.contains(atom) {
+                    atoms.push(atom.clone());
+                }
+            }
+        }
+    }
+
+    fn evaluate(&self, atoms: &[Atom], case: u32) -> bool {
+        match self {
+            Formula::And(a, b) => a.evaluate(atoms, case) && b.evaluate(atoms, case),
+            Formula::Or(a, b) => a.evaluate(atoms, case) || b.evaluate(atoms, case),
+            Formula::Xor(a, b) => a.evaluate(atoms, case) != b.evaluate(atoms, case),
+            Formula::Not(a) => !a.evaluate(atoms, case),
+            Formula::Constant(value) => *value,
+            Formula::Atom(atom) => {
+                let i = atoms
+                    .iter()
+                    .position(|a| a == atom)
+                    .unwrap_or_else(|| unreachable!());
+                case >> i & 1 == 1
+            }
+        }
+    }
+
+    fn map_atoms(self, f: &impl Fn(Atom) -> Atom) -> Formula {
+        let map = |formula: Box<Formula>| Box::new(formula.map_atoms(f));
+        match self {
+            Formula::And(a, b) => Formula::And(map(a), map(b)),
+            Formula::Or(a, b) => Formula::Or(map(a), map(b)),
+            Formula::Xor(a, b) => Formula::Xor(map(a), map(b)),
+            Formula::Not(a) => Formula::Not(map(a)),
+            Formula::Constant(value) => Formula::Constant(value),
+            Formula::Atom(atom) => Formula::Atom(f(atom)),
+        }
+    }
+}
+
+/// Whether there is a case where all formulas have the given values,
+/// unknown if there are too many atoms to check
+fn satisfiable(formulas: &[(Formula, bool)]) -> Option<bool> {
+    let mut atoms = vec![];
+    for (formula, _) in formulas {
+        formula.atoms(&mut atoms);
+    }
+    if atoms.len() > MAX_ATOMS {
+        return None;
+    }
+    Some((0..1u32 << atoms.len()).any(|case| {
+        formulas
+            .iter()
+            .all(|(formula, value)| formula.evaluate(&atoms, case) == *value)
+    }))
+}
+
+/// An output or flag written by a rule, with the conditions under which it happens
+struct Write {
+    target: Target,
+    /// The value it's set to, none for a toggle
+    value: Option<Value>,
+    guards: Vec<(Formula, bool)>,
+}
+
+struct Linter<'a> {
+    declarations: &'a IODeclarations,
+    warnings: Vec<Warning>,
+    /// The outermost call or include, warnings inside it are reported there
+    location: Option<SourceLoc>,
+    used: HashSet<&'a EntityID>,
+    handled: HashSet<Target>,
+    written: HashSet<Target>,
+    /// Was checks on outputs and flags, in the order they appear
+    was_checks: Vec<(Target, Option<SourceLoc>)>,
+}
+
+/// Lints the program, lints that are allowed in the settings are left out
+pub(crate) fn lint(program: &Program) -> Vec<Warning> {
+    let mut linter = Linter {
+        declarations: &program.declarations,
+        warnings: vec![],
+        location: None,
+        used: HashSet::new(),
+        handled: HashSet::new(),
+        written: HashSet::new(),
+        was_checks: vec![],
+    };
+    linter.statements(&program.statements);
+    linter.unwritten_was_checks();
+    linter.unused_entities();
+    linter.conflicting_writes(program);
+    let levels = &program.declarations.settings.lints;
+    linter
+        .warnings
+        .into_iter()
+        .filter_map(|warning| {
+            let level = levels.get(&warning.lint).copied().unwrap_or_default();
+            (level != LintLevel::Allow).then_some(Warning { level, ..warning })
+        })
+        .collect()
+}
+
+fn find_pin<'a>(map: &'a HashMap<EntityID, IODeclaration>, pin: &PinID) -> Option<&'a EntityID> {
+    map.iter()
+        .find(|(_, declaration)| declaration.pin == *pin)
+        .map(|(id, _)| id)
+}
+
+/// Every top-level statement is a rule, and so is every statement of an included file
+fn add_rule<'a>(
+    statement: &'a Statement,
+    location: SourceLoc,
+    rules: &mut Vec<(&'a Statement, SourceLoc)>,
+) {
+    if let Statement::Include { statements, .. } = statement {
+        for statement in statements {
+            add_rule(statement, location, rules);
+        }
+    } else {
+        rules.push((statement, location));
+    }
+}
+
+impl<'a> Linter<'a> {
+    fn warn(
+        &mut self,
+        lint: Lint,
+        message: String,
+        entity: Option<EntityID>,
+        location: Option<SourceLoc>,
+    ) {
+        self.warnings.push(Warning {
+            lint,
+            level: LintLevel::Warn,
+            message,
+            entity,
+            location: self.location.or(location),
+        });
+    }
+
+    fn entity(&self, id: &EntityID) -> Target {
+        let declarations = self.declarations;
+        if let Some(declaration) = declarations.inputs.get(id) {
+            Target::Input(declaration.pin)
+        } else if let Some(declaration) = declarations.outputs.get(id) {
+            Target::Output(declaration.pin)
+        } else if let Some(declaration) = declarations.flags.get(id) {
+            Target::Flag(declaration.pin)
+        } else if declarations.groups.contains_key(id) {
+            Target::Group(id.clone())
+        } else {
+            Target::Unknown(id.clone())
+        }
+    }
+
+    fn input(&self, input: &Input) -> Target {
+        match input {
+            Input::Number(pin) => Target::Input(*pin),
+            Input::Entity(id) => self.entity(id),
+        }
+    }
+
+    fn output(&self, output: &Output) -> Target {
+        match output {
+            Output::Number(pin) => Target::Output(*pin),
+            Output::Flag(pin) => Target::Flag(*pin),
+            Output::Entity(id) => self.entity(id),
+        }
+    }
+
+    /// The declared name of the target, or how it's written without one, e.g. `output 3`
+    fn name(&self, target: &Target) -> String {
+        let declarations = self.declarations;
+        let (kind, pin, map) = match target {
+            Target::Input(pin) => ("input", pin, &declarations.inputs),
+            Target::Output(pin) => ("output", pin, &declarations.outputs),
+            Target::Flag(pin) => ("flag", pin, &declarations.flags),
+            Target::Group(id) => return format!("group {id}"),
+            Target::Unknown(id) => return id.to_string(),
+        };
+        find_pin(map, pin).map_or_else(|| format!("{kind} {pin}"), ToString::to_string)
+    }
+
+    fn declared_id(&self, target: &Target) -> Option<EntityID> {
+        let declarations = self.declarations;
+        match target {
+            Target::Input(pin) => find_pin(&declarations.inputs, pin).cloned(),
+            Target::Output(pin) => find_pin(&declarations.outputs, pin).cloned(),
+            Target::Flag(pin) => find_pin(&declarations.flags, pin).cloned(),
+            Target::Group(id) => Some(id.clone()),
+            Target::Unknown(_) => None,
+        }
+    }
+
+    fn use_entity(&mut self, id: &'a EntityID) {
+        self.used.insert(id);
+        // The outputs of a group are used with it
+        if let Some(group) = self.declarations.groups.get(id) {
+            self.used.extend(group.outputs.iter());
+        }
+    }
+
+    fn use_input(&mut self, input: &'a Input) {
+        if let Input::Entity(id) = input {
+            self.use_entity(id);
+        }
+    }
+
+    fn use_output(&mut self, output: &'a Output) {
+        if let Output::Entity(id) = output {
+            self.use_entity(id);
+        }
+    }
+
+    fn statements(&mut self, statements: &'a [Statement]) {
+        self.redundant_sets(statements);
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &'a Statement) {
+        match statement {
+            Statement::Action(action, _) => self.action(action),
+            Statement::IfElse(condition, if_block, else_block, location) => {
+                self.if_chain(condition, if_block, else_block, *location)
+            }
+            Statement::Event {
+                input, statements, ..
+            }
+            | Statement::Gesture {
+                input, statements, ..
+            } => {
+                self.use_input(input);
+                self.handled.insert(self.input(input));
+                self.statements(statements);
+            }
+            Statement::After { statements, .. } => self.statements(statements),
+            Statement::Call {
+                location,
+                statements,
+                ..
+            }
+            | Statement::Include {
+                location,
+                statements,
+                ..
+            } => {
+                let outer = self.location;
+                self.location = outer.or(Some(*location));
+                self.statements(statements);
+                self.location = outer;
+            }
+        }
+    }
+
+    fn action(&mut self, action: &'a Action) {
+        match action {
+            Action::Toggle(output) | Action::Set(output, _) => {
+                self.use_output(output);
+                self.written.insert(self.output(output));
+            }
+            Action::ToggleGroup(group) | Action::SetGroup(group, _) => {
+                self.use_entity(group);
+                if let Some(group) = self.declarations.groups.get(group) {
+                    for output in &group.outputs {
+                        self.written.insert(self.entity(output));
+                    }
+                }
+            }
+            Action::Cancel(_) => {}
+        }
+    }
+
+    /// Setting an output right after setting or toggling it undoes the first action,
+    /// and so does toggling it twice
+    fn redundant_sets(&mut self, statements: &[Statement]) {
+        for pair in statements.windows(2) {
+            let [Statement::Action(first, location), Statement::Action(second, _)] = pair else {
+                continue;
+            };
+            let (target, message) = match (first, second) {
+                (Action::Set(a, _) | Action::Toggle(a), Action::Set(b, _))
+                    if self.output(a) == self.output(b) =>
+                {
+                    let target = self.output(a);
+                    let name = self.name(&target);
+                    (
+                        target,
+                        format!("{name} is set again right after, the first action has no effect"),
+                    )
+                }
+                (Action::Toggle(a), Action::Toggle(b)) if self.output(a) == self.output(b) => {
+                    let target = self.output(a);
+                    let name = self.name(&target);
+                    (
+                        target,
+                        format!("{name} is toggled twice in a row, which has no effect"),
+                    )
+                }
+                (Action::SetGroup(a, _) | Action::ToggleGroup(a), Action::SetGroup(b, _))
+                    if a == b =>
+                {
+                    (
+                        Target::Group(a.clone()),
+                        format!(
+                            "group {a} is set again right after, the first action has no effect"
+                        ),
+                    )
+                }
+                _ => continue,
+            };
+            let entity = self.declared_id(&target);
+            self.warn(Lint::RedundantSet, message, entity, Some(*location));
+        }
+    }
+
+    fn condition(&mut self, condition: &'a Condition, location: SourceLoc) {
+        match condition {
+            Condition::And(a, b) | Condition::Or(a, b) | Condition::Xor(a, b) => {
+                self.condition(a, location);
+                self.condition(b, location);
+            }
+            Condition::Not(a) => self.condition(a, location),
+            Condition::Input(input, _, _) => self.use_input(input),
+            Condition::Output(output, is_was, _) => {
+                self.use_output(output);
+                if *is_was == IsWas::Was {
+                    self.was_checks
+                        .push((self.output(output), self.location.or(Some(location))));
+                }
+            }
+            Condition::Entity(id, is_was, _) => {
+                self.use_entity(id);
+                let target = self.entity(id);
+                let written = matches!(target, Target::Output(_) | Target::Flag(_));
+                if *is_was == IsWas::Was && written {
+                    self.was_checks
+                        .push((target, self.location.or(Some(location))));
+                }
+            }
+            Condition::Group(_, group, _, _) => self.use_entity(group),
+            Condition::Timer(_, _) | Condition::Constant(_) => {}
+        }
+    }
+
+    /// The atom of a condition that isn't a combination of other conditions,
+    /// with whether the condition is true when the atom is
+    fn atom(&self, condition: &Condition) -> Option<(Atom, bool)> {
+        let high = |value: &Value| *value == Value::High;
+        Some(match condition {
+            Condition::Input(input, is_was, value) => {
+                (Atom::Pin(self.input(input), *is_was), high(value))
+            }
+            Condition::Output(output, is_was, value) => {
+                (Atom::Pin(self.output(output), *is_was), high(value))
+            }
+            Condition::Entity(id, is_was, value) => {
+                (Atom::Pin(self.entity(id), *is_was), high(value))
+            }
+            Condition::Group(quantifier, group, is_was, value) => (
+                Atom::Group(*quantifier, group.clone(), *is_was, *value),
+                true,
+            ),
+            Condition::Timer(timer, status) => (Atom::Timer(timer.clone(), *status), true),
+            _ => return None,
+        })
+    }
+
+    fn formula(&self, condition: &Condition) -> Formula {
+        let binary =
+            |a: &Condition, b: &Condition| (Box::new(self.formula(a)), Box::new(self.formula(b)));
+        match condition {
+            Condition::And(a, b) => {
+                let (a, b) = binary(a, b);
+                Formula::And(a, b)
+            }
+            Condition::Or(a, b) => {
+                let (a, b) = binary(a, b);
+                Formula::Or(a, b)
+            }
+            Condition::Xor(a, b) => {
+                let (a, b) = binary(a, b);
+                Formula::Xor(a, b)
+            }
+            Condition::Not(a) => Formula::Not(Box::new(self.formula(a))),
+            Condition::Constant(value) => Formula::Constant(*value),
+            condition => {
+                let (atom, when) = self.atom(condition).unwrap_or_else(|| unreachable!());
+                if when {
+                    Formula::Atom(atom)
+                } else {
+                    Formula::Not(Box::new(Formula::Atom(atom)))
+                }
+            }
+        }
+    }
+
+    fn satisfiable(&self, conditions: &[(&Condition, bool)]) -> Option<bool> {
+        let formulas = conditions
+            .iter()
+            .map(|(condition, value)| (self.formula(condition), *value))
+            .collect::<Vec<_>>();
+        satisfiable(&formulas)
+    }
+
+    /// Constant conditions without atoms, like `if false`, are written on purpose
+    fn constant_condition(&mut self, condition: &Condition, location: SourceLoc) -> Option<bool> {
+        let mut atoms = vec![];
+        self.formula(condition).atoms(&mut atoms);
+        if atoms.is_empty() {
+            return None;
+        }
+        let constant = if self.satisfiable(&[(condition, false)]) == Some(false) {
+            true
+        } else if self.satisfiable(&[(condition, true)]) == Some(false) {
+            false
+        } else {
+            return None;
+        };
+        self.warn(
+            Lint::ConstantCondition,
+            format!("Condition is always {constant}"),
+            None,
+            Some(location),
+        );
+        Some(constant)
+    }
+
+    /// Checks the branches of `if ... else if ... else` together, a branch is unreachable
+    /// if its condition can't be true when all the conditions before it are false
+    fn if_chain(
+        &mut self,
+        mut condition: &'a Condition,
+        mut if_block: &'a [Statement],
+        mut else_block: &'a [Statement],
+        mut location: SourceLoc,
+    ) {
+        let mut previous: Vec<(&Condition, bool)> = vec![];
+        loop {
+            self.condition(condition, location);
+            let constant = self.constant_condition(condition, location);
+            let mut branch = previous.clone();
+            branch.push((condition, true));
+            if !previous.is_empty()
+                && constant != Some(false)
+                && self.satisfiable(&branch) == Some(false)
+            {
+                self.warn(
+                    Lint::UnreachableElse,
+                    "This else if branch is unreachable, the conditions before it cover its cases"
+                        .to_owned(),
+                    None,
+                    Some(location),
+                );
+            }
+            self.statements(if_block);
+            previous.push((condition, false));
+            match else_block {
+                [Statement::IfElse(next, next_if_block, next_else_block, next_location)] => {
+                    condition = next;
+                    if_block = next_if_block;
+                    else_block = next_else_block;
+                    location = *next_location;
+                }
+                [] => return,
+                statements => {
+                    if self.satisfiable(&previous) == Some(false) {
+                        self.warn(
+                            Lint::UnreachableElse,
+                            "This else branch is unreachable, the conditions before it are always true"
+                                .to_owned(),
+                            None,
+                            statements.first().map(Statement::location),
+                        );
+                    }
+                    self.statements(statements);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn unwritten_was_checks(&mut self) {
+        let mut reported = HashSet::new();
+        for (target, location) in std::mem::take(&mut self.was_checks) {
+            if self.written.contains(&target) || !reported.insert(target.clone()) {
+                continue;
+            }
+            let name = self.name(&target);
+            self.warnings.push(Warning {
+                lint: Lint::UnwrittenWasCheck,
+                level: LintLevel::Warn,
+                message: format!("{name} is checked with `was`, but the program never writes it"),
+                entity: self.declared_id(&target),
+                location,
+            });
+        }
+    }
+
+    /// Unused inputs are only reported as unused, not as unhandled too
+    fn unused_entities(&mut self) {
+        let declarations = self.declarations;
+        let kinds = [
+            ("Input", declarations.inputs.keys().collect::<Vec<_>>()),
+            ("Output", declarations.outputs.keys().collect()),
+            ("Flag", declarations.flags.keys().collect()),
+            ("Group", declarations.groups.keys().collect()),
+        ];
+        for (kind, mut ids) in kinds {
+            ids.sort_by_key(|id| id.to_string());
+            for id in ids {
+                if !self.used.contains(id) {
+                    self.warn(
+                        Lint::UnusedEntity,
+                        format!("{kind} {id} is declared, but never used"),
+                        Some(id.clone()),
+                        None,
+                    );
+                } else if kind == "Input" && !self.handled.contains(&self.entity(id)) {
+                    self.warn(
+                        Lint::UnhandledInput,
+                        format!("Input {id} has no event handler"),
+                        Some(id.clone()),
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    /// The outputs and flags an action writes, with the value it sets them to
+    fn action_writes(&self, action: &Action) -> Vec<(Target, Option<Value>)> {
+        let (targets, value) = match action {
+            Action::Toggle(output) => (vec![self.output(output)], None),
+            Action::Set(output, value) => (vec![self.output(output)], Some(*value)),
+            Action::ToggleGroup(group) | Action::SetGroup(group, _) => {
+                let outputs = self.declarations.groups.get(group);
+                let targets = outputs.map_or(vec![], |group| {
+                    group.outputs.iter().map(|id| self.entity(id)).collect()
+                });
+                match action {
+                    Action::SetGroup(_, value) => (targets, Some(*value)),
+                    _ => (targets, None),
+                }
+            }
+            Action::Cancel(_) => (vec![], None),
+        };
+        targets
+            .into_iter()
+            .filter(|target| matches!(target, Target::Output(_) | Target::Flag(_)))
+            .map(|target| (target, value))
+            .collect()
+    }
+
+    /// Checks of outputs and flags see the writes of the rules before,
+    /// so a check after another rule wrote the target is independent of one before it
+    fn version(&self, atom: Atom, versions: &HashMap<Target, usize>) -> Atom {
+        let writes = |target: &Target| versions.get(target).copied().unwrap_or(0);
+        let version = match &atom {
+            Atom::Pin(target, IsWas::Is) => writes(target),
+            Atom::Group(_, group, IsWas::Is, _) => {
+                self.declarations.groups.get(group).map_or(0, |group| {
+                    group
+                        .outputs
+                        .iter()
+                        .map(|id| writes(&self.entity(id)))
+                        .sum()
+                })
+            }
+            _ => 0,
+        };
+        if version == 0 {
+            atom
+        } else {
+            Atom::Version(Box::new(atom), version)
+        }
+    }
+
+    fn guarded_writes(
+        &self,
+        statement: &Statement,
+        guards: &mut Vec<(Formula, bool)>,
+        versions: &HashMap<Target, usize>,
+        triggers: &mut usize,
+        writes: &mut Vec<Write>,
+    ) {
+        match statement {
+            Statement::Action(action, _) => {
+                for (target, value) in self.action_writes(action) {
+                    writes.push(Write {
+                        target,
+                        value,
+                        guards: guards.clone(),
+                    });
+                }
+            }
+            Statement::IfElse(condition, if_block, else_block, _) => {
+                let formula = self
+                    .formula(condition)
+                    .map_atoms(&|atom| self.version(atom, versions));
+                guards.push((formula, true));
+                self.guarded_block(if_block, guards, versions, triggers, writes);
+                guards.last_mut().unwrap_or_else(|| unreachable!()).1 = false;
+                self.guarded_block(else_block, guards, versions, triggers, writes);
+                guards.pop();
+            }
+            Statement::Event {
+                edge,
+                input,
+                statements,
+                ..
+            } => {
+                let target = self.input(input);
+                guards.push((Formula::Atom(Atom::Changed(target.clone())), true));
+                guards.push((
+                    Formula::Atom(Atom::Pin(target, IsWas::Is)),
+                    *edge == Edge::Rising,
+                ));
+                self.guarded_block(statements, guards, versions, triggers, writes);
+                guards.truncate(guards.len() - 2);
+            }
+            Statement::Gesture { statements, .. } | Statement::After { statements, .. } => {
+                *triggers += 1;
+                guards.push((Formula::Atom(Atom::Trigger(*triggers)), true));
+                self.guarded_block(statements, guards, versions, triggers, writes);
+                guards.pop();
+            }
+            Statement::Call { statements, .. } | Statement::Include { statements, .. } => {
+                self.guarded_block(statements, guards, versions, triggers, writes)
+            }
+        }
+    }
+
+    fn guarded_block(
+        &self,
+        statements: &[Statement],
+        guards: &mut Vec<(Formula, bool)>,
+        versions: &HashMap<Target, usize>,
+        triggers: &mut usize,
+        writes: &mut Vec<Write>,
+    ) {
+        for statement in statements {
+            self.guarded_writes(statement, guards, versions, triggers, writes);
+        }
+    }
+
+    /// Reports outputs and flags written by two rules that can run in the same cycle,
+    /// unless both set the same value
+    fn conflicting_writes(&mut self, program: &'a Program) {
+        let mut rules = vec![];
+        for (statement, location) in program.statements.iter().zip(&program.source_locations) {
+            add_rule(statement, *location, &mut rules);
+        }
+        let mut versions = HashMap::new();
+        let mut triggers = 0;
+        let mut writes = vec![];
+        for (statement, _) in &rules {
+            let mut rule_writes = vec![];
+            self.guarded_writes(
+                statement,
+                &mut vec![],
+                &versions,
+                &mut triggers,
+                &mut rule_writes,
+            );
+            let targets = rule_writes
+                .iter()
+                .map(|write| write.target.clone())
+                .collect::<HashSet<_>>();
+            for target in targets {
+                *versions.entry(target).or_insert(0) += 1;
+            }
+            writes.push(rule_writes);
+        }
+
+        for (j, second) in writes.iter().enumerate() {
+            let mut reported = HashSet::new();
+            for (i, first) in writes[..j].iter().enumerate() {
+                for a in first {
+                    for b in second {
+                        let same_value = a.value.is_some() && a.value == b.value;
+                        if a.target != b.target
+                            || same_value
+                            || reported.contains(&(rules[i].1, &a.target))
+                        {
+                            continue;
+                        }
+                        let guards = [a.guards.as_slice(), b.guards.as_slice()].concat();
+                        if satisfiable(&guards) != Some(true) {
+                            continue;
+                        }
+                        reported.insert((rules[i].1, &a.target));
+                        let name = self.name(&a.target);
+                        // Statements of the same included file share its location
+                        let message = if rules[i].1 == rules[j].1 {
+                            format!("{name} is written by two statements of the included file in the same cycle")
+                        } else {
+                            format!(
+                                "{name} is written here and by the statement at {}, in the same cycle",
+                                rules[i].1
+                            )
+                        };
+                        self.warnings.push(Warning {
+                            lint: Lint::ConflictingWrites,
+                            level: LintLevel::Warn,
+                            message,
+                            entity: self.declared_id(&a.target),
+                            location: Some(rules[j].1),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shal::parser::parse;
+    use crate::shal::tests::DECLARATIONS;
+
+    fn lint_str(body: &str) -> Vec<(Lint, String)> {
+        let program = parse(&format!("{DECLARATIONS}{body}")).unwrap();
+        lint(&program)
+            .into_iter()
+            .map(|warning| (warning.lint, warning.message))
+            .collect()
+    }
+
+    /// Uses all entities, so only the lint under test warns
+    const USE_ALL: &str =
+        "on redge button {}\non fedge switch { if any of group all is high {} }\n";
+
+    #[test]
+    fn test_lint_clean_program() {
+        assert_eq!(lint_str(USE_ALL), vec![]);
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}if button is high and switch is low {{}} else if button is low {{}} else {{}}"
+            )),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_lint_unused_entity() {
+        assert_eq!(
+            lint_str("on redge button toggle light;\n"),
+            vec![
+                (
+                    Lint::UnusedEntity,
+                    "Input switch is declared, but never used".to_owned()
+                ),
+                (
+                    Lint::UnusedEntity,
+                    "Output fan is declared, but never used".to_owned()
+                ),
+                (
+                    Lint::UnusedEntity,
+                    "Group all is declared, but never used".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_redundant_set() {
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}on fedge button {{ set light high; set output 0 low; }}\n\
+                 on redge button {{ toggle fan; toggle fan; toggle light; set fan high; set group all low; }}\n"
+            )),
+            vec![
+                (
+                    Lint::RedundantSet,
+                    "light is set again right after, the first action has no effect".to_owned()
+                ),
+                (
+                    Lint::RedundantSet,
+                    "fan is toggled twice in a row, which has no effect".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_constant_condition() {
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}if light is high and output 0 is low {{}}\n\
+                 if button is high or not (button is high) {{}}\n\
+                 if false {{}}\n\
+                 if light is high and fan is low {{}}\n"
+            )),
+            vec![
+                (
+                    Lint::ConstantCondition,
+                    "Condition is always false".to_owned()
+                ),
+                (
+                    Lint::ConstantCondition,
+                    "Condition is always true".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_unwritten_was_check() {
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}on redge switch toggle light;\n\
+                 if output 5 was high and light was high and flag 2 was low {{}}\n\
+                 if output 5 was low {{}}\n"
+            )),
+            vec![
+                (
+                    Lint::UnwrittenWasCheck,
+                    "output 5 is checked with `was`, but the program never writes it".to_owned()
+                ),
+                (
+                    Lint::UnwrittenWasCheck,
+                    "flag 2 is checked with `was`, but the program never writes it".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_unreachable_else() {
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}if button is high {{}} else if button is low {{}} else {{ toggle light; }}\n\
+                 if button is high or switch is high {{}} else if switch is high {{}}\n\
+                 if button is high {{}} else if button is high and switch is low {{}}\n"
+            )),
+            vec![
+                (
+                    Lint::UnreachableElse,
+                    "This else branch is unreachable, the conditions before it are always true"
+                        .to_owned()
+                ),
+                (
+                    Lint::UnreachableElse,
+                    "This else if branch is unreachable, the conditions before it cover its cases"
+                        .to_owned()
+                ),
+                (
+                    Lint::UnreachableElse,
+                    "This else if branch is unreachable, the conditions before it cover its cases"
+                        .to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_unhandled_input() {
+        assert_eq!(
+            lint_str("on redge button toggle group all;\nif switch is high { toggle output 5; }\n"),
+            vec![(
+                Lint::UnhandledInput,
+                "Input switch has no event handler".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_lint_conflicting_writes() {
+        let program = parse(&format!(
+            "{DECLARATIONS}{USE_ALL}on fedge button set light high;\n\
+             on fedge button toggle light;\n\
+             on fedge button {{ if fan is high {{ set fan low; }} }}\n\
+             on fedge button {{ if fan is low {{ set fan high; }} }}\n\
+             on redge switch set light high;\n\
+             on redge switch set light high;\n\
+             on fedge button {{ if switch is high {{ set flag 1 high; }} }}\n\
+             on fedge button {{ if switch is low {{ toggle flag 1; }} }}\n"
+        ))
+        .unwrap();
+        let warnings = lint(&program);
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|warning| (warning.lint, warning.message.as_str(), warning.location))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Lint::ConflictingWrites,
+                    "light is written here and by the statement at line 17, col 1, in the same cycle",
+                    Some(SourceLoc(18, 1))
+                ),
+                (
+                    Lint::ConflictingWrites,
+                    "fan is written here and by the statement at line 19, col 1, in the same cycle",
+                    Some(SourceLoc(20, 1))
+                ),
+                // Several inputs can change in the same cycle
+                (
+                    Lint::ConflictingWrites,
+                    "light is written here and by the statement at line 18, col 1, in the same cycle",
+                    Some(SourceLoc(21, 1))
+                ),
+                (
+                    Lint::ConflictingWrites,
+                    "light is written here and by the statement at line 18, col 1, in the same cycle",
+                    Some(SourceLoc(22, 1))
+                ),
+            ]
+        );
+        assert_eq!(warnings[0].entity, Some("light".try_into().unwrap()));
+        let conflict = |line| {
+            vec![(
+                Lint::ConflictingWrites,
+                format!("light is written here and by the statement at {line}, in the same cycle"),
+            )]
+        };
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}on fedge button toggle light;\non fedge switch toggle light;\n"
+            )),
+            conflict("line 17, col 1")
+        );
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}on longpress button toggle light;\nafter 1s toggle light;\n"
+            )),
+            conflict("line 17, col 1")
+        );
+        // The same input can't change both ways in one cycle
+        assert_eq!(
+            lint_str(&format!(
+                "{USE_ALL}on fedge button toggle light;\non redge button toggle light;\n"
+            )),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_lint_levels() {
+        let program = parse(
+            "{\n\
+             \x20 inputs: {button: {pin: 0}}\n\
+             \x20 settings: {lints: {unused_entity: \"allow\", constant_condition: \"deny\"}}\n\
+             }\n\
+             ---\n\
+             if input 1 is high and input 1 is low {}\n",
+        )
+        .unwrap();
+        let warnings = lint(&program);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].lint, Lint::ConstantCondition);
+        assert_eq!(warnings[0].level, LintLevel::Deny);
+        assert_eq!(
+            warnings[0].to_string(),
+            "Condition is always false, at line 6, col 4 [constant_condition]"
+        );
+        assert!(parse("{settings: {lints: {unused: \"allow\"}}}\n---\n").is_err());
+    }
+
+    #[test]
+    fn test_lint_location() {
+        let program = parse(&format!(
+            "{DECLARATIONS}rule blink(a) {{ on longpress button {{ toggle a; toggle a; }} }}\n{USE_ALL}blink(light);\n"
+        ))
+        .unwrap();
+        let warnings = lint(&program);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].location, Some(SourceLoc(18, 1)));
+        assert_eq!(warnings[0].entity, Some("light".try_into().unwrap()));
+    }
+
+    #[test]
+    fn test_lint_statement_locations() {
+        let program = parse(&format!(
+            "{DECLARATIONS}{USE_ALL}on redge switch {{ set light high; set light low; }}\n\
+             if button is high {{}} else if button is low {{}} else {{ toggle fan; }}\n\
+             if button is high and button is low {{}}\n\
+             if output 5 was high and light was high {{}}\n"
+        ))
+        .unwrap();
+        assert_eq!(
+            lint(&program)
+                .into_iter()
+                .map(|warning| (warning.lint, warning.location))
+                .collect::<Vec<_>>(),
+            vec![
+                (Lint::RedundantSet, Some(SourceLoc(17, 19))),
+                (Lint::UnreachableElse, Some(SourceLoc(18, 54))),
+                (Lint::ConstantCondition, Some(SourceLoc(19, 4))),
+                (Lint::UnwrittenWasCheck, Some(SourceLoc(20, 4))),
+            ]
+        );
+    }
+}
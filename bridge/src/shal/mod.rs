@@ -2,8 +2,13 @@ pub mod ast;
 pub mod bytecode;
 mod common;
 pub mod compiler;
-#[cfg(test)]
-mod interpreter;
+pub mod equivalence;
+pub mod formatter;
+pub(crate) mod interpreter;
+pub mod language_server;
+pub mod linter;
+pub mod optimizer;
 pub mod parser;
+pub mod source_map;
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
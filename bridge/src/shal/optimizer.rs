@@ -0,0 +1,1068 @@
+use crate::shal::ast::PinID;
+use crate::shal::bytecode::{
+    InOut, Instruction, Origin, Program, TimerCheck, TimerID, STACK_LIMIT,
+};
+use crate::shal::common::{IsWas, Value};
+use std::collections::HashSet;
+
+/// A condition, as pushed on the stack by a sequence of tests and boolean operators
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    /// An ON, IF or IF TIMER instruction
+    Test(Instruction),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Xor(Box<Expr>, Box<Expr>),
+}
+
+/// The structure of the code the compiler generates: every block pushes its condition,
+/// runs its body, optionally flips the condition with NOT and runs the else body, and pops it.
+/// Statements keep the source location of the instructions they were parsed from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Statement {
+    Action(Instruction, Option<Origin>),
+    Block {
+        condition: Expr,
+        body: Vec<Statement>,
+        else_body: Option<Vec<Statement>>,
+        location: Option<Origin>,
+    },
+}
+
+/// Instructions with the location of each of them, if it is known
+#[derive(Debug, Default)]
+struct Code {
+    instructions: Vec<Instruction>,
+    locations: Vec<Option<Origin>>,
+}
+
+impl Code {
+    fn push(&mut self, instruction: Instruction, location: Option<Origin>) {
+        self.instructions.push(instruction);
+        self.locations.push(location);
+    }
+}
+
+/// State of the VM that can be changed while the program runs
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+enum Target {
+    Output(PinID),
+    Flag(PinID),
+    Timer(TimerID),
+}
+
+#[derive(Debug, Default)]
+struct Effects {
+    reads: HashSet<Target>,
+    writes: HashSet<Target>,
+}
+
+impl Effects {
+    /// Whether running `self` and `other` in either order gives the same result
+    fn commutes_with(&self, other: &Effects) -> bool {
+        self.writes.is_disjoint(&other.reads)
+            && self.writes.is_disjoint(&other.writes)
+            && other.writes.is_disjoint(&self.reads)
+    }
+}
+
+/// Optimizes the size of a compiled program, the result behaves the same in every state.
+///
+/// Event blocks for the same input and blocks with the same condition are merged, common
+/// condition prefixes are shared, actions that are overwritten right away are removed
+/// and conditions are simplified. Event blocks that only toggle an output are
+/// written as one ON TOGGLE instruction. Programs that don't have the structure the compiler
+/// generates are returned as they are, as are optimized programs that exceed the stack limit.
+pub(crate) fn optimize(program: &Program) -> Program {
+    let Some(mut statements) = parse(&program.instructions, &program.origins) else {
+        return program.clone();
+    };
+    loop {
+        let optimized = optimize_statements(statements.clone());
+        if optimized == statements {
+            break;
+        }
+        statements = optimized;
+    }
+    let mut code = Code::default();
+    emit_statements(&statements, &mut code);
+    let code = compact(code);
+    let mut instructions = code.instructions;
+    instructions.push(Instruction::End);
+    let optimized = Program {
+        declarations: program.declarations.clone(),
+        instructions,
+        origins: code
+            .locations
+            .into_iter()
+            .collect::<Option<_>>()
+            .unwrap_or_default(),
+        files: program.files.clone(),
+        timer_sources: program.timer_sources.clone(),
+    };
+    let depth = |program: &Program| {
+        program
+            .check_stack_depth(None)
+            .unwrap_or_else(|_| unreachable!())
+    };
+    if optimized.calc_length() > program.calc_length()
+        || (depth(&optimized) > STACK_LIMIT && depth(&optimized) > depth(program))
+    {
+        return program.clone();
+    }
+    optimized
+}
+
+/// Replaces every ON, TOGGLE and POP sequence by an ON TOGGLE instruction
+fn compact(code: Code) -> Code {
+    let Code {
+        instructions,
+        locations,
+    } = code;
+    let mut compacted = Code::default();
+    let mut i = 0;
+    while i < instructions.len() {
+        match instructions[i..] {
+            [Instruction::On { input, edge }, Instruction::Toggle { output }, Instruction::Pop, ..] =>
+            {
+                compacted.push(
+                    Instruction::OnToggle {
+                        input,
+                        edge,
+                        output,
+                    },
+                    locations[i],
+                );
+                i += 3;
+            }
+            _ => {
+                compacted.push(instructions[i], locations[i]);
+                i += 1;
+            }
+        }
+    }
+    compacted
+}
+
+fn is_test(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::On { .. } | Instruction::If { .. } | Instruction::IfTimer { .. }
+    )
+}
+
+fn is_action(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Set { .. }
+            | Instruction::Toggle { .. }
+            | Instruction::SetFlag { .. }
+            | Instruction::ToggleFlag { .. }
+            | Instruction::StartTimer { .. }
+            | Instruction::CancelTimer { .. }
+    )
+}
+
+/// Parses the instructions into statements, returns `None` if they don't have
+/// the structure the compiler generates
+fn parse(instructions: &[Instruction], locations: &[Origin]) -> Option<Vec<Statement>> {
+    let (last, instructions) = instructions.split_last()?;
+    if *last != Instruction::End {
+        return None;
+    }
+    let mut position = 0;
+    let statements = parse_statements(instructions, locations, &mut position)?;
+    (position == instructions.len()).then_some(statements)
+}
+
+fn parse_statements(
+    instructions: &[Instruction],
+    locations: &[Origin],
+    position: &mut usize,
+) -> Option<Vec<Statement>> {
+    let mut statements = Vec::new();
+    while let Some(instruction) = instructions.get(*position) {
+        let location = locations.get(*position).copied();
+        if let Instruction::OnToggle {
+            input,
+            edge,
+            output,
+        } = *instruction
+        {
+            statements.push(Statement::Block {
+                condition: Expr::Test(Instruction::On { input, edge }),
+                body: vec![Statement::Action(Instruction::Toggle { output }, location)],
+                else_body: None,
+                location,
+            });
+            *position += 1;
+        } else if is_action(instruction) {
+            statements.push(Statement::Action(*instruction, location));
+            *position += 1;
+        } else if is_test(instruction) {
+            statements.push(parse_block(instructions, locations, position)?);
+        } else {
+            break;
+        }
+    }
+    Some(statements)
+}
+
+fn parse_block(
+    instructions: &[Instruction],
+    locations: &[Origin],
+    position: &mut usize,
+) -> Option<Statement> {
+    let location = locations.get(*position).copied();
+    // The condition is followed by the body, which can start with the condition of a nested
+    // block: the condition ends at the last point where exactly one value was pushed
+    let mut depth = 0;
+    let mut end = *position;
+    for (i, instruction) in instructions.iter().enumerate().skip(*position) {
+        match instruction {
+            instruction if is_test(instruction) => depth += 1,
+            Instruction::Not => {}
+            Instruction::And | Instruction::Or | Instruction::Xor if depth >= 2 => depth -= 1,
+            _ => break,
+        }
+        if depth == 1 {
+            end = i + 1;
+        }
+    }
+    let condition = parse_condition(&instructions[*position..end])?;
+    *position = end;
+    let body = parse_statements(instructions, locations, position)?;
+    let else_body = if instructions.get(*position) == Some(&Instruction::Not) {
+        *position += 1;
+        Some(parse_statements(instructions, locations, position)?)
+    } else {
+        None
+    };
+    if instructions.get(*position) != Some(&Instruction::Pop) {
+        return None;
+    }
+    *position += 1;
+    Some(Statement::Block {
+        condition,
+        body,
+        else_body,
+        location,
+    })
+}
+
+fn parse_condition(instructions: &[Instruction]) -> Option<Expr> {
+    let mut stack = Vec::new();
+    for instruction in instructions {
+        let expr = match instruction {
+            Instruction::Not => Expr::Not(Box::new(stack.pop()?)),
+            Instruction::And | Instruction::Or | Instruction::Xor => {
+                let right = Box::new(stack.pop()?);
+                let left = Box::new(stack.pop()?);
+                match instruction {
+                    Instruction::And => Expr::And(left, right),
+                    Instruction::Or => Expr::Or(left, right),
+                    _ => Expr::Xor(left, right),
+                }
+            }
+            test => Expr::Test(*test),
+        };
+        stack.push(expr);
+    }
+    let condition = stack.pop()?;
+    stack.is_empty().then_some(condition)
+}
+
+fn emit_statements(statements: &[Statement], code: &mut Code) {
+    for statement in statements {
+        match statement {
+            Statement::Action(action, location) => code.push(*action, *location),
+            Statement::Block {
+                condition,
+                body,
+                else_body,
+                location,
+            } => {
+                emit_condition(condition, *location, code);
+                emit_statements(body, code);
+                if let Some(else_body) = else_body {
+                    code.push(Instruction::Not, *location);
+                    emit_statements(else_body, code);
+                }
+                code.push(Instruction::Pop, *location);
+            }
+        }
+    }
+}
+
+fn emit_condition(condition: &Expr, location: Option<Origin>, code: &mut Code) {
+    match condition {
+        Expr::Test(test) => code.push(*test, location),
+        Expr::Not(expr) => {
+            emit_condition(expr, location, code);
+            code.push(Instruction::Not, location);
+        }
+        Expr::And(left, right) | Expr::Or(left, right) | Expr::Xor(left, right) => {
+            emit_condition(left, location, code);
+            emit_condition(right, location, code);
+            let operator = match condition {
+                Expr::And(..) => Instruction::And,
+                Expr::Or(..) => Instruction::Or,
+                _ => Instruction::Xor,
+            };
+            code.push(operator, location);
+        }
+    }
+}
+
+/// The size of the condition in bytes
+fn size(condition: &Expr) -> usize {
+    match condition {
+        Expr::Test(_) => 2,
+        Expr::Not(expr) => 1 + size(expr),
+        Expr::And(left, right) | Expr::Or(left, right) | Expr::Xor(left, right) => {
+            1 + size(left) + size(right)
+        }
+    }
+}
+
+/// The state a test reads that can change while the program runs: `was` checks,
+/// inputs and elapsed timers stay the same during a cycle
+fn condition_reads(condition: &Expr, reads: &mut HashSet<Target>) {
+    match condition {
+        Expr::Test(Instruction::If {
+            number,
+            is_was: IsWas::Is,
+            in_out: InOut::Output,
+            ..
+        }) => {
+            reads.insert(Target::Output(*number));
+        }
+        Expr::Test(Instruction::If {
+            number,
+            is_was: IsWas::Is,
+            in_out: InOut::Flag,
+            ..
+        }) => {
+            reads.insert(Target::Flag(*number));
+        }
+        Expr::Test(Instruction::IfTimer {
+            timer,
+            check: TimerCheck::Running,
+        }) => {
+            reads.insert(Target::Timer(*timer));
+        }
+        Expr::Test(_) => {}
+        Expr::Not(expr) => condition_reads(expr, reads),
+        Expr::And(left, right) | Expr::Or(left, right) | Expr::Xor(left, right) => {
+            condition_reads(left, reads);
+            condition_reads(right, reads);
+        }
+    }
+}
+
+fn target(action: &Instruction) -> Option<Target> {
+    match *action {
+        Instruction::Set { output, .. } | Instruction::Toggle { output } => {
+            Some(Target::Output(output))
+        }
+        Instruction::SetFlag { flag, .. } | Instruction::ToggleFlag { flag } => {
+            Some(Target::Flag(flag))
+        }
+        Instruction::StartTimer { timer, .. } | Instruction::CancelTimer { timer } => {
+            Some(Target::Timer(timer))
+        }
+        _ => None,
+    }
+}
+
+/// Whether the action replaces the state of its target, rather than toggling it
+fn overwrites(action: &Instruction) -> bool {
+    !matches!(
+        action,
+        Instruction::Toggle { .. } | Instruction::ToggleFlag { .. }
+    )
+}
+
+fn add_effects(statement: &Statement, effects: &mut Effects) {
+    match statement {
+        Statement::Action(action, _) => {
+            if let Some(target) = target(action) {
+                if !overwrites(action) {
+                    effects.reads.insert(target);
+                }
+                effects.writes.insert(target);
+            }
+        }
+        Statement::Block {
+            condition,
+            body,
+            else_body,
+            ..
+        } => {
+            condition_reads(condition, &mut effects.reads);
+            for statement in body.iter().chain(else_body.iter().flatten()) {
+                add_effects(statement, effects);
+            }
+        }
+    }
+}
+
+fn effects<'a>(statements: impl IntoIterator<Item = &'a Statement>) -> Effects {
+    let mut effects = Effects::default();
+    for statement in statements {
+        add_effects(statement, &mut effects);
+    }
+    effects
+}
+
+fn optimize_statements(statements: Vec<Statement>) -> Vec<Statement> {
+    let statements = statements
+        .into_iter()
+        .filter_map(optimize_statement)
+        .collect();
+    let statements = merge_blocks(statements);
+    remove_redundant_actions(statements)
+}
+
+fn optimize_statement(statement: Statement) -> Option<Statement> {
+    let Statement::Block {
+        condition,
+        body,
+        else_body,
+        location,
+    } = statement
+    else {
+        return Some(statement);
+    };
+    let condition = simplify(condition);
+    let body = optimize_statements(body);
+    let else_body = else_body
+        .map(optimize_statements)
+        .filter(|else_body| !else_body.is_empty());
+    match (body.is_empty(), else_body) {
+        // Conditions have no side effects
+        (true, None) => None,
+        (true, Some(else_body)) => Some(Statement::Block {
+            condition: negate(condition),
+            body: else_body,
+            else_body: None,
+            location,
+        }),
+        (false, else_body) => Some(Statement::Block {
+            condition,
+            body,
+            else_body,
+            location,
+        }),
+    }
+}
+
+/// Merges every block into an earlier block with the same condition, or with a common
+/// condition prefix, if it can be moved past the statements in between
+fn merge_blocks(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut merged: Vec<Statement> = Vec::new();
+    'statements: for statement in statements {
+        if matches!(statement, Statement::Block { .. }) {
+            let statement_effects = effects([&statement]);
+            for i in (0..merged.len()).rev() {
+                if let Some(block) = merge(&merged[i], &statement) {
+                    merged[i] = block;
+                    continue 'statements;
+                }
+                if !effects([&merged[i]]).commutes_with(&statement_effects) {
+                    break;
+                }
+            }
+        }
+        merged.push(statement);
+    }
+    merged
+}
+
+/// Merges two consecutive blocks into one, if the first one doesn't change the
+/// outcome of the shared part of the condition
+fn merge(first: &Statement, second: &Statement) -> Option<Statement> {
+    let (
+        Statement::Block {
+            condition: first_condition,
+            body: first_body,
+            else_body: first_else,
+            location: first_location,
+        },
+        Statement::Block {
+            condition: second_condition,
+            body: second_body,
+            else_body: second_else,
+            location: second_location,
+        },
+    ) = (first, second)
+    else {
+        return None;
+    };
+    let first_writes = effects(first_body.iter().chain(first_else.iter().flatten())).writes;
+    let unchanged = |condition: &Expr| {
+        let mut reads = HashSet::new();
+        condition_reads(condition, &mut reads);
+        first_writes.is_disjoint(&reads)
+    };
+    if first_condition == second_condition {
+        if !unchanged(first_condition) {
+            return None;
+        }
+        let else_body = match (first_else, second_else) {
+            (None, None) => None,
+            (first_else, second_else) => Some(
+                first_else
+                    .iter()
+                    .chain(second_else.iter())
+                    .flatten()
+                    .cloned()
+                    .collect(),
+            ),
+        };
+        return Some(Statement::Block {
+            condition: first_condition.clone(),
+            body: first_body.iter().chain(second_body).cloned().collect(),
+            else_body,
+            location: *first_location,
+        });
+    }
+    if first_else.is_some() || second_else.is_some() {
+        return None;
+    }
+    let first_conjuncts = conjuncts(first_condition);
+    let second_conjuncts = conjuncts(second_condition);
+    let shared = first_conjuncts
+        .iter()
+        .zip(&second_conjuncts)
+        .take_while(|(first, second)| first == second)
+        .count();
+    if shared == 0 {
+        return None;
+    }
+    let prefix = conjunction(&first_conjuncts[..shared])?;
+    if !unchanged(&prefix) {
+        return None;
+    }
+    let nest =
+        |conjuncts: &[Expr], body: &Vec<Statement>, location: &Option<Origin>| match conjunction(
+            conjuncts,
+        ) {
+            Some(condition) => vec![Statement::Block {
+                condition,
+                body: body.clone(),
+                else_body: None,
+                location: *location,
+            }],
+            None => body.clone(),
+        };
+    let mut body = nest(&first_conjuncts[shared..], first_body, first_location);
+    body.extend(nest(
+        &second_conjuncts[shared..],
+        second_body,
+        second_location,
+    ));
+    Some(Statement::Block {
+        condition: prefix,
+        body,
+        else_body: None,
+        location: *first_location,
+    })
+}
+
+/// The operands of a chain of ANDs, from left to right
+fn conjuncts(condition: &Expr) -> Vec<Expr> {
+    match condition {
+        Expr::And(left, right) => {
+            let mut conjuncts = conjuncts(left);
+            conjuncts.extend(self::conjuncts(right));
+            conjuncts
+        }
+        condition => vec![condition.clone()],
+    }
+}
+
+fn conjunction(conjuncts: &[Expr]) -> Option<Expr> {
+    conjuncts
+        .iter()
+        .cloned()
+        .reduce(|left, right| Expr::And(Box::new(left), Box::new(right)))
+}
+
+/// Removes actions that are overwritten by a later action in the same body before
+/// anything reads their target, pairs of toggles, and sets to the value set before
+fn remove_redundant_actions(mut statements: Vec<Statement>) -> Vec<Statement> {
+    let mut i = 0;
+    while i < statements.len() {
+        let Statement::Action(action, _) = statements[i] else {
+            i += 1;
+            continue;
+        };
+        let Some(action_target) = target(&action) else {
+            i += 1;
+            continue;
+        };
+        let later = statements[i + 1..].iter().position(|statement| {
+            let effects = effects([statement]);
+            effects.reads.contains(&action_target) || effects.writes.contains(&action_target)
+        });
+        let earlier = statements[..i]
+            .iter()
+            .rposition(|statement| effects([statement]).writes.contains(&action_target));
+        match (later.map(|j| (i + 1 + j, &statements[i + 1 + j])), earlier) {
+            (Some((_, Statement::Action(next, _))), _)
+                if overwrites(next) && target(next) == Some(action_target) =>
+            {
+                statements.remove(i);
+            }
+            (Some((j, Statement::Action(next, _))), _)
+                if !overwrites(&action) && *next == action =>
+            {
+                statements.remove(j);
+                statements.remove(i);
+            }
+            (_, Some(j))
+                if overwrites(&action)
+                    && matches!(statements[j], Statement::Action(earlier, _) if earlier == action) =>
+            {
+                statements.remove(i);
+            }
+            _ => i += 1,
+        }
+    }
+    statements
+}
+
+/// Simplifies a condition: negations are pushed into tests and operators where that
+/// makes the condition smaller, and duplicate operands of AND and OR are removed
+fn simplify(condition: Expr) -> Expr {
+    match condition {
+        Expr::Test(_) => condition,
+        Expr::Not(expr) => negate(simplify(*expr)),
+        Expr::And(left, right) => {
+            let (left, right) = (simplify(*left), simplify(*right));
+            if left == right {
+                left
+            } else {
+                Expr::And(Box::new(left), Box::new(right))
+            }
+        }
+        Expr::Or(left, right) => {
+            let (left, right) = (simplify(*left), simplify(*right));
+            if left == right {
+                left
+            } else {
+                Expr::Or(Box::new(left), Box::new(right))
+            }
+        }
+        Expr::Xor(left, right) => {
+            // Negations of the operands cancel out, at most one is kept for the result
+            let (left, left_negated) = strip_not(simplify(*left));
+            let (right, right_negated) = strip_not(simplify(*right));
+            let xor = Expr::Xor(Box::new(left), Box::new(right));
+            if left_negated != right_negated {
+                negate(xor)
+            } else {
+                xor
+            }
+        }
+    }
+}
+
+fn strip_not(condition: Expr) -> (Expr, bool) {
+    match condition {
+        Expr::Not(expr) => (*expr, true),
+        condition => (condition, false),
+    }
+}
+
+/// The smallest condition that is the negation of a simplified condition
+fn negate(condition: Expr) -> Expr {
+    let candidates = match condition {
+        Expr::Test(Instruction::If {
+            number,
+            is_was,
+            value,
+            in_out,
+        }) => {
+            let value = match value {
+                Value::Low => Value::High,
+                Value::High => Value::Low,
+            };
+            return Expr::Test(Instruction::If {
+                number,
+                is_was,
+                value,
+                in_out,
+            });
+        }
+        Expr::Not(expr) => return *expr,
+        Expr::And(ref left, ref right) => vec![Expr::Or(
+            Box::new(negate(*left.clone())),
+            Box::new(negate(*right.clone())),
+        )],
+        Expr::Or(ref left, ref right) => vec![Expr::And(
+            Box::new(negate(*left.clone())),
+            Box::new(negate(*right.clone())),
+        )],
+        Expr::Xor(ref left, ref right) => vec![
+            Expr::Xor(Box::new(negate(*left.clone())), right.clone()),
+            Expr::Xor(left.clone(), Box::new(negate(*right.clone()))),
+        ],
+        Expr::Test(_) => vec![],
+    };
+    let not = Expr::Not(Box::new(condition));
+    candidates
+        .into_iter()
+        .filter(|candidate| size(candidate) < size(&not))
+        .min_by_key(size)
+        .unwrap_or(not)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit_statements, optimize, parse, Code, Expr, Statement};
+    use crate::shal::bytecode::Instruction::{End, If, On, OnToggle, Pop, Set, Toggle};
+    use crate::shal::bytecode::{InOut, Instruction, Program, TimerCheck, TimerDuration};
+    use crate::shal::common::{Edge, IsWas, Value};
+    use crate::shal::equivalence::compare;
+    use crate::shal::tests::compile_source;
+    use proptest::prelude::*;
+    use std::time::Duration;
+
+    /// Optimizes the program, and checks that it still behaves the same
+    fn optimize_source(source: &str) -> Program {
+        let program = compile_source(source);
+        let optimized = optimize(&program);
+        assert_eq!(compare(&program, &optimized).counterexamples, vec![]);
+        optimized
+    }
+
+    fn on(input: u8) -> Instruction {
+        On {
+            input: input.try_into().unwrap(),
+            edge: Edge::Rising,
+        }
+    }
+
+    fn check(number: u8, in_out: InOut, value: Value) -> Instruction {
+        If {
+            number: number.try_into().unwrap(),
+            is_was: IsWas::Is,
+            value,
+            in_out,
+        }
+    }
+
+    fn set(output: u8, value: Value) -> Instruction {
+        Set {
+            output: output.try_into().unwrap(),
+            value,
+        }
+    }
+
+    fn toggle(output: u8) -> Instruction {
+        Toggle {
+            output: output.try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_compiled_program() {
+        let program = compile_source(include_str!("../../static/standaertha.shal"));
+        let statements = parse(&program.instructions, &program.origins).unwrap();
+        let mut code = Code::default();
+        emit_statements(&statements, &mut code);
+        code.push(End, None);
+        assert_eq!(code.instructions, program.instructions);
+        // Every instruction but the final END keeps its location
+        let locations: Vec<_> = program.origins.iter().copied().map(Some).collect();
+        assert_eq!(code.locations[..locations.len()], locations);
+        assert_eq!(program.instructions.len(), locations.len() + 1);
+    }
+
+    #[test]
+    fn test_merge_events() {
+        let program = optimize_source(
+            "on redge input 0 set output 0 high;
+             on redge input 1 toggle output 1;
+             on redge input 0 set output 2 high;",
+        );
+        assert_eq!(
+            program.instructions,
+            vec![
+                on(0),
+                set(0, Value::High),
+                set(2, Value::High),
+                Pop,
+                OnToggle {
+                    input: 1.try_into().unwrap(),
+                    edge: Edge::Rising,
+                    output: 1.try_into().unwrap(),
+                },
+                End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_dependent_blocks() {
+        // The first block changes the condition of the second one, and the second block
+        // can't be moved past the toggle
+        for source in [
+            "if output 0 is low { set output 0 high; }
+             if output 0 is low { set output 1 high; }",
+            "on redge input 0 { set output 1 high; }
+             toggle output 1;
+             on redge input 0 { set output 1 low; }",
+        ] {
+            assert_eq!(optimize_source(source), compile_source(source));
+        }
+    }
+
+    #[test]
+    fn test_share_condition_prefix() {
+        let program = optimize_source(
+            "if input 0 is high and input 1 is high { set output 0 high; }
+             if input 0 is high and input 2 is high { set output 1 high; }
+             if input 0 is high { set output 2 high; }",
+        );
+        assert_eq!(
+            program.instructions,
+            vec![
+                check(0, InOut::Input, Value::High),
+                check(1, InOut::Input, Value::High),
+                set(0, Value::High),
+                Pop,
+                check(2, InOut::Input, Value::High),
+                set(1, Value::High),
+                Pop,
+                set(2, Value::High),
+                Pop,
+                End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_redundant_actions() {
+        let program = optimize_source(
+            "on redge input 0 {
+               toggle output 0;
+               set output 0 high;
+               toggle output 1;
+               toggle output 1;
+               set output 2 low;
+               if input 1 is high { set output 3 high; }
+               set output 2 low;
+             }",
+        );
+        assert_eq!(
+            program.instructions,
+            vec![
+                on(0),
+                set(0, Value::High),
+                check(1, InOut::Input, Value::High),
+                set(3, Value::High),
+                Pop,
+                set(2, Value::Low),
+                Pop,
+                End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simplify_conditions() {
+        let program = optimize_source(
+            "if not (output 0 is high and flag 1 is low) { set output 2 high; }
+             if input 0 is high {} else { set output 3 high; }",
+        );
+        assert_eq!(
+            program.instructions,
+            vec![
+                check(0, InOut::Output, Value::Low),
+                check(1, InOut::Flag, Value::High),
+                Instruction::Or,
+                set(2, Value::High),
+                Pop,
+                check(0, InOut::Input, Value::Low),
+                set(3, Value::High),
+                Pop,
+                End
+            ]
+        );
+        // The negation of a xor moves into one of its operands
+        let program = optimize_source(
+            "if not input 0 is high xor not input 1 is high { set output 0 high; }
+             if not (input 0 is high xor input 1 is high) { set output 1 high; }",
+        );
+        assert_eq!(
+            program.instructions,
+            vec![
+                check(0, InOut::Input, Value::Low),
+                check(1, InOut::Input, Value::Low),
+                Instruction::Xor,
+                set(0, Value::High),
+                Pop,
+                check(0, InOut::Input, Value::Low),
+                check(1, InOut::Input, Value::High),
+                Instruction::Xor,
+                set(1, Value::High),
+                Pop,
+                End
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_unstructured_program() {
+        let program = Program {
+            declarations: Default::default(),
+            instructions: vec![on(0), on(1), Instruction::And, toggle(0)],
+            origins: vec![],
+            files: vec![],
+            timer_sources: vec![],
+        };
+        assert_eq!(optimize(&program), program);
+    }
+
+    #[test]
+    fn test_compact_on_toggle() {
+        let program = optimize_source(
+            "if input 2 is high {
+               on fedge input 0 toggle output 0;
+             }
+             on redge input 1 {
+               toggle output 1;
+               toggle output 2;
+             }",
+        );
+        assert_eq!(
+            program.instructions,
+            vec![
+                check(2, InOut::Input, Value::High),
+                OnToggle {
+                    input: 0.try_into().unwrap(),
+                    edge: Edge::Falling,
+                    output: 0.try_into().unwrap(),
+                },
+                Pop,
+                on(1),
+                toggle(1),
+                toggle(2),
+                Pop,
+                End
+            ]
+        );
+        // Compacted programs can be optimized again
+        assert_eq!(optimize(&program), program);
+    }
+
+    #[test]
+    fn test_optimize_full_program() {
+        let program = compile_source(include_str!("../../static/standaertha.shal"));
+        let optimized = optimize(&program);
+        assert!(optimized.calc_length() <= program.calc_length());
+        assert_eq!(compare(&program, &optimized).counterexamples, vec![]);
+    }
+
+    fn arb_test() -> impl Strategy<Value = Instruction> {
+        let pin = || (0u8..2).prop_map(|pin| pin.try_into().unwrap());
+        prop_oneof![
+            (pin(), any::<bool>()).prop_map(|(input, rising)| On {
+                input,
+                edge: if rising { Edge::Rising } else { Edge::Falling },
+            }),
+            (pin(), any::<bool>(), any::<bool>(), 0usize..3).prop_map(
+                |(number, is, high, in_out)| If {
+                    number,
+                    is_was: if is { IsWas::Is } else { IsWas::Was },
+                    value: if high { Value::High } else { Value::Low },
+                    in_out: [InOut::Input, InOut::Output, InOut::Flag][in_out],
+                }
+            ),
+            any::<bool>().prop_map(|running| Instruction::IfTimer {
+                timer: 0.try_into().unwrap(),
+                check: if running {
+                    TimerCheck::Running
+                } else {
+                    TimerCheck::Elapsed
+                },
+            }),
+        ]
+    }
+
+    fn arb_action() -> impl Strategy<Value = Instruction> {
+        let pin = || (0u8..2).prop_map(|pin| pin.try_into().unwrap());
+        let value = || any::<bool>().prop_map(|high| if high { Value::High } else { Value::Low });
+        prop_oneof![
+            (pin(), value()).prop_map(|(output, value)| Set { output, value }),
+            pin().prop_map(|output| Toggle { output }),
+            (pin(), value()).prop_map(|(flag, value)| Instruction::SetFlag { flag, value }),
+            pin().prop_map(|flag| Instruction::ToggleFlag { flag }),
+            Just(Instruction::StartTimer {
+                timer: 0.try_into().unwrap(),
+                duration: TimerDuration::from_duration(Duration::from_secs(1)).unwrap(),
+            }),
+            Just(Instruction::CancelTimer {
+                timer: 0.try_into().unwrap(),
+            }),
+        ]
+    }
+
+    fn arb_condition() -> impl Strategy<Value = Expr> {
+        arb_test()
+            .prop_map(Expr::Test)
+            .prop_recursive(3, 8, 2, |inner| {
+                prop_oneof![
+                    inner.clone().prop_map(|expr| Expr::Not(Box::new(expr))),
+                    (inner.clone(), inner.clone())
+                        .prop_map(|(left, right)| Expr::And(Box::new(left), Box::new(right))),
+                    (inner.clone(), inner.clone())
+                        .prop_map(|(left, right)| Expr::Or(Box::new(left), Box::new(right))),
+                    (inner.clone(), inner)
+                        .prop_map(|(left, right)| Expr::Xor(Box::new(left), Box::new(right))),
+                ]
+            })
+    }
+
+    fn arb_statements() -> impl Strategy<Value = Vec<Statement>> {
+        let statement = arb_action()
+            .prop_map(|action| Statement::Action(action, None))
+            .prop_recursive(3, 24, 4, |inner| {
+                (
+                    arb_condition(),
+                    prop::collection::vec(inner.clone(), 0..4),
+                    prop::option::of(prop::collection::vec(inner, 0..4)),
+                )
+                    .prop_map(|(condition, body, else_body)| Statement::Block {
+                        condition,
+                        body,
+                        else_body,
+                        location: None,
+                    })
+            });
+        prop::collection::vec(statement, 0..8)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_optimize_equivalent(statements in arb_statements()) {
+            let mut code = Code::default();
+            emit_statements(&statements, &mut code);
+            code.push(End, None);
+            let program = Program {
+                declarations: Default::default(),
+                instructions: code.instructions,
+                origins: vec![],
+                files: vec![],
+                timer_sources: vec![],
+            };
+            let optimized = optimize(&program);
+            prop_assert!(optimized.calc_length() <= program.calc_length());
+            prop_assert_eq!(compare(&program, &optimized).counterexamples, vec![]);
+        }
+    }
+}
@@ -1,27 +1,45 @@
 use crate::shal::ast::{
-    Action, Condition, EntityID, IODeclarations, Input, InvalidEntityIDError, InvalidPinIDError,
-    Output, PinID, Program, Statement,
+    Action, Condition, EntityID, Gesture, IODeclarations, Input, InvalidEntityIDError,
+    InvalidPinIDError, Output, PinID, Program, Quantifier, SourceLoc, Statement, TimerStatus,
 };
-use crate::shal::common::{Edge, IsWas, Value};
+use crate::shal::common;
+use crate::shal::common::{DurationError, Edge, IsWas, Value};
 use crate::shal::parser::ParseError::{
-    DoubleInputPinError, DoubleOutputPinError, DuplicateEntityIDError,
+    DeclarationError, DeclarationFileError, DoubleFlagError, DoubleInputPinError,
+    DoubleOutputPinError, DuplicateEntityIDError, DuplicateParameterError, DuplicateRuleError,
+    EmptyGroupError, IncludeCycleError, IncludeError, IncludeNotSupportedError, IncludeReadError,
+    RuleArgumentCountError, RuleArgumentTypeError, UnknownDeclarationFormatError,
+    UnknownGroupOutputError, UnknownRuleError,
 };
 use pest::iterators::Pair;
+use pest::pratt_parser::{Assoc, Op, PrattParser};
 use pest::Parser;
 use pest_derive::Parser;
 use regex::RegexBuilder;
-use std::collections::HashSet;
+use schemars::{schema_for, Schema};
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Parser)]
 #[grammar = "shal/shal.pest"]
-struct ShalParser;
+pub(crate) struct ShalParser;
 
 #[derive(Error, Debug)]
 pub enum ParseError {
     #[error("Failed to parse entity declarations")]
     EntityParseError(#[from] deser_hjson::Error),
+    #[error("Invalid declarations at line {line}, col {col} ({path}): {message}")]
+    DeclarationError {
+        path: String,
+        line: usize,
+        col: usize,
+        message: String,
+    },
     #[error("Failed to parse program")]
     PestParseError(#[from] Box<pest::error::Error<Rule>>),
     #[error("Duplicate entity id: {id}, all entity ids must be unique")]
@@ -30,59 +48,804 @@ pub enum ParseError {
     DoubleInputPinError { pin: PinID },
     #[error("Double use of output pin {pin} for two different outputs")]
     DoubleOutputPinError { pin: PinID },
+    #[error("Double use of flag {pin} for two different flags")]
+    DoubleFlagError { pin: PinID },
+    #[error("Group {group} has no outputs")]
+    EmptyGroupError { group: EntityID },
+    #[error("Group {group} contains {output}, which is not a declared output")]
+    UnknownGroupOutputError { group: EntityID, output: EntityID },
     #[error("Invalid pin ID")]
     InvalidPinIDError(#[from] InvalidPinIDError),
+    #[error("Pin ID out of range: {pin}, ids must be in range [0, 32)")]
+    PinIDOutOfRangeError { pin: String },
     #[error("Invalid entity ID")]
     InvalidEntityIDError(#[from] InvalidEntityIDError),
+    #[error("Duration out of range: {duration}")]
+    DurationOutOfRangeError { duration: String },
+    #[error("Invalid duration: {duration}, expected e.g. 500ms, 10s, 2min or 1h")]
+    InvalidDurationError { duration: String },
+    #[error("Duplicate rule: {rule}, all rule names must be unique")]
+    DuplicateRuleError { rule: EntityID },
+    #[error("Duplicate parameter {parameter} of rule {rule}")]
+    DuplicateParameterError { rule: EntityID, parameter: EntityID },
+    #[error("Unknown rule: {rule} at {location}, rules have to be defined before they are called")]
+    UnknownRuleError { rule: EntityID, location: SourceLoc },
+    #[error("Rule {rule} takes {expected} arguments, but {got} were given at {location}")]
+    RuleArgumentCountError {
+        rule: EntityID,
+        expected: usize,
+        got: usize,
+        location: SourceLoc,
+    },
+    #[error("Invalid argument for {parameter} of rule {rule} at {location}, it is used as a different kind of entity")]
+    RuleArgumentTypeError {
+        rule: EntityID,
+        parameter: EntityID,
+        location: SourceLoc,
+    },
+    #[error("Includes are only supported when compiling a program file, at {location}")]
+    IncludeNotSupportedError { location: SourceLoc },
+    #[error("Include cycle: {} is included by itself at {location}", path.display())]
+    IncludeCycleError { path: PathBuf, location: SourceLoc },
+    #[error("Failed to read {} included at {location}", path.display())]
+    IncludeReadError {
+        path: PathBuf,
+        location: SourceLoc,
+        source: io::Error,
+    },
+    #[error("{source}, in {} included at {location}", path.display())]
+    IncludeError {
+        path: PathBuf,
+        location: SourceLoc,
+        source: Box<ParseError>,
+    },
+    #[error("Unknown declarations format: {format}, expected hjson, toml or yaml")]
+    UnknownDeclarationFormatError { format: String },
+    #[error("Failed to read file")]
+    IOError(#[from] io::Error),
+    #[error("{source}, in declarations file {}", path.display())]
+    DeclarationFileError {
+        path: PathBuf,
+        source: Box<ParseError>,
+    },
 }
 
+impl From<DurationError> for ParseError {
+    fn from(error: DurationError) -> Self {
+        match error {
+            DurationError::OutOfRange { duration } => {
+                ParseError::DurationOutOfRangeError { duration }
+            }
+            DurationError::Invalid { duration } => ParseError::InvalidDurationError { duration },
+        }
+    }
+}
+
+/// What an event block reacts to
+enum Trigger {
+    Edge(Edge),
+    Gesture(Gesture),
+}
+
+/// Parses a program that doesn't include other files
+#[cfg(test)]
 pub(crate) fn parse(input: &str) -> Result<Program, ParseError> {
-    let mut program = Program::default();
+    ProgramParser::new(None).parse(input)
+}
 
-    let separator = RegexBuilder::new(r"^---\s*$")
-        .multi_line(true)
-        .build()
-        .unwrap_or_else(|_| unreachable!());
-    let splits: Vec<&str> = separator.splitn(input, 2).collect();
-    if splits.len() == 2 {
-        let first_split = *splits.first().unwrap_or_else(|| unreachable!());
-        let declarations = deser_hjson::from_str(first_split)?;
-        validate_declarations(&declarations)?;
-        program.declarations = declarations;
+/// Parses the program at `path`, which can include other files.
+/// These are read with `read`, relative to the directory of the program.
+/// Declarations can also be loaded from a separate file, in any of the declaration formats.
+pub(crate) fn parse_file<F: Fn(&Path) -> io::Result<String>>(
+    path: &Path,
+    input: &str,
+    declarations: Option<&Path>,
+    read: F,
+) -> Result<Program, ParseError> {
+    let path = normalize(path);
+    let includes = Includes {
+        read: &read,
+        directory: path.parent().map(Path::to_owned).unwrap_or_default(),
+        stack: vec![path],
+        included: HashSet::new(),
+    };
+    let mut parser = ProgramParser::new(Some(includes));
+    if let Some(declarations) = declarations {
+        parser.load_declarations(declarations)?;
+    }
+    parser.parse(input)
+}
+
+struct Includes<'a> {
+    read: &'a dyn Fn(&Path) -> io::Result<String>,
+    directory: PathBuf,
+    /// The files that are being parsed, starting with the main program
+    stack: Vec<PathBuf>,
+    /// Files are only included once, so shared rules can be included by several files
+    included: HashSet<PathBuf>,
+}
+
+struct ProgramParser<'a> {
+    declarations: IODeclarations,
+    rules: Rules,
+    includes: Option<Includes<'a>>,
+}
+
+impl<'a> ProgramParser<'a> {
+    fn new(includes: Option<Includes<'a>>) -> Self {
+        ProgramParser {
+            declarations: IODeclarations::default(),
+            rules: Rules {
+                definitions: HashMap::new(),
+                line_offset: 0,
+                file: None,
+            },
+            includes,
+        }
+    }
+
+    fn parse(mut self, input: &str) -> Result<Program, ParseError> {
+        let (statements, source_locations) = self.parse_source(input)?;
+        validate_declarations(&self.declarations)?;
+        Ok(Program {
+            declarations: self.declarations,
+            statements,
+            source_locations,
+        })
+    }
+
+    fn parse_source(
+        &mut self,
+        input: &str,
+    ) -> Result<(Vec<Statement>, Vec<SourceLoc>), ParseError> {
+        let (header, body) = split_declarations(input)?;
+        if let Some((header, format)) = header {
+            let declarations = parse_declarations(header, format)?;
+            let main = self.includes.as_ref().is_none_or(|i| i.stack.len() == 1);
+            self.merge_declarations(declarations, main)?;
+        }
+
+        let pest_program = ShalParser::parse(Rule::program, body)
+            .map_err(Box::new)?
+            .next();
+
+        let pest_program = pest_program.unwrap_or_else(|| unreachable!());
+
+        // The program is a suffix of the input, locations are reported in lines of the input
+        let header = &input[..input.len() - body.len()];
+        self.rules.line_offset = header.matches('\n').count();
+        let mut statements = vec![];
+        let mut source_locations = vec![];
+        for pair in pest_program.into_inner() {
+            let (line, col) = pair.line_col();
+            let location = SourceLoc(line + self.rules.line_offset, col);
+            let statement = match pair.as_rule() {
+                Rule::include => self.handle_include(pair)?,
+                Rule::rule_definition => {
+                    self.rules.handle_rule_definition(pair)?;
+                    None
+                }
+                Rule::top_level_statement => {
+                    Some(match self.rules.handle_top_level_statement(pair)? {
+                        Template::Statement(statement) => statement,
+                        Template::Call(call) => self.rules.expand(call)?,
+                    })
+                }
+                _ => None,
+            };
+            if let Some(statement) = statement {
+                statements.push(statement);
+                source_locations.push(location);
+            }
+        }
+        Ok((statements, source_locations))
+    }
+
+    /// Settings are only taken from the main program
+    fn merge_declarations(
+        &mut self,
+        declarations: IODeclarations,
+        main: bool,
+    ) -> Result<(), ParseError> {
+        let IODeclarations {
+            inputs,
+            outputs,
+            flags,
+            groups,
+            settings,
+        } = declarations;
+        if main {
+            self.declarations.settings = settings;
+        }
+        merge_map(&mut self.declarations.inputs, inputs)?;
+        merge_map(&mut self.declarations.outputs, outputs)?;
+        merge_map(&mut self.declarations.flags, flags)?;
+        merge_map(&mut self.declarations.groups, groups)
+    }
+
+    /// Loads a file with only declarations, e.g. the pin map of the installation
+    fn load_declarations(&mut self, path: &Path) -> Result<(), ParseError> {
+        let includes = self.includes.as_mut().unwrap_or_else(|| unreachable!());
+        let path = normalize(path);
+        let load = |includes: &mut Includes| {
+            let format = DeclarationFormat::from_path(&path).ok_or_else(|| {
+                UnknownDeclarationFormatError {
+                    format: path
+                        .extension()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                }
+            })?;
+            includes.included.insert(path.clone());
+            parse_declarations(&(includes.read)(&path)?, format)
+        };
+        let declarations =
+            load(includes).and_then(|declarations| self.merge_declarations(declarations, false));
+        declarations.map_err(|source| DeclarationFileError {
+            path,
+            source: Box::new(source),
+        })
+    }
+
+    fn handle_include(&mut self, pair: Pair<Rule>) -> Result<Option<Statement>, ParseError> {
+        let (line, col) = pair.line_col();
+        let location = SourceLoc(line + self.rules.line_offset, col);
+        let Some(includes) = &mut self.includes else {
+            return Err(IncludeNotSupportedError { location });
+        };
+        let string = pair
+            .into_inner()
+            .next()
+            .unwrap()
+            .into_inner()
+            .next()
+            .unwrap();
+        let path = normalize(&includes.directory.join(string.as_str()));
+        if includes.stack.contains(&path) {
+            return Err(IncludeCycleError { path, location });
+        }
+        if !includes.included.insert(path.clone()) {
+            return Ok(None);
+        }
+        let input = (includes.read)(&path).map_err(|source| IncludeReadError {
+            path: path.clone(),
+            location,
+            source,
+        })?;
+        let wrap = |source| IncludeError {
+            path: path.clone(),
+            location,
+            source: Box::new(source),
+        };
+
+        if let Some(format) = DeclarationFormat::from_path(&path) {
+            let declarations = parse_declarations(&input, format).map_err(wrap)?;
+            self.merge_declarations(declarations, false).map_err(wrap)?;
+            return Ok(None);
+        }
+
+        includes.stack.push(path.clone());
+        let line_offset = self.rules.line_offset;
+        let file = self.rules.file.replace(path.clone());
+        let result = self.parse_source(&input);
+        self.rules.line_offset = line_offset;
+        self.rules.file = file;
+        if let Some(includes) = &mut self.includes {
+            includes.stack.pop();
+        }
+
+        let (statements, _) = result.map_err(wrap)?;
+        Ok(Some(Statement::Include {
+            path,
+            location,
+            statements,
+        }))
+    }
+}
+
+fn merge_map<V>(
+    map: &mut HashMap<EntityID, V>,
+    other: HashMap<EntityID, V>,
+) -> Result<(), ParseError> {
+    for (id, value) in other {
+        if map.contains_key(&id) {
+            return Err(DuplicateEntityIDError { id });
+        }
+        map.insert(id, value);
+    }
+    Ok(())
+}
+
+/// Resolves `.` and `..` without accessing the file system, so a file is always known by the same path
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if matches!(result.components().next_back(), Some(Component::Normal(_))) {
+                    result.pop();
+                } else {
+                    result.push(component);
+                }
+            }
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
+struct RuleDefinition {
+    parameters: Vec<EntityID>,
+    body: Vec<Template>,
+    /// The included file the rule is defined in, none for the main program
+    file: Option<PathBuf>,
+}
+
+/// A statement in the body of a rule, calls are only expanded when the rule itself is,
+/// so parameters of the caller are not substituted in the rule that is called
+enum Template {
+    Statement(Statement),
+    Call(RuleCall),
+}
+
+struct RuleCall {
+    rule: EntityID,
+    location: SourceLoc,
+    arguments: Vec<Argument>,
+}
+
+/// The rules defined so far
+struct Rules {
+    definitions: HashMap<EntityID, RuleDefinition>,
+    /// Lines above the program in the file that is being parsed
+    line_offset: usize,
+    /// The included file that is being parsed, none for the main program
+    file: Option<PathBuf>,
+}
+
+impl Rules {
+    fn handle_rule_definition(&mut self, pair: Pair<Rule>) -> Result<(), ParseError> {
+        let mut pairs = pair.into_inner();
+        let rule = handle_entity_id(pairs.next().unwrap())?;
+        if self.definitions.contains_key(&rule) {
+            return Err(DuplicateRuleError { rule });
+        }
+        let parameters = pairs
+            .next()
+            .unwrap()
+            .into_inner()
+            .map(handle_entity_id)
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(parameter) = find_double(parameters.iter()) {
+            return Err(DuplicateParameterError {
+                parameter: parameter.clone(),
+                rule,
+            });
+        }
+        let body = pairs
+            .map(|pair| self.handle_top_level_statement(pair))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.definitions.insert(
+            rule,
+            RuleDefinition {
+                parameters,
+                body,
+                file: self.file.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_top_level_statement(&self, pair: Pair<Rule>) -> Result<Template, ParseError> {
+        let statement = pair.clone().into_inner().next().unwrap();
+        if statement.as_rule() == Rule::rule_call {
+            return Ok(Template::Call(self.handle_rule_call(statement)?));
+        }
+        Ok(Template::Statement(handle_statement(
+            pair,
+            self.line_offset,
+        )?))
+    }
+
+    /// Rules have to be defined before they are called, which also rules out recursion
+    fn handle_rule_call(&self, pair: Pair<Rule>) -> Result<RuleCall, ParseError> {
+        let (line, col) = pair.line_col();
+        let location = SourceLoc(line + self.line_offset, col);
+        let mut pairs = pair.into_inner();
+        let rule = handle_entity_id(pairs.next().unwrap())?;
+        let Some(definition) = self.definitions.get(&rule) else {
+            return Err(UnknownRuleError { rule, location });
+        };
+        let arguments = pairs.map(handle_argument).collect::<Result<Vec<_>, _>>()?;
+        if arguments.len() != definition.parameters.len() {
+            return Err(RuleArgumentCountError {
+                expected: definition.parameters.len(),
+                got: arguments.len(),
+                rule,
+                location,
+            });
+        }
+        Ok(RuleCall {
+            rule,
+            location,
+            arguments,
+        })
+    }
+
+    fn expand(&self, call: RuleCall) -> Result<Statement, ParseError> {
+        let definition = &self.definitions[&call.rule];
+        let expansion = Expansion {
+            arguments: definition.parameters.iter().zip(call.arguments).collect(),
+            rule: &call.rule,
+            location: call.location,
+        };
+        let mut statements = vec![];
+        for template in definition.body.iter() {
+            statements.push(match template {
+                Template::Statement(statement) => expansion.statement(statement)?,
+                Template::Call(inner) => self.expand(RuleCall {
+                    rule: inner.rule.clone(),
+                    location: inner.location,
+                    arguments: inner
+                        .arguments
+                        .iter()
+                        .map(|argument| expansion.argument(argument))
+                        .collect(),
+                })?,
+            });
+        }
+        Ok(Statement::Call {
+            rule: call.rule,
+            location: call.location,
+            file: definition.file.clone(),
+            statements,
+        })
     }
+}
+
+/// What a rule is called with, pins can only be used in place of an input, output or flag
+#[derive(Clone)]
+enum Argument {
+    Entity(EntityID),
+    Input(PinID),
+    Output(PinID),
+    Flag(PinID),
+}
+
+fn handle_argument(pair: Pair<Rule>) -> Result<Argument, ParseError> {
+    let argument = pair.into_inner().next().unwrap();
+    Ok(match argument.as_rule() {
+        Rule::input => Argument::Input(handle_input(argument)?),
+        Rule::output => Argument::Output(handle_output(argument)?),
+        Rule::flag => Argument::Flag(handle_flag(argument)?),
+        Rule::entity_id => Argument::Entity(handle_entity_id(argument)?),
+        _ => unreachable!(),
+    })
+}
+
+/// Substitutes the parameters of a rule in its statements
+struct Expansion<'a> {
+    arguments: HashMap<&'a EntityID, Argument>,
+    rule: &'a EntityID,
+    location: SourceLoc,
+}
+
+impl Expansion<'_> {
+    fn type_error(&self, parameter: &EntityID) -> ParseError {
+        RuleArgumentTypeError {
+            rule: self.rule.clone(),
+            parameter: parameter.clone(),
+            location: self.location,
+        }
+    }
+
+    /// Substitutes the argument of a call in the body of the rule
+    fn argument(&self, argument: &Argument) -> Argument {
+        match argument {
+            Argument::Entity(id) => self.arguments.get(id).unwrap_or(argument).clone(),
+            _ => argument.clone(),
+        }
+    }
+
+    /// Substitutes a group or timer name
+    fn entity(&self, id: &EntityID) -> Result<EntityID, ParseError> {
+        match self.arguments.get(id) {
+            None => Ok(id.clone()),
+            Some(Argument::Entity(entity)) => Ok(entity.clone()),
+            Some(_) => Err(self.type_error(id)),
+        }
+    }
+
+    fn input(&self, input: &Input) -> Result<Input, ParseError> {
+        let Input::Entity(id) = input else {
+            return Ok(input.clone());
+        };
+        match self.arguments.get(id) {
+            None => Ok(input.clone()),
+            Some(Argument::Entity(entity)) => Ok(Input::Entity(entity.clone())),
+            Some(Argument::Input(pin)) => Ok(Input::Number(*pin)),
+            Some(_) => Err(self.type_error(id)),
+        }
+    }
+
+    fn output(&self, output: &Output) -> Result<Output, ParseError> {
+        let Output::Entity(id) = output else {
+            return Ok(output.clone());
+        };
+        match self.arguments.get(id) {
+            None => Ok(output.clone()),
+            Some(Argument::Entity(entity)) => Ok(Output::Entity(entity.clone())),
+            Some(Argument::Output(pin)) => Ok(Output::Number(*pin)),
+            Some(Argument::Flag(pin)) => Ok(Output::Flag(*pin)),
+            Some(Argument::Input(_)) => Err(self.type_error(id)),
+        }
+    }
+
+    fn condition(&self, condition: &Condition) -> Result<Condition, ParseError> {
+        let boxed = |condition: &Condition| self.condition(condition).map(Box::new);
+        Ok(match condition {
+            Condition::And(l, r) => Condition::And(boxed(l)?, boxed(r)?),
+            Condition::Or(l, r) => Condition::Or(boxed(l)?, boxed(r)?),
+            Condition::Xor(l, r) => Condition::Xor(boxed(l)?, boxed(r)?),
+            Condition::Not(condition) => Condition::Not(boxed(condition)?),
+            Condition::Input(input, tspec, value) => {
+                Condition::Input(self.input(input)?, *tspec, *value)
+            }
+            Condition::Output(output, tspec, value) => {
+                Condition::Output(self.output(output)?, *tspec, *value)
+            }
+            Condition::Entity(id, tspec, value) => match self.arguments.get(id) {
+                None => condition.clone(),
+                Some(Argument::Entity(entity)) => Condition::Entity(entity.clone(), *tspec, *value),
+                Some(Argument::Input(pin)) => Condition::Input(Input::Number(*pin), *tspec, *value),
+                Some(Argument::Output(pin)) => {
+                    Condition::Output(Output::Number(*pin), *tspec, *value)
+                }
+                Some(Argument::Flag(pin)) => Condition::Output(Output::Flag(*pin), *tspec, *value),
+            },
+            Condition::Group(quantifier, group, tspec, value) => {
+                Condition::Group(*quantifier, self.entity(group)?, *tspec, *value)
+            }
+            Condition::Timer(timer, status) => Condition::Timer(self.entity(timer)?, *status),
+            Condition::Constant(_) => condition.clone(),
+        })
+    }
+
+    fn action(&self, action: &Action) -> Result<Action, ParseError> {
+        Ok(match action {
+            Action::Toggle(output) => Action::Toggle(self.output(output)?),
+            Action::Set(output, value) => Action::Set(self.output(output)?, *value),
+            Action::ToggleGroup(group) => Action::ToggleGroup(self.entity(group)?),
+            Action::SetGroup(group, value) => Action::SetGroup(self.entity(group)?, *value),
+            Action::Cancel(timer) => Action::Cancel(self.entity(timer)?),
+        })
+    }
+
+    fn statements(&self, statements: &[Statement]) -> Result<Vec<Statement>, ParseError> {
+        statements
+            .iter()
+            .map(|statement| self.statement(statement))
+            .collect()
+    }
+
+    fn statement(&self, statement: &Statement) -> Result<Statement, ParseError> {
+        Ok(match statement {
+            Statement::Action(action, location) => {
+                Statement::Action(self.action(action)?, *location)
+            }
+            Statement::IfElse(condition, if_block, else_block, location) => Statement::IfElse(
+                self.condition(condition)?,
+                self.statements(if_block)?,
+                self.statements(else_block)?,
+                *location,
+            ),
+            Statement::Event {
+                edge,
+                input,
+                statements,
+                location,
+            } => Statement::Event {
+                edge: *edge,
+                input: self.input(input)?,
+                statements: self.statements(statements)?,
+                location: *location,
+            },
+            Statement::Gesture {
+                gesture,
+                input,
+                statements,
+                location,
+            } => Statement::Gesture {
+                gesture: *gesture,
+                input: self.input(input)?,
+                statements: self.statements(statements)?,
+                location: *location,
+            },
+            Statement::After {
+                duration,
+                timer,
+                statements,
+                location,
+            } => Statement::After {
+                duration: *duration,
+                timer: timer.as_ref().map(|timer| self.entity(timer)).transpose()?,
+                statements: self.statements(statements)?,
+                location: *location,
+            },
+            Statement::Call { .. } => unreachable!("calls in rules are kept as templates"),
+            Statement::Include { .. } => unreachable!("rules can't include files"),
+        })
+    }
+}
 
-    if splits.is_empty() {
-        return Ok(program);
+/// The formats declarations can be written in, they all result in the same declarations
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum DeclarationFormat {
+    Hjson,
+    Toml,
+    Yaml,
+}
+
+impl DeclarationFormat {
+    /// The name after the `---` separator, HJSON if there is none
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "hjson" | "json" => Some(DeclarationFormat::Hjson),
+            "toml" => Some(DeclarationFormat::Toml),
+            "yaml" | "yml" => Some(DeclarationFormat::Yaml),
+            _ => None,
+        }
     }
 
-    let last_split = *splits.last().unwrap_or_else(|| unreachable!());
-    let pest_program = ShalParser::parse(Rule::program, last_split)
-        .map_err(Box::new)?
-        .next();
+    /// Files with another extension are programs
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?;
+        if extension.is_empty() {
+            return None;
+        }
+        Self::from_name(extension)
+    }
+}
 
-    let pest_program = pest_program.unwrap_or_else(|| unreachable!());
+/// The declarations above the `---` separator, in the format named by the separator
+pub(crate) type Header<'a> = (&'a str, DeclarationFormat);
+
+/// Splits the input in the declarations header, if there is one, and the program
+pub(crate) fn split_declarations(input: &str) -> Result<(Option<Header<'_>>, &str), ParseError> {
+    // The separator can name the format of the header, e.g. `--- yaml`
+    let separator = RegexBuilder::new(r"^---[ \t]*([A-Za-z]*)\s*$")
+        .multi_line(true)
+        .build()
+        .unwrap_or_else(|_| unreachable!());
+    let Some(captures) = separator.captures(input) else {
+        return Ok((None, input));
+    };
+    let separator = captures.get(0).unwrap_or_else(|| unreachable!());
+    let format = &captures[1];
+    let format =
+        DeclarationFormat::from_name(format).ok_or_else(|| UnknownDeclarationFormatError {
+            format: format.to_owned(),
+        })?;
+    Ok((
+        Some((&input[..separator.start()], format)),
+        &input[separator.end()..],
+    ))
+}
 
-    for pair in pest_program.into_inner() {
-        if Rule::top_level_statement == pair.as_rule() {
-            program.statements.push(handle_statement(pair)?);
+/// Reports where in the header deserialization failed, with a path like `inputs.button.pin`
+pub(crate) fn parse_declarations(
+    header: &str,
+    format: DeclarationFormat,
+) -> Result<IODeclarations, ParseError> {
+    match format {
+        DeclarationFormat::Hjson => parse_hjson_declarations(header),
+        DeclarationFormat::Toml => {
+            let error = |path: String, error: toml::de::Error| {
+                let start = error.span().map_or(0, |span| span.start);
+                let line_start = header[..start].rfind('\n').map_or(0, |i| i + 1);
+                DeclarationError {
+                    path,
+                    line: header[..start].matches('\n').count() + 1,
+                    col: header[line_start..start].chars().count() + 1,
+                    message: error.message().to_owned(),
+                }
+            };
+            let deserializer =
+                toml::Deserializer::parse(header).map_err(|e| error(".".to_owned(), e))?;
+            serde_path_to_error::deserialize(deserializer)
+                .map_err(|e| error(e.path().to_string(), e.into_inner()))
+        }
+        DeclarationFormat::Yaml => {
+            let deserializer = serde_yaml_ng::Deserializer::from_str(header);
+            serde_path_to_error::deserialize(deserializer).map_err(|e| {
+                let path = e.path().to_string();
+                let error = e.into_inner();
+                let (line, col) = error
+                    .location()
+                    .map_or((0, 0), |location| (location.line(), location.column()));
+                DeclarationError {
+                    path,
+                    line,
+                    col,
+                    message: error.to_string(),
+                }
+            })
         }
     }
+}
+
+fn parse_hjson_declarations(header: &str) -> Result<IODeclarations, ParseError> {
+    let error = match deser_hjson::from_str(header) {
+        Ok(declarations) => return Ok(declarations),
+        Err(error) => error,
+    };
+    // HJSON errors only have a location, deserializing again from a generic value gives the path,
+    // except for errors that get lost in the value, like duplicate keys
+    let path = deser_hjson::from_str::<serde_json::Value>(header)
+        .ok()
+        .and_then(|value| serde_path_to_error::deserialize::<_, IODeclarations>(value).err())
+        .map_or_else(|| ".".to_owned(), |error| error.path().to_string());
+    Err(match error {
+        deser_hjson::Error::Syntax {
+            line, col, code, ..
+        } => DeclarationError {
+            path,
+            line,
+            col,
+            message: format!("{code:?}"),
+        },
+        deser_hjson::Error::Serde { line, col, message } => DeclarationError {
+            path,
+            line,
+            col,
+            message,
+        },
+        error => error.into(),
+    })
+}
 
-    Ok(program)
+/// The JSON schema of the declarations header
+pub fn declarations_schema() -> Schema {
+    schema_for!(IODeclarations)
 }
 
 fn validate_declarations(declarations: &IODeclarations) -> Result<(), ParseError> {
     let inputs = &declarations.inputs;
     let outputs = &declarations.outputs;
-    if let Some(id) = find_double(inputs.keys().chain(outputs.keys())) {
+    let flags = &declarations.flags;
+    let groups = &declarations.groups;
+    let ids = inputs.keys().chain(outputs.keys()).chain(flags.keys());
+    if let Some(id) = find_double(ids.chain(groups.keys())) {
         return Err(DuplicateEntityIDError { id: id.clone() });
     }
+    for (group, declaration) in groups {
+        if declaration.outputs.is_empty() {
+            return Err(EmptyGroupError {
+                group: group.clone(),
+            });
+        }
+        if let Some(output) = declaration
+            .outputs
+            .iter()
+            .find(|output| !outputs.contains_key(output))
+        {
+            return Err(UnknownGroupOutputError {
+                group: group.clone(),
+                output: output.clone(),
+            });
+        }
+    }
     if let Some(pin) = find_double(inputs.values().map(|d| d.pin)) {
         return Err(DoubleInputPinError { pin });
     }
     if let Some(pin) = find_double(outputs.values().map(|d| d.pin)) {
         return Err(DoubleOutputPinError { pin });
     }
+    if let Some(pin) = find_double(flags.values().map(|d| d.pin)) {
+        return Err(DoubleFlagError { pin });
+    }
     Ok(())
 }
 
@@ -97,33 +860,47 @@ fn find_double<Item: Eq + Hash, I: Iterator<Item = Item>>(iterator: I) -> Option
     None
 }
 
-fn handle_statement(pair: Pair<Rule>) -> Result<Statement, ParseError> {
+/// The location of a pair, in lines of the file it is in
+fn location(pair: &Pair<Rule>, line_offset: usize) -> SourceLoc {
+    let (line, col) = pair.line_col();
+    SourceLoc(line + line_offset, col)
+}
+
+fn handle_statement(pair: Pair<Rule>, line_offset: usize) -> Result<Statement, ParseError> {
     let statement = pair.into_inner().next().unwrap();
     Ok(match statement.as_rule() {
-        Rule::action => handle_action(statement)?,
-        Rule::condition_block => handle_condition_block(statement)?,
-        Rule::event_block => handle_event_block(statement)?,
+        Rule::action => handle_action(statement, line_offset)?,
+        Rule::condition_block => handle_condition_block(statement, line_offset)?,
+        Rule::event_block => handle_event_block(statement, line_offset)?,
+        Rule::after_block => handle_after_block(statement, line_offset)?,
         _ => {
             unimplemented!()
         }
     })
 }
 
-fn handle_action(pair: Pair<Rule>) -> Result<Statement, ParseError> {
+fn handle_action(pair: Pair<Rule>, line_offset: usize) -> Result<Statement, ParseError> {
+    let location = location(&pair, line_offset);
     let action = pair.into_inner().next().unwrap();
-    Ok(Statement::Action(match action.as_rule() {
-        Rule::toggle_action => handle_toggle_action(action)?,
-        Rule::set_action => handle_set_action(action)?,
-        _ => {
-            unimplemented!()
-        }
-    }))
+    Ok(Statement::Action(
+        match action.as_rule() {
+            Rule::toggle_action => handle_toggle_action(action)?,
+            Rule::set_action => handle_set_action(action)?,
+            Rule::cancel_action => handle_cancel_action(action)?,
+            _ => {
+                unimplemented!()
+            }
+        },
+        location,
+    ))
 }
 
 fn handle_toggle_action(pair: Pair<Rule>) -> Result<Action, ParseError> {
-    Ok(Action::Toggle(handle_output_or_entity_id(
-        pair.into_inner().next().unwrap(),
-    )?))
+    let target = pair.into_inner().next().unwrap();
+    if target.as_rule() == Rule::group {
+        return Ok(Action::ToggleGroup(handle_group(target)?));
+    }
+    Ok(Action::Toggle(handle_output_or_entity_id(target)?))
 }
 
 fn handle_input_or_entity_id(pair: Pair<Rule>) -> Result<Input, ParseError> {
@@ -139,6 +916,7 @@ fn handle_input_or_entity_id(pair: Pair<Rule>) -> Result<Input, ParseError> {
 fn handle_output_or_entity_id(pair: Pair<Rule>) -> Result<Output, ParseError> {
     Ok(match pair.as_rule() {
         Rule::output => Output::Number(handle_output(pair)?),
+        Rule::flag => Output::Flag(handle_flag(pair)?),
         Rule::entity_id => Output::Entity(handle_entity_id(pair)?),
         _ => {
             unimplemented!()
@@ -154,9 +932,23 @@ fn handle_output(pair: Pair<Rule>) -> Result<PinID, ParseError> {
     handle_number(pair.into_inner().next().unwrap())
 }
 
+fn handle_flag(pair: Pair<Rule>) -> Result<PinID, ParseError> {
+    handle_number(pair.into_inner().next().unwrap())
+}
+
+fn handle_group(pair: Pair<Rule>) -> Result<EntityID, ParseError> {
+    handle_entity_id(pair.into_inner().next().unwrap())
+}
+
 fn handle_number(pair: Pair<Rule>) -> Result<PinID, ParseError> {
     if pair.as_rule() == Rule::pin_id {
-        Ok(pair.as_str().parse::<u8>().unwrap().try_into()?)
+        let pin = pair.as_str();
+        let id = pin
+            .parse::<u8>()
+            .map_err(|_| ParseError::PinIDOutOfRangeError {
+                pin: pin.to_owned(),
+            })?;
+        Ok(id.try_into()?)
     } else {
         unimplemented!()
     }
@@ -176,40 +968,62 @@ fn handle_value(pair: Pair<Rule>) -> Value {
 
 fn handle_set_action(pair: Pair<Rule>) -> Result<Action, ParseError> {
     let mut pairs = pair.into_inner();
-    let output = handle_output_or_entity_id(pairs.next().unwrap())?;
+    let target = pairs.next().unwrap();
     let value = handle_value(pairs.next().unwrap());
-    Ok(Action::Set(output, value))
+    if target.as_rule() == Rule::group {
+        return Ok(Action::SetGroup(handle_group(target)?, value));
+    }
+    Ok(Action::Set(handle_output_or_entity_id(target)?, value))
 }
 
-fn handle_condition_block(pair: Pair<Rule>) -> Result<Statement, ParseError> {
+fn handle_cancel_action(pair: Pair<Rule>) -> Result<Action, ParseError> {
+    Ok(Action::Cancel(handle_entity_id(
+        pair.into_inner().next().unwrap(),
+    )?))
+}
+
+fn handle_condition_block(pair: Pair<Rule>, line_offset: usize) -> Result<Statement, ParseError> {
     let mut pairs = pair.into_inner();
-    let (condition, if_statements) = handle_if_block(pairs.next().unwrap())?;
+    let (condition, if_statements, location) = handle_if_block(pairs.next().unwrap(), line_offset)?;
     let else_statements = if let Some(else_block) = pairs.next() {
-        handle_else_block(else_block)?
+        handle_else_block(else_block, line_offset)?
     } else {
         vec![]
     };
-    Ok(Statement::IfElse(condition, if_statements, else_statements))
+    Ok(Statement::IfElse(
+        condition,
+        if_statements,
+        else_statements,
+        location,
+    ))
 }
 
-fn handle_if_block(pair: Pair<Rule>) -> Result<(Condition, Vec<Statement>), ParseError> {
+/// The condition, the statements and the location of the condition
+fn handle_if_block(
+    pair: Pair<Rule>,
+    line_offset: usize,
+) -> Result<(Condition, Vec<Statement>, SourceLoc), ParseError> {
     let mut pairs = pair.into_inner();
-    let condition = handle_condition(pairs.next().unwrap())?;
-    let statements: Result<Vec<_>, _> = pairs.map(|pair| handle_statement(pair)).collect();
-    Ok((condition, statements?))
+    let condition = pairs.next().unwrap();
+    let location = location(&condition, line_offset);
+    let condition = handle_condition(condition)?;
+    let statements: Result<Vec<_>, _> = pairs
+        .map(|pair| handle_statement(pair, line_offset))
+        .collect();
+    Ok((condition, statements?, location))
 }
 
-fn handle_else_block(pair: Pair<Rule>) -> Result<Vec<Statement>, ParseError> {
+fn handle_else_block(pair: Pair<Rule>, line_offset: usize) -> Result<Vec<Statement>, ParseError> {
     let mut pairs = pair.into_inner();
     if let Some(next) = pairs.next() {
         Ok(match next.as_rule() {
             Rule::condition_block => {
-                vec![handle_condition_block(next)?]
+                vec![handle_condition_block(next, line_offset)?]
             }
             Rule::statement => {
-                let mut result = vec![handle_statement(next)?];
+                let mut result = vec![handle_statement(next, line_offset)?];
                 for statement in pairs {
-                    result.push(handle_statement(statement)?);
+                    result.push(handle_statement(statement, line_offset)?);
                 }
                 result
             }
@@ -220,20 +1034,27 @@ fn handle_else_block(pair: Pair<Rule>) -> Result<Vec<Statement>, ParseError> {
     }
 }
 
+/// Operators from lowest to highest precedence, `not` binds tighter than all of them
+static PRATT_PARSER: LazyLock<PrattParser<Rule>> = LazyLock::new(|| {
+    PrattParser::new()
+        .op(Op::infix(Rule::op_or, Assoc::Left))
+        .op(Op::infix(Rule::op_xor, Assoc::Left))
+        .op(Op::infix(Rule::op_and, Assoc::Left))
+});
+
 fn handle_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
-    let mut pairs = pair.into_inner();
-    let lcondition = handle_lcondition(pairs.next().unwrap())?;
-    if let Some(boolean_operator) = pairs.next() {
-        let rcondition = handle_condition(pairs.next().unwrap())?;
-        Ok(match boolean_operator.as_str() {
-            "and" => Condition::And(Box::new(lcondition), Box::new(rcondition)),
-            "or" => Condition::Or(Box::new(lcondition), Box::new(rcondition)),
-            "xor" => Condition::Xor(Box::new(lcondition), Box::new(rcondition)),
-            _ => unimplemented!(),
+    PRATT_PARSER
+        .map_primary(handle_lcondition)
+        .map_infix(|lcondition, operator, rcondition| {
+            let (l, r) = (Box::new(lcondition?), Box::new(rcondition?));
+            Ok(match operator.as_rule() {
+                Rule::op_and => Condition::And(l, r),
+                Rule::op_or => Condition::Or(l, r),
+                Rule::op_xor => Condition::Xor(l, r),
+                _ => unreachable!(),
+            })
         })
-    } else {
-        Ok(lcondition)
-    }
+        .parse(pair.into_inner())
 }
 
 fn handle_lcondition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
@@ -242,8 +1063,12 @@ fn handle_lcondition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
         Rule::condition => handle_condition(condition)?,
         Rule::input_condition => handle_input_condition(condition)?,
         Rule::output_condition => handle_output_condition(condition)?,
+        Rule::flag_condition => handle_flag_condition(condition)?,
+        Rule::group_condition => handle_group_condition(condition)?,
         Rule::not_condition => handle_not_condition(condition)?,
         Rule::entity_condition => handle_entity_condition(condition)?,
+        Rule::timer_condition => handle_timer_condition(condition)?,
+        Rule::constant_condition => Condition::Constant(condition.as_str() == "true"),
         _ => unimplemented!(),
     })
 }
@@ -272,6 +1097,27 @@ fn handle_output_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
     Ok(Condition::Output(output, tspec, value))
 }
 
+fn handle_flag_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
+    let mut pairs = pair.into_inner();
+    let flag = Output::Flag(handle_flag(pairs.next().unwrap())?);
+    let tspec = handle_tspec(pairs.next().unwrap());
+    let value = handle_value(pairs.next().unwrap());
+    Ok(Condition::Output(flag, tspec, value))
+}
+
+fn handle_group_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
+    let mut pairs = pair.into_inner();
+    let quantifier = match pairs.next().unwrap().as_str() {
+        "any" => Quantifier::Any,
+        "all" => Quantifier::All,
+        _ => unreachable!(),
+    };
+    let group = handle_group(pairs.next().unwrap())?;
+    let tspec = handle_tspec(pairs.next().unwrap());
+    let value = handle_value(pairs.next().unwrap());
+    Ok(Condition::Group(quantifier, group, tspec, value))
+}
+
 fn handle_not_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
     Ok(Condition::Not(Box::new(handle_lcondition(
         pair.into_inner().next().unwrap(),
@@ -286,50 +1132,63 @@ fn handle_entity_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
     Ok(Condition::Entity(entity, tspec, value))
 }
 
-fn handle_event_block(pair: Pair<Rule>) -> Result<Statement, ParseError> {
+fn handle_timer_condition(pair: Pair<Rule>) -> Result<Condition, ParseError> {
     let mut pairs = pair.into_inner();
-    let (edge, input) = handle_event(pairs.next().unwrap())?;
-    let next = pairs.next();
-    if let Some(next) = next {
-        Ok(match next.as_rule() {
-            Rule::action => Statement::Event {
-                edge,
-                input,
-                statements: vec![handle_action(next)?],
-            },
-            Rule::statement => {
-                let mut statements = vec![];
-                statements.push(handle_statement(next)?);
-                for statement in pairs {
-                    match statement.as_rule() {
-                        Rule::statement => {
-                            statements.push(handle_statement(statement)?);
-                        }
-                        _ => unimplemented!(),
-                    }
-                }
-                Statement::Event {
-                    edge,
-                    input,
-                    statements,
-                }
-            }
+    let timer = handle_entity_id(pairs.next().unwrap())?;
+    let status = match pairs.next().unwrap().as_str() {
+        "running" => TimerStatus::Running,
+        "stopped" => TimerStatus::Stopped,
+        _ => unreachable!(),
+    };
+    Ok(Condition::Timer(timer, status))
+}
+
+fn handle_event_block(pair: Pair<Rule>, line_offset: usize) -> Result<Statement, ParseError> {
+    let location = location(&pair, line_offset);
+    let mut pairs = pair.into_inner();
+    let (trigger, input) = handle_event(pairs.next().unwrap())?;
+    let mut statements = vec![];
+    for next in pairs {
+        match next.as_rule() {
+            Rule::action => statements.push(handle_action(next, line_offset)?),
+            Rule::statement => statements.push(handle_statement(next, line_offset)?),
             _ => unimplemented!(),
-        })
-    } else {
-        Ok(Statement::Event {
+        }
+    }
+    Ok(match trigger {
+        Trigger::Edge(edge) => Statement::Event {
             edge,
             input,
-            statements: vec![],
-        })
-    }
+            statements,
+            location,
+        },
+        Trigger::Gesture(gesture) => Statement::Gesture {
+            gesture,
+            input,
+            statements,
+            location,
+        },
+    })
 }
 
-fn handle_event(pair: Pair<Rule>) -> Result<(Edge, Input), ParseError> {
+fn handle_event(pair: Pair<Rule>) -> Result<(Trigger, Input), ParseError> {
     let mut pairs = pair.into_inner();
-    let edge = handle_edge(pairs.next().unwrap());
+    let trigger = pairs.next().unwrap();
+    let trigger = match trigger.as_rule() {
+        Rule::edge => Trigger::Edge(handle_edge(trigger)),
+        Rule::gesture => Trigger::Gesture(handle_gesture(trigger)),
+        _ => unreachable!(),
+    };
     let input = handle_input_or_entity_id(pairs.next().unwrap())?;
-    Ok((edge, input))
+    Ok((trigger, input))
+}
+
+fn handle_gesture(pair: Pair<Rule>) -> Gesture {
+    match pair.as_str() {
+        "longpress" => Gesture::LongPress,
+        "doubleclick" => Gesture::DoubleClick,
+        _ => unreachable!(),
+    }
 }
 
 fn handle_edge(pair: Pair<Rule>) -> Edge {
@@ -340,15 +1199,49 @@ fn handle_edge(pair: Pair<Rule>) -> Edge {
     }
 }
 
+fn handle_after_block(pair: Pair<Rule>, line_offset: usize) -> Result<Statement, ParseError> {
+    let location = location(&pair, line_offset);
+    let mut pairs = pair.into_inner().peekable();
+    let duration = handle_duration(pairs.next().unwrap())?;
+    let timer = match pairs.peek() {
+        Some(next) if next.as_rule() == Rule::timer_name => Some(handle_entity_id(
+            pairs.next().unwrap().into_inner().next().unwrap(),
+        )?),
+        _ => None,
+    };
+    let mut statements = vec![];
+    for next in pairs {
+        match next.as_rule() {
+            Rule::action => statements.push(handle_action(next, line_offset)?),
+            Rule::statement => statements.push(handle_statement(next, line_offset)?),
+            _ => unreachable!(),
+        }
+    }
+    Ok(Statement::After {
+        duration,
+        timer,
+        statements,
+        location,
+    })
+}
+
+fn handle_duration(pair: Pair<Rule>) -> Result<Duration, ParseError> {
+    Ok(common::parse_duration(pair.as_str())?)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::shal::ast::{
-        Action, Condition, IODeclaration, IODeclarations, Input, Output, Program, Statement,
+        Action, Condition, EntityID, Gesture, IODeclaration, IODeclarations, Input, Output,
+        Program, Quantifier, Settings, SourceLoc, Statement, TimerStatus,
     };
     use crate::shal::common;
     use crate::shal::common::{IsWas, Value};
-    use crate::shal::parser::parse;
+    use crate::shal::parser::{declarations_schema, parse, parse_file, ParseError};
     use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
 
     #[test]
     fn test_parse() {
@@ -364,8 +1257,12 @@ mod tests {
                         }
                     ),]),
                     outputs: Default::default(),
+                    flags: HashMap::new(),
+                    groups: HashMap::new(),
+                    settings: Default::default(),
                 },
                 statements: vec![],
+                source_locations: vec![],
             }
         );
         assert_eq!(
@@ -380,46 +1277,59 @@ mod tests {
                             name: None
                         }
                     ),]),
+                    flags: HashMap::new(),
+                    groups: HashMap::new(),
+                    settings: Default::default(),
                 },
                 statements: vec![],
+                source_locations: vec![],
             }
         );
         assert_eq!(
             &parse("toggle output 1;").unwrap(),
             &Program {
                 declarations: Default::default(),
-                statements: vec![Statement::Action(Action::Toggle(Output::Number(
-                    1.try_into().unwrap()
-                )),)],
+                statements: vec![Statement::Action(
+                    Action::Toggle(Output::Number(1.try_into().unwrap())),
+                    SourceLoc(1, 1)
+                )],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
             &parse("toggle light_downstairs;").unwrap(),
             &Program {
                 declarations: Default::default(),
-                statements: vec![Statement::Action(Action::Toggle(Output::Entity(
-                    "light_downstairs".try_into().unwrap()
-                )))],
+                statements: vec![Statement::Action(
+                    Action::Toggle(Output::Entity("light_downstairs".try_into().unwrap())),
+                    SourceLoc(1, 1)
+                )],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
             &parse("set output 3 high;").unwrap(),
             &Program {
                 declarations: Default::default(),
-                statements: vec![Statement::Action(Action::Set(
-                    Output::Number(3.try_into().unwrap()),
-                    Value::High
-                ))],
+                statements: vec![Statement::Action(
+                    Action::Set(Output::Number(3.try_into().unwrap()), Value::High),
+                    SourceLoc(1, 1)
+                )],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
             &parse("set light_upstairs low;").unwrap(),
             &Program {
                 declarations: Default::default(),
-                statements: vec![Statement::Action(Action::Set(
-                    Output::Entity("light_upstairs".try_into().unwrap()),
-                    Value::Low
-                ))],
+                statements: vec![Statement::Action(
+                    Action::Set(
+                        Output::Entity("light_upstairs".try_into().unwrap()),
+                        Value::Low
+                    ),
+                    SourceLoc(1, 1)
+                )],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
@@ -429,10 +1339,13 @@ mod tests {
                 statements: vec![Statement::Event {
                     edge: common::Edge::Rising,
                     input: Input::Number(3.try_into().unwrap()),
-                    statements: vec![Statement::Action(Action::Toggle(Output::Number(
-                        4.try_into().unwrap()
-                    ))),],
-                },]
+                    statements: vec![Statement::Action(
+                        Action::Toggle(Output::Number(4.try_into().unwrap())),
+                        SourceLoc(1, 18)
+                    )],
+                    location: SourceLoc(1, 1),
+                },],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
@@ -443,13 +1356,18 @@ mod tests {
                     edge: common::Edge::Falling,
                     input: Input::Number(5.try_into().unwrap()),
                     statements: vec![
-                        Statement::Action(Action::Toggle(Output::Number(4.try_into().unwrap()))),
-                        Statement::Action(Action::Set(
-                            Output::Number(6.try_into().unwrap()),
-                            Value::High,
-                        )),
+                        Statement::Action(
+                            Action::Toggle(Output::Number(4.try_into().unwrap())),
+                            SourceLoc(1, 20)
+                        ),
+                        Statement::Action(
+                            Action::Set(Output::Number(6.try_into().unwrap()), Value::High),
+                            SourceLoc(1, 37)
+                        ),
                     ],
-                },]
+                    location: SourceLoc(1, 1),
+                },],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
@@ -471,10 +1389,13 @@ mod tests {
                         )),
                     ),
                     vec![],
-                    vec![Statement::Action(Action::Toggle(Output::Number(
-                        4.try_into().unwrap()
-                    ))),],
+                    vec![Statement::Action(
+                        Action::Toggle(Output::Number(4.try_into().unwrap())),
+                        SourceLoc(1, 57)
+                    )],
+                    SourceLoc(1, 4),
                 )],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         assert_eq!(
@@ -496,10 +1417,688 @@ mod tests {
                     ),
                     vec![],
                     vec![],
+                    SourceLoc(1, 4),
                 )],
+                source_locations: vec![SourceLoc(1, 1)],
             }
         );
         let parse_result = parse(include_str!("../../static/standaertha.shal"));
         assert!(matches!(&parse_result, &Ok(Program { .. })));
     }
+
+    #[test]
+    fn test_parse_after() {
+        assert_eq!(
+            &parse(
+                "on fedge button {
+                   after 2min as stairs_off set light low;
+                   after 500ms { cancel stairs_off; }
+                 }
+                 if timer stairs_off is running {}"
+            )
+            .unwrap(),
+            &Program {
+                declarations: Default::default(),
+                statements: vec![
+                    Statement::Event {
+                        edge: common::Edge::Falling,
+                        input: Input::Entity("button".try_into().unwrap()),
+                        statements: vec![
+                            Statement::After {
+                                duration: Duration::from_secs(120),
+                                timer: Some("stairs_off".try_into().unwrap()),
+                                statements: vec![Statement::Action(
+                                    Action::Set(
+                                        Output::Entity("light".try_into().unwrap()),
+                                        Value::Low
+                                    ),
+                                    SourceLoc(2, 45)
+                                )],
+                                location: SourceLoc(2, 20),
+                            },
+                            Statement::After {
+                                duration: Duration::from_millis(500),
+                                timer: None,
+                                statements: vec![Statement::Action(
+                                    Action::Cancel("stairs_off".try_into().unwrap()),
+                                    SourceLoc(3, 34)
+                                )],
+                                location: SourceLoc(3, 20),
+                            },
+                        ],
+                        location: SourceLoc(1, 1),
+                    },
+                    Statement::IfElse(
+                        Condition::Timer("stairs_off".try_into().unwrap(), TimerStatus::Running),
+                        vec![],
+                        vec![],
+                        SourceLoc(5, 21),
+                    ),
+                ],
+                source_locations: vec![SourceLoc(1, 1), SourceLoc(5, 18)],
+            }
+        );
+        assert!(parse("after 5mins toggle light;").is_err());
+        assert!(matches!(
+            parse("after 99999999999999999h toggle light;"),
+            Err(ParseError::DurationOutOfRangeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        let program = parse(
+            "{settings: {longpress: \"2s\"}}
+---
+             on longpress input 0 {}
+             on doubleclick button {}",
+        )
+        .unwrap();
+        assert_eq!(
+            Settings {
+                longpress: Duration::from_secs(2),
+                doubleclick: Duration::from_millis(500),
+                lints: HashMap::new(),
+            },
+            program.declarations.settings
+        );
+        assert_eq!(
+            vec![
+                Statement::Gesture {
+                    gesture: Gesture::LongPress,
+                    input: Input::Number(0.try_into().unwrap()),
+                    statements: vec![],
+                    location: SourceLoc(3, 14),
+                },
+                Statement::Gesture {
+                    gesture: Gesture::DoubleClick,
+                    input: Input::Entity("button".try_into().unwrap()),
+                    statements: vec![],
+                    location: SourceLoc(4, 14),
+                },
+            ],
+            program.statements
+        );
+        assert!(matches!(
+            parse("{settings: {longpress: \"2 s\"}}\n---\n"),
+            Err(ParseError::DeclarationError { path, .. }) if path == "settings.longpress"
+        ));
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let program = parse(
+            "{flags: {away: {pin: 0}}}
+---
+             toggle flag 1;
+             if away is high and flag 1 was low {
+               set away low;
+             }",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Statement::Action(
+                    Action::Toggle(Output::Flag(1.try_into().unwrap())),
+                    SourceLoc(3, 14)
+                ),
+                Statement::IfElse(
+                    Condition::And(
+                        Box::new(Condition::Entity(
+                            "away".try_into().unwrap(),
+                            IsWas::Is,
+                            Value::High
+                        )),
+                        Box::new(Condition::Output(
+                            Output::Flag(1.try_into().unwrap()),
+                            IsWas::Was,
+                            Value::Low
+                        )),
+                    ),
+                    vec![Statement::Action(
+                        Action::Set(Output::Entity("away".try_into().unwrap()), Value::Low),
+                        SourceLoc(5, 16)
+                    )],
+                    vec![],
+                    SourceLoc(4, 17),
+                ),
+            ],
+            program.statements
+        );
+        assert!(matches!(
+            parse("{flags: {away: {pin: 0}, night: {pin: 0}}}\n---\n"),
+            Err(ParseError::DoubleFlagError { .. })
+        ));
+        assert!(matches!(
+            parse("{outputs: {away: {pin: 0}}, flags: {away: {pin: 1}}}\n---\n"),
+            Err(ParseError::DuplicateEntityIDError { .. })
+        ));
+        assert!(matches!(
+            parse("toggle flag 32;"),
+            Err(ParseError::InvalidPinIDError(_))
+        ));
+        assert!(matches!(
+            parse("toggle flag 300;"),
+            Err(ParseError::PinIDOutOfRangeError { .. })
+        ));
+        assert!(matches!(
+            parse("if input 99999999999 is high { set output 300 high; }"),
+            Err(ParseError::PinIDOutOfRangeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let header = "{
+  outputs: {
+    light_kitchen: {pin: 0}
+    light_hall: {pin: 1}
+  }
+  groups: {
+    downstairs: {outputs: [\"light_kitchen\", \"light_hall\"], name: \"Downstairs\"}
+  }
+}
+---
+";
+        let program = parse(&format!(
+            "{header}
+             toggle group downstairs;
+             if all of group downstairs was high {{
+               set group downstairs low;
+             }}
+             set groupies high;"
+        ))
+        .unwrap();
+        let downstairs: EntityID = "downstairs".try_into().unwrap();
+        assert_eq!(
+            vec![
+                EntityID::try_from("light_kitchen").unwrap(),
+                EntityID::try_from("light_hall").unwrap()
+            ],
+            program.declarations.groups[&downstairs].outputs
+        );
+        assert_eq!(
+            vec![
+                Statement::Action(Action::ToggleGroup(downstairs.clone()), SourceLoc(12, 14)),
+                Statement::IfElse(
+                    Condition::Group(Quantifier::All, downstairs.clone(), IsWas::Was, Value::High),
+                    vec![Statement::Action(
+                        Action::SetGroup(downstairs.clone(), Value::Low),
+                        SourceLoc(14, 16)
+                    )],
+                    vec![],
+                    SourceLoc(13, 17),
+                ),
+                Statement::Action(
+                    Action::Set(Output::Entity("groupies".try_into().unwrap()), Value::High),
+                    SourceLoc(16, 14)
+                ),
+            ],
+            program.statements
+        );
+        assert!(matches!(
+            parse("{outputs: {a: {pin: 0}}, groups: {g: {outputs: [\"b\"]}}}\n---\n"),
+            Err(ParseError::UnknownGroupOutputError { .. })
+        ));
+        assert!(matches!(
+            parse("{groups: {g: {outputs: []}}}\n---\n"),
+            Err(ParseError::EmptyGroupError { .. })
+        ));
+        assert!(matches!(
+            parse("{outputs: {g: {pin: 0}}, groups: {g: {outputs: [\"g\"]}}}\n---\n"),
+            Err(ParseError::DuplicateEntityIDError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let condition = |source: &str| {
+            let program = parse(&format!("if {source} {{}}")).unwrap();
+            match &program.statements[..] {
+                [Statement::IfElse(condition, _, _, _)] => condition.clone(),
+                _ => unreachable!(),
+            }
+        };
+        let entity = |id: &str| {
+            Box::new(Condition::Entity(
+                id.try_into().unwrap(),
+                IsWas::Is,
+                Value::High,
+            ))
+        };
+        // not > and > xor > or
+        assert_eq!(
+            Condition::Or(
+                Box::new(Condition::And(entity("a"), entity("b"))),
+                Box::new(Condition::Xor(
+                    entity("c"),
+                    Box::new(Condition::And(
+                        entity("d"),
+                        Box::new(Condition::Not(entity("e")))
+                    ))
+                )),
+            ),
+            condition("a is high and b is high or c is high xor d is high and not e is high")
+        );
+        // Left associative
+        assert_eq!(
+            Condition::Or(
+                Box::new(Condition::Or(entity("a"), entity("b"))),
+                entity("c")
+            ),
+            condition("a is high or b is high or c is high")
+        );
+        assert_eq!(
+            Condition::And(
+                Box::new(Condition::Or(entity("a"), entity("b"))),
+                entity("c")
+            ),
+            condition("(a is high or b is high) and c is high")
+        );
+        assert_eq!(
+            Condition::Or(Box::new(Condition::Constant(true)), entity("true_")),
+            condition("true or true_ is high")
+        );
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let program = parse(
+            "{\n  inputs: {\n    btn: {pin: 0}\n  }\n}\n---\n\
+             rule toggle_pair(btn, light) {\n\
+               on redge btn toggle light;\n\
+             }\n\
+             toggle_pair(input 1, output 2);\n\
+             toggle_pair(btn, flag 3);\n",
+        )
+        .unwrap();
+        let expansion = |input: Input, output: Output| {
+            vec![Statement::Event {
+                edge: common::Edge::Rising,
+                input,
+                statements: vec![Statement::Action(Action::Toggle(output), SourceLoc(8, 14))],
+                location: SourceLoc(8, 1),
+            }]
+        };
+        let rule = EntityID::try_from("toggle_pair").unwrap();
+        assert_eq!(
+            vec![
+                Statement::Call {
+                    rule: rule.clone(),
+                    location: SourceLoc(10, 1),
+                    file: None,
+                    statements: expansion(
+                        Input::Number(1.try_into().unwrap()),
+                        Output::Number(2.try_into().unwrap())
+                    ),
+                },
+                Statement::Call {
+                    rule,
+                    location: SourceLoc(11, 1),
+                    file: None,
+                    statements: expansion(
+                        Input::Entity("btn".try_into().unwrap()),
+                        Output::Flag(3.try_into().unwrap())
+                    ),
+                },
+            ],
+            program.statements
+        );
+
+        let nested = parse(
+            "rule a(x) { if x is high { set y low; } }\n\
+             rule b(x, y) { a(y); after 1s as x toggle y; }\n\
+             b(t, output 1);",
+        )
+        .unwrap();
+        let [Statement::Call { statements, .. }] = &nested.statements[..] else {
+            panic!("expected a single call");
+        };
+        assert!(matches!(
+            &statements[..],
+            [
+                Statement::Call { .. },
+                Statement::After { timer: Some(_), .. }
+            ]
+        ));
+        let [Statement::Call {
+            statements: inner, ..
+        }, ..] = &statements[..]
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            vec![Statement::IfElse(
+                Condition::Output(
+                    Output::Number(1.try_into().unwrap()),
+                    IsWas::Is,
+                    Value::High
+                ),
+                // Only parameters are substituted
+                vec![Statement::Action(
+                    Action::Set(Output::Entity("y".try_into().unwrap()), Value::Low),
+                    SourceLoc(1, 28)
+                )],
+                vec![],
+                SourceLoc(1, 16)
+            )],
+            *inner
+        );
+
+        assert!(matches!(
+            parse("foo(bar);"),
+            Err(ParseError::UnknownRuleError { .. })
+        ));
+        // Rules can't call themselves
+        assert!(matches!(
+            parse("rule foo() { foo(); }"),
+            Err(ParseError::UnknownRuleError { .. })
+        ));
+        assert!(matches!(
+            parse("rule foo() {} rule foo() {}"),
+            Err(ParseError::DuplicateRuleError { .. })
+        ));
+        assert!(matches!(
+            parse("rule foo(a, a) {}"),
+            Err(ParseError::DuplicateParameterError { .. })
+        ));
+        assert!(matches!(
+            parse("rule foo(a) {}\n\nfoo(a, b);"),
+            Err(ParseError::RuleArgumentCountError {
+                expected: 1,
+                got: 2,
+                location: SourceLoc(3, 1),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("rule foo(a) { toggle a; }\nfoo(input 1);"),
+            Err(ParseError::RuleArgumentTypeError {
+                location: SourceLoc(2, 1),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("rule foo(a) { cancel a; }\nfoo(output 1);"),
+            Err(ParseError::RuleArgumentTypeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_includes() {
+        let files = HashMap::from([
+            (
+                "home/declarations.shal",
+                "{\n  inputs: {\n    btn: {pin: 0}\n  }\n  outputs: {\n    light: {pin: 1}\n  }\n\
+                 settings: {\n    longpress: \"2s\"\n  }\n}\n---\n",
+            ),
+            (
+                "home/templates.shal",
+                "rule toggle_pair(b, l) {\n  on redge b toggle l;\n}\n",
+            ),
+            (
+                "home/floors/ground.shal",
+                "include \"templates.shal\";\ntoggle_pair(btn, light);\n",
+            ),
+            ("home/cycle.shal", "include \"floors/../main.shal\";"),
+            ("home/bad.shal", "\n\nfoo();"),
+        ]);
+        let parse_main = |input: &str| {
+            parse_file(Path::new("./home/main.shal"), input, None, |path| {
+                files
+                    .get(path.to_str().unwrap())
+                    .map(|file| file.to_string())
+                    .ok_or(io::ErrorKind::NotFound.into())
+            })
+        };
+
+        let program = parse_main(
+            "{\n  settings: {\n    longpress: \"3s\"\n  }\n}\n---\n\
+             include \"declarations.shal\";\n\
+             include \"./floors/ground.shal\";\n\
+             include \"templates.shal\";\n\
+             toggle_pair(input 2, output 3);\n",
+        )
+        .unwrap();
+        assert!(program
+            .declarations
+            .inputs
+            .contains_key(&"btn".try_into().unwrap()));
+        assert!(program
+            .declarations
+            .outputs
+            .contains_key(&"light".try_into().unwrap()));
+        // Settings are taken from the main program
+        assert_eq!(
+            Duration::from_secs(3),
+            program.declarations.settings.longpress
+        );
+        let path = |path: &str| PathBuf::from(path);
+        match &program.statements[..] {
+            [Statement::Include {
+                path: declarations,
+                location: SourceLoc(7, 1),
+                statements: no_statements,
+            }, Statement::Include {
+                path: ground,
+                location: SourceLoc(8, 1),
+                statements: ground_statements,
+            }, Statement::Call {
+                location: SourceLoc(10, 1),
+                ..
+            }] => {
+                assert_eq!(&path("home/declarations.shal"), declarations);
+                assert!(no_statements.is_empty());
+                assert_eq!(&path("home/floors/ground.shal"), ground);
+                // Templates are included by the ground floor, and only included once
+                assert!(matches!(
+                    &ground_statements[..],
+                    [
+                        Statement::Include {
+                            location: SourceLoc(1, 1),
+                            ..
+                        },
+                        Statement::Call {
+                            location: SourceLoc(2, 1),
+                            ..
+                        }
+                    ]
+                ));
+            }
+            statements => panic!("unexpected statements: {statements:?}"),
+        }
+
+        assert!(matches!(
+            parse_main("include \"cycle.shal\";"),
+            Err(ParseError::IncludeError {
+                source,
+                ..
+            }) if matches!(*source, ParseError::IncludeCycleError { ref path, .. } if path == Path::new("home/main.shal"))
+        ));
+        assert!(matches!(
+            parse_main("\ninclude \"missing.shal\";"),
+            Err(ParseError::IncludeReadError {
+                location: SourceLoc(2, 1),
+                ..
+            })
+        ));
+        // Locations are reported in the included file
+        assert!(matches!(
+            parse_main("include \"bad.shal\";"),
+            Err(ParseError::IncludeError {
+                source,
+                ..
+            }) if matches!(*source, ParseError::UnknownRuleError { location: SourceLoc(3, 1), .. })
+        ));
+        assert!(matches!(
+            parse_main("{\n  inputs: {\n    btn: {pin: 3}\n  }\n}\n---\ninclude \"declarations.shal\";"),
+            Err(ParseError::IncludeError {
+                source,
+                ..
+            }) if matches!(*source, ParseError::DuplicateEntityIDError { .. })
+        ));
+        assert!(matches!(
+            parse("include \"templates.shal\";"),
+            Err(ParseError::IncludeNotSupportedError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_declaration_errors() {
+        let parse_header = |header: &str| parse(&format!("{header}\n---\n"));
+        let declaration_error = |header: &str| match parse_header(header) {
+            Err(ParseError::DeclarationError {
+                path, line, col, ..
+            }) => (path, line, col),
+            result => panic!("expected a declaration error: {result:?}"),
+        };
+        assert_eq!(
+            ("inputs.button.pin".to_owned(), 3, 21),
+            declaration_error("{\n  inputs: {\n    button: {pin: 40}\n  }\n}")
+        );
+        assert_eq!(
+            ("outputs.light.nme".to_owned(), 3, 24),
+            declaration_error("{\n  outputs: {\n    light: {pin: 4, nme: \"Light\"}\n  }\n}")
+        );
+        assert_eq!(
+            ("outputs.light.pin".to_owned(), 3, 18),
+            declaration_error("{\n  outputs: {\n    light: {pin: \"4\"}\n  }\n}")
+        );
+        assert_eq!(
+            ("inputs.9button".to_owned(), 3, 12),
+            declaration_error("{\n  inputs: {\n    9button: {pin: 4}\n  }\n}")
+        );
+        // Duplicate keys only have a location
+        assert_eq!(
+            (".".to_owned(), 5, 3),
+            declaration_error("{\n  inputs: {\n    a: {pin: 4}\n    a: {pin: 5}\n  }\n}")
+        );
+        // Inputs and outputs can't share an ID, or it would be ambiguous in conditions
+        assert!(matches!(
+            parse_header(
+                "{\n  inputs: {\n    a: {pin: 4}\n  }\n  outputs: {\n    a: {pin: 4}\n  }\n}"
+            ),
+            Err(ParseError::DuplicateEntityIDError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_declaration_formats() {
+        let hjson = "{
+  inputs: {
+    button: {pin: 0, name: \"Button\"}
+  }
+  outputs: {
+    light: {pin: 1}
+  }
+  groups: {
+    lights: {outputs: [\"light\"]}
+  }
+  settings: {
+    longpress: \"2s\"
+  }
+}
+---
+";
+        let toml = "[inputs]
+button = {pin = 0, name = \"Button\"}
+
+[outputs.light]
+pin = 1
+
+[groups.lights]
+outputs = [\"light\"]
+
+[settings]
+longpress = \"2s\"
+--- toml
+";
+        let yaml = "inputs:
+  button:
+    pin: 0
+    name: Button
+outputs:
+  light: {pin: 1}
+groups:
+  lights:
+    outputs: [light]
+settings:
+  longpress: 2s
+--- yaml
+";
+        let declarations = parse(hjson).unwrap().declarations;
+        assert_eq!(Duration::from_secs(2), declarations.settings.longpress);
+        assert_eq!(declarations, parse(toml).unwrap().declarations);
+        assert_eq!(declarations, parse(yaml).unwrap().declarations);
+
+        // Standalone declaration files, included or given separately
+        let files = HashMap::from([
+            ("pins.yaml", &yaml[..yaml.find("---").unwrap()]),
+            ("pins.toml", &toml[..toml.find("---").unwrap()]),
+            ("pins.txt", ""),
+        ]);
+        let parse_main = |input: &str, declarations: Option<&str>| {
+            parse_file(
+                Path::new("main.shal"),
+                input,
+                declarations.map(Path::new),
+                |path| {
+                    files
+                        .get(path.to_str().unwrap())
+                        .map(|file| file.to_string())
+                        .ok_or(io::ErrorKind::NotFound.into())
+                },
+            )
+        };
+        let program = parse_main(
+            "include \"pins.yaml\";\non fedge button toggle light;",
+            None,
+        );
+        let program = program.unwrap();
+        // Settings are only taken from the main program
+        assert_eq!(Settings::default(), program.declarations.settings);
+        assert_eq!(declarations.inputs, program.declarations.inputs);
+        assert_eq!(1, program.statements.len());
+        let program = parse_main("toggle light;", Some("pins.toml")).unwrap();
+        assert_eq!(declarations.outputs, program.declarations.outputs);
+        // The declarations file is only loaded once
+        assert!(parse_main("include \"pins.toml\";", Some("pins.toml")).is_ok());
+        assert!(matches!(
+            parse_main("", Some("pins.txt")),
+            Err(ParseError::DeclarationFileError { source, .. })
+                if matches!(*source, ParseError::UnknownDeclarationFormatError { .. })
+        ));
+        assert!(matches!(
+            parse_main("{inputs: {button: {pin: 3}}}\n---\n", Some("pins.toml")),
+            Err(ParseError::DuplicateEntityIDError { .. })
+        ));
+
+        let declaration_error = |input: &str| match parse(input) {
+            Err(ParseError::DeclarationError {
+                path, line, col, ..
+            }) => (path, line, col),
+            result => panic!("expected a declaration error: {result:?}"),
+        };
+        assert_eq!(
+            ("outputs.light.pin".to_owned(), 3, 7),
+            declaration_error("[inputs]\n[outputs.light]\npin = 40\n--- toml\n")
+        );
+        assert_eq!(
+            ("outputs.light.pin".to_owned(), 3, 10),
+            declaration_error("inputs: {}\noutputs:\n  light: {pin: 40}\n--- yaml\n")
+        );
+        assert!(matches!(
+            parse("inputs = {}\n--- xml\n"),
+            Err(ParseError::UnknownDeclarationFormatError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_declarations_schema() {
+        // Regenerate with `sha_bridge schema > doc/shal/declarations.schema.json`
+        assert_eq!(
+            include_str!("../../../doc/shal/declarations.schema.json"),
+            serde_json::to_string_pretty(&declarations_schema()).unwrap() + "\n"
+        );
+    }
 }
@@ -0,0 +1,250 @@
+use crate::shal::ast::{EntityID, IODeclaration, PinID, SourceLoc};
+use crate::shal::bytecode::{InOut, Instruction, Program};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Maps the bytes of a program back to the SHAL source they were compiled from,
+/// it is stored next to the program so errors of the controller can point to the source
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SourceMap {
+    /// The CRC of the program, as in its header
+    pub crc: u16,
+    /// The length of the code of the program, as in its header
+    pub length: u16,
+    pub entries: Vec<SourceMapEntry>,
+}
+
+/// The source of one instruction
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SourceMapEntry {
+    /// Offset of the first byte of the instruction in the code
+    pub offset: u16,
+    /// Number of bytes of the instruction
+    pub size: u16,
+    /// The file the statement of the instruction is written in
+    pub file: PathBuf,
+    pub line: usize,
+    pub col: usize,
+    /// The input, output or flag the instruction uses
+    pub entity: Option<EntityID>,
+}
+
+/// Where in the source an error happened
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceMapLocation<'a> {
+    pub entry: &'a SourceMapEntry,
+}
+
+impl Display for SourceMapLocation<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {}",
+            self.entry.file.display(),
+            SourceLoc(self.entry.line, self.entry.col)
+        )?;
+        if let Some(entity) = &self.entry.entity {
+            write!(f, " ({entity})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors of the controller end in " at byte <offset>" when they happen while running the program
+static ERROR_OFFSET: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r" at byte (\d+)$").unwrap_or_else(|_| unreachable!()));
+
+/// The offset in the code an error message of the controller refers to, if any
+pub fn error_offset(message: &str) -> Option<u16> {
+    ERROR_OFFSET
+        .captures(message)
+        .and_then(|captures| captures[1].parse().ok())
+}
+
+impl SourceMap {
+    /// Creates the source map of a program compiled from `file`. Instructions without a
+    /// source location are left out, so this is empty for programs that were not compiled
+    /// from source. Instructions point to the statement or condition they were compiled from,
+    /// in the included file or the file of the rule it is written in.
+    pub fn new(program: &Program, file: &Path) -> SourceMap {
+        let header = program.header();
+        let mut entries = vec![];
+        let mut offset = 0;
+        for (i, instr) in program.instructions.iter().enumerate() {
+            let size = instr.byte_size() as u16;
+            if let Some(origin) = program.origins.get(i) {
+                let SourceLoc(line, col) = origin.location;
+                let file = match origin.file {
+                    Some(i) => program.files[i].clone(),
+                    None => file.to_owned(),
+                };
+                entries.push(SourceMapEntry {
+                    offset,
+                    size,
+                    file,
+                    line,
+                    col,
+                    entity: entity(program, instr),
+                });
+            }
+            offset += size;
+        }
+        SourceMap {
+            crc: header.crc,
+            length: header.length,
+            entries,
+        }
+    }
+
+    /// Whether this is the source map of the program with this CRC and length
+    pub fn matches(&self, crc: u16, length: u16) -> bool {
+        self.crc == crc && self.length == length
+    }
+
+    /// The source of the instruction at `offset`, which can be any byte of the instruction
+    pub fn lookup(&self, offset: u16) -> Option<SourceMapLocation<'_>> {
+        let i = self
+            .entries
+            .partition_point(|entry| entry.offset + entry.size <= offset);
+        self.entries
+            .get(i)
+            .filter(|entry| entry.offset <= offset)
+            .map(|entry| SourceMapLocation { entry })
+    }
+}
+
+/// The declared entity with this pin, the first by name if several share it
+fn declared(declarations: &HashMap<EntityID, IODeclaration>, pin: PinID) -> Option<EntityID> {
+    declarations
+        .iter()
+        .filter(|(_, declaration)| declaration.pin == pin)
+        .map(|(id, _)| id)
+        .min_by_key(|id| <&str>::from(*id))
+        .cloned()
+}
+
+fn entity(program: &Program, instr: &Instruction) -> Option<EntityID> {
+    let declarations = &program.declarations;
+    match *instr {
+        Instruction::Set { output, .. }
+        | Instruction::Toggle { output }
+        | Instruction::OnToggle { output, .. } => declared(&declarations.outputs, output),
+        Instruction::SetFlag { flag, .. } | Instruction::ToggleFlag { flag } => {
+            declared(&declarations.flags, flag)
+        }
+        Instruction::On { input, .. } => declared(&declarations.inputs, input),
+        Instruction::If { number, in_out, .. } => match in_out {
+            InOut::Input => declared(&declarations.inputs, number),
+            InOut::Output => declared(&declarations.outputs, number),
+            InOut::Flag => declared(&declarations.flags, number),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shal::compiler::compile;
+    use crate::shal::optimizer::optimize;
+    use crate::shal::parser::parse_file;
+    use crate::shal::source_map::{error_offset, SourceMap, SourceMapEntry};
+    use crate::shal::tests::{compile_source, DECLARATIONS};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_source_map() {
+        let source =
+            format!("{DECLARATIONS}set light high;\nif button is high {{\n  toggle light;\n}}\n");
+        let program = compile_source(&source);
+        let source_map = SourceMap::new(&program, Path::new("main.shal"));
+        let header = program.header();
+        assert!(source_map.matches(header.crc, header.length));
+        let entry = |offset, size, line, col, entity: Option<&str>| SourceMapEntry {
+            offset,
+            size,
+            file: PathBuf::from("main.shal"),
+            line,
+            col,
+            entity: entity.map(|entity| entity.try_into().unwrap()),
+        };
+        // Nested statements point to themselves, the end of a block to its condition
+        assert_eq!(
+            vec![
+                entry(0, 2, 15, 1, Some("light")),
+                entry(2, 2, 16, 4, Some("button")),
+                entry(4, 2, 17, 3, Some("light")),
+                entry(6, 1, 16, 4, None),
+            ],
+            source_map.entries
+        );
+
+        let location = source_map.lookup(5).unwrap();
+        assert_eq!(&entry(4, 2, 17, 3, Some("light")), location.entry);
+        assert_eq!("main.shal, line 17, col 3 (light)", location.to_string());
+        assert_eq!(None, source_map.lookup(7));
+    }
+
+    #[test]
+    fn test_source_map_optimized() {
+        let source = format!(
+            "{DECLARATIONS}on redge button toggle light;\n\non fedge button set light low;\n"
+        );
+        let compiled = compile_source(&source);
+        let program = optimize(&compiled);
+        let source_map = SourceMap::new(&program, Path::new("main.shal"));
+        let lines: Vec<_> = source_map
+            .entries
+            .iter()
+            .map(|entry| (entry.offset, entry.line))
+            .collect();
+        assert_eq!(vec![(0, 15), (2, 17), (4, 17), (6, 17)], lines);
+    }
+
+    #[test]
+    fn test_source_map_include() {
+        let program = compile(
+            &parse_file(
+                Path::new("main.shal"),
+                "set output 0 high;\ninclude \"lights.shal\";\nlights_off();",
+                None,
+                |_| {
+                    Ok("set output 1 low;\n\
+                        rule lights_off() {\n\
+                          if output 1 is high { set output 2 low; }\n\
+                        }"
+                    .to_owned())
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let source_map = SourceMap::new(&program, Path::new("main.shal"));
+        let locations: Vec<_> = source_map
+            .entries
+            .iter()
+            .map(|entry| (entry.file.to_str().unwrap(), entry.line, entry.col))
+            .collect();
+        // Statements of an included file and of the rules defined in it point to that file
+        assert_eq!(
+            vec![
+                ("main.shal", 1, 1),
+                ("lights.shal", 1, 1),
+                ("lights.shal", 3, 4),
+                ("lights.shal", 3, 23),
+                ("lights.shal", 3, 4),
+            ],
+            locations
+        );
+    }
+
+    #[test]
+    fn test_error_offset() {
+        assert_eq!(Some(12), error_offset("Unknown instruction at byte 12"));
+        assert_eq!(None, error_offset("Reached end of program"));
+        assert_eq!(None, error_offset("Unknown instruction at byte 123456"));
+    }
+}
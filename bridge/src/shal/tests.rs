@@ -1,12 +1,35 @@
+use crate::shal::bytecode::Program;
 use crate::shal::compiler::compile;
 use crate::shal::parser::parse;
 
+/// Declarations for testing the body of a program
+pub(crate) const DECLARATIONS: &str = "{\n\
+    \x20 inputs: {\n\
+    \x20   button: {pin: 0}\n\
+    \x20   switch: {pin: 1}\n\
+    \x20 }\n\
+    \x20 outputs: {\n\
+    \x20   light: {pin: 0}\n\
+    \x20   fan: {pin: 1}\n\
+    \x20 }\n\
+    \x20 groups: {\n\
+    \x20   all: {outputs: [\"light\", \"fan\"]}\n\
+    \x20 }\n\
+    }\n\
+    ---\n";
+
+/// Compiles a program that is known to be valid
+pub(crate) fn compile_source(source: &str) -> Program {
+    compile(&parse(source).unwrap()).unwrap()
+}
+
 #[test]
 fn test_full_program() {
     let ast_program = parse(include_str!("../../static/standaertha.shal")).unwrap();
     let bytecode_program = compile(&ast_program).unwrap();
 
-    assert_eq!(Ok(175), bytecode_program.check_program_size(None));
+    assert_eq!(Ok(178), bytecode_program.check_program_size(None));
+    // `or` is left associative, so the chain of three outputs only needs two stack slots
     assert_eq!(Ok(2), bytecode_program.check_stack_depth(None));
 }
 
@@ -34,22 +34,35 @@ namespace StandaertHA::Comm {
     Uninit = 0,
 
     Update = 'u', // Update from controller (output state + button events)
+    FullUpdate = 'U', // Update from controller (output state + input state + button events)
     Command = 'c', // Commands from host
+    SetOutputs = 'o', // Set multiple outputs at once from host (mask + values)
+    Flags = 'v', // Flags of the program from controller
+    SetFlags = 'V', // Set multiple flags at once from host (mask + values)
     Fail = 'F', // Error message
     Info = 'I', // Info message
+    Capabilities = 'C', // Supported program versions and features from controller
+    Status = 'm', // Status of the controller (MODE switch) from controller
 
-    ProgramStart = 's', // Start transmit program (program header (8 bytes))
-    ProgramStartAck = 'S', // Acknowledge transmit program (program header (8 bytes))
+    ProgramStart = 's', // Start transmit program (program header (10 bytes))
+    ProgramStartAck = 'S', // Acknowledge transmit program (program header (10 bytes))
     ProgramData = 'd', // Send program data (middle) (127 bytes)
     ProgramEnd = 'e', // End of program data (max. 127 bytes)
-    ProgramEndAck = 'E', // Acknowledge program end (program header (8 bytes))
+    ProgramEndAck = 'E', // Acknowledge program end (program header (10 bytes))
   };
 
   static_assert(
     Util::Pred::all_different(
       MessageType::Update,
+      MessageType::FullUpdate,
       MessageType::Command,
+      MessageType::SetOutputs,
+      MessageType::Flags,
+      MessageType::SetFlags,
       MessageType::Fail,
+      MessageType::Info,
+      MessageType::Capabilities,
+      MessageType::Status,
       MessageType::ProgramStart,
       MessageType::ProgramStartAck,
       MessageType::ProgramData,
@@ -66,12 +79,40 @@ namespace StandaertHA::Comm {
 
   static_assert(sizeof(UpdateMsg) <= MAX_MESSAGE_BODY_LENGTH);
 
+  struct FullUpdateMsg {
+    uint32_t outputs;
+    uint32_t inputs;
+    Event events[MAX_MESSAGE_BODY_LENGTH - sizeof(outputs) - sizeof(inputs)];
+  } __attribute__((packed));
+
+  static_assert(sizeof(FullUpdateMsg) <= MAX_MESSAGE_BODY_LENGTH);
+
   struct CommandMsg {
     Command command[MAX_MESSAGE_BODY_LENGTH];
   } __attribute__((packed));
 
   static_assert(sizeof(CommandMsg) <= MAX_MESSAGE_BODY_LENGTH);
 
+  struct SetOutputsMsg {
+    uint32_t mask;
+    uint32_t values;
+  } __attribute__((packed));
+
+  static_assert(sizeof(SetOutputsMsg) <= MAX_MESSAGE_BODY_LENGTH);
+
+  struct FlagsMsg {
+    uint32_t flags;
+  } __attribute__((packed));
+
+  static_assert(sizeof(FlagsMsg) <= MAX_MESSAGE_BODY_LENGTH);
+
+  struct SetFlagsMsg {
+    uint32_t mask;
+    uint32_t values;
+  } __attribute__((packed));
+
+  static_assert(sizeof(SetFlagsMsg) <= MAX_MESSAGE_BODY_LENGTH);
+
   struct FailMsg {
     unsigned char message[MAX_MESSAGE_BODY_LENGTH];
   } __attribute__((packed));
@@ -84,6 +125,23 @@ namespace StandaertHA::Comm {
 
   static_assert(sizeof(InfoMsg) <= MAX_MESSAGE_BODY_LENGTH);
 
+  struct CapabilitiesMsg {
+    uint8_t min_version;
+    uint8_t max_version;
+    uint8_t features;
+  } __attribute__((packed));
+
+  static_assert(sizeof(CapabilitiesMsg) <= MAX_MESSAGE_BODY_LENGTH);
+
+  // Set in the status when the MODE switch enables the program
+  constexpr uint8_t STATUS_PROGRAM_ENABLED = 0b0000'0001U;
+
+  struct StatusMsg {
+    uint8_t status;
+  } __attribute__((packed));
+
+  static_assert(sizeof(StatusMsg) <= MAX_MESSAGE_BODY_LENGTH);
+
   struct ProgramStart {
     Shal::Interpreter::ProgramHeader header;
   } __attribute__((packed));
@@ -116,9 +174,15 @@ namespace StandaertHA::Comm {
 
   union MsgBody {
     UpdateMsg update;
+    FullUpdateMsg full_update;
     CommandMsg command;
+    SetOutputsMsg set_outputs;
+    FlagsMsg flags;
+    SetFlagsMsg set_flags;
     FailMsg fail_msg;
     InfoMsg info_msg;
+    CapabilitiesMsg capabilities;
+    StatusMsg status;
     ProgramStart program_start;
     ProgramStartAck program_start_ack;
     ProgramData program_data;
@@ -136,9 +200,13 @@ namespace StandaertHA::Comm {
     Message() noexcept;
 
     explicit Message(const UpdateMsg& update, uint8_t event_count) noexcept;
+    explicit Message(const FullUpdateMsg& full_update, uint8_t event_count) noexcept;
     explicit Message(const CommandMsg& command, uint8_t command_count) noexcept;
+    explicit Message(const FlagsMsg& flags) noexcept;
     explicit Message(const FailMsg& fail_msg, uint8_t size) noexcept;
     explicit Message(const InfoMsg& info_msg, uint8_t size) noexcept;
+    explicit Message(const CapabilitiesMsg& capabilities) noexcept;
+    explicit Message(const StatusMsg& status) noexcept;
     explicit Message(const ProgramStart& program_start) noexcept;
     explicit Message(const ProgramStartAck& program_start_ack) noexcept;
     explicit Message(const ProgramData& program_data, uint8_t byte_count) noexcept;
@@ -150,7 +218,10 @@ namespace StandaertHA::Comm {
     [[nodiscard]] constexpr uint8_t body_length() const noexcept { return body_length_; }
 
     [[nodiscard]] constexpr const UpdateMsg& body_as_update() const noexcept { return body_.update; }
+    [[nodiscard]] constexpr const FullUpdateMsg& body_as_full_update() const noexcept { return body_.full_update; }
     [[nodiscard]] constexpr const CommandMsg& body_as_command_msg() const noexcept { return body_.command; }
+    [[nodiscard]] constexpr const SetOutputsMsg& body_as_set_outputs_msg() const noexcept { return body_.set_outputs; }
+    [[nodiscard]] constexpr const SetFlagsMsg& body_as_set_flags_msg() const noexcept { return body_.set_flags; }
     [[nodiscard]] constexpr const FailMsg& body_as_fail_msg() const noexcept { return body_.fail_msg; }
     [[nodiscard]] constexpr const InfoMsg& body_as_info_msg() const noexcept { return body_.info_msg; }
     [[nodiscard]] constexpr const ProgramStart& body_as_program_start() const noexcept { return body_.program_start; }
@@ -33,10 +33,15 @@ namespace StandaertHA {
 
     extern void send(const Message& message) noexcept;
     extern void send_update(const State& state) noexcept;
+    extern void send_flags(const State& state) noexcept;
+    extern void send_capabilities() noexcept;
+    extern void send_status(const State& state) noexcept;
     extern void send_program_start_ack(const Shal::Interpreter::ProgramHeader& header) noexcept;
     extern void send_program_end_ack(const Shal::Interpreter::ProgramHeader& header) noexcept;
 
     extern void send_error(const char* message, size_t size) noexcept;
+    // Send the error followed by " at byte <offset>", the offset of the failing byte in the code
+    extern void send_error(const char* message, size_t size, uint16_t offset) noexcept;
     extern void send_info(const char* message, size_t size) noexcept;
 
     template<size_t size>
@@ -45,6 +50,12 @@ namespace StandaertHA {
       send_error(message, size - 1);
     }
 
+    template<size_t size>
+    inline void send_error(const char (&message)[size], const uint16_t offset) noexcept
+    {
+      send_error(message, size - 1, offset);
+    }
+
     template<size_t size>
     inline void send_info(const char (&message)[size]) noexcept
     {
@@ -25,5 +25,7 @@ namespace StandaertHA::Messages {
   const char UNKNOWN_INSTRUCTION[] PROGMEM = {"Unknown instruction"};
   const char PREVIOUS_BYTE_ERROR[] PROGMEM = {"Previous byte is not first byte"};
   const char END_OF_PROGRAM[] PROGMEM = {"Reached end of program"};
+  const char AT_BYTE[] PROGMEM = {" at byte "};
   const char PROGRAM_VERIFICATION_ERROR[] PROGMEM = {"Program CRC check failed!"};
+  const char UNSUPPORTED_PROGRAM_ERROR[] PROGMEM = {"Program version or features not supported!"};
 }
@@ -24,6 +24,13 @@ namespace StandaertHA::Shal::Bytecode {
   constexpr uint8_t IF_IS_WAS_MASK     = UINT8_C(0b0000'0100U);
   constexpr uint8_t IF_IO_MASK         = UINT8_C(0b0000'0010U);
   constexpr uint8_t IF_VALUE_MASK      = UINT8_C(0b0000'0001U);
+  constexpr uint8_t START_TIMER_UNIT_MASK = UINT8_C(0b0001'1000U);
+  constexpr uint8_t START_TIMER_ID_MASK   = UINT8_C(0b0000'0111U);
+  constexpr uint8_t IF_TIMER_CHECK_MASK   = UINT8_C(0b0000'0001U);
+  constexpr uint8_t IF_FLAG_IS_WAS_MASK   = UINT8_C(0b0000'0010U);
+  constexpr uint8_t IF_FLAG_VALUE_MASK    = UINT8_C(0b0000'0001U);
+  constexpr uint8_t ON_TOGGLE_EDGE_MASK   = UINT8_C(0b0010'0000U);
+  constexpr uint8_t ON_TOGGLE_PIN_MASK    = UINT8_C(0b0001'1111U);
 
   constexpr uint8_t SINGLE_BYTE_MASK   = UINT8_C(0b0111'1111U);
   constexpr uint8_t DUAL_BYTE_MASK     = UINT8_C(0b0011'1111U);
@@ -31,6 +38,8 @@ namespace StandaertHA::Shal::Bytecode {
   constexpr uint8_t SINGLE_BYTE_PREFIX = UINT8_C(0b0000'0000U);
   constexpr uint8_t FIRST_BYTE_PREFIX  = UINT8_C(0b1000'0000U);
   constexpr uint8_t SECOND_BYTE_PREFIX = UINT8_C(0b1100'0000U);
+  // First byte of ON TOGGLE, which has no room for an opcode
+  constexpr uint8_t COMPACT_BYTE_PREFIX = UINT8_C(0b0100'0000U);
 
   constexpr uint8_t INSTR_END          = UINT8_C(0b0000'0000U);
   constexpr uint8_t INSTR_AND          = UINT8_C(0b0000'0001U);
@@ -43,19 +52,52 @@ namespace StandaertHA::Shal::Bytecode {
   constexpr uint8_t INSTR_TOGGLE       = UINT8_C(0b0000'0010U);
   constexpr uint8_t INSTR_ON           = UINT8_C(0b0000'0100U);
   constexpr uint8_t INSTR_IF           = UINT8_C(0b0000'1000U);
+  constexpr uint8_t INSTR_IF_TIMER     = UINT8_C(0b0001'0000U);
+  constexpr uint8_t INSTR_CANCEL_TIMER = UINT8_C(0b0001'0010U);
+  constexpr uint8_t INSTR_START_TIMER  = UINT8_C(0b0010'0000U);
+  constexpr uint8_t INSTR_SET_FLAG     = UINT8_C(0b0001'0100U);
+  constexpr uint8_t INSTR_TOGGLE_FLAG  = UINT8_C(0b0001'0110U);
+  constexpr uint8_t INSTR_IF_FLAG      = UINT8_C(0b0001'1000U);
 
   constexpr uint8_t INSTR_SET_MASK     = DUAL_BYTE_MASK & ~SET_VALUE_MASK;
   constexpr uint8_t INSTR_TOGGLE_MASK  = DUAL_BYTE_MASK;
   constexpr uint8_t INSTR_ON_MASK      = DUAL_BYTE_MASK & ~ON_EDGE_MASK;
   constexpr uint8_t INSTR_IF_MASK      = DUAL_BYTE_MASK & ~IF_IS_WAS_MASK & ~IF_IO_MASK & ~IF_VALUE_MASK;
+  constexpr uint8_t INSTR_IF_TIMER_MASK     = DUAL_BYTE_MASK & ~IF_TIMER_CHECK_MASK;
+  constexpr uint8_t INSTR_CANCEL_TIMER_MASK = DUAL_BYTE_MASK;
+  constexpr uint8_t INSTR_START_TIMER_MASK  = DUAL_BYTE_MASK & ~START_TIMER_UNIT_MASK & ~START_TIMER_ID_MASK;
+  constexpr uint8_t INSTR_SET_FLAG_MASK     = DUAL_BYTE_MASK & ~SET_VALUE_MASK;
+  constexpr uint8_t INSTR_TOGGLE_FLAG_MASK  = DUAL_BYTE_MASK;
+  constexpr uint8_t INSTR_IF_FLAG_MASK      = DUAL_BYTE_MASK & ~IF_FLAG_IS_WAS_MASK & ~IF_FLAG_VALUE_MASK;
 
   static_assert(INSTR_SET_MASK    == UINT8_C(0b0011'1110));
   static_assert(INSTR_TOGGLE_MASK == UINT8_C(0b0011'1111));
   static_assert(INSTR_ON_MASK     == UINT8_C(0b0011'1110));
   static_assert(INSTR_IF_MASK     == UINT8_C(0b0011'1000));
+  static_assert(INSTR_IF_TIMER_MASK     == UINT8_C(0b0011'1110));
+  static_assert(INSTR_CANCEL_TIMER_MASK == UINT8_C(0b0011'1111));
+  static_assert(INSTR_START_TIMER_MASK  == UINT8_C(0b0010'0000));
+  static_assert(INSTR_SET_FLAG_MASK     == UINT8_C(0b0011'1110));
+  static_assert(INSTR_TOGGLE_FLAG_MASK  == UINT8_C(0b0011'1111));
+  static_assert(INSTR_IF_FLAG_MASK      == UINT8_C(0b0011'1100));
+
+  constexpr uint8_t NB_TIMERS = 8U;
+
+  // Duration of one unit of a START TIMER instruction, in milliseconds,
+  // indexed by the unit bits (tenths of a second, seconds, minutes, hours)
+  constexpr unsigned long TIMER_UNIT_MILLIS[] = {
+    100UL,
+    1000UL,
+    60UL * 1000UL,
+    60UL * 60UL * 1000UL,
+  };
 
   constexpr bool is_single_byte(uint8_t byte) {
-    return (byte & FIRST_BYTE_PREFIX) != FIRST_BYTE_PREFIX;
+    return (byte & SECOND_BYTE_PREFIX) == SINGLE_BYTE_PREFIX;
+  }
+
+  constexpr bool is_compact_byte(uint8_t byte) {
+    return (byte & SECOND_BYTE_PREFIX) == COMPACT_BYTE_PREFIX;
   }
 
   constexpr bool is_dual_byte(uint8_t byte) {
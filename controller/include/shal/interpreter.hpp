@@ -25,10 +25,50 @@
 
 namespace StandaertHA::Shal::Interpreter {
 
-  constexpr const uint8_t PROGRAM_HEADER_SIZE = 8U;
+  constexpr const uint8_t PROGRAM_HEADER_SIZE = 10U;
+
+  // Bytecode format versions this controller can run
+  constexpr const uint8_t MIN_FORMAT_VERSION = 1U;
+  constexpr const uint8_t MAX_FORMAT_VERSION = 1U;
+
+  // Features a program may use, set in the header
+  constexpr const uint8_t FEATURE_TIMERS = 0b0000'0001U;
+  constexpr const uint8_t FEATURE_FLAGS = 0b0000'0010U;
+  constexpr const uint8_t FEATURE_ON_TOGGLE = 0b0000'0100U;
+  constexpr const uint8_t SUPPORTED_FEATURES = FEATURE_TIMERS | FEATURE_FLAGS | FEATURE_ON_TOGGLE;
 
   class Program;
 
+  /**
+   * Timer state, kept across cycles
+   */
+  class Timers {
+  public:
+    // Stops the timers whose deadline has passed,
+    // and marks them as elapsed for this cycle
+    void update(unsigned long now) noexcept;
+
+    void start(uint8_t timer, unsigned long now, unsigned long duration) noexcept;
+    void cancel(uint8_t timer) noexcept;
+    void clear() noexcept;
+
+    [[nodiscard]] bool running(uint8_t timer) const noexcept
+    {
+      return (running_ & (UINT8_C(1) << timer)) != 0U;
+    }
+
+    [[nodiscard]] bool elapsed(uint8_t timer) const noexcept
+    {
+      return (elapsed_ & (UINT8_C(1) << timer)) != 0U;
+    }
+
+  private:
+    unsigned long start_[Bytecode::NB_TIMERS] = {};
+    unsigned long duration_[Bytecode::NB_TIMERS] = {};
+    uint8_t running_ = 0U;
+    uint8_t elapsed_ = 0U;
+  };
+
   class VmContext {
   private:
     using BitSet32 = Collections::BitSet32;
@@ -38,16 +78,27 @@ namespace StandaertHA::Shal::Interpreter {
     const BitSet32& new_input_;
     const BitSet32& old_output_;
     BitSet32 new_output_;
+    const BitSet32& old_flags_;
+    BitSet32 new_flags_;
     BitStack32 stack_;
+    Timers& timers_;
+    const unsigned long now_;
 
   public:
     VmContext(const BitSet32& old_input,
               const BitSet32& new_input,
-              const BitSet32& old_output)
+              const BitSet32& old_output,
+              const BitSet32& old_flags,
+              Timers& timers,
+              unsigned long now)
       : old_input_(old_input),
         new_input_(new_input),
         old_output_(old_output),
-        new_output_(old_output)
+        new_output_(old_output),
+        old_flags_(old_flags),
+        new_flags_(old_flags),
+        timers_(timers),
+        now_(now)
     { }
 
     VmContext(const VmContext&) = delete;
@@ -62,6 +113,11 @@ namespace StandaertHA::Shal::Interpreter {
       return new_output_;
     }
 
+    [[nodiscard]] const BitSet32& new_flags() const noexcept
+    {
+      return new_flags_;
+    }
+
     // Execute one cycle of the program,
     // returns false if it failed to execute
     // properly for some reason
@@ -86,6 +142,7 @@ namespace StandaertHA::Shal::Interpreter {
     enum class InOut {
       Input,
       Output,
+      Flag,
     };
 
 
@@ -97,8 +154,14 @@ namespace StandaertHA::Shal::Interpreter {
 
     void instrSet(uint8_t output, Value value) noexcept;
     void instrToggle(uint8_t output) noexcept;
+    void instrSetFlag(uint8_t flag, Value value) noexcept;
+    void instrToggleFlag(uint8_t flag) noexcept;
     void instrOn(Edge edge, uint8_t input) noexcept;
     void instrIf(InOut inOut, IsWas isWas, uint8_t n, Value value) noexcept;
+    void instrStartTimer(uint8_t timer, uint8_t unit, uint8_t value) noexcept;
+    void instrCancelTimer(uint8_t timer) noexcept;
+    void instrIfTimer(uint8_t timer, bool running) noexcept;
+    void instrOnToggle(Edge edge, uint8_t input, uint8_t output) noexcept;
   };
 
   // EEPROM size is defined for Nano Every,
@@ -120,16 +183,28 @@ namespace StandaertHA::Shal::Interpreter {
     {}
 
     [[nodiscard]] const Magic& magic() const { return magic_; }
+    [[nodiscard]] uint8_t version() const { return version_; }
+    [[nodiscard]] uint8_t features() const { return features_; }
     [[nodiscard]] uint16_t length() const { return Util::Inet::ntohs(length_); }
     [[nodiscard]] uint16_t crc() const { return Util::Inet::ntohs(crc_); }
 
+    // Whether this controller can run a program with this header
+    [[nodiscard]] bool is_supported() const
+    {
+      return version_ >= MIN_FORMAT_VERSION &&
+             version_ <= MAX_FORMAT_VERSION &&
+             (features_ & ~SUPPORTED_FEATURES) == 0;
+    }
+
   private:
     Magic magic_ = {'S', 'H', 'A', 'L'};
+    uint8_t version_ = MAX_FORMAT_VERSION;
+    uint8_t features_ = UINT8_C(0);
     uint16_t length_ = UINT16_C(0);
     uint16_t crc_ = UINT16_C(0);
   } __attribute__((packed));
 
-  static_assert(sizeof(ProgramHeader) == 8);
+  static_assert(sizeof(ProgramHeader) == PROGRAM_HEADER_SIZE);
 
   constexpr uint16_t MAX_CODE_SIZE = PROGRAM_SIZE - sizeof(ProgramHeader);
 
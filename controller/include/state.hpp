@@ -18,6 +18,7 @@
 #include "comm/message.hpp"
 #include "comm/serial.hpp"
 #include "hal/io.hpp"
+#include "hal/mode.hpp"
 #include "shal/interpreter.hpp"
 
 namespace StandaertHA {
@@ -31,6 +32,11 @@ namespace StandaertHA {
      */
     Collections::BitSet32 output{0};
 
+    /**
+     * Flags of the program, as an array of 32 bits, 1 for HIGH, 0 for LOW
+     */
+    Collections::BitSet32 flags{0};
+
     /**
      * Serial state (input buffer)
      */
@@ -81,6 +87,11 @@ namespace StandaertHA {
       unsigned long timestamps[HAL::IO::NB_INPUTS];
     } input;
 
+    /**
+     * Whether the MODE switch enables the program, read every loop
+     */
+    Mode mode = Mode::PROGRAM_DISABLED;
+
     /**
      * Need to send full state on next loop (refresh)
      */
@@ -95,13 +106,22 @@ namespace StandaertHA {
 
     Shal::Interpreter::Program program;
 
+    /**
+     * Timers used by the program
+     */
+    Shal::Interpreter::Timers timers;
+
     void handle_message() noexcept;
     [[nodiscard]] bool run_program() noexcept;
     void update_outputs(const Collections::BitSet32& output_before) const noexcept;
-    void send_update(const Collections::BitSet32& output_before) noexcept;
+    void send_update(const Collections::BitSet32& output_before,
+                     const Collections::BitSet32& flags_before,
+                     Mode mode_before) noexcept;
 
   private:
     void handle_command_message() noexcept;
+    void handle_set_outputs_message() noexcept;
+    void handle_set_flags_message() noexcept;
     void handle_program_message() noexcept;
     void receive_program_data() noexcept;
     void abort_upload() noexcept;
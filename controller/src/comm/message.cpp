@@ -50,6 +50,16 @@ Message::Message(const UpdateMsg& update, uint8_t event_count) noexcept
   crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
 }
 
+Message::Message(const FullUpdateMsg& full_update, uint8_t event_count) noexcept
+  : body_{
+      .full_update = full_update,
+    },
+    type_(MessageType::FullUpdate),
+    body_length_(event_count + sizeof(full_update.outputs) + sizeof(full_update.inputs))
+{
+  crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
+}
+
 Message::Message(const CommandMsg& command, uint8_t command_count) noexcept
   : body_{
       .command = command,
@@ -60,6 +70,16 @@ Message::Message(const CommandMsg& command, uint8_t command_count) noexcept
   crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
 }
 
+Message::Message(const FlagsMsg& flags) noexcept
+  : body_{
+      .flags = flags,
+    },
+    type_(MessageType::Flags),
+    body_length_(sizeof(FlagsMsg))
+{
+  crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
+}
+
 Message::Message(const FailMsg& fail_msg, uint8_t size) noexcept
   : body_{
       .fail_msg = fail_msg,
@@ -80,6 +100,26 @@ Message::Message(const InfoMsg& info_msg, uint8_t size) noexcept
   crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
 }
 
+Message::Message(const CapabilitiesMsg& capabilities) noexcept
+  : body_{
+      .capabilities = capabilities,
+    },
+    type_(MessageType::Capabilities),
+    body_length_(sizeof(CapabilitiesMsg))
+{
+  crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
+}
+
+Message::Message(const StatusMsg& status) noexcept
+  : body_{
+      .status = status,
+    },
+    type_(MessageType::Status),
+    body_length_(sizeof(StatusMsg))
+{
+  crc_ = calc_crc(); // NOLINT(*-prefer-member-initializer)
+}
+
 Message::Message(const ProgramStart& program_start) noexcept
   : body_{
       .program_start = program_start,
@@ -156,6 +196,16 @@ Message Message::from_buffer(const uint8_t *buffer, uint8_t size) noexcept
       type == static_cast<uint8_t>(MessageType::ProgramStartAck) ||
       type == static_cast<uint8_t>(MessageType::ProgramEndAck)) {
     if (size - MESSAGE_HEADER_LENGTH != Shal::Interpreter::PROGRAM_HEADER_SIZE) {
+      // Length is not exactly 10 bytes?
+      return result;
+    }
+  } else if (type == static_cast<uint8_t>(MessageType::SetOutputs)) {
+    if (size - MESSAGE_HEADER_LENGTH != sizeof(SetOutputsMsg)) {
+      // Length is not exactly 8 bytes?
+      return result;
+    }
+  } else if (type == static_cast<uint8_t>(MessageType::SetFlags)) {
+    if (size - MESSAGE_HEADER_LENGTH != sizeof(SetFlagsMsg)) {
       // Length is not exactly 8 bytes?
       return result;
     }
@@ -15,11 +15,13 @@
 
 #include "comm/serial.hpp"
 
+#include "messages.hpp"
 #include "state.hpp"
 #include "hal/io.hpp"
 #include "util/slip.hpp"
 
 #include <Arduino.h>
+#include <stdlib.h>
 
 namespace StandaertHA::Comm::Serial {
 
@@ -95,8 +97,9 @@ namespace StandaertHA::Comm::Serial {
 
   void send_update(const State& state) noexcept
   {
-    Comm::UpdateMsg update_msg;
+    Comm::FullUpdateMsg update_msg;
     update_msg.outputs = Util::Inet::htonl(state.output.value());
+    update_msg.inputs = Util::Inet::htonl(state.input.current.value());
     uint8_t event_count = 0;
     for (uint8_t i = 0; i < HAL::IO::NB_INPUTS; ++i) {
       // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
@@ -117,6 +120,35 @@ namespace StandaertHA::Comm::Serial {
     send(message);
   }
 
+  void send_flags(const State& state) noexcept
+  {
+    Comm::FlagsMsg flags_msg;
+    flags_msg.flags = Util::Inet::htonl(state.flags.value());
+
+    Comm::Message message(flags_msg);
+    send(message);
+  }
+
+  void send_capabilities() noexcept
+  {
+    Comm::CapabilitiesMsg capabilities_msg;
+    capabilities_msg.min_version = Shal::Interpreter::MIN_FORMAT_VERSION;
+    capabilities_msg.max_version = Shal::Interpreter::MAX_FORMAT_VERSION;
+    capabilities_msg.features = Shal::Interpreter::SUPPORTED_FEATURES;
+
+    Comm::Message message(capabilities_msg);
+    send(message);
+  }
+
+  void send_status(const State& state) noexcept
+  {
+    Comm::StatusMsg status_msg;
+    status_msg.status = state.mode == Mode::PROGRAM_ENABLED ? Comm::STATUS_PROGRAM_ENABLED : 0U;
+
+    Comm::Message message(status_msg);
+    send(message);
+  }
+
   void send_program_start_ack(const Shal::Interpreter::ProgramHeader& header) noexcept
   {
     Comm::ProgramStartAck program_start_ack;
@@ -148,6 +180,30 @@ namespace StandaertHA::Comm::Serial {
     send(message);
   }
 
+  void send_error(const char * const error_message, const size_t size, const uint16_t offset) noexcept
+  {
+    Comm::FailMsg fail_msg{};
+    size_t i = 0;
+    for (; i < size && i < sizeof(fail_msg.message); ++i) {
+      // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+      fail_msg.message[i] = pgm_read_byte_near(error_message + i);
+    }
+    for (size_t j = 0; j < sizeof(Messages::AT_BYTE) - 1 && i < sizeof(fail_msg.message); ++i, ++j) {
+      // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+      fail_msg.message[i] = pgm_read_byte_near(Messages::AT_BYTE + j);
+    }
+    // Enough for the largest uint16_t and the terminating NUL
+    char digits[6] = {};
+    utoa(offset, digits, 10);
+    for (size_t j = 0; digits[j] != '\0' && i < sizeof(fail_msg.message); ++i, ++j) {
+      // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+      fail_msg.message[i] = digits[j];
+    }
+
+    Comm::Message message(fail_msg, i);
+    send(message);
+  }
+
   void send_info(const char * const info_message, const size_t size) noexcept
   {
     Comm::InfoMsg info_msg{};
@@ -80,6 +80,8 @@ void loop() {
   using namespace StandaertHA;
 
   const Collections::BitSet32 output_before = state.output;
+  const Collections::BitSet32 flags_before = state.flags;
+  const Mode mode_before = state.mode;
 
   if (Comm::Serial::receive(state)) {
     state.handle_message();
@@ -88,5 +90,5 @@ void loop() {
   bool success = state.run_program();
   digitalWrite(LED_BUILTIN, success ? LOW : HIGH);
   state.update_outputs(output_before);
-  state.send_update(output_before);
+  state.send_update(output_before, flags_before, mode_before);
 }
@@ -28,6 +28,7 @@ namespace StandaertHA::Shal::Interpreter {
   {
     using namespace Bytecode;
     new_output_ = old_output_;
+    new_flags_ = old_flags_;
     uint8_t prevByte = INSTR_END;
     const uint8_t* code = program.code();
     for (uint16_t i = UINT16_C(0); i < program.header().length(); ++i) {
@@ -54,13 +55,28 @@ namespace StandaertHA::Shal::Interpreter {
             break;
           default:
             new_output_ = old_output_;
-            Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION);
+            new_flags_ = old_flags_;
+            Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION, i);
             return false;
         }
+      } else if (is_second_byte(byte) && is_compact_byte(prevByte)) {
+        const uint8_t output = byte & DUAL_BYTE_MASK;
+        if (output > ON_TOGGLE_PIN_MASK) {
+          new_output_ = old_output_;
+          new_flags_ = old_flags_;
+          Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION, i);
+          return false;
+        }
+        instrOnToggle(
+          (prevByte & ON_TOGGLE_EDGE_MASK) != 0 ? Edge::Rising : Edge::Falling,
+          prevByte & ON_TOGGLE_PIN_MASK,
+          output
+        );
       } else if (is_second_byte(byte)) {
         if (!is_first_byte(prevByte)) {
           new_output_ = old_output_;
-          Comm::Serial::send_error(Messages::PREVIOUS_BYTE_ERROR);
+          new_flags_ = old_flags_;
+          Comm::Serial::send_error(Messages::PREVIOUS_BYTE_ERROR, i);
           return false;
         }
         const uint8_t value = byte & DUAL_BYTE_MASK;
@@ -86,19 +102,53 @@ namespace StandaertHA::Shal::Interpreter {
             value,
             (instr & IF_VALUE_MASK) != 0 ? Value::High : Value::Low
           );
+        } else if ((instr & INSTR_START_TIMER_MASK) == INSTR_START_TIMER) {
+          instrStartTimer(
+            instr & START_TIMER_ID_MASK,
+            (instr & START_TIMER_UNIT_MASK) >> 3U,
+            value
+          );
+        } else if ((instr & INSTR_CANCEL_TIMER_MASK) == INSTR_CANCEL_TIMER && value < NB_TIMERS) {
+          instrCancelTimer(
+            value
+          );
+        } else if ((instr & INSTR_SET_FLAG_MASK) == INSTR_SET_FLAG) {
+          instrSetFlag(
+            value,
+            (instr & SET_VALUE_MASK) != 0 ? Value::High : Value::Low
+          );
+        } else if ((instr & INSTR_TOGGLE_FLAG_MASK) == INSTR_TOGGLE_FLAG) {
+          instrToggleFlag(
+            value
+          );
+        } else if ((instr & INSTR_IF_FLAG_MASK) == INSTR_IF_FLAG) {
+          instrIf(
+            InOut::Flag,
+            (instr & IF_FLAG_IS_WAS_MASK) != 0 ? IsWas::Is : IsWas::Was,
+            value,
+            (instr & IF_FLAG_VALUE_MASK) != 0 ? Value::High : Value::Low
+          );
+        } else if ((instr & INSTR_IF_TIMER_MASK) == INSTR_IF_TIMER && value < NB_TIMERS) {
+          instrIfTimer(
+            value,
+            (instr & IF_TIMER_CHECK_MASK) != 0
+          );
         } else {
           new_output_ = old_output_;
-          Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION);
+          new_flags_ = old_flags_;
+          Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION, i);
           return false;
         }
-      } else if (!is_first_byte(byte)) {
+      } else if (!is_first_byte(byte) && !is_compact_byte(byte)) {
         new_output_ = old_output_;
-        Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION);
+        new_flags_ = old_flags_;
+        Comm::Serial::send_error(Messages::UNKNOWN_INSTRUCTION, i);
         return false;
       }
       prevByte = byte;
     }
     new_output_ = old_output_;
+    new_flags_ = old_flags_;
     Comm::Serial::send_error(Messages::END_OF_PROGRAM);
     return false;
   }
@@ -152,6 +202,23 @@ namespace StandaertHA::Shal::Interpreter {
     new_output_.set(output, !old);
   }
 
+  void VmContext::instrSetFlag(uint8_t flag, Value value) noexcept
+  {
+    if (!stack_.all_one()) {
+      return;
+    }
+    new_flags_.set(flag, value == Value::High);
+  }
+
+  void VmContext::instrToggleFlag(uint8_t flag) noexcept
+  {
+    if (!stack_.all_one()) {
+      return;
+    }
+    auto old = new_flags_.get(flag);
+    new_flags_.set(flag, !old);
+  }
+
   void VmContext::instrOn(Edge edge, uint8_t input) noexcept
   {
     switch (edge) {
@@ -180,14 +247,21 @@ namespace StandaertHA::Shal::Interpreter {
         // isWas == IsWas::Is
         set = &new_input_;
       }
-    } else {
-      // inOut == InOut::Output
+    } else if (inOut == InOut::Output) {
       if (isWas == IsWas::Was) {
         set = &old_output_;
       } else {
         // isWas == IsWas::Is
         set = &new_output_;
       }
+    } else {
+      // inOut == InOut::Flag
+      if (isWas == IsWas::Was) {
+        set = &old_flags_;
+      } else {
+        // isWas == IsWas::Is
+        set = &new_flags_;
+      }
     }
     if (value == Value::Low) {
       auto b = !set->get(n);
@@ -199,6 +273,72 @@ namespace StandaertHA::Shal::Interpreter {
     }
   }
 
+  void VmContext::instrStartTimer(uint8_t timer, uint8_t unit, uint8_t value) noexcept
+  {
+    if (!stack_.all_one()) {
+      return;
+    }
+    // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+    timers_.start(timer, now_, value * Bytecode::TIMER_UNIT_MILLIS[unit]);
+  }
+
+  void VmContext::instrCancelTimer(uint8_t timer) noexcept
+  {
+    if (!stack_.all_one()) {
+      return;
+    }
+    timers_.cancel(timer);
+  }
+
+  void VmContext::instrIfTimer(uint8_t timer, bool running) noexcept
+  {
+    if (running) {
+      stack_.push(timers_.running(timer));
+    } else {
+      stack_.push(timers_.elapsed(timer));
+    }
+  }
+
+  void VmContext::instrOnToggle(Edge edge, uint8_t input, uint8_t output) noexcept
+  {
+    instrOn(edge, input);
+    instrToggle(output);
+    instrPop();
+  }
+
+  void Timers::update(unsigned long now) noexcept
+  {
+    elapsed_ = 0U;
+    for (uint8_t i = 0U; i < Bytecode::NB_TIMERS; ++i) {
+      // Unsigned subtraction, so this keeps working when millis() wraps around
+      // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+      if (running(i) && now - start_[i] >= duration_[i]) {
+        running_ &= ~(UINT8_C(1) << i);
+        elapsed_ |= UINT8_C(1) << i;
+      }
+    }
+  }
+
+  void Timers::start(uint8_t timer, unsigned long now, unsigned long duration) noexcept
+  {
+    // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+    start_[timer] = now;
+    // NOLINTNEXTLINE(cppcoreguidelines-pro-bounds-constant-array-index)
+    duration_[timer] = duration;
+    running_ |= UINT8_C(1) << timer;
+  }
+
+  void Timers::cancel(uint8_t timer) noexcept
+  {
+    running_ &= ~(UINT8_C(1) << timer);
+  }
+
+  void Timers::clear() noexcept
+  {
+    running_ = 0U;
+    elapsed_ = 0U;
+  }
+
   Program::Program()
   {
     clear();
@@ -216,6 +356,11 @@ namespace StandaertHA::Shal::Interpreter {
       return false;
     }
 
+    if (!header_.is_supported()) {
+      // Unknown format version or features
+      return false;
+    }
+
     if (header_.length() > sizeof(code_)) {
       // Length too long
       return false;
@@ -253,6 +398,12 @@ namespace StandaertHA::Shal::Interpreter {
       return false;
     }
 
+    if (!header_.is_supported()) {
+      // Saved by a firmware with a different format
+      clear();
+      return false;
+    }
+
     if (header_.length() > sizeof(code_)) {
       clear();
       return false;
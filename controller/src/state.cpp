@@ -18,6 +18,9 @@
 #include "messages.hpp"
 
 #include "hal/mode.hpp"
+#include "util/inet.hpp"
+
+#include <Arduino.h>
 
 namespace StandaertHA {
 
@@ -28,6 +31,14 @@ namespace StandaertHA {
         handle_command_message();
       }
         break;
+      case Comm::MessageType::SetOutputs: {
+        handle_set_outputs_message();
+      }
+        break;
+      case Comm::MessageType::SetFlags: {
+        handle_set_flags_message();
+      }
+        break;
       case Comm::MessageType::ProgramStart:
       case Comm::MessageType::ProgramData:
       case Comm::MessageType::ProgramEnd:
@@ -41,7 +52,8 @@ namespace StandaertHA {
 
   bool State::run_program() noexcept
   {
-    if (HAL::read_mode() == Mode::PROGRAM_DISABLED) {
+    mode = HAL::read_mode();
+    if (mode == Mode::PROGRAM_DISABLED) {
       // Program is disabled
       return true;
     }
@@ -52,9 +64,13 @@ namespace StandaertHA {
     const Collections::BitSet32 input_old(input.previous);
     const Collections::BitSet32 input_new(input.current);
     const Collections::BitSet32 output_old(output);
-    Shal::Interpreter::VmContext vmContext(input_old, input_new, output_old);
+    const Collections::BitSet32 flags_old(flags);
+    const unsigned long now = millis();
+    timers.update(now);
+    Shal::Interpreter::VmContext vmContext(input_old, input_new, output_old, flags_old, timers, now);
     bool success = vmContext.run(program);
     output = vmContext.new_output();
+    flags = vmContext.new_flags();
     return success;
   }
 
@@ -65,7 +81,9 @@ namespace StandaertHA {
     }
   }
 
-  void State::send_update(const Collections::BitSet32 &output_before) noexcept
+  void State::send_update(const Collections::BitSet32 &output_before,
+                          const Collections::BitSet32 &flags_before,
+                          const Mode mode_before) noexcept
   {
     const bool refresh_requested = refresh;
     const bool input_changed = input.current != input.previous;
@@ -73,6 +91,15 @@ namespace StandaertHA {
     if (refresh_requested || input_changed || output_changed) {
       Comm::Serial::send_update(*this);
     }
+    if (refresh_requested || flags != flags_before) {
+      Comm::Serial::send_flags(*this);
+    }
+    if (refresh_requested || mode != mode_before) {
+      Comm::Serial::send_status(*this);
+    }
+    if (refresh_requested) {
+      Comm::Serial::send_capabilities();
+    }
     refresh = false;
   }
 
@@ -90,9 +117,30 @@ namespace StandaertHA {
     }
   }
 
+  void State::handle_set_outputs_message() noexcept
+  {
+    const auto& set_outputs_msg = message.body_as_set_outputs_msg();
+    const uint32_t mask = Util::Inet::ntohl(set_outputs_msg.mask);
+    const uint32_t values = Util::Inet::ntohl(set_outputs_msg.values);
+    output = Collections::BitSet32((output.value() & ~mask) | (values & mask));
+  }
+
+  void State::handle_set_flags_message() noexcept
+  {
+    const auto& set_flags_msg = message.body_as_set_flags_msg();
+    const uint32_t mask = Util::Inet::ntohl(set_flags_msg.mask);
+    const uint32_t values = Util::Inet::ntohl(set_flags_msg.values);
+    flags = Collections::BitSet32((flags.value() & ~mask) | (values & mask));
+  }
+
   void State::handle_program_message() noexcept {
     switch (message.type()) {
       case Comm::MessageType::ProgramStart: {
+        if (!message.body_as_program_start().header.is_supported()) {
+          // Don't start the upload, keep running the current program
+          Comm::Serial::send_error(Messages::UNSUPPORTED_PROGRAM_ERROR);
+          break;
+        }
         upload_state.uploading = true;
         upload_state.position = 0;
         memcpy(&program.header(), &message.body_as_program_start().header, sizeof(Shal::Interpreter::ProgramHeader));
@@ -167,6 +215,9 @@ namespace StandaertHA {
     }
     // Upload done, save to EEPROM
     program.save();
+    // Timers and flags of the old program mean nothing to the new one
+    timers.clear();
+    flags = Collections::BitSet32(0);
     Comm::Serial::send_program_end_ack(program.header());
   }
 
@@ -9,20 +9,24 @@ function shal_language() {
         keywords: [
             'if', 'else', 'on',
             'redge', 'fedge',
+            'longpress', 'doubleclick',
             'toggle', 'set',
-            'high', 'low'
+            'high', 'low', 'true', 'false',
+            'after', 'as', 'cancel', 'rule', 'include',
+            'timer', 'running', 'stopped'
         ],
 
         typeKeywords: [
-            'input', 'output', 'entity'
+            'input', 'output', 'flag', 'group', 'entity'
         ],
 
         operators: [
-            'or', 'and', 'is', 'was', 'xor', 'not'
+            'or', 'and', 'is', 'was', 'xor', 'not', 'any', 'all', 'of'
         ],
 
         brackets: [
-            ['{', '}', 'delimiter.curly']
+            ['{', '}', 'delimiter.curly'],
+            ['(', ')', 'delimiter.parenthesis']
         ],
 
         // The main tokenizer for our languages
@@ -42,7 +46,10 @@ function shal_language() {
                 {include: '@whitespace'},
 
                 // delimiters
-                [/[{}]/, '@brackets'],
+                [/[{}()]/, '@brackets'],
+
+                // include paths
+                [/"[^"\n]*"/, 'string'],
 
                 // assignment
                 [/=/, 'operator'],
@@ -51,7 +58,7 @@ function shal_language() {
                 [/[0-9]+/, 'number'],
 
                 // delimiter
-                [/[;]/, 'delimiter'],
+                [/[;,]/, 'delimiter'],
             ],
 
             whitespace: [